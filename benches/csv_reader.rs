@@ -0,0 +1,64 @@
+//! Rows per second of DefaultTransactionRequestsReader with the fast and the serde based parsing. Run with
+//! `cargo bench --bench csv_reader`.
+//!
+//! Baseline, 1M rows each, on a single core x86_64 Linux machine:
+//! - fast: 3.24M rows/s
+//! - serde: 1.74M rows/s
+
+use std::{io::Write, time::Duration};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use simple_payment_engine::transaction_requests_reader::{
+    CsvParsing, DefaultTransactionRequestsReader, TransactionRequestsReader,
+};
+use tempfile::NamedTempFile;
+
+const ROWS: u32 = 1_000_000;
+
+// Mostly deposits and withdrawals, with every tenth row a dispute without the amount
+fn generated_file() -> NamedTempFile {
+    let mut file = NamedTempFile::new().expect("Failed creating the input file");
+    writeln!(file, "type, client, tx, amount").unwrap();
+    for i in 1..=ROWS {
+        let client = i % 1000;
+        match i % 10 {
+            0 => writeln!(file, "dispute, {}, {},", client, i - 1),
+            1..=6 => writeln!(
+                file,
+                "deposit, {}, {}, {}.{:04}",
+                client,
+                i,
+                i % 997,
+                i % 10_000
+            ),
+            _ => writeln!(
+                file,
+                "withdrawal, {}, {}, {}.{:02}",
+                client,
+                i,
+                i % 13,
+                i % 100
+            ),
+        }
+        .unwrap();
+    }
+    file.flush().unwrap();
+    file
+}
+
+fn csv_reader(criterion: &mut Criterion) {
+    let file = generated_file();
+    let path = file.path().to_str().unwrap();
+    let mut group = criterion.benchmark_group("csv_reader");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(15));
+    for (name, parsing) in [("fast", CsvParsing::Fast), ("serde", CsvParsing::Serde)] {
+        let reader = DefaultTransactionRequestsReader::new(path).with_parsing(parsing);
+        group.bench_function(name, |bencher| bencher.iter(|| reader.read().count()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, csv_reader);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+//! Compares the serial processing with 4 shards on the DummyReader stream. Run with `cargo bench --bench sharding`.
+
+use std::time::Instant;
+
+use simple_payment_engine::{
+    customer_account_provider::InMemoryCustomerAccountProvider,
+    sharded_transactions_manager::ShardedTransactionsManager,
+    transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+    transaction_request::TransactionRequest,
+    transaction_requests_reader::{DummyReader, TransactionRequestsReader},
+    transactions_manager::DefaultTransactionsManager,
+};
+
+// DummyReader deposits everything to a single client, spreading them so the shards get some work
+fn requests() -> impl Iterator<Item = TransactionRequest> {
+    DummyReader::new(1_000_000)
+        .read()
+        .map(|request| TransactionRequest {
+            client_id: (request.transaction_id % 1000) as u16,
+            ..request
+        })
+}
+
+fn main() {
+    let start = Instant::now();
+    let mut serial_manager = DefaultTransactionsManager::new(
+        InMemoryTransactionHistoryProvider::new(),
+        InMemoryCustomerAccountProvider::new(),
+    );
+    serial_manager
+        .process_all(requests(), None)
+        .expect("Serial processing failed");
+    println!("serial: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let mut sharded_manager = ShardedTransactionsManager::new(4);
+    sharded_manager
+        .process_all(requests())
+        .expect("Sharded processing failed");
+    println!("4 shards: {:?}", start.elapsed());
+}
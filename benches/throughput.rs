@@ -0,0 +1,135 @@
+//! Transactions per second of DefaultTransactionsManager with the in-memory providers, and with the sled history
+//! provider. Run with
+//! `cargo bench --bench throughput`.
+//!
+//! Baseline, 1M requests each, on a single core x86_64 Linux machine:
+//! - deposits_to_one_client: 1.51M requests/s
+//! - deposits_to_many_clients: 0.78M requests/s
+//! - mixed_with_disputes: 0.89M requests/s
+//!
+//! The realistic mix of 30% withdrawals, 5% disputes and resolves and deposits, with 100k and 1M requests:
+//! - realistic_mix_100k: 1.10M requests/s
+//! - realistic_mix_1000k: 0.71M requests/s
+//!
+//! The sled history provider with 100k deposits, written one by one and in batches of 1024:
+//! - sled_deposits: 0.080M requests/s
+//! - sled_deposits_batched: 0.074M requests/s
+//!
+//! The write batches don't pay off with the bundled providers, as neither of them has a round trip per call to save.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use simple_payment_engine::{
+    customer_account_provider::InMemoryCustomerAccountProvider,
+    runner::run,
+    transaction_history_provider::{
+        in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        sled_transaction_history_provider::SledTransactionHistoryProvider,
+    },
+    transaction_requests_reader::{DummyReader, SyntheticReader, TransactionRequestsReader},
+    transactions_manager::DefaultTransactionsManager,
+};
+
+const REQUESTS: u32 = 1_000_000;
+// The client IDs are u16, so this is as close to 100k clients as the engine goes
+const CLIENTS: u16 = u16::MAX;
+const SEED: u64 = 42;
+
+// The realistic mix, the rest of the requests are deposits
+const WITHDRAWAL_PERCENT: u64 = 30;
+const DISPUTE_PERCENT: u64 = 5;
+const SMALL_REQUESTS: u32 = 100_000;
+
+// The sled runs are an order of magnitude slower, so they get fewer requests
+const SLED_REQUESTS: u32 = 100_000;
+// The batch size of the *_batched runs, see DefaultTransactionsManager::with_write_batch_size
+const WRITE_BATCH_SIZE: usize = 1024;
+
+fn in_memory_manager() -> DefaultTransactionsManager {
+    DefaultTransactionsManager::new(
+        InMemoryTransactionHistoryProvider::new(),
+        InMemoryCustomerAccountProvider::new(),
+    )
+}
+
+fn sled_manager() -> DefaultTransactionsManager {
+    DefaultTransactionsManager::new(
+        SledTransactionHistoryProvider::new_temporary().expect("Opening sled failed"),
+        InMemoryCustomerAccountProvider::new(),
+    )
+}
+
+fn bench_reader(
+    criterion: &mut Criterion,
+    name: &str,
+    reader: &dyn TransactionRequestsReader,
+    requests: u32,
+    new_manager: &dyn Fn() -> DefaultTransactionsManager,
+) {
+    let mut group = criterion.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(requests as u64));
+    // A single iteration takes around a second, the default 100 samples would take minutes
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(15));
+    group.bench_function(name, |bencher| {
+        bencher.iter_batched(
+            new_manager,
+            |mut manager| run(reader, &mut manager).expect("Processing failed"),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn throughput(criterion: &mut Criterion) {
+    bench_reader(
+        criterion,
+        "deposits_to_one_client",
+        &DummyReader::new(REQUESTS),
+        REQUESTS,
+        &in_memory_manager,
+    );
+    bench_reader(
+        criterion,
+        "deposits_to_many_clients",
+        &SyntheticReader::new(REQUESTS, CLIENTS, SEED),
+        REQUESTS,
+        &in_memory_manager,
+    );
+    bench_reader(
+        criterion,
+        "mixed_with_disputes",
+        &SyntheticReader::new(REQUESTS, CLIENTS, SEED).with_dispute_percent(10),
+        REQUESTS,
+        &in_memory_manager,
+    );
+    for requests in [SMALL_REQUESTS, REQUESTS] {
+        bench_reader(
+            criterion,
+            &format!("realistic_mix_{}k", requests / 1000),
+            &SyntheticReader::new(requests, CLIENTS, SEED)
+                .with_withdrawal_percent(WITHDRAWAL_PERCENT)
+                .with_dispute_percent(DISPUTE_PERCENT),
+            requests,
+            &in_memory_manager,
+        );
+    }
+    bench_reader(
+        criterion,
+        "sled_deposits",
+        &SyntheticReader::new(SLED_REQUESTS, CLIENTS, SEED),
+        SLED_REQUESTS,
+        &sled_manager,
+    );
+    bench_reader(
+        criterion,
+        "sled_deposits_batched",
+        &SyntheticReader::new(SLED_REQUESTS, CLIENTS, SEED),
+        SLED_REQUESTS,
+        &|| sled_manager().with_write_batch_size(WRITE_BATCH_SIZE),
+    );
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,196 @@
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+
+use crate::{
+    common_types::CustomerId,
+    customer_account_provider::{CustomerAccountProvider, CustomerAccountReport},
+    errors::PaymentEngineError,
+};
+
+// Async counterpart of the CustomerAccountProvider, for the providers backed by remote stores. Same as there, the
+// balances are kept per client and currency, while the lock applies to all the currencies of the client
+#[async_trait(?Send)]
+pub trait AsyncCustomerAccountProvider {
+    async fn get_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError>;
+    async fn get_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError>;
+    async fn get_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<Option<bool>, PaymentEngineError>;
+    async fn set_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    async fn set_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+        locked: bool,
+    ) -> Result<(), PaymentEngineError>;
+    async fn adjust_balances(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    async fn increment_charged_back(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    async fn add_deposited(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    async fn add_withdrawn(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    async fn adjust_open_disputes(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        delta: i32,
+    ) -> Result<(), PaymentEngineError>;
+    async fn set_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        timestamp: i64,
+    ) -> Result<(), PaymentEngineError>;
+    async fn list_accounts_sorted(&self) -> Result<Vec<CustomerAccountReport>, PaymentEngineError>;
+}
+
+// Lets the existing sync providers, e.g. the in-memory one, be used in the async path
+pub struct SyncCustomerAccountProviderAdapter<P: CustomerAccountProvider> {
+    inner: P,
+}
+
+impl<P: CustomerAccountProvider> SyncCustomerAccountProviderAdapter<P> {
+    pub fn new(inner: P) -> Self {
+        SyncCustomerAccountProviderAdapter { inner }
+    }
+}
+
+#[async_trait(?Send)]
+impl<P: CustomerAccountProvider> AsyncCustomerAccountProvider
+    for SyncCustomerAccountProviderAdapter<P>
+{
+    async fn get_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        self.inner.get_available(customer_id, currency)
+    }
+
+    async fn get_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        self.inner.get_held_amount(customer_id, currency)
+    }
+
+    async fn get_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<Option<bool>, PaymentEngineError> {
+        self.inner.get_locked_status(customer_id)
+    }
+
+    async fn set_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner.set_available(customer_id, currency, balance)
+    }
+
+    async fn set_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+        locked: bool,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner.set_locked_status(customer_id, locked)
+    }
+
+    async fn adjust_balances(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner
+            .adjust_balances(customer_id, currency, available_delta, held_delta)
+    }
+
+    async fn increment_charged_back(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner
+            .increment_charged_back(customer_id, currency, amount)
+    }
+
+    async fn add_deposited(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner.add_deposited(customer_id, currency, amount)
+    }
+
+    async fn add_withdrawn(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner.add_withdrawn(customer_id, currency, amount)
+    }
+
+    async fn adjust_open_disputes(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        delta: i32,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner
+            .adjust_open_disputes(customer_id, currency, delta)
+    }
+
+    async fn set_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        timestamp: i64,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner
+            .set_last_activity(customer_id, currency, timestamp)
+    }
+
+    async fn list_accounts_sorted(&self) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        self.inner.list_accounts_sorted()
+    }
+}
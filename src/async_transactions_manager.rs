@@ -0,0 +1,626 @@
+use std::io::Write;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    async_customer_account_provider::AsyncCustomerAccountProvider,
+    errors::PaymentEngineError,
+    logging::SKIPPED_REQUESTS_TARGET,
+    report_formatter::CsvReportFormatter,
+    transaction_history_provider::async_transaction_history_provider::AsyncTransactionHistoryProvider,
+    transaction_outcome::{ProcessingSummary, SkipReason, TransactionOutcome},
+    transaction_request::{TransactionRequest, TransactionType},
+    transactions_manager::{
+        duplicate_skip_reason, write_accounts, LockPolicy, ValidationConfig, DEFAULT_CURRENCY,
+        DEFAULT_MAX_DISPUTES, DEFAULT_SCALE,
+    },
+};
+
+use log::{error, info, warn};
+
+// Same rules as the DefaultTransactionsManager, but awaiting the providers, for the stores that can't be accessed synchronously.
+// The requests without a currency are always in the DEFAULT_CURRENCY
+pub struct AsyncTransactionsManager {
+    transaction_history_provider: Box<dyn AsyncTransactionHistoryProvider>,
+    customer_account_provider: Box<dyn AsyncCustomerAccountProvider>,
+    lock_policy: LockPolicy,
+    validation_config: ValidationConfig,
+    allow_redispute: bool,
+    max_disputes: Option<u32>,
+    summary: ProcessingSummary,
+}
+
+impl AsyncTransactionsManager {
+    pub fn new(
+        transaction_history_provider: impl AsyncTransactionHistoryProvider + 'static,
+        customer_account_provider: impl AsyncCustomerAccountProvider + 'static,
+    ) -> Self {
+        AsyncTransactionsManager {
+            transaction_history_provider: Box::new(transaction_history_provider),
+            customer_account_provider: Box::new(customer_account_provider),
+            lock_policy: LockPolicy::default(),
+            validation_config: ValidationConfig::default(),
+            allow_redispute: true,
+            max_disputes: Some(DEFAULT_MAX_DISPUTES),
+            summary: ProcessingSummary::default(),
+        }
+    }
+
+    pub fn with_lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    pub fn with_validation_config(mut self, validation_config: ValidationConfig) -> Self {
+        self.validation_config = validation_config;
+        self
+    }
+
+    pub fn with_allow_redispute(mut self, allow_redispute: bool) -> Self {
+        self.allow_redispute = allow_redispute;
+        self
+    }
+
+    pub fn with_max_disputes(mut self, max_disputes: Option<u32>) -> Self {
+        self.max_disputes = max_disputes;
+        self
+    }
+
+    // Counters of everything handled so far
+    pub fn summary(&self) -> &ProcessingSummary {
+        &self.summary
+    }
+
+    pub fn structure_validation(&self, transaction_request: &TransactionRequest) -> bool {
+        self.validation_config.is_valid(transaction_request)
+    }
+
+    // Validates and handles the requests one by one, stopping only on fatal errors
+    pub async fn process_all(
+        &mut self,
+        requests: impl Iterator<Item = TransactionRequest>,
+    ) -> Result<ProcessingSummary, PaymentEngineError> {
+        for request in requests {
+            if self.structure_validation(&request) {
+                match self.handle_transaction(request).await {
+                    Ok(TransactionOutcome::Executed) => {}
+                    Ok(TransactionOutcome::Skipped(reason)) => {
+                        info!(target: SKIPPED_REQUESTS_TARGET, "Request skipped: {:?}", reason)
+                    }
+                    Err(e) if e.is_fatal() => return Err(e),
+                    Err(e) => warn!(target: SKIPPED_REQUESTS_TARGET, "Request skipped: {}", e),
+                }
+            } else {
+                info!(
+                    target: SKIPPED_REQUESTS_TARGET,
+                    "Request with invalid structure skipped: {:?}",
+                    request
+                );
+                self.summary.record(
+                    &request.transaction_type,
+                    &Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure)),
+                );
+            }
+        }
+        Ok(self.summary.clone())
+    }
+
+    pub async fn handle_transaction(
+        &mut self,
+        mut transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        self.validation_config
+            .drop_ignored_amount(&mut transaction_request);
+        let transaction_type = transaction_request.transaction_type.clone();
+        let (client_id, timestamp) = (transaction_request.client_id, transaction_request.timestamp);
+        let currency = transaction_request.currency_or(DEFAULT_CURRENCY).to_owned();
+        // The callers may skip structure_validation, so the handlers never see e.g. a negative amount
+        let result = if !self.structure_validation(&transaction_request) {
+            Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure))
+        } else if self.is_blocked_by_lock(&transaction_request).await? {
+            info!(
+                "The account of customer {} is locked, skipping {:?} request.",
+                transaction_request.client_id, transaction_type
+            );
+            Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked))
+        } else {
+            match &transaction_type {
+                TransactionType::Deposit => self.deposit(transaction_request).await,
+                TransactionType::Withdrawal => self.withdraw(transaction_request).await,
+                TransactionType::Dispute => self.dispute(transaction_request).await,
+                TransactionType::Resolve | TransactionType::Chargeback => {
+                    self.settle_dispute(transaction_request).await
+                }
+                // Unlocking is only supported by the DefaultTransactionsManager
+                TransactionType::Unlock => {
+                    Ok(TransactionOutcome::Skipped(SkipReason::UnlockForbidden))
+                }
+            }
+        };
+        // Only the executed transactions count as activity of the client
+        let result = match (result, timestamp) {
+            (Ok(TransactionOutcome::Executed), Some(timestamp)) => self
+                .customer_account_provider
+                .set_last_activity(client_id, &currency, timestamp)
+                .await
+                .map(|()| TransactionOutcome::Executed),
+            (result, _) => result,
+        };
+        self.summary.record(&transaction_type, &result);
+        result
+    }
+
+    // Writes the CSV report of all accounts sorted by client into the given writer
+    pub async fn write_report(&self, writer: &mut dyn Write) -> Result<(), PaymentEngineError> {
+        write_accounts(
+            writer,
+            self.customer_account_provider
+                .list_accounts_sorted()
+                .await?
+                .into_iter(),
+            &CsvReportFormatter::default(),
+            DEFAULT_SCALE,
+            false,
+        )
+    }
+
+    // Withdrawals check the lock themselves, as they are blocked under any policy
+    async fn is_blocked_by_lock(
+        &mut self,
+        transaction_request: &TransactionRequest,
+    ) -> Result<bool, PaymentEngineError> {
+        if self.lock_policy != LockPolicy::BlockAll
+            || transaction_request.transaction_type == TransactionType::Withdrawal
+        {
+            return Ok(false);
+        }
+        Ok(self
+            .customer_account_provider
+            .get_locked_status(transaction_request.client_id)
+            .await?
+            .unwrap_or(false))
+    }
+
+    async fn deposit(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(stored) = self
+            .transaction_history_provider
+            .read_transaction(transaction_request.transaction_id)
+            .await?
+        {
+            return Ok(TransactionOutcome::Skipped(duplicate_skip_reason(
+                &stored,
+                &transaction_request,
+            )));
+        }
+        let existing_amount = self
+            .customer_account_provider
+            .get_available(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+            )
+            .await?
+            .unwrap_or(Decimal::ZERO);
+        let transaction_amount =
+            transaction_request
+                .amount
+                .ok_or(PaymentEngineError::AmountMissing(
+                    transaction_request.transaction_id,
+                ))?;
+        let new_amount = match existing_amount.checked_add(transaction_amount) {
+            Some(new_amount) => new_amount,
+            None => {
+                error!(
+                    "Depositing {} would overflow the available funds of customer {}, skipping",
+                    transaction_amount, transaction_request.client_id
+                );
+                return Ok(TransactionOutcome::Skipped(SkipReason::Overflow));
+            }
+        };
+        if let Some(skip_reason) = skip_reason_of(
+            self.customer_account_provider
+                .set_available(
+                    transaction_request.client_id,
+                    transaction_request.currency_or(DEFAULT_CURRENCY),
+                    new_amount,
+                )
+                .await,
+        )? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        self.customer_account_provider
+            .add_deposited(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+                transaction_amount,
+            )
+            .await?;
+        self.transaction_history_provider
+            .write_transaction(transaction_request)
+            .await?;
+        Ok(TransactionOutcome::Executed)
+    }
+
+    async fn withdraw(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(stored) = self
+            .transaction_history_provider
+            .read_transaction(transaction_request.transaction_id)
+            .await?
+        {
+            return Ok(TransactionOutcome::Skipped(duplicate_skip_reason(
+                &stored,
+                &transaction_request,
+            )));
+        }
+        if self
+            .customer_account_provider
+            .get_locked_status(transaction_request.client_id)
+            .await?
+            .unwrap_or(false)
+        {
+            info!(
+                "The account of customer {} is locked, skipping withdrawal request.",
+                transaction_request.client_id
+            );
+            return Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked));
+        }
+        let existing_amount = match self
+            .customer_account_provider
+            .get_available(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+            )
+            .await?
+        {
+            Some(existing_amount) => existing_amount,
+            None => {
+                info!(
+                    "The customer {} doesn't have any available funds, skipping the withdraw request.",
+                    transaction_request.client_id
+                );
+                return Ok(TransactionOutcome::Skipped(SkipReason::AccountNotFound));
+            }
+        };
+        let transaction_amount =
+            transaction_request
+                .amount
+                .ok_or(PaymentEngineError::AmountMissing(
+                    transaction_request.transaction_id,
+                ))?;
+        if existing_amount < transaction_amount {
+            info!(
+                "The customer {} doesn't have enough available funds to withdraw {}",
+                transaction_request.client_id, transaction_amount
+            );
+            return Ok(TransactionOutcome::Skipped(SkipReason::InsufficientFunds));
+        }
+        let new_amount = match existing_amount.checked_sub(transaction_amount) {
+            Some(new_amount) => new_amount,
+            None => return Ok(TransactionOutcome::Skipped(SkipReason::Overflow)),
+        };
+        if let Some(skip_reason) = skip_reason_of(
+            self.customer_account_provider
+                .set_available(
+                    transaction_request.client_id,
+                    transaction_request.currency_or(DEFAULT_CURRENCY),
+                    new_amount,
+                )
+                .await,
+        )? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        self.customer_account_provider
+            .add_withdrawn(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+                transaction_amount,
+            )
+            .await?;
+        self.transaction_history_provider
+            .write_transaction(transaction_request)
+            .await?;
+        Ok(TransactionOutcome::Executed)
+    }
+
+    async fn dispute(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        let disputed_transaction = match self
+            .transaction_history_provider
+            .read_transaction(transaction_request.transaction_id)
+            .await?
+        {
+            Some(disputed_transaction) => disputed_transaction,
+            None => return Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound)),
+        };
+        if disputed_transaction.client_id != transaction_request.client_id {
+            info!("Client ID of the disputed transaction doesn't match the client ID of the request, possibly malicious client");
+            return Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch));
+        }
+        if disputed_transaction.currency_or(DEFAULT_CURRENCY)
+            != transaction_request.currency_or(DEFAULT_CURRENCY)
+        {
+            return Ok(TransactionOutcome::Skipped(SkipReason::CurrencyMismatch));
+        }
+        let disputed_amount =
+            disputed_transaction
+                .amount
+                .ok_or(PaymentEngineError::AmountMissing(
+                    disputed_transaction.transaction_id,
+                ))?;
+        let disputed_transaction_state = self
+            .transaction_history_provider
+            .read_transaction_state(transaction_request.transaction_id)
+            .await?
+            .unwrap_or_default();
+        if disputed_transaction_state.held || disputed_transaction_state.charged_back {
+            info!(
+                "Transaction {} already on hold or charged back, not holding again",
+                transaction_request.transaction_id
+            );
+            return Ok(TransactionOutcome::Skipped(SkipReason::AlreadyDisputed));
+        }
+        if !self.allow_redispute && disputed_transaction_state.resolved {
+            info!(
+                "Transaction {} was already resolved, disputing it again is not allowed",
+                transaction_request.transaction_id
+            );
+            return Ok(TransactionOutcome::Skipped(SkipReason::RedisputeForbidden));
+        }
+        if let Some(max_disputes) = self.max_disputes {
+            if disputed_transaction_state.dispute_count >= max_disputes {
+                warn!(
+                    "Transaction {} was disputed {} times already, skipping the dispute",
+                    transaction_request.transaction_id, disputed_transaction_state.dispute_count
+                );
+                return Ok(TransactionOutcome::Skipped(SkipReason::DisputeLimitReached));
+            }
+        }
+        let available_change = match disputed_transaction.transaction_type {
+            TransactionType::Deposit => -disputed_amount,
+            TransactionType::Withdrawal => Decimal::ZERO,
+            _ => return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputable)),
+        };
+        if let Some(skip_reason) = skip_reason_of(
+            self.customer_account_provider
+                .adjust_balances(
+                    transaction_request.client_id,
+                    transaction_request.currency_or(DEFAULT_CURRENCY),
+                    available_change,
+                    disputed_amount,
+                )
+                .await,
+        )? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        self.customer_account_provider
+            .adjust_open_disputes(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+                1,
+            )
+            .await?;
+        let mut new_transaction_state = disputed_transaction_state;
+        new_transaction_state.held = true;
+        new_transaction_state.dispute_count += 1;
+        self.transaction_history_provider
+            .write_transaction_state(transaction_request.transaction_id, new_transaction_state)
+            .await?;
+        Ok(TransactionOutcome::Executed)
+    }
+
+    // Resolves and chargebacks only differ in where the held funds go and in locking the account
+    async fn settle_dispute(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        let is_chargeback = transaction_request.transaction_type == TransactionType::Chargeback;
+        let disputed_transaction = match self
+            .transaction_history_provider
+            .read_transaction(transaction_request.transaction_id)
+            .await?
+        {
+            Some(disputed_transaction) => disputed_transaction,
+            None => return Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound)),
+        };
+        if disputed_transaction.client_id != transaction_request.client_id {
+            info!("Client ID of the disputed transaction doesn't match the client ID of the request, possibly malicious client");
+            return Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch));
+        }
+        if disputed_transaction.currency_or(DEFAULT_CURRENCY)
+            != transaction_request.currency_or(DEFAULT_CURRENCY)
+        {
+            return Ok(TransactionOutcome::Skipped(SkipReason::CurrencyMismatch));
+        }
+        let disputed_amount =
+            disputed_transaction
+                .amount
+                .ok_or(PaymentEngineError::AmountMissing(
+                    disputed_transaction.transaction_id,
+                ))?;
+        let disputed_transaction_state = match self
+            .transaction_history_provider
+            .read_transaction_state(transaction_request.transaction_id)
+            .await?
+        {
+            Some(state) if state.held && !state.charged_back => state,
+            _ => {
+                info!(
+                    "Transaction {} is not on hold or was charged back, skipping the {:?} request",
+                    transaction_request.transaction_id, transaction_request.transaction_type
+                );
+                return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed));
+            }
+        };
+        // Same as in the DefaultTransactionsManager, funds not held for a held transaction fail the run
+        match self
+            .customer_account_provider
+            .get_held_amount(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+            )
+            .await?
+        {
+            Some(held_amount) if held_amount >= disputed_amount => {}
+            _ => {
+                return Err(PaymentEngineError::InvariantViolation(format!(
+                    "Transaction {} is on hold, but customer {} doesn't have its funds held",
+                    transaction_request.transaction_id, transaction_request.client_id
+                )))
+            }
+        }
+        // Resolved deposits and charged back withdrawals return the held amount to the available funds
+        let returns_funds = match disputed_transaction.transaction_type {
+            TransactionType::Deposit => !is_chargeback,
+            _ => is_chargeback,
+        };
+        let available_change = if returns_funds {
+            disputed_amount
+        } else {
+            Decimal::ZERO
+        };
+        if let Some(skip_reason) = skip_reason_of(
+            self.customer_account_provider
+                .adjust_balances(
+                    transaction_request.client_id,
+                    transaction_request.currency_or(DEFAULT_CURRENCY),
+                    available_change,
+                    -disputed_amount,
+                )
+                .await,
+        )? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        self.customer_account_provider
+            .adjust_open_disputes(
+                transaction_request.client_id,
+                transaction_request.currency_or(DEFAULT_CURRENCY),
+                -1,
+            )
+            .await?;
+        let mut new_transaction_state = disputed_transaction_state;
+        new_transaction_state.held = false;
+        if is_chargeback {
+            self.customer_account_provider
+                .increment_charged_back(
+                    transaction_request.client_id,
+                    transaction_request.currency_or(DEFAULT_CURRENCY),
+                    disputed_amount,
+                )
+                .await?;
+            self.customer_account_provider
+                .set_locked_status(transaction_request.client_id, true)
+                .await?;
+            new_transaction_state.charged_back = true;
+        } else {
+            new_transaction_state.resolved = true;
+        }
+        self.transaction_history_provider
+            .write_transaction_state(transaction_request.transaction_id, new_transaction_state)
+            .await?;
+        Ok(TransactionOutcome::Executed)
+    }
+}
+
+// Account provider failures that only affect the given request are skipped, the rest are propagated
+fn skip_reason_of(
+    result: Result<(), PaymentEngineError>,
+) -> Result<Option<SkipReason>, PaymentEngineError> {
+    match result {
+        Ok(()) => Ok(None),
+        Err(PaymentEngineError::AccountNotFound(_)) => Ok(Some(SkipReason::AccountNotFound)),
+        Err(PaymentEngineError::Overflow(_)) => Ok(Some(SkipReason::Overflow)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        async_customer_account_provider::SyncCustomerAccountProviderAdapter,
+        customer_account_provider::InMemoryCustomerAccountProvider,
+        transaction_history_provider::{
+            async_transaction_history_provider::SyncTransactionHistoryProviderAdapter,
+            in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        },
+        transaction_requests_reader::read_transaction_requests,
+    };
+    use rust_decimal::RoundingStrategy;
+    use std::{cell::Cell, io::Cursor, rc::Rc};
+
+    use super::*;
+
+    fn in_memory_manager() -> AsyncTransactionsManager {
+        AsyncTransactionsManager::new(
+            SyncTransactionHistoryProviderAdapter::new(InMemoryTransactionHistoryProvider::new()),
+            SyncCustomerAccountProviderAdapter::new(InMemoryCustomerAccountProvider::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn processes_a_csv_through_the_sync_providers() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10.0\n\
+                   deposit,2,2,5.0\n\
+                   withdrawal,1,3,4.0\n\
+                   withdrawal,2,4,6.0\n\
+                   dispute,1,1,\n\
+                   resolve,1,1,\n\
+                   dispute,2,2,\n\
+                   chargeback,2,2,\n";
+        let requests = read_transaction_requests(
+            Cursor::new(csv),
+            4,
+            RoundingStrategy::MidpointNearestEven,
+            Rc::new(Cell::new(0)),
+        );
+        let mut manager = in_memory_manager();
+        let summary = manager.process_all(requests).await.unwrap();
+        assert_eq!(summary.skipped_because(SkipReason::InsufficientFunds), 1);
+
+        let mut output = Vec::new();
+        manager.write_report(&mut output).await.unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n1,6,0,6,false,,USD,0,0\n2,0,0,0,true,,USD,5,0\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn chargeback_of_withdrawal_returns_the_funds() {
+        let mut manager = in_memory_manager();
+        for (transaction_type, transaction_id, amount) in [
+            (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+            (TransactionType::Withdrawal, 2, Some(Decimal::new(4, 0))),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Chargeback, 2, None),
+        ] {
+            let outcome = manager
+                .handle_transaction(TransactionRequest {
+                    transaction_type,
+                    client_id: 1,
+                    transaction_id,
+                    amount,
+                    timestamp: None,
+                    currency: None,
+                })
+                .await
+                .unwrap();
+            assert!(outcome.is_executed());
+        }
+        let accounts = manager
+            .customer_account_provider
+            .list_accounts_sorted()
+            .await
+            .unwrap();
+        assert_eq!(accounts[0].available, Decimal::new(10, 0));
+        assert_eq!(accounts[0].held, Decimal::ZERO);
+        assert!(accounts[0].locked);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    common_types::{Currency, CustomerId, TransactionId},
+    errors::PaymentEngineError,
+    transaction_outcome::TransactionOutcome,
+    transaction_request::TransactionType,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditField {
+    Available,
+    Held,
+    Locked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditValue {
+    Amount(Decimal),
+    Flag(bool),
+}
+
+impl Display for AuditValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditValue::Amount(amount) => write!(f, "{}", amount),
+            AuditValue::Flag(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+// Written as plain values, so the audit CSV has the same cell format as the report
+impl Serialize for AuditValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// A single change of an account field, with the transaction request that caused it. For the dispute-family
+// requests the transaction ID is the one of the disputed transaction, as that's what the requests reference.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    pub client: CustomerId,
+    pub field: AuditField,
+    pub old_value: AuditValue,
+    pub new_value: AuditValue,
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub currency: Currency,
+}
+
+pub trait AuditSink {
+    fn record(&mut self, event: AuditEvent) -> Result<(), PaymentEngineError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditDecision {
+    Executed,
+    Skipped,
+}
+
+// What the engine decided about a request, with the balances of the client in the currency of the request after it.
+// The requests failing with an error are recorded as skipped, with the error as the reason.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    pub client: CustomerId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub decision: AuditDecision,
+    // None for the executed requests
+    pub reason: Option<String>,
+    pub available_after: Decimal,
+    pub held_after: Decimal,
+}
+
+impl AuditDecision {
+    // The decision and the reason of the record
+    pub(crate) fn of(
+        result: &Result<TransactionOutcome, PaymentEngineError>,
+    ) -> (AuditDecision, Option<String>) {
+        match result {
+            Ok(TransactionOutcome::Executed) => (AuditDecision::Executed, None),
+            Ok(TransactionOutcome::Skipped(reason)) => {
+                (AuditDecision::Skipped, Some(reason.to_string()))
+            }
+            Err(e) => (AuditDecision::Skipped, Some(e.to_string())),
+        }
+    }
+}
+
+// Receives a record for every request the manager handles, while AuditSink only gets the changes of the accounts
+pub trait AuditRecordSink {
+    fn record(&mut self, record: AuditRecord) -> Result<(), PaymentEngineError>;
+}
+
+#[derive(Default)]
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+    fn record(&mut self, _event: AuditEvent) -> Result<(), PaymentEngineError> {
+        Ok(())
+    }
+}
+
+// Appends the events as CSV rows, flushing when dropped
+pub struct CsvAuditSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl CsvAuditSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> CsvAuditSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvAuditSink {
+            writer: WriterBuilder::new().has_headers(true).from_writer(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> Result<W, PaymentEngineError> {
+        self.writer
+            .into_inner()
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))
+    }
+}
+
+impl<W: Write> AuditSink for CsvAuditSink<W> {
+    fn record(&mut self, event: AuditEvent) -> Result<(), PaymentEngineError> {
+        Ok(self.writer.serialize(event)?)
+    }
+}
+
+// Appends the records as CSV rows, flushing when dropped
+pub struct CsvAuditRecordSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl CsvAuditRecordSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> CsvAuditRecordSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvAuditRecordSink {
+            writer: WriterBuilder::new().has_headers(true).from_writer(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> Result<W, PaymentEngineError> {
+        self.writer
+            .into_inner()
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))
+    }
+}
+
+impl<W: Write> AuditRecordSink for CsvAuditRecordSink<W> {
+    fn record(&mut self, record: AuditRecord) -> Result<(), PaymentEngineError> {
+        Ok(self.writer.serialize(record)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_audit_sink_writes_a_row_per_event() {
+        let mut sink = CsvAuditSink::new(vec![]);
+        sink.record(AuditEvent {
+            client: 1,
+            field: AuditField::Available,
+            old_value: AuditValue::Amount(Decimal::ZERO),
+            new_value: AuditValue::Amount(Decimal::new(15, 1)),
+            transaction_id: 1,
+            transaction_type: TransactionType::Deposit,
+            currency: "USD".to_owned(),
+        })
+        .unwrap();
+        sink.record(AuditEvent {
+            client: 1,
+            field: AuditField::Locked,
+            old_value: AuditValue::Flag(false),
+            new_value: AuditValue::Flag(true),
+            transaction_id: 1,
+            transaction_type: TransactionType::Chargeback,
+            currency: "USD".to_owned(),
+        })
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(sink.into_inner().unwrap()).unwrap(),
+            "client,field,old_value,new_value,tx,type,currency\n1,available,0,1.5,1,deposit,USD\n1,locked,false,true,1,chargeback,USD\n"
+        );
+    }
+
+    #[test]
+    fn csv_audit_record_sink_writes_a_row_per_record() {
+        let mut sink = CsvAuditRecordSink::new(vec![]);
+        for (transaction_id, result) in [
+            (1, Ok(TransactionOutcome::Executed)),
+            (2, Err(PaymentEngineError::AmountMissing(2))),
+        ] {
+            let (decision, reason) = AuditDecision::of(&result);
+            sink.record(AuditRecord {
+                transaction_id,
+                client: 1,
+                transaction_type: TransactionType::Deposit,
+                decision,
+                reason,
+                available_after: Decimal::new(15, 1),
+                held_after: Decimal::ZERO,
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(sink.into_inner().unwrap()).unwrap(),
+            format!(
+                "tx,client,type,decision,reason,available_after,held_after\n\
+                 1,1,deposit,executed,,1.5,0\n\
+                 2,1,deposit,skipped,{},1.5,0\n",
+                PaymentEngineError::AmountMissing(2)
+            )
+        );
+    }
+}
@@ -0,0 +1,829 @@
+use log::LevelFilter;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    common_types::CustomerId,
+    duplicate_detector::DedupeMode,
+    logging::LogConfig,
+    report_formatter::ReportFormat,
+    transactions_manager::{DEFAULT_CURRENCY, DEFAULT_MAX_PARKED, DEFAULT_SCALE},
+};
+
+pub const USAGE: &str = "Usage: simple_payment_engine [INPUT...|-] [--output PATH] [--report-format csv|json|jsonl|table] [--detailed] [--fixed-scale] [--strict] [--allow-unlock] [--ignore-follow-up-amounts] [--scale N] [--rounding truncate|half-up|half-even] [--default-currency C] [--log-level LEVEL] [--log-file PATH] [--quiet] [--stress COUNT] [--audit-file PATH] [--audit PATH] [--snapshot-every N] [--snapshot-path PATH] [--resume-from PATH] [--checkpoint DIR] [--resume DIR] [--reconcile] [--disputes] [--verify] [--client ID] [--as-of TS] [--threads N] [--manifest PATH] [--force] [--journal PATH] [--out-of-order] [--max-parked N] [--max-tx-amount A] [--max-account-total A] [--dedupe exact|bloom:FPR] [--dry-run]
+
+  INPUT                 CSV file with the transactions, stdin is read when missing or \"-\". Several files or glob
+                        patterns are read in the order of their paths
+  --output PATH         Writes the report into the file instead of stdout
+  --report-format FORMAT
+                        Report format, csv (default), json, jsonl or table
+  --detailed            Adds the total deposited and withdrawn amounts of each account to the report
+  --fixed-scale         Writes every amount of the report with exactly --scale decimal places
+  --strict              Stops on the first inconsistent transaction with a non-zero exit code
+  --allow-unlock        Handles the unlock requests instead of skipping them
+  --ignore-follow-up-amounts
+                        Accepts the disputes, resolves and chargebacks with an amount, ignoring it
+  --scale N             Decimal places of the amounts and the report, up to 28, 4 by default
+  --rounding MODE       Rounding of the input amounts with more decimal places, truncate (default), half-up or half-even
+  --default-currency C  Currency of the requests without one, USD by default
+  --log-level LEVEL     off, error, warn (default), info, debug or trace
+  --log-file PATH       Writes the logs into the file instead of stderr
+  --quiet               Leaves the individual skipped requests out of the logs
+  --stress COUNT        Processes COUNT generated requests instead of reading the input, printing the throughput
+                        and the peak memory into stderr
+  --audit-file PATH     Writes a CSV row for every account field change into the file
+  --audit PATH          Writes a CSV row for every request into the file, with the decision, the skip reason and the
+                        available and held funds of the client after it. --audit-records is accepted as well
+  --snapshot-every N    Saves the state of the engine into the snapshot file after every N requests
+  --snapshot-path PATH  Snapshot file, snapshot.json by default
+  --resume-from PATH    Restores the state from the snapshot and skips the input requests it covers
+  --checkpoint DIR      Saves the accounts and the transaction history into the directory after processing
+  --resume DIR          Restores the accounts and the transaction history from the checkpoint directory before
+                        processing the input as new requests
+  --reconcile           Checks the accounts against the transaction history after processing, reporting the differences
+  --disputes            Writes the transactions still under dispute as CSV into stderr after processing
+  --verify              Checks that the account totals match the executed requests, exiting with 1 when they don't
+  --client ID           Writes the statement of the client in the default currency instead of the report
+  --as-of TS            Only processes the requests up to the Unix time TS in milliseconds, the ones without a
+                        timestamp included, giving the accounts as they were then
+  --threads N           Processes the clients in N parallel shards, 1 by default. Can't be combined with --strict,
+                        --audit-file, --audit, the snapshots, --reconcile, --disputes, --verify or --client
+  --manifest PATH       Records the content hash of the processed input file into the JSON manifest, refusing to
+                        process an input it already holds with exit code 3
+  --force               Processes the input even when the manifest holds it already
+  --journal PATH        Replays the journal of an earlier run first when it exists, then appends the requests
+                        changing the state to it. Can't be combined with --strict, the snapshots or --threads
+  --out-of-order        Holds back the disputes, resolves and chargebacks arriving before the transaction they
+                        reference, handling them once it arrives
+  --max-parked N        Requests held back by --out-of-order at once, 10000 by default. The oldest one is dropped
+                        when there's no room left
+  --max-tx-amount A     Skips the deposits and withdrawals above the amount
+  --max-account-total A
+                        Skips the deposits bringing the available and held funds of an account above the amount
+  --dedupe MODE         Keeps the IDs of the executed transactions for the duplicate check instead of reading the
+                        history for every new ID, all of them with exact or in a bloom filter with bloom:FPR. The
+                        filter still reads the history for about FPR of the new IDs, e.g. 0.001, in return for
+                        bounded memory
+  --dry-run             Only validates the input, writing the findings instead of the report as csv or json and
+                        exiting with 1 when there are any. Can only be combined with the input, reading and report
+                        format flags";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliConfig {
+    // The input files in the order they're read, with the glob patterns expanded. Empty means stdin
+    pub inputs: Vec<String>,
+    // None means stdout
+    pub output: Option<String>,
+    pub format: ReportFormat,
+    pub detailed: bool,
+    pub fixed_scale: bool,
+    pub strict: bool,
+    pub allow_unlock: bool,
+    pub ignore_follow_up_amounts: bool,
+    pub scale: u32,
+    pub rounding: RoundingStrategy,
+    pub default_currency: String,
+    pub log_level: LevelFilter,
+    // None means stderr
+    pub log_file: Option<String>,
+    pub quiet: bool,
+    // Number of generated deposits to process instead of the input
+    pub stress: Option<u32>,
+    pub audit_file: Option<String>,
+    // The file of the per-request decisions, unlike audit_file that gets the field changes
+    pub audit_records: Option<String>,
+    // Number of requests between the snapshots, None disables them
+    pub snapshot_every: Option<usize>,
+    pub snapshot_path: String,
+    pub resume_from: Option<String>,
+    // The directory the stores are saved into after processing, and the one they're restored from before it
+    pub checkpoint: Option<String>,
+    pub resume: Option<String>,
+    pub reconcile: bool,
+    pub disputes: bool,
+    pub verify: bool,
+    // The client whose statement is written instead of the report
+    pub client: Option<CustomerId>,
+    // The timestamp the requests are replayed up to
+    pub as_of: Option<i64>,
+    // Number of the shards processing the clients in parallel, 1 runs serially
+    pub threads: usize,
+    // The manifest of the processed inputs, None disables the re-run detection
+    pub manifest: Option<String>,
+    // Processes the inputs the manifest already holds
+    pub force: bool,
+    // The journal to recover the state from and append the handled requests to
+    pub journal: Option<String>,
+    // Parks the requests referencing the transactions that didn't arrive yet
+    pub out_of_order: bool,
+    pub max_parked: usize,
+    // The limits of the risk team, None doesn't check them
+    pub max_tx_amount: Option<Decimal>,
+    pub max_account_total: Option<Decimal>,
+    // None checks the duplicates against the history only
+    pub dedupe: Option<DedupeMode>,
+    // Validates the input without processing it, writing the findings instead of the report
+    pub dry_run: bool,
+}
+
+impl Default for CliConfig {
+    fn default() -> Self {
+        CliConfig {
+            inputs: vec![],
+            output: None,
+            format: ReportFormat::Csv,
+            detailed: false,
+            fixed_scale: false,
+            strict: false,
+            allow_unlock: false,
+            ignore_follow_up_amounts: false,
+            scale: DEFAULT_SCALE,
+            rounding: RoundingStrategy::ToZero,
+            default_currency: DEFAULT_CURRENCY.to_owned(),
+            log_level: LevelFilter::Warn,
+            log_file: None,
+            quiet: false,
+            stress: None,
+            audit_file: None,
+            audit_records: None,
+            snapshot_every: None,
+            snapshot_path: "snapshot.json".to_owned(),
+            resume_from: None,
+            checkpoint: None,
+            resume: None,
+            reconcile: false,
+            disputes: false,
+            verify: false,
+            client: None,
+            as_of: None,
+            threads: 1,
+            manifest: None,
+            force: false,
+            journal: None,
+            out_of_order: false,
+            max_parked: DEFAULT_MAX_PARKED,
+            max_tx_amount: None,
+            max_account_total: None,
+            dedupe: None,
+            dry_run: false,
+        }
+    }
+}
+
+impl CliConfig {
+    pub fn log_config(&self) -> LogConfig {
+        LogConfig {
+            level: self.log_level,
+            file: self.log_file.clone(),
+            quiet: self.quiet,
+        }
+    }
+}
+
+// Parses the arguments without the program name, the error is the message to show above the usage
+pub fn parse_cli(args: Vec<String>) -> Result<CliConfig, String> {
+    let mut config = CliConfig::default();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", flag))
+        };
+        match arg.as_str() {
+            "--output" => config.output = Some(value("--output")?),
+            // --format is kept for the scripts written before the other formats were added
+            "--report-format" | "--format" => {
+                config.format = match value(&arg)?.as_str() {
+                    "csv" => ReportFormat::Csv,
+                    "json" => ReportFormat::Json,
+                    "jsonl" => ReportFormat::Jsonl,
+                    "table" => ReportFormat::Table,
+                    format => return Err(format!("Unknown format {}", format)),
+                }
+            }
+            "--strict" => config.strict = true,
+            "--allow-unlock" => config.allow_unlock = true,
+            "--ignore-follow-up-amounts" => config.ignore_follow_up_amounts = true,
+            "--scale" => {
+                let scale = value("--scale")?;
+                config.scale = scale
+                    .parse()
+                    .map_err(|_| format!("Invalid scale {}", scale))?;
+                // The most decimal places a Decimal can have
+                if config.scale > 28 {
+                    return Err(format!("Scale {} is above the supported 28", scale));
+                }
+            }
+            "--rounding" => {
+                config.rounding = match value("--rounding")?.as_str() {
+                    "truncate" => RoundingStrategy::ToZero,
+                    "half-up" => RoundingStrategy::MidpointAwayFromZero,
+                    "half-even" => RoundingStrategy::MidpointNearestEven,
+                    rounding => return Err(format!("Unknown rounding {}", rounding)),
+                }
+            }
+            "--default-currency" => config.default_currency = value("--default-currency")?,
+            "--log-level" => {
+                let log_level = value("--log-level")?;
+                config.log_level = log_level
+                    .parse()
+                    .map_err(|_| format!("Invalid log level {}", log_level))?;
+            }
+            "--log-file" => config.log_file = Some(value("--log-file")?),
+            "--quiet" => config.quiet = true,
+            "--stress" => {
+                let count = value("--stress")?;
+                config.stress = Some(
+                    count
+                        .parse()
+                        .map_err(|_| format!("Invalid stress count {}", count))?,
+                );
+            }
+            "--audit-file" => config.audit_file = Some(value("--audit-file")?),
+            // --audit-records is kept for the scripts written before it was shortened
+            "--audit" | "--audit-records" => config.audit_records = Some(value(&arg)?),
+            "--snapshot-every" => {
+                let count = value("--snapshot-every")?;
+                config.snapshot_every = Some(
+                    count
+                        .parse()
+                        .ok()
+                        .filter(|count| *count > 0)
+                        .ok_or_else(|| format!("Invalid snapshot interval {}", count))?,
+                );
+            }
+            "--snapshot-path" => config.snapshot_path = value("--snapshot-path")?,
+            "--resume-from" => config.resume_from = Some(value("--resume-from")?),
+            "--checkpoint" => config.checkpoint = Some(value("--checkpoint")?),
+            "--resume" => config.resume = Some(value("--resume")?),
+            "--reconcile" => config.reconcile = true,
+            "--disputes" => config.disputes = true,
+            "--detailed" => config.detailed = true,
+            "--fixed-scale" => config.fixed_scale = true,
+            "--verify" => config.verify = true,
+            "--client" => {
+                let client = value("--client")?;
+                config.client = Some(
+                    client
+                        .parse()
+                        .map_err(|_| format!("Invalid client {}", client))?,
+                );
+            }
+            "--as-of" => {
+                let timestamp = value("--as-of")?;
+                config.as_of = Some(
+                    timestamp
+                        .parse()
+                        .map_err(|_| format!("Invalid timestamp {}", timestamp))?,
+                );
+            }
+            "--threads" => {
+                let threads = value("--threads")?;
+                config.threads = threads
+                    .parse()
+                    .ok()
+                    .filter(|threads| *threads > 0)
+                    .ok_or_else(|| format!("Invalid thread count {}", threads))?;
+            }
+            "--manifest" => config.manifest = Some(value("--manifest")?),
+            "--force" => config.force = true,
+            "--journal" => config.journal = Some(value("--journal")?),
+            "--out-of-order" => config.out_of_order = true,
+            "--max-parked" => {
+                let max_parked = value("--max-parked")?;
+                config.max_parked = max_parked
+                    .parse()
+                    .map_err(|_| format!("Invalid parking capacity {}", max_parked))?;
+            }
+            "--max-tx-amount" => {
+                config.max_tx_amount = Some(parse_limit(&value("--max-tx-amount")?)?)
+            }
+            "--max-account-total" => {
+                config.max_account_total = Some(parse_limit(&value("--max-account-total")?)?)
+            }
+            "--dedupe" => config.dedupe = Some(parse_dedupe_mode(&value("--dedupe")?)?),
+            "--dry-run" => config.dry_run = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown flag {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() > 1 && positional.iter().any(|input| input == "-") {
+        return Err("stdin can't be read along with input files".to_owned());
+    }
+    config.inputs = expand_input_patterns(positional.into_iter().filter(|input| input != "-"))?;
+    // Only a file can be hashed before processing it
+    if config.manifest.is_some() && (config.inputs.is_empty() || config.stress.is_some()) {
+        return Err("--manifest needs an input file".to_owned());
+    }
+    if config.manifest.is_some() && config.inputs.len() > 1 {
+        return Err("--manifest takes a single input file".to_owned());
+    }
+    // Both restore the state the run starts from
+    if config.resume.is_some() && config.resume_from.is_some() {
+        return Err("--resume can't be combined with --resume-from".to_owned());
+    }
+    // The journal recovers the state on its own and the journaled requests go through TransactionsManager only
+    if config.journal.is_some() {
+        let journal_exclusive = [
+            ("--strict", config.strict),
+            ("--snapshot-every", config.snapshot_every.is_some()),
+            ("--resume-from", config.resume_from.is_some()),
+            ("--checkpoint", config.checkpoint.is_some()),
+            ("--resume", config.resume.is_some()),
+            ("--threads", config.threads > 1),
+        ];
+        if let Some((flag, _)) = journal_exclusive.iter().find(|(_, set)| *set) {
+            return Err(format!("--journal can't be combined with {}", flag));
+        }
+    }
+    // The dry run only reads the input, so the flags of the processing and the state don't apply to it
+    if config.dry_run {
+        let processing_only = [
+            ("--strict", config.strict),
+            ("--stress", config.stress.is_some()),
+            ("--audit-file", config.audit_file.is_some()),
+            ("--audit", config.audit_records.is_some()),
+            ("--snapshot-every", config.snapshot_every.is_some()),
+            ("--resume-from", config.resume_from.is_some()),
+            ("--checkpoint", config.checkpoint.is_some()),
+            ("--resume", config.resume.is_some()),
+            ("--reconcile", config.reconcile),
+            ("--disputes", config.disputes),
+            ("--verify", config.verify),
+            ("--client", config.client.is_some()),
+            ("--threads", config.threads > 1),
+            ("--manifest", config.manifest.is_some()),
+            ("--journal", config.journal.is_some()),
+            ("--out-of-order", config.out_of_order),
+            ("--dedupe", config.dedupe.is_some()),
+        ];
+        if let Some((flag, _)) = processing_only.iter().find(|(_, set)| *set) {
+            return Err(format!("--dry-run can't be combined with {}", flag));
+        }
+        if !matches!(config.format, ReportFormat::Csv | ReportFormat::Json) {
+            return Err("--dry-run writes the findings as csv or json only".to_owned());
+        }
+    }
+    // The shards only run the transactions, the rest needs the state of a single manager
+    if config.threads > 1 {
+        let serial_only = [
+            ("--strict", config.strict),
+            ("--audit-file", config.audit_file.is_some()),
+            ("--audit", config.audit_records.is_some()),
+            ("--snapshot-every", config.snapshot_every.is_some()),
+            ("--resume-from", config.resume_from.is_some()),
+            ("--checkpoint", config.checkpoint.is_some()),
+            ("--resume", config.resume.is_some()),
+            ("--reconcile", config.reconcile),
+            ("--disputes", config.disputes),
+            ("--verify", config.verify),
+            ("--client", config.client.is_some()),
+        ];
+        if let Some((flag, _)) = serial_only.iter().find(|(_, set)| *set) {
+            return Err(format!("--threads can't be combined with {}", flag));
+        }
+    }
+    Ok(config)
+}
+
+fn parse_limit(limit: &str) -> Result<Decimal, String> {
+    limit
+        .parse()
+        .ok()
+        .filter(|limit: &Decimal| *limit > Decimal::ZERO)
+        .ok_or_else(|| format!("Invalid limit {}", limit))
+}
+
+fn parse_dedupe_mode(mode: &str) -> Result<DedupeMode, String> {
+    if mode == "exact" {
+        return Ok(DedupeMode::Exact);
+    }
+    let Some(false_positive_rate) = mode.strip_prefix("bloom:") else {
+        return Err(format!("Unknown dedupe mode {}", mode));
+    };
+    false_positive_rate
+        .parse()
+        .ok()
+        .filter(|rate: &Decimal| *rate > Decimal::ZERO && *rate < Decimal::ONE)
+        .map(|false_positive_rate| DedupeMode::Bloom {
+            false_positive_rate,
+        })
+        .ok_or_else(|| format!("Invalid false positive rate {}", false_positive_rate))
+}
+
+// Replaces the glob patterns with the paths they match, sorting all the inputs so the runs don't depend on the order
+// they were passed in. The paths without any pattern characters are kept even when the file doesn't exist, so
+// reading it reports the error.
+fn expand_input_patterns(inputs: impl Iterator<Item = String>) -> Result<Vec<String>, String> {
+    let mut expanded = vec![];
+    for input in inputs {
+        if !input.contains(['*', '?', '[']) {
+            expanded.push(input);
+            continue;
+        }
+        let paths = glob::glob(&input).map_err(|e| format!("Invalid pattern {}: {}", input, e))?;
+        let matched = expanded.len();
+        for path in paths {
+            let path = path.map_err(|e| format!("Failed reading {}", e.path().display()))?;
+            expanded.push(path.to_string_lossy().into_owned());
+        }
+        if expanded.len() == matched {
+            return Err(format!("No input file matches {}", input));
+        }
+    }
+    expanded.sort_unstable();
+    expanded.dedup();
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliConfig, String> {
+        parse_cli(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    #[test]
+    fn no_arguments_read_stdin_with_the_defaults() {
+        assert_eq!(parse(&[]), Ok(CliConfig::default()));
+        assert_eq!(parse(&["-"]), Ok(CliConfig::default()));
+    }
+
+    #[test]
+    fn input_and_output_paths() {
+        assert_eq!(
+            parse(&["input.csv", "--output", "report.csv"]),
+            Ok(CliConfig {
+                inputs: vec!["input.csv".to_owned()],
+                output: Some("report.csv".to_owned()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["-", "--output", "report.csv"]),
+            Ok(CliConfig {
+                output: Some("report.csv".to_owned()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn all_the_flags_together() {
+        assert_eq!(
+            parse(&[
+                "--strict",
+                "--allow-unlock",
+                "--ignore-follow-up-amounts",
+                "--report-format",
+                "jsonl",
+                "--detailed",
+                "--fixed-scale",
+                "--scale",
+                "2",
+                "--rounding",
+                "half-even",
+                "--default-currency",
+                "EUR",
+                "--log-level",
+                "info",
+                "--log-file",
+                "engine.log",
+                "--quiet",
+                "--stress",
+                "1000",
+                "--audit-file",
+                "audit.csv",
+                "--audit",
+                "decisions.csv",
+                "--snapshot-every",
+                "500",
+                "--snapshot-path",
+                "state.json",
+                "--resume-from",
+                "old.json",
+                "--checkpoint",
+                "checkpoint",
+                "--reconcile",
+                "--disputes",
+                "--verify",
+                "--client",
+                "42",
+                "--as-of",
+                "1700000000000",
+                "--force",
+                "--out-of-order",
+                "--max-parked",
+                "50",
+                "--max-tx-amount",
+                "1000.5",
+                "--max-account-total",
+                "5000",
+                "--dedupe",
+                "bloom:0.001",
+            ]),
+            Ok(CliConfig {
+                format: ReportFormat::Jsonl,
+                detailed: true,
+                fixed_scale: true,
+                strict: true,
+                allow_unlock: true,
+                ignore_follow_up_amounts: true,
+                scale: 2,
+                rounding: RoundingStrategy::MidpointNearestEven,
+                default_currency: "EUR".to_owned(),
+                log_level: LevelFilter::Info,
+                log_file: Some("engine.log".to_owned()),
+                quiet: true,
+                stress: Some(1000),
+                audit_file: Some("audit.csv".to_owned()),
+                audit_records: Some("decisions.csv".to_owned()),
+                snapshot_every: Some(500),
+                snapshot_path: "state.json".to_owned(),
+                resume_from: Some("old.json".to_owned()),
+                checkpoint: Some("checkpoint".to_owned()),
+                reconcile: true,
+                disputes: true,
+                verify: true,
+                client: Some(42),
+                as_of: Some(1700000000000),
+                force: true,
+                out_of_order: true,
+                max_parked: 50,
+                max_tx_amount: Some(Decimal::new(10005, 1)),
+                max_account_total: Some(Decimal::new(5000, 0)),
+                dedupe: Some(DedupeMode::Bloom {
+                    false_positive_rate: Decimal::new(1, 3)
+                }),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn manifest_needs_an_input_file() {
+        assert_eq!(
+            parse(&["input.csv", "--manifest", "manifest.json"]),
+            Ok(CliConfig {
+                inputs: vec!["input.csv".to_owned()],
+                manifest: Some("manifest.json".to_owned()),
+                ..Default::default()
+            })
+        );
+        for args in [
+            &["--manifest", "manifest.json"][..],
+            &["-", "--manifest", "manifest.json"],
+            &["input.csv", "--stress", "10", "--manifest", "manifest.json"],
+        ] {
+            assert_eq!(
+                parse(args),
+                Err("--manifest needs an input file".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn journal_excludes_the_other_recovery_features() {
+        assert_eq!(
+            parse(&["--journal", "journal.jsonl"]).unwrap().journal,
+            Some("journal.jsonl".to_owned())
+        );
+        assert_eq!(
+            parse(&["--journal", "journal.jsonl", "--resume-from", "old.json"]),
+            Err("--journal can't be combined with --resume-from".to_owned())
+        );
+        assert_eq!(
+            parse(&["--journal", "journal.jsonl", "--threads", "2"]),
+            Err("--journal can't be combined with --threads".to_owned())
+        );
+    }
+
+    #[test]
+    fn audit_records_is_an_alias_of_audit() {
+        for flag in ["--audit", "--audit-records"] {
+            assert_eq!(
+                parse(&[flag, "decisions.csv"]).unwrap().audit_records,
+                Some("decisions.csv".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn resume_excludes_the_snapshot_it_would_replace() {
+        assert_eq!(
+            parse(&["--resume", "checkpoint", "--checkpoint", "checkpoint"]),
+            Ok(CliConfig {
+                checkpoint: Some("checkpoint".to_owned()),
+                resume: Some("checkpoint".to_owned()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["--resume", "checkpoint", "--resume-from", "old.json"]),
+            Err("--resume can't be combined with --resume-from".to_owned())
+        );
+        assert_eq!(
+            parse(&["--threads", "4", "--resume", "checkpoint"]),
+            Err("--threads can't be combined with --resume".to_owned())
+        );
+    }
+
+    #[test]
+    fn dry_run_only_takes_the_input_flags() {
+        let config = parse(&[
+            "input.csv",
+            "--dry-run",
+            "--format",
+            "json",
+            "--max-tx-amount",
+            "100",
+        ])
+        .unwrap();
+        assert!(config.dry_run);
+        assert_eq!(config.max_tx_amount, Some(Decimal::new(100, 0)));
+        assert_eq!(
+            parse(&["input.csv", "--dry-run", "--journal", "journal.jsonl"]),
+            Err("--dry-run can't be combined with --journal".to_owned())
+        );
+        assert_eq!(
+            parse(&["input.csv", "--dry-run", "--format", "table"]),
+            Err("--dry-run writes the findings as csv or json only".to_owned())
+        );
+        assert_eq!(
+            parse(&["--max-account-total", "-1"]),
+            Err("Invalid limit -1".to_owned())
+        );
+    }
+
+    #[test]
+    fn dedupe_modes() {
+        assert_eq!(
+            parse(&["--dedupe", "exact"]).unwrap().dedupe,
+            Some(DedupeMode::Exact)
+        );
+        assert_eq!(
+            parse(&["--dedupe", "bloom:0.01"]).unwrap().dedupe,
+            Some(DedupeMode::Bloom {
+                false_positive_rate: Decimal::new(1, 2)
+            })
+        );
+        assert_eq!(
+            parse(&["--dedupe", "hash"]),
+            Err("Unknown dedupe mode hash".to_owned())
+        );
+        for rate in ["0", "1", "-0.1", "often"] {
+            assert_eq!(
+                parse(&["--dedupe", &format!("bloom:{}", rate)]),
+                Err(format!("Invalid false positive rate {}", rate))
+            );
+        }
+    }
+
+    #[test]
+    fn report_formats() {
+        for (name, format) in [
+            ("csv", ReportFormat::Csv),
+            ("json", ReportFormat::Json),
+            ("jsonl", ReportFormat::Jsonl),
+            ("table", ReportFormat::Table),
+        ] {
+            assert_eq!(parse(&["--report-format", name]).unwrap().format, format);
+            assert_eq!(parse(&["--format", name]).unwrap().format, format);
+        }
+    }
+
+    #[test]
+    fn threads_exclude_the_single_manager_features() {
+        assert_eq!(parse(&["--threads", "4"]).unwrap().threads, 4);
+        assert_eq!(
+            parse(&["--threads", "0"]),
+            Err("Invalid thread count 0".to_owned())
+        );
+        assert_eq!(
+            parse(&["--threads", "4", "--verify"]),
+            Err("--threads can't be combined with --verify".to_owned())
+        );
+        assert!(parse(&["--threads", "1", "--verify"]).is_ok());
+        assert_eq!(
+            parse(&["--threads", "4", "--audit", "decisions.csv"]),
+            Err("--threads can't be combined with --audit".to_owned())
+        );
+        assert_eq!(
+            parse(&["--threads", "4", "--client", "7"]),
+            Err("--threads can't be combined with --client".to_owned())
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!(
+            parse(&["--format", "xml"]),
+            Err("Unknown format xml".to_owned())
+        );
+        assert_eq!(
+            parse(&["--scale", "-1"]),
+            Err("Invalid scale -1".to_owned())
+        );
+        assert_eq!(
+            parse(&["--scale", "29"]),
+            Err("Scale 29 is above the supported 28".to_owned())
+        );
+        assert_eq!(
+            parse(&["--client", "70000"]),
+            Err("Invalid client 70000".to_owned())
+        );
+        assert_eq!(
+            parse(&["--as-of", "yesterday"]),
+            Err("Invalid timestamp yesterday".to_owned())
+        );
+        assert_eq!(
+            parse(&["--rounding", "up"]),
+            Err("Unknown rounding up".to_owned())
+        );
+        assert_eq!(
+            parse(&["--log-level", "loud"]),
+            Err("Invalid log level loud".to_owned())
+        );
+        assert_eq!(
+            parse(&["--snapshot-every", "0"]),
+            Err("Invalid snapshot interval 0".to_owned())
+        );
+        assert_eq!(
+            parse(&["--stress"]),
+            Err("Missing value for --stress".to_owned())
+        );
+    }
+
+    #[test]
+    fn unknown_flags_and_extra_arguments_are_rejected() {
+        assert_eq!(
+            parse(&["--verbose"]),
+            Err("Unknown flag --verbose".to_owned())
+        );
+    }
+
+    #[test]
+    fn several_inputs_are_read_in_the_order_of_their_paths() {
+        assert_eq!(
+            parse(&["c.csv", "a.csv", "b.csv"]).unwrap().inputs,
+            vec!["a.csv", "b.csv", "c.csv"]
+        );
+        // Two inputs stay two inputs, the output is only ever set with --output
+        assert_eq!(
+            parse(&["day2.csv", "day1.csv"]),
+            Ok(CliConfig {
+                inputs: vec!["day1.csv".to_owned(), "day2.csv".to_owned()],
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["-", "report.csv"]),
+            Err("stdin can't be read along with input files".to_owned())
+        );
+        assert_eq!(
+            parse(&["b.csv", "a.csv", "--output", "report.csv"]),
+            Ok(CliConfig {
+                inputs: vec!["a.csv".to_owned(), "b.csv".to_owned()],
+                output: Some("report.csv".to_owned()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse(&["a.csv", "-", "b.csv"]),
+            Err("stdin can't be read along with input files".to_owned())
+        );
+        assert_eq!(
+            parse(&["a.csv", "b.csv", "c.csv", "--manifest", "manifest.json"]),
+            Err("--manifest takes a single input file".to_owned())
+        );
+    }
+
+    #[test]
+    fn glob_patterns_are_expanded() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = |name: &str| directory.path().join(name).to_str().unwrap().to_owned();
+        for name in [
+            "tx-2024-01-02.csv",
+            "tx-2024-01-01.csv",
+            "tx-2024-02-01.csv",
+        ] {
+            std::fs::write(path(name), "").unwrap();
+        }
+        assert_eq!(
+            parse(&[
+                &path("tx-2024-01-*.csv"),
+                &path("tx-2024-01-01.csv"),
+                "--output",
+                "report.csv"
+            ])
+            .unwrap()
+            .inputs,
+            vec![path("tx-2024-01-01.csv"), path("tx-2024-01-02.csv")]
+        );
+        assert_eq!(
+            parse(&[&path("tx-2023-*.csv")]),
+            Err(format!("No input file matches {}", path("tx-2023-*.csv")))
+        );
+    }
+}
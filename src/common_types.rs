@@ -1,2 +1,3 @@
 pub type CustomerId = u16;
-pub type TransactionId = u32;
\ No newline at end of file
+pub type TransactionId = u32;
+pub type Currency = String;
\ No newline at end of file
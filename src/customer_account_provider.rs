@@ -1,117 +1,788 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
 
 use mockall::predicate::*;
 use mockall::*;
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 
-use crate::common_types::CustomerId;
+use crate::{
+    common_types::{Currency, CustomerId},
+    errors::PaymentEngineError,
+};
 
+// The balances are kept per client and currency, while the lock applies to all the currencies of the client
 #[automock]
 pub trait CustomerAccountProvider {
-    fn get_available(&mut self, customer_id: CustomerId) -> Result<Option<Decimal>, String>;
-    fn get_held_amount(&mut self, customer_id: CustomerId) -> Result<Option<Decimal>, String>;
-    fn get_locked_status(&mut self, customer_id: CustomerId) -> Result<Option<bool>, String>;
-    fn set_available(&mut self, customer_id: CustomerId, balance: Decimal) -> Result<(), String>;
-    fn set_held_amount(&mut self, customer_id: CustomerId, balance: Decimal) -> Result<(), String>;
-    fn set_locked_status(&mut self, customer_id: CustomerId, locked: bool) -> Result<(), String>;
-    fn list_accounts(&self) -> Result<Vec<CustomerAccountReport>, String>;
+    fn get_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError>;
+    fn get_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError>;
+    // None when the client has no account in any currency
+    fn get_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<Option<bool>, PaymentEngineError>;
+    fn get_total(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError>;
+    // Creates the account when missing, locked if the client is already locked in another currency
+    fn set_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    fn set_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    fn set_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+        locked: bool,
+    ) -> Result<(), PaymentEngineError>;
+    // Moves the funds between available and held in one call, so the account is never left half-updated
+    fn adjust_balances(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    // Applies the whole mutation or nothing of it, the providers backed by a store should write it in one batch or
+    // transaction of the store
+    fn apply_mutation(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        mutation: AccountMutation,
+    ) -> Result<(), PaymentEngineError>;
+    // Adds the amount to the lifetime charged back volume of the account
+    fn increment_charged_back(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    // Adds the amount of an executed deposit to the lifetime deposited sum of the account
+    fn add_deposited(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    // Adds the amount of an executed withdrawal to the lifetime withdrawn sum of the account
+    fn add_withdrawn(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError>;
+    // Changes the number of the transactions of the account currently on hold
+    fn adjust_open_disputes(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        delta: i32,
+    ) -> Result<(), PaymentEngineError>;
+    fn get_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<i64>, PaymentEngineError>;
+    // Keeps the latest of the given and the stored timestamps, as the input is not guaranteed to be ordered
+    fn set_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        timestamp: i64,
+    ) -> Result<(), PaymentEngineError>;
+    // Lets the report stream the accounts one by one instead of materializing all of them. The order is up to the
+    // provider, the manager sorts the report rows itself
+    fn iter_accounts<'a>(&'a self) -> Box<dyn Iterator<Item = CustomerAccountReport> + 'a>;
+    // The accounts of the client in all the currencies
+    fn list_client_accounts(
+        &self,
+        customer_id: CustomerId,
+    ) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        Ok(self
+            .iter_accounts()
+            .filter(|account| account.client == customer_id)
+            .collect())
+    }
+    // The account of the client in the currency as its report row, so the callers don't need a getter per field
+    fn get_account(
+        &self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<CustomerAccountReport>, PaymentEngineError> {
+        Ok(self
+            .list_client_accounts(customer_id)?
+            .into_iter()
+            .find(|account| account.currency == currency))
+    }
+    fn list_accounts(&self) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        Ok(self.iter_accounts().collect())
+    }
+    fn list_accounts_sorted(&self) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        let mut accounts = self.list_accounts()?;
+        sort_accounts(&mut accounts);
+        Ok(accounts)
+    }
 }
 
-#[derive(Default)]
-struct CustomerAccount {
-    available: Decimal,
-    held: Decimal,
-    locked: bool,
+// By client, then by currency
+pub(crate) fn sort_accounts(accounts: &mut [CustomerAccountReport]) {
+    accounts.sort_unstable_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
 }
 
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct CustomerAccount {
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    // Tracked separately from available and held, so that the invariant checks can catch them drifting apart
+    pub(crate) total: Decimal,
+    pub(crate) locked: bool,
+    pub(crate) last_activity: Option<i64>,
+    // Lifetime sum of the charged back amounts
+    pub(crate) total_charged_back: Decimal,
+    // Number of the transactions currently on hold
+    pub(crate) open_disputes: u32,
+    // Lifetime sums of the executed deposits and withdrawals
+    pub(crate) total_deposited: Decimal,
+    pub(crate) total_withdrawn: Decimal,
+}
+
+// The account changes are computed on copies, so every provider can validate them before storing
 impl CustomerAccount {
-    fn new(available: Decimal, held: Decimal, locked: bool) -> Self {
+    pub(crate) fn new(available: Decimal, held: Decimal, locked: bool) -> Self {
         CustomerAccount {
             available,
             held,
+            total: available + held,
             locked,
+            last_activity: None,
+            total_charged_back: Decimal::ZERO,
+            open_disputes: 0,
+            total_deposited: Decimal::ZERO,
+            total_withdrawn: Decimal::ZERO,
+        }
+    }
+
+    pub(crate) fn with_activity_at(self, timestamp: i64) -> Self {
+        CustomerAccount {
+            last_activity: Some(
+                self.last_activity
+                    .map_or(timestamp, |last| last.max(timestamp)),
+            ),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_available(
+        self,
+        customer_id: CustomerId,
+        balance: Decimal,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(CustomerAccount {
+            available: balance,
+            total: checked_balance(
+                customer_id,
+                (self.total - self.available).checked_add(balance),
+            )?,
+            ..self
+        })
+    }
+
+    pub(crate) fn with_held(
+        self,
+        customer_id: CustomerId,
+        balance: Decimal,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(CustomerAccount {
+            held: balance,
+            total: checked_balance(customer_id, (self.total - self.held).checked_add(balance))?,
+            ..self
+        })
+    }
+
+    pub(crate) fn adjusted(
+        self,
+        customer_id: CustomerId,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(CustomerAccount {
+            available: checked_balance(customer_id, self.available.checked_add(available_delta))?,
+            held: checked_balance(customer_id, self.held.checked_add(held_delta))?,
+            total: checked_balance(
+                customer_id,
+                self.total
+                    .checked_add(available_delta)
+                    .and_then(|total| total.checked_add(held_delta)),
+            )?,
+            ..self
+        })
+    }
+
+    pub(crate) fn with_charged_back(
+        self,
+        customer_id: CustomerId,
+        amount: Decimal,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(CustomerAccount {
+            total_charged_back: checked_balance(
+                customer_id,
+                self.total_charged_back.checked_add(amount),
+            )?,
+            ..self
+        })
+    }
+
+    pub(crate) fn with_deposited(
+        self,
+        customer_id: CustomerId,
+        amount: Decimal,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(CustomerAccount {
+            total_deposited: checked_balance(
+                customer_id,
+                self.total_deposited.checked_add(amount),
+            )?,
+            ..self
+        })
+    }
+
+    pub(crate) fn with_withdrawn(
+        self,
+        customer_id: CustomerId,
+        amount: Decimal,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(CustomerAccount {
+            total_withdrawn: checked_balance(
+                customer_id,
+                self.total_withdrawn.checked_add(amount),
+            )?,
+            ..self
+        })
+    }
+
+    // Saturating, as the accounts restored from the older snapshots start with no open disputes while they may have
+    // transactions on hold
+    pub(crate) fn with_open_disputes_changed_by(self, delta: i32) -> Self {
+        CustomerAccount {
+            open_disputes: self.open_disputes.saturating_add_signed(delta),
+            ..self
+        }
+    }
+
+    pub(crate) fn mutated(
+        self,
+        customer_id: CustomerId,
+        mutation: AccountMutation,
+    ) -> Result<Self, PaymentEngineError> {
+        Ok(self
+            .adjusted(customer_id, mutation.available_delta, mutation.held_delta)?
+            .with_charged_back(customer_id, mutation.charged_back)?
+            .with_open_disputes_changed_by(mutation.open_disputes_delta))
+    }
+
+    pub(crate) fn check_invariants(
+        &self,
+        customer_id: CustomerId,
+    ) -> Result<(), PaymentEngineError> {
+        if self.held < Decimal::ZERO {
+            return Err(PaymentEngineError::InvariantViolation(format!(
+                "Held amount of customer {} would become negative: {}",
+                customer_id, self.held
+            )));
+        }
+        Ok(())
+    }
+
+    // The total follows from the balances, so it's checked against the previous account instead of their sum
+    pub(crate) fn check_total_change(
+        &self,
+        customer_id: CustomerId,
+        previous: &CustomerAccount,
+        total_change: TotalChange,
+    ) -> Result<(), PaymentEngineError> {
+        let allowed = match total_change {
+            TotalChange::Any => true,
+            TotalChange::Unchanged => self.total == previous.total,
+            // Moving the funds between available and held keeps the total, only the holds of a withdrawal and the
+            // chargebacks take them into or out of held alone
+            TotalChange::ByHeldOnly => {
+                self.total == previous.total || self.available == previous.available
+            }
+        };
+        if !allowed {
+            return Err(PaymentEngineError::InvariantViolation(format!(
+                "Total of customer {} would change from {} to {} while available changes from {} to {}",
+                customer_id, previous.total, self.total, previous.available, self.available
+            )));
+        }
+        Ok(())
+    }
+
+    // Rounds the amounts to the scale with banker's rounding, so the repeated arithmetic can't grow their scale. The
+    // total is recomputed from the rounded balances to keep it their sum.
+    pub(crate) fn normalized(self, scale: u32) -> Self {
+        let round = |amount: Decimal| {
+            amount.round_dp_with_strategy(scale, RoundingStrategy::MidpointNearestEven)
+        };
+        let (available, held) = (round(self.available), round(self.held));
+        CustomerAccount {
+            available,
+            held,
+            total: available + held,
+            total_charged_back: round(self.total_charged_back),
+            total_deposited: round(self.total_deposited),
+            total_withdrawn: round(self.total_withdrawn),
+            ..self
         }
     }
+
+    pub(crate) fn report(&self, client: CustomerId, currency: &str) -> CustomerAccountReport {
+        CustomerAccountReport {
+            client,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            last_activity: self.last_activity,
+            currency: currency.to_owned(),
+            total_charged_back: self.total_charged_back,
+            open_disputes: self.open_disputes,
+            total_deposited: self.total_deposited,
+            total_withdrawn: self.total_withdrawn,
+        }
+    }
+}
+
+// The balance changes of a dispute, resolve or chargeback, along with the lock of the client for the chargebacks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountMutation {
+    pub available_delta: Decimal,
+    pub held_delta: Decimal,
+    pub lock: bool,
+    // Added to the lifetime charged back volume
+    pub charged_back: Decimal,
+    pub open_disputes_delta: i32,
+}
+
+// How an operation may change the total of the account, checked along with the other invariants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TotalChange {
+    // Deposits, withdrawals and the repair of the held amount
+    Any,
+    // Disputes, resolves and chargebacks
+    ByHeldOnly,
+    // Only the counters and the activity
+    Unchanged,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CustomerAccountReport {
     pub client: CustomerId,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    // Appended after the original columns, so the consumers relying on their order keep working
+    pub last_activity: Option<i64>,
+    pub currency: Currency,
+    // Defaulted for the snapshots taken before they were tracked
+    #[serde(default)]
+    pub total_charged_back: Decimal,
+    #[serde(default)]
+    pub open_disputes: u32,
+    // Only in the detailed report, so the default columns stay the same
+    #[serde(default)]
+    pub total_deposited: Decimal,
+    #[serde(default)]
+    pub total_withdrawn: Decimal,
 }
 
+#[derive(Default)]
 pub struct InMemoryCustomerAccountProvider {
-    storage: HashMap<CustomerId, CustomerAccount>,
+    // The accounts of each client by currency, all of them sharing the locked flag
+    storage: HashMap<CustomerId, HashMap<Currency, CustomerAccount>>,
+    check_invariants: bool,
+    // The scale the balances are rounded to when written, None keeps them as they are
+    scale: Option<u32>,
 }
 
 impl InMemoryCustomerAccountProvider {
     pub fn new() -> Self {
+        Self::new_with_invariant_checks(false)
+    }
+
+    // Validates every mutation before applying it, useful for testing and debugging the manager logic
+    pub fn new_with_invariant_checks(check_invariants: bool) -> Self {
         InMemoryCustomerAccountProvider {
             storage: HashMap::new(),
+            check_invariants,
+            scale: None,
         }
     }
-}
-impl CustomerAccountProvider for InMemoryCustomerAccountProvider {
-    fn get_available(&mut self, customer_id: CustomerId) -> Result<Option<Decimal>, String> {
-        Ok(self.storage.get(&customer_id).map(|c| c.available))
+
+    // Rounds the written balances to the scale, should match the scale of the manager
+    pub fn new_with_scale(scale: u32) -> Self {
+        InMemoryCustomerAccountProvider {
+            scale: Some(scale),
+            ..Self::new()
+        }
     }
 
-    fn get_held_amount(&mut self, customer_id: CustomerId) -> Result<Option<Decimal>, String> {
-        Ok(self.storage.get(&customer_id).map(|c| c.held))
+    // Rounds the written balances to the scale from now on, e.g. after loading a snapshot
+    pub(crate) fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = Some(scale);
+        self
     }
 
-    fn get_locked_status(&mut self, customer_id: CustomerId) -> Result<Option<bool>, String> {
-        Ok(self.storage.get(&customer_id).map(|c| c.locked))
+    // Writes all the accounts as JSON, to be read back with load_snapshot
+    pub fn save_snapshot(&self, writer: impl Write) -> Result<(), PaymentEngineError> {
+        serde_json::to_writer(writer, &self.storage)
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))
     }
 
-    fn set_available(&mut self, customer_id: CustomerId, balance: Decimal) -> Result<(), String> {
-        if let Some(customer_account) = self.storage.get_mut(&customer_id) {
-            customer_account.available = balance;
-        } else {
-            self.storage.insert(
-                customer_id,
-                CustomerAccount::new(balance, Decimal::ZERO, false),
-            );
+    // The accounts are stored exactly as they were saved, without the invariant checks and the scale
+    pub fn load_snapshot(reader: impl Read) -> Result<Self, PaymentEngineError> {
+        Ok(InMemoryCustomerAccountProvider {
+            storage: serde_json::from_reader(reader)
+                .map_err(|e| PaymentEngineError::Storage(e.to_string()))?,
+            ..Self::new()
+        })
+    }
+
+    // Restores the accounts exactly as they were reported, e.g. from a snapshot
+    pub fn from_accounts(accounts: impl IntoIterator<Item = CustomerAccountReport>) -> Self {
+        let mut customer_account_provider = Self::new();
+        for account in accounts {
+            customer_account_provider.restore_account(account);
         }
-        Ok(())
+        customer_account_provider
     }
 
-    fn set_held_amount(&mut self, customer_id: CustomerId, balance: Decimal) -> Result<(), String> {
-        if let Some(customer_account) = self.storage.get_mut(&customer_id) {
-            customer_account.held = balance;
-        } else {
-            // Something went wrong... If the original transaction existed, then the account would exist as well
-            panic!("Putting amount on hold on a non-existing account");
+    // Stored as it is, without the invariant checks, as it's a copy of an account that was already accepted
+    pub(crate) fn restore_account(&mut self, account: CustomerAccountReport) {
+        self.storage.entry(account.client).or_default().insert(
+            account.currency,
+            CustomerAccount {
+                available: account.available,
+                held: account.held,
+                total: account.total,
+                locked: account.locked,
+                last_activity: account.last_activity,
+                total_charged_back: account.total_charged_back,
+                open_disputes: account.open_disputes,
+                total_deposited: account.total_deposited,
+                total_withdrawn: account.total_withdrawn,
+            },
+        );
+    }
+
+    // The change is applied to a copy first, so a rejected mutation leaves the stored account untouched
+    fn commit(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        account: CustomerAccount,
+        total_change: TotalChange,
+    ) -> Result<(), PaymentEngineError> {
+        if self.check_invariants {
+            account.check_invariants(customer_id)?;
+            if let Some(previous) = self.account(customer_id, currency) {
+                account.check_total_change(customer_id, previous, total_change)?;
+            }
+        }
+        let account = match self.scale {
+            Some(scale) => account.normalized(scale),
+            None => account,
+        };
+        let accounts = self.storage.entry(customer_id).or_default();
+        match accounts.get_mut(currency) {
+            Some(stored) => *stored = account,
+            None => {
+                accounts.insert(currency.to_owned(), account);
+            }
         }
         Ok(())
     }
 
-    fn set_locked_status(&mut self, customer_id: CustomerId, locked: bool) -> Result<(), String> {
-        if let Some(customer_account) = self.storage.get_mut(&customer_id) {
-            customer_account.locked = locked;
-        } else {
-            // Something went wrong... If the original transaction existed, then the account would exist as well
-            panic!("Locking a non-existing account");
+    fn account(&self, customer_id: CustomerId, currency: &str) -> Option<&CustomerAccount> {
+        self.storage
+            .get(&customer_id)
+            .and_then(|accounts| accounts.get(currency))
+    }
+
+    fn existing_account(
+        &self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<CustomerAccount, PaymentEngineError> {
+        // Something went wrong... If the original transaction existed, then the account would exist as well
+        self.account(customer_id, currency)
+            .copied()
+            .ok_or(PaymentEngineError::AccountNotFound(customer_id))
+    }
+}
+// The balances are never allowed to overflow silently or panic, instead the mutation is rejected
+pub(crate) fn checked_balance(
+    customer_id: CustomerId,
+    balance: Option<Decimal>,
+) -> Result<Decimal, PaymentEngineError> {
+    balance.ok_or(PaymentEngineError::Overflow(customer_id))
+}
+
+impl CustomerAccountProvider for InMemoryCustomerAccountProvider {
+    fn get_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        Ok(self.account(customer_id, currency).map(|c| c.available))
+    }
+
+    fn get_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        Ok(self.account(customer_id, currency).map(|c| c.held))
+    }
+
+    fn get_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<Option<bool>, PaymentEngineError> {
+        Ok(self
+            .storage
+            .get(&customer_id)
+            .and_then(|accounts| accounts.values().next())
+            .map(|c| c.locked))
+    }
+
+    fn get_total(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        Ok(self.account(customer_id, currency).map(|c| c.total))
+    }
+
+    fn set_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let account = match self.account(customer_id, currency) {
+            Some(customer_account) => customer_account.with_available(customer_id, balance)?,
+            None => CustomerAccount::new(
+                balance,
+                Decimal::ZERO,
+                self.get_locked_status(customer_id)?.unwrap_or(false),
+            ),
+        };
+        self.commit(customer_id, currency, account, TotalChange::Any)
+    }
+
+    fn set_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.with_held(customer_id, balance)?,
+            TotalChange::Any,
+        )
+    }
+
+    fn set_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+        locked: bool,
+    ) -> Result<(), PaymentEngineError> {
+        match self.storage.get_mut(&customer_id) {
+            Some(accounts) if !accounts.is_empty() => {
+                for account in accounts.values_mut() {
+                    account.locked = locked;
+                }
+                Ok(())
+            }
+            _ => Err(PaymentEngineError::AccountNotFound(customer_id)),
+        }
+    }
+
+    fn adjust_balances(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.adjusted(customer_id, available_delta, held_delta)?,
+            TotalChange::ByHeldOnly,
+        )
+    }
+
+    fn apply_mutation(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        mutation: AccountMutation,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.mutated(customer_id, mutation)?,
+            TotalChange::ByHeldOnly,
+        )?;
+        // Can't fail anymore, the client has at least the account committed above
+        if mutation.lock {
+            self.set_locked_status(customer_id, true)?;
         }
         Ok(())
     }
 
-    fn list_accounts(&self) -> Result<Vec<CustomerAccountReport>, String> {
-        return Ok(self
+    fn increment_charged_back(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.with_charged_back(customer_id, amount)?,
+            TotalChange::Unchanged,
+        )
+    }
+
+    fn add_deposited(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.with_deposited(customer_id, amount)?,
+            TotalChange::Unchanged,
+        )
+    }
+
+    fn add_withdrawn(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.with_withdrawn(customer_id, amount)?,
+            TotalChange::Unchanged,
+        )
+    }
+
+    fn adjust_open_disputes(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        delta: i32,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.with_open_disputes_changed_by(delta),
+            TotalChange::Unchanged,
+        )
+    }
+
+    fn get_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<i64>, PaymentEngineError> {
+        Ok(self
+            .account(customer_id, currency)
+            .and_then(|c| c.last_activity))
+    }
+
+    fn set_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        timestamp: i64,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.commit(
+            customer_id,
+            currency,
+            customer_account.with_activity_at(timestamp),
+            TotalChange::Unchanged,
+        )
+    }
+
+    fn iter_accounts(&self) -> Box<dyn Iterator<Item = CustomerAccountReport> + '_> {
+        Box::new(self.storage.iter().flat_map(|(client, accounts)| {
+            accounts
+                .iter()
+                .map(|(currency, account)| account.report(*client, currency))
+        }))
+    }
+
+    fn list_client_accounts(
+        &self,
+        customer_id: CustomerId,
+    ) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        Ok(self
             .storage
-            .iter()
-            .map(|(client, account)| CustomerAccountReport {
-                client: *client,
-                available: account.available,
-                held: account.held,
-                locked: account.locked,
-                total: account.available + account.held,
-            })
-            .collect());
+            .get(&customer_id)
+            .into_iter()
+            .flatten()
+            .map(|(currency, account)| account.report(customer_id, currency))
+            .collect())
+    }
+
+    fn get_account(
+        &self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<CustomerAccountReport>, PaymentEngineError> {
+        Ok(self
+            .account(customer_id, currency)
+            .map(|account| account.report(customer_id, currency)))
     }
 }
 
@@ -124,16 +795,22 @@ mod in_memory_customer_account_provider_tests {
         let customer_id = 1;
         let available = Decimal::new(10, 0);
         let mut storage = HashMap::new();
-        storage.insert(
-            customer_id,
-            CustomerAccount {
-                available,
-                ..Default::default()
-            },
-        );
-        let mut customer_account_provider = InMemoryCustomerAccountProvider { storage };
+        storage
+            .entry(customer_id)
+            .or_insert_with(HashMap::new)
+            .insert(
+                "USD".to_owned(),
+                CustomerAccount {
+                    available,
+                    ..Default::default()
+                },
+            );
+        let mut customer_account_provider = InMemoryCustomerAccountProvider {
+            storage,
+            ..Default::default()
+        };
         assert_eq!(
-            customer_account_provider.get_available(customer_id),
+            customer_account_provider.get_available(customer_id, "USD"),
             Ok(Some(available))
         );
     }
@@ -142,9 +819,12 @@ mod in_memory_customer_account_provider_tests {
     fn get_available_works_as_expected_with_missing_account() {
         let customer_id = 1;
         let storage = HashMap::new();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider { storage };
+        let mut customer_account_provider = InMemoryCustomerAccountProvider {
+            storage,
+            ..Default::default()
+        };
         assert_eq!(
-            customer_account_provider.get_available(customer_id),
+            customer_account_provider.get_available(customer_id, "USD"),
             Ok(None)
         );
     }
@@ -154,16 +834,22 @@ mod in_memory_customer_account_provider_tests {
         let customer_id = 1;
         let held = Decimal::new(10, 0);
         let mut storage = HashMap::new();
-        storage.insert(
-            customer_id,
-            CustomerAccount {
-                held,
-                ..Default::default()
-            },
-        );
-        let mut customer_account_provider = InMemoryCustomerAccountProvider { storage };
+        storage
+            .entry(customer_id)
+            .or_insert_with(HashMap::new)
+            .insert(
+                "USD".to_owned(),
+                CustomerAccount {
+                    held,
+                    ..Default::default()
+                },
+            );
+        let mut customer_account_provider = InMemoryCustomerAccountProvider {
+            storage,
+            ..Default::default()
+        };
         assert_eq!(
-            customer_account_provider.get_held_amount(customer_id),
+            customer_account_provider.get_held_amount(customer_id, "USD"),
             Ok(Some(held))
         );
     }
@@ -172,9 +858,12 @@ mod in_memory_customer_account_provider_tests {
     fn get_held_amount_works_as_expected_with_missing_account() {
         let customer_id = 1;
         let storage = HashMap::new();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider { storage };
+        let mut customer_account_provider = InMemoryCustomerAccountProvider {
+            storage,
+            ..Default::default()
+        };
         assert_eq!(
-            customer_account_provider.get_held_amount(customer_id),
+            customer_account_provider.get_held_amount(customer_id, "USD"),
             Ok(None)
         );
     }
@@ -184,14 +873,20 @@ mod in_memory_customer_account_provider_tests {
         let customer_id = 1;
         let locked = false;
         let mut storage = HashMap::new();
-        storage.insert(
-            customer_id,
-            CustomerAccount {
-                locked,
-                ..Default::default()
-            },
-        );
-        let mut customer_account_provider = InMemoryCustomerAccountProvider { storage };
+        storage
+            .entry(customer_id)
+            .or_insert_with(HashMap::new)
+            .insert(
+                "USD".to_owned(),
+                CustomerAccount {
+                    locked,
+                    ..Default::default()
+                },
+            );
+        let mut customer_account_provider = InMemoryCustomerAccountProvider {
+            storage,
+            ..Default::default()
+        };
         assert_eq!(
             customer_account_provider.get_locked_status(customer_id),
             Ok(Some(locked))
@@ -202,7 +897,10 @@ mod in_memory_customer_account_provider_tests {
     fn get_locked_status_works_as_expected_with_missing_account() {
         let customer_id = 1;
         let storage = HashMap::new();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider { storage };
+        let mut customer_account_provider = InMemoryCustomerAccountProvider {
+            storage,
+            ..Default::default()
+        };
         assert_eq!(
             customer_account_provider.get_locked_status(customer_id),
             Ok(None)
@@ -215,10 +913,10 @@ mod in_memory_customer_account_provider_tests {
         let balance = Decimal::new(10, 0);
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
         assert!(customer_account_provider
-            .set_available(customer_id, balance)
+            .set_available(customer_id, "USD", balance)
             .is_ok());
         assert_eq!(
-            customer_account_provider.get_available(customer_id),
+            customer_account_provider.get_available(customer_id, "USD"),
             Ok(Some(balance))
         );
     }
@@ -229,24 +927,30 @@ mod in_memory_customer_account_provider_tests {
         let balance = Decimal::new(10, 0);
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
         customer_account_provider
-            .set_available(customer_id, Decimal::new(10, 0))
+            .set_available(customer_id, "USD", Decimal::new(10, 0))
             .expect("Couldn't create the account");
         assert!(customer_account_provider
-            .set_held_amount(customer_id, balance)
+            .set_held_amount(customer_id, "USD", balance)
             .is_ok());
         assert_eq!(
-            customer_account_provider.get_held_amount(customer_id),
+            customer_account_provider.get_held_amount(customer_id, "USD"),
             Ok(Some(balance))
         );
     }
 
     #[test]
-    #[should_panic]
-    fn set_held_amount_panics_when_no_account_found() {
+    fn set_held_amount_fails_when_no_account_found() {
         let customer_id = 1;
         let balance = Decimal::new(10, 0);
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        let _ = customer_account_provider.set_held_amount(customer_id, balance);
+        assert_eq!(
+            customer_account_provider.set_held_amount(customer_id, "USD", balance),
+            Err(PaymentEngineError::AccountNotFound(customer_id))
+        );
+        assert_eq!(
+            customer_account_provider.get_held_amount(customer_id, "USD"),
+            Ok(None)
+        );
     }
 
     #[test]
@@ -255,7 +959,7 @@ mod in_memory_customer_account_provider_tests {
         let locked = true;
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
         customer_account_provider
-            .set_available(customer_id, Decimal::new(10, 0))
+            .set_available(customer_id, "USD", Decimal::new(10, 0))
             .expect("Couldn't create the account");
         assert!(customer_account_provider
             .set_locked_status(customer_id, locked)
@@ -267,34 +971,42 @@ mod in_memory_customer_account_provider_tests {
     }
 
     #[test]
-    #[should_panic]
-    fn set_locked_status_panics_when_no_account_found() {
+    fn set_locked_status_fails_when_no_account_found() {
         let customer_id = 1;
         let locked = true;
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        let _ = customer_account_provider.set_locked_status(customer_id, locked);
+        assert_eq!(
+            customer_account_provider.set_locked_status(customer_id, locked),
+            Err(PaymentEngineError::AccountNotFound(customer_id))
+        );
     }
 
     #[test]
     fn list_accounts_works_as_expected() {
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
         customer_account_provider
-            .set_available(1, Decimal::new(10, 0))
+            .set_available(1, "USD", Decimal::new(10, 0))
             .unwrap();
         customer_account_provider
-            .set_available(2, Decimal::new(11, 0))
+            .set_available(2, "USD", Decimal::new(11, 0))
             .unwrap();
         customer_account_provider
-            .set_held_amount(2, Decimal::new(12, 0))
+            .set_held_amount(2, "USD", Decimal::new(12, 0))
             .unwrap();
         let accounts = customer_account_provider.list_accounts();
-        let expected_accounts = vec![
+        let expected_accounts = [
             CustomerAccountReport {
                 client: 1,
                 available: Decimal::new(10, 0),
                 held: Decimal::new(0, 0),
                 total: Decimal::new(10, 0),
                 locked: false,
+                last_activity: None,
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::ZERO,
+                open_disputes: 0,
+                total_deposited: Decimal::ZERO,
+                total_withdrawn: Decimal::ZERO,
             },
             CustomerAccountReport {
                 client: 2,
@@ -302,6 +1014,12 @@ mod in_memory_customer_account_provider_tests {
                 held: Decimal::new(12, 0),
                 total: Decimal::new(23, 0),
                 locked: false,
+                last_activity: None,
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::ZERO,
+                open_disputes: 0,
+                total_deposited: Decimal::ZERO,
+                total_withdrawn: Decimal::ZERO,
             },
         ];
         assert!(accounts.is_ok());
@@ -311,4 +1029,378 @@ mod in_memory_customer_account_provider_tests {
             .iter()
             .all(|account| accounts.contains(account)));
     }
+
+    #[test]
+    fn list_accounts_sorted_orders_by_client() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        for client in [5, 1, 3] {
+            customer_account_provider
+                .set_available(client, "USD", Decimal::new(10, 0))
+                .unwrap();
+        }
+        let clients: Vec<CustomerId> = customer_account_provider
+            .list_accounts_sorted()
+            .unwrap()
+            .iter()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(clients, vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn get_total_follows_the_balance_changes() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        assert_eq!(customer_account_provider.get_total(1, "USD"), Ok(None));
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(5, 0))
+            .unwrap();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(7, 0))
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.get_total(1, "USD"),
+            Ok(Some(Decimal::new(12, 0)))
+        );
+    }
+
+    #[test]
+    fn adjust_balances_moves_funds_in_one_call() {
+        let mut customer_account_provider =
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true);
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        assert!(customer_account_provider
+            .adjust_balances(1, "USD", Decimal::new(-4, 0), Decimal::new(4, 0))
+            .is_ok());
+        assert_eq!(
+            customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(6, 0)))
+        );
+        assert_eq!(
+            customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(4, 0)))
+        );
+        assert_eq!(
+            customer_account_provider.get_total(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+    }
+
+    #[test]
+    fn adjust_balances_fails_when_no_account_found() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        assert_eq!(
+            customer_account_provider.adjust_balances(1, "USD", Decimal::ONE, Decimal::ONE),
+            Err(PaymentEngineError::AccountNotFound(1))
+        );
+        assert_eq!(customer_account_provider.get_available(1, "USD"), Ok(None));
+    }
+
+    #[test]
+    fn rejected_mutation_neither_moves_funds_nor_locks() {
+        let mut customer_account_provider =
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true);
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_available(1, "EUR", Decimal::new(5, 0))
+            .unwrap();
+        let result = customer_account_provider.apply_mutation(
+            1,
+            "USD",
+            AccountMutation {
+                available_delta: Decimal::ZERO,
+                held_delta: -Decimal::ONE,
+                lock: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+        assert_eq!(
+            customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+        assert_eq!(
+            customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert!(customer_account_provider
+            .apply_mutation(
+                1,
+                "USD",
+                AccountMutation {
+                    available_delta: -Decimal::ONE,
+                    held_delta: Decimal::ONE,
+                    lock: true,
+                    ..Default::default()
+                },
+            )
+            .is_ok());
+        assert_eq!(
+            customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+        assert_eq!(
+            customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(9, 0)))
+        );
+        assert_eq!(
+            customer_account_provider.get_available(1, "EUR"),
+            Ok(Some(Decimal::new(5, 0)))
+        );
+    }
+
+    #[test]
+    fn charged_back_volume_and_open_disputes_are_reported() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        assert_eq!(
+            customer_account_provider.adjust_open_disputes(1, "USD", 1),
+            Err(PaymentEngineError::AccountNotFound(1))
+        );
+        customer_account_provider
+            .set_available(1, "USD", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .adjust_open_disputes(1, "USD", 2)
+            .unwrap();
+        customer_account_provider
+            .adjust_open_disputes(1, "USD", -1)
+            .unwrap();
+        customer_account_provider
+            .increment_charged_back(1, "USD", Decimal::new(25, 1))
+            .unwrap();
+        customer_account_provider
+            .increment_charged_back(1, "USD", Decimal::ONE)
+            .unwrap();
+        let account = customer_account_provider.list_accounts().unwrap().remove(0);
+        assert_eq!(account.open_disputes, 1);
+        assert_eq!(account.total_charged_back, Decimal::new(35, 1));
+    }
+
+    #[test]
+    fn get_account_gives_the_account_in_the_currency() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        assert_eq!(customer_account_provider.get_account(1, "USD"), Ok(None));
+        customer_account_provider
+            .set_available(1, "USD", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::ONE)
+            .unwrap();
+        let account = customer_account_provider
+            .get_account(1, "USD")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            account,
+            customer_account_provider.list_accounts().unwrap()[0]
+        );
+        assert_eq!(account.total, Decimal::new(11, 0));
+        assert_eq!(customer_account_provider.get_account(1, "EUR"), Ok(None));
+    }
+
+    #[test]
+    fn balances_are_rounded_to_the_scale_when_written() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new_with_scale(4);
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(1000000, 5))
+            .unwrap();
+        let available = customer_account_provider
+            .get_available(1, "USD")
+            .unwrap()
+            .unwrap();
+        assert_eq!(available.to_string(), "10.0000");
+        // Banker's rounding, the midpoints go to the even digit
+        for (balance, rounded) in [
+            (Decimal::new(100005, 5), "1.0000"),
+            (Decimal::new(100015, 5), "1.0002"),
+            (Decimal::new(-100015, 5), "-1.0002"),
+        ] {
+            customer_account_provider
+                .set_held_amount(1, "USD", balance)
+                .unwrap();
+            let account = customer_account_provider.list_accounts().unwrap().remove(0);
+            assert_eq!(account.held.to_string(), rounded);
+            assert_eq!(account.total, account.available + account.held);
+        }
+        // Kept as they are without a scale
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(100005, 5))
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(100005, 5)))
+        );
+    }
+
+    #[test]
+    fn snapshot_restores_the_accounts() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(12345, 5))
+            .unwrap();
+        customer_account_provider
+            .set_available(1, "EUR", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "EUR", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .add_deposited(1, "USD", Decimal::new(12345, 5))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        let mut snapshot = vec![];
+        customer_account_provider
+            .save_snapshot(&mut snapshot)
+            .unwrap();
+        let restored_provider =
+            InMemoryCustomerAccountProvider::load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored_provider.storage, customer_account_provider.storage);
+        assert!(matches!(
+            InMemoryCustomerAccountProvider::load_snapshot("[]".as_bytes()),
+            Err(PaymentEngineError::Storage(_))
+        ));
+    }
+
+    #[test]
+    fn deposited_and_withdrawn_amounts_are_reported() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        assert_eq!(
+            customer_account_provider.add_deposited(1, "USD", Decimal::TEN),
+            Err(PaymentEngineError::AccountNotFound(1))
+        );
+        customer_account_provider
+            .set_available(1, "USD", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .add_deposited(1, "USD", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .add_deposited(1, "USD", Decimal::new(25, 1))
+            .unwrap();
+        customer_account_provider
+            .add_withdrawn(1, "USD", Decimal::ONE)
+            .unwrap();
+        let account = customer_account_provider.list_accounts().unwrap().remove(0);
+        assert_eq!(account.total_deposited, Decimal::new(125, 1));
+        assert_eq!(account.total_withdrawn, Decimal::ONE);
+    }
+
+    #[test]
+    fn invariant_checks_reject_negative_held_amount() {
+        let mut customer_account_provider =
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true);
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let result =
+            customer_account_provider.adjust_balances(1, "USD", Decimal::ONE, -Decimal::ONE);
+        assert!(matches!(
+            result,
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+        assert_eq!(
+            customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+    }
+
+    #[test]
+    fn invariant_checks_reject_a_total_change_outside_deposits_and_withdrawals() {
+        let mut customer_account_provider =
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true);
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        // Takes the funds out of available without holding them
+        let result =
+            customer_account_provider.adjust_balances(1, "USD", -Decimal::ONE, Decimal::ZERO);
+        assert!(matches!(
+            result,
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+        assert_eq!(
+            customer_account_provider.get_total(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        // Holding them keeps the total, holding a withdrawn amount leaves available as it was
+        customer_account_provider
+            .adjust_balances(1, "USD", -Decimal::ONE, Decimal::ONE)
+            .unwrap();
+        customer_account_provider
+            .adjust_balances(1, "USD", Decimal::ZERO, Decimal::ONE)
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.get_total(1, "USD"),
+            Ok(Some(Decimal::new(11, 0)))
+        );
+    }
+
+    #[test]
+    fn invariant_checks_are_disabled_by_default() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        assert!(customer_account_provider
+            .set_held_amount(1, "USD", -Decimal::ONE)
+            .is_ok());
+    }
+
+    #[test]
+    fn adjust_balances_fails_on_overflow() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::MAX)
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.adjust_balances(1, "USD", Decimal::ONE, Decimal::ZERO),
+            Err(PaymentEngineError::Overflow(1))
+        );
+        assert_eq!(
+            customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::MAX))
+        );
+    }
+
+    #[test]
+    fn set_last_activity_keeps_the_latest_timestamp() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        assert_eq!(
+            customer_account_provider.set_last_activity(1, "USD", 10),
+            Err(PaymentEngineError::AccountNotFound(1))
+        );
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.get_last_activity(1, "USD"),
+            Ok(None)
+        );
+        customer_account_provider
+            .set_last_activity(1, "USD", 20)
+            .unwrap();
+        customer_account_provider
+            .set_last_activity(1, "USD", 15)
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.get_last_activity(1, "USD"),
+            Ok(Some(20))
+        );
+    }
 }
@@ -0,0 +1,300 @@
+//! Validation of an input without touching any provider, for checking a file before it's committed into the persistent
+//! ones. Only the input itself is checked, so the findings don't cover e.g. the balances or the accounts.
+
+use std::{collections::HashSet, io::Write};
+
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::{
+    common_types::TransactionId,
+    errors::PaymentEngineError,
+    transaction_outcome::{SkipReason, TransactionOutcome},
+    transaction_request::TransactionRequest,
+    transactions_manager::TransactionsManager,
+    validation::{references_transaction, ValidationConfig},
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingKind {
+    // The row couldn't be parsed into a request
+    MalformedRow,
+    // The request fails the structure validation of the managers, e.g. a deposit without amount
+    InvalidStructure,
+    // A deposit or withdrawal above the max amount of the validation config
+    AmountAboveMax,
+    // A deposit or withdrawal reusing the ID of an earlier one of the input
+    DuplicateTransactionId,
+    // A dispute, resolve or chargeback of an ID no earlier deposit or withdrawal of the input has
+    UnknownReference,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    // 1-based position of the row in the input, the malformed rows counted as well
+    pub row: usize,
+    // None for the malformed rows
+    #[serde(rename = "tx")]
+    pub transaction_id: Option<TransactionId>,
+    pub kind: FindingKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FindingsFormat {
+    #[default]
+    Csv,
+    // A single JSON array of the findings
+    Json,
+}
+
+// Handles the requests like a manager would, but only records what's wrong with them. The valid requests come out
+// as executed and the rest as skipped with the reason the managers would give.
+#[derive(Default)]
+pub struct DryRunValidator {
+    validation_config: ValidationConfig,
+    findings_format: FindingsFormat,
+    // The valid deposits and withdrawals so far
+    seen_ids: HashSet<TransactionId>,
+    rows: usize,
+    findings: Vec<Finding>,
+}
+
+impl DryRunValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_validation_config(mut self, validation_config: ValidationConfig) -> Self {
+        self.validation_config = validation_config;
+        self
+    }
+
+    pub fn with_findings_format(mut self, findings_format: FindingsFormat) -> Self {
+        self.findings_format = findings_format;
+        self
+    }
+
+    // Validates the rows of TransactionRequestsReader::read_fallible, the malformed ones recorded as findings
+    pub fn validate_all(
+        &mut self,
+        requests: impl Iterator<Item = Result<TransactionRequest, PaymentEngineError>>,
+    ) -> Result<(), PaymentEngineError> {
+        for request in requests {
+            match request {
+                Ok(request) => {
+                    self.handle_transaction(request)?;
+                }
+                Err(PaymentEngineError::MalformedRow { .. }) => {
+                    self.rows += 1;
+                    self.record(None, FindingKind::MalformedRow);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    fn record(&mut self, transaction_id: Option<TransactionId>, kind: FindingKind) {
+        self.findings.push(Finding {
+            row: self.rows,
+            transaction_id,
+            kind,
+        });
+    }
+
+    fn find(&self, transaction_request: &TransactionRequest) -> Option<FindingKind> {
+        // Checked first, as is_valid rejects these as well
+        if self
+            .validation_config
+            .exceeds_max_amount(transaction_request)
+        {
+            return Some(FindingKind::AmountAboveMax);
+        }
+        if !self.validation_config.is_valid(transaction_request) {
+            return Some(FindingKind::InvalidStructure);
+        }
+        let seen = self.seen_ids.contains(&transaction_request.transaction_id);
+        match (
+            references_transaction(&transaction_request.transaction_type),
+            seen,
+        ) {
+            (true, false) => Some(FindingKind::UnknownReference),
+            (false, true) => Some(FindingKind::DuplicateTransactionId),
+            _ => None,
+        }
+    }
+}
+
+impl TransactionsManager for DryRunValidator {
+    fn structure_validation(&self, transaction_request: &TransactionRequest) -> bool {
+        self.validation_config.is_valid(transaction_request)
+    }
+
+    fn handle_transaction(
+        &mut self,
+        mut transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        self.rows += 1;
+        self.validation_config
+            .drop_ignored_amount(&mut transaction_request);
+        let transaction_id = transaction_request.transaction_id;
+        let Some(kind) = self.find(&transaction_request) else {
+            if !references_transaction(&transaction_request.transaction_type) {
+                self.seen_ids.insert(transaction_id);
+            }
+            return Ok(TransactionOutcome::Executed);
+        };
+        self.record(Some(transaction_id), kind);
+        let reason = match kind {
+            FindingKind::DuplicateTransactionId => SkipReason::DuplicateTransaction,
+            FindingKind::UnknownReference => SkipReason::TransactionNotFound,
+            _ => SkipReason::InvalidStructure,
+        };
+        Ok(TransactionOutcome::Skipped(reason))
+    }
+
+    // Writes the findings in their row order, the CSV header along with the first of them
+    fn write_report(&self, writer: &mut dyn Write) -> Result<(), PaymentEngineError> {
+        match self.findings_format {
+            FindingsFormat::Csv => {
+                let mut writer = WriterBuilder::new().has_headers(true).from_writer(writer);
+                for finding in &self.findings {
+                    writer.serialize(finding)?;
+                }
+                Ok(writer.flush()?)
+            }
+            FindingsFormat::Json => {
+                serde_json::to_writer(&mut *writer, &self.findings)
+                    .map_err(|e| PaymentEngineError::Storage(e.to_string()))?;
+                writer.write_all(b"\n")?;
+                Ok(writer.flush()?)
+            }
+        }
+    }
+
+    fn print_report(&self) -> Result<(), PaymentEngineError> {
+        self.write_report(&mut std::io::stdout().lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        transaction_request::TransactionType,
+        transaction_requests_reader::{
+            DefaultTransactionRequestsReader, TransactionRequestsReader,
+        },
+    };
+
+    use super::*;
+
+    fn validated(input: &str, mut validator: DryRunValidator) -> DryRunValidator {
+        let directory = tempfile::tempdir().unwrap();
+        let input_path = directory.path().join("transactions.csv");
+        std::fs::write(&input_path, input).unwrap();
+        let reader = DefaultTransactionRequestsReader::new(input_path.to_str().unwrap());
+        validator.validate_all(reader.read_fallible()).unwrap();
+        validator
+    }
+
+    fn finding(row: usize, transaction_id: Option<TransactionId>, kind: FindingKind) -> Finding {
+        Finding {
+            row,
+            transaction_id,
+            kind,
+        }
+    }
+
+    #[test]
+    fn every_kind_of_finding_is_recorded_with_its_row() {
+        let validator = validated(
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             dispute,1,2,\n\
+             deposit,2,1,3.0\n\
+             withdrawal,1,3,\n\
+             deposit,1,4,1000.5\n\
+             #garbage;;\n\
+             withdrawal,1,5,1.0\n\
+             resolve,1,1,\n",
+            DryRunValidator::new().with_validation_config(ValidationConfig {
+                max_amount: Some(Decimal::new(1000, 0)),
+                ..Default::default()
+            }),
+        );
+        assert_eq!(
+            validator.findings(),
+            [
+                finding(2, Some(2), FindingKind::UnknownReference),
+                finding(3, Some(1), FindingKind::DuplicateTransactionId),
+                finding(4, Some(3), FindingKind::InvalidStructure),
+                finding(5, Some(4), FindingKind::AmountAboveMax),
+                finding(6, None, FindingKind::MalformedRow),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_transactions_are_unknown_to_the_later_references() {
+        let mut validator = DryRunValidator::new();
+        let mut handle = |transaction_type, amount| {
+            validator.handle_transaction(TransactionRequest {
+                transaction_type,
+                client_id: 1,
+                transaction_id: 7,
+                amount,
+                timestamp: None,
+                currency: None,
+            })
+        };
+        assert_eq!(
+            handle(TransactionType::Deposit, None),
+            Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure))
+        );
+        assert_eq!(
+            handle(TransactionType::Dispute, None),
+            Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound))
+        );
+    }
+
+    #[test]
+    fn findings_are_written_as_csv_or_json() {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,1,5.0\nchargeback,1,9,\n";
+        let mut output = vec![];
+        validated(input, DryRunValidator::new())
+            .write_report(&mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "row,tx,kind\n2,1,duplicate_transaction_id\n3,9,unknown_reference\n"
+        );
+        let mut output = vec![];
+        validated(
+            input,
+            DryRunValidator::new().with_findings_format(FindingsFormat::Json),
+        )
+        .write_report(&mut output)
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[{\"row\":2,\"tx\":1,\"kind\":\"duplicate_transaction_id\"},\
+             {\"row\":3,\"tx\":9,\"kind\":\"unknown_reference\"}]\n"
+        );
+        // No findings give an empty CSV, like an empty report
+        let mut output = vec![];
+        validated(
+            "type,client,tx,amount\ndeposit,1,1,5.0\n",
+            DryRunValidator::new(),
+        )
+        .write_report(&mut output)
+        .unwrap();
+        assert!(output.is_empty());
+    }
+}
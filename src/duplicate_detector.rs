@@ -0,0 +1,167 @@
+//! Detection of the deposits and withdrawals reusing the ID of an executed one, answered before the history is read.
+//! The exact detector remembers every ID, the bloom one keeps its memory bounded at the cost of reading the history
+//! for a small share of the new IDs too.
+
+use std::collections::HashSet;
+
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+
+use crate::common_types::TransactionId;
+
+pub trait DuplicateDetector {
+    // False only when the ID was never inserted, the approximate detectors may give true for a new one
+    fn contains(&self, transaction_id: TransactionId) -> bool;
+    fn insert(&mut self, transaction_id: TransactionId);
+}
+
+impl<T: DuplicateDetector + ?Sized> DuplicateDetector for Box<T> {
+    fn contains(&self, transaction_id: TransactionId) -> bool {
+        (**self).contains(transaction_id)
+    }
+
+    fn insert(&mut self, transaction_id: TransactionId) {
+        (**self).insert(transaction_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeMode {
+    Exact,
+    // The rate is between 0 and 1, e.g. 0.001 for one new ID in a thousand read from the history
+    Bloom { false_positive_rate: Decimal },
+}
+
+impl DedupeMode {
+    // The capacity is the number of IDs the bloom filter is sized for, the exact detector grows with the IDs instead
+    pub fn detector(self, capacity: usize) -> Box<dyn DuplicateDetector> {
+        match self {
+            DedupeMode::Exact => Box::new(ExactDuplicateDetector::default()),
+            DedupeMode::Bloom {
+                false_positive_rate,
+            } => Box::new(BloomDuplicateDetector::new(
+                capacity,
+                false_positive_rate.to_f64().unwrap_or(1.0),
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ExactDuplicateDetector {
+    transaction_ids: HashSet<TransactionId>,
+}
+
+impl DuplicateDetector for ExactDuplicateDetector {
+    fn contains(&self, transaction_id: TransactionId) -> bool {
+        self.transaction_ids.contains(&transaction_id)
+    }
+
+    fn insert(&mut self, transaction_id: TransactionId) {
+        self.transaction_ids.insert(transaction_id);
+    }
+}
+
+// IDs the bloom filter of --dedupe is sized for when the input size is unknown, e.g. on stdin. About 1.8 MB at a rate
+// of 0.001
+pub const DEFAULT_BLOOM_CAPACITY: usize = 1_000_000;
+
+const DEFAULT_BLOOM_SEED: u64 = 0x5eed;
+
+// Sets hash_count bits per ID, derived from two hashes of the ID. The hashes only depend on the seed, so the same
+// input always gives the same false positives.
+pub struct BloomDuplicateDetector {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+    seed: u64,
+}
+
+impl BloomDuplicateDetector {
+    // Sized for capacity IDs at the false positive rate, the rate grows when more IDs are inserted
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        Self::new_with_seed(capacity, false_positive_rate, DEFAULT_BLOOM_SEED)
+    }
+
+    pub fn new_with_seed(capacity: usize, false_positive_rate: f64, seed: u64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let false_positive_rate = false_positive_rate.clamp(f64::EPSILON, 0.5);
+        let bit_count = (-capacity * false_positive_rate.ln() / std::f64::consts::LN_2.powi(2))
+            .ceil()
+            .max(64.0) as u64;
+        let hash_count = ((bit_count as f64 / capacity) * std::f64::consts::LN_2)
+            .round()
+            .max(1.0) as u32;
+        BloomDuplicateDetector {
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+            hash_count,
+            seed,
+        }
+    }
+
+    fn bit_positions(&self, transaction_id: TransactionId) -> impl Iterator<Item = u64> + '_ {
+        let first = split_mix(transaction_id as u64 ^ self.seed);
+        // Odd, so the positions don't repeat before covering the filter
+        let second = split_mix(first) | 1;
+        (0..self.hash_count as u64)
+            .map(move |index| first.wrapping_add(index.wrapping_mul(second)) % self.bit_count)
+    }
+}
+
+impl DuplicateDetector for BloomDuplicateDetector {
+    fn contains(&self, transaction_id: TransactionId) -> bool {
+        self.bit_positions(transaction_id)
+            .all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+
+    fn insert(&mut self, transaction_id: TransactionId) {
+        let positions: Vec<u64> = self.bit_positions(transaction_id).collect();
+        for position in positions {
+            self.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+    }
+}
+
+fn split_mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9e3779b97f4a7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_detector_contains_only_the_inserted_ids() {
+        let mut detector = ExactDuplicateDetector::default();
+        detector.insert(1);
+        detector.insert(u32::MAX);
+        assert!(detector.contains(1));
+        assert!(detector.contains(u32::MAX));
+        assert!(!detector.contains(2));
+    }
+
+    #[test]
+    fn bloom_detector_never_misses_an_inserted_id_and_keeps_to_its_rate() {
+        let mut detector = BloomDuplicateDetector::new_with_seed(10_000, 0.01, 7);
+        for transaction_id in 0..10_000 {
+            detector.insert(transaction_id);
+        }
+        assert!((0..10_000).all(|transaction_id| detector.contains(transaction_id)));
+        let false_positives = (10_000..110_000)
+            .filter(|transaction_id| detector.contains(*transaction_id))
+            .count();
+        // About 1000 expected, the seed makes the count the same on every run
+        assert!((500..1500).contains(&false_positives));
+        let same_seed = {
+            let mut detector = BloomDuplicateDetector::new_with_seed(10_000, 0.01, 7);
+            (0..10_000).for_each(|transaction_id| detector.insert(transaction_id));
+            (10_000..110_000)
+                .filter(|transaction_id| detector.contains(*transaction_id))
+                .count()
+        };
+        assert_eq!(same_seed, false_positives);
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt::{self, Display};
+
+use rust_decimal::Decimal;
+
+use crate::common_types::{Currency, CustomerId, TransactionId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentEngineError {
+    AccountLocked(CustomerId),
+    AccountNotFound(CustomerId),
+    InsufficientFunds(CustomerId),
+    DuplicateTransaction(TransactionId),
+    ConflictingDuplicate(TransactionId),
+    // The ID of a transaction of stored_client_id reused by client_id
+    CrossClientIdReuse {
+        transaction_id: TransactionId,
+        stored_client_id: CustomerId,
+        client_id: CustomerId,
+    },
+    TransactionNotFound(TransactionId),
+    ClientMismatch {
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+    },
+    AmountMissing(TransactionId),
+    // The balance of the customer would go beyond the Decimal limits
+    Overflow(CustomerId),
+    // The provider refused a mutation that would break the consistency of the account
+    InvariantViolation(String),
+    // Settling a dispute would leave the held funds negative or above the amounts of the open disputes of the account
+    HeldFundsOutOfBounds {
+        client_id: CustomerId,
+        currency: Currency,
+        held: Decimal,
+        disputed: Decimal,
+    },
+    // Failures of the underlying storage, reader or writer, not related to the transactions themselves
+    Storage(String),
+    // An input row that couldn't be parsed into a request, line is 1-based and None when the reader can't tell it
+    MalformedRow {
+        line: Option<u64>,
+        reason: String,
+    },
+    // Strict mode stopped the run, row is the 1-based position of the request in the input
+    StrictModeViolation {
+        row: usize,
+        transaction_id: TransactionId,
+        reason: Box<PaymentEngineError>,
+    },
+}
+
+impl PaymentEngineError {
+    // Storage failures and invariant violations mean the system can't be trusted anymore, while the rest only affect a single request
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            PaymentEngineError::Storage(_)
+                | PaymentEngineError::InvariantViolation(_)
+                | PaymentEngineError::HeldFundsOutOfBounds { .. }
+        )
+    }
+}
+
+impl Display for PaymentEngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentEngineError::AccountLocked(client_id) => {
+                write!(f, "The account of customer {} is locked", client_id)
+            }
+            PaymentEngineError::AccountNotFound(client_id) => {
+                write!(f, "The customer {} doesn't have an account", client_id)
+            }
+            PaymentEngineError::InsufficientFunds(client_id) => {
+                write!(
+                    f,
+                    "The customer {} doesn't have enough available funds",
+                    client_id
+                )
+            }
+            PaymentEngineError::DuplicateTransaction(transaction_id) => {
+                write!(f, "Transaction {} already exists", transaction_id)
+            }
+            PaymentEngineError::ConflictingDuplicate(transaction_id) => write!(
+                f,
+                "Transaction {} already exists with different content",
+                transaction_id
+            ),
+            PaymentEngineError::CrossClientIdReuse {
+                transaction_id,
+                stored_client_id,
+                client_id,
+            } => write!(
+                f,
+                "Transaction {} of customer {} reused by customer {}",
+                transaction_id, stored_client_id, client_id
+            ),
+            PaymentEngineError::TransactionNotFound(transaction_id) => {
+                write!(f, "Transaction {} not found", transaction_id)
+            }
+            PaymentEngineError::ClientMismatch {
+                transaction_id,
+                client_id,
+            } => write!(
+                f,
+                "Transaction {} doesn't belong to the customer {}",
+                transaction_id, client_id
+            ),
+            PaymentEngineError::AmountMissing(transaction_id) => {
+                write!(f, "Transaction {} doesn't have amount", transaction_id)
+            }
+            PaymentEngineError::Overflow(client_id) => {
+                write!(f, "The balance of customer {} would overflow", client_id)
+            }
+            PaymentEngineError::InvariantViolation(message) => {
+                write!(f, "Invariant violation: {}", message)
+            }
+            PaymentEngineError::HeldFundsOutOfBounds {
+                client_id,
+                currency,
+                held,
+                disputed,
+            } => write!(
+                f,
+                "Held funds of customer {} would be {} {} while the open disputes hold {}",
+                client_id, held, currency, disputed
+            ),
+            PaymentEngineError::Storage(message) => write!(f, "Storage failure: {}", message),
+            PaymentEngineError::MalformedRow { line, reason } => write!(
+                f,
+                "Malformed row at line {}: {}",
+                line.map_or("unknown".to_owned(), |line| line.to_string()),
+                reason
+            ),
+            PaymentEngineError::StrictModeViolation {
+                row,
+                transaction_id,
+                reason,
+            } => write!(
+                f,
+                "Row {}: transaction {} rejected in strict mode: {}",
+                row, transaction_id, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PaymentEngineError {}
+
+impl From<String> for PaymentEngineError {
+    fn from(message: String) -> Self {
+        PaymentEngineError::Storage(message)
+    }
+}
+
+impl From<csv::Error> for PaymentEngineError {
+    fn from(e: csv::Error) -> Self {
+        PaymentEngineError::Storage(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for PaymentEngineError {
+    fn from(e: std::io::Error) -> Self {
+        PaymentEngineError::Storage(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod payment_engine_error_tests {
+    use super::*;
+
+    #[test]
+    fn only_storage_errors_are_fatal() {
+        assert!(PaymentEngineError::Storage("disk full".to_owned()).is_fatal());
+        assert!(!PaymentEngineError::AccountLocked(1).is_fatal());
+        assert!(!PaymentEngineError::AmountMissing(1).is_fatal());
+        assert!(PaymentEngineError::HeldFundsOutOfBounds {
+            client_id: 1,
+            currency: "USD".to_owned(),
+            held: Decimal::ONE,
+            disputed: Decimal::ZERO,
+        }
+        .is_fatal());
+    }
+
+    #[test]
+    fn string_errors_convert_to_storage() {
+        assert_eq!(
+            PaymentEngineError::from("failure".to_owned()),
+            PaymentEngineError::Storage("failure".to_owned())
+        );
+    }
+
+    #[test]
+    fn display_includes_the_details() {
+        assert_eq!(
+            PaymentEngineError::ClientMismatch {
+                transaction_id: 2,
+                client_id: 1
+            }
+            .to_string(),
+            "Transaction 2 doesn't belong to the customer 1"
+        );
+    }
+
+    #[test]
+    fn strict_mode_violation_display_includes_the_row() {
+        assert_eq!(
+            PaymentEngineError::StrictModeViolation {
+                row: 3,
+                transaction_id: 7,
+                reason: Box::new(PaymentEngineError::InsufficientFunds(1)),
+            }
+            .to_string(),
+            "Row 3: transaction 7 rejected in strict mode: The customer 1 doesn't have enough available funds"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::{cell::RefCell, rc::Rc};
+
+use log::{info, warn};
+use rust_decimal::Decimal;
+
+use crate::{
+    common_types::{Currency, CustomerId, TransactionId},
+    transaction_outcome::SkipReason,
+    transaction_request::TransactionType,
+};
+
+// Hooks into the requests handled by the DefaultTransactionsManager, e.g. for alerting. Every hook does nothing by
+// default, so the listeners only implement the ones they need. They're called after the request was handled, in the
+// order of the trait methods.
+pub trait TransactionEventListener {
+    fn on_transaction_executed(
+        &mut self,
+        _transaction_type: &TransactionType,
+        _transaction_id: TransactionId,
+        _client_id: CustomerId,
+    ) {
+    }
+
+    fn on_transaction_skipped(
+        &mut self,
+        _transaction_type: &TransactionType,
+        _transaction_id: TransactionId,
+        _client_id: CustomerId,
+        _reason: SkipReason,
+    ) {
+    }
+
+    // The account of the client got locked by the transaction, by a chargeback or by the fraud rules
+    fn on_account_locked(&mut self, _client_id: CustomerId, _transaction_id: TransactionId) {}
+
+    // The available funds of the account went below zero with the transaction, e.g. a dispute of an already spent
+    // deposit. Not called again while they stay negative.
+    fn on_negative_available(
+        &mut self,
+        _client_id: CustomerId,
+        _currency: &str,
+        _available: Decimal,
+    ) {
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionEvent {
+    Executed {
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+        client: CustomerId,
+    },
+    Skipped {
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+        client: CustomerId,
+        reason: SkipReason,
+    },
+    AccountLocked {
+        client: CustomerId,
+        transaction_id: TransactionId,
+    },
+    NegativeAvailable {
+        client: CustomerId,
+        currency: Currency,
+        available: Decimal,
+    },
+}
+
+// Logs the locks and the negative balances, the skipped requests are logged by process_all already
+#[derive(Default)]
+pub struct LoggingListener;
+
+impl TransactionEventListener for LoggingListener {
+    fn on_account_locked(&mut self, client_id: CustomerId, transaction_id: TransactionId) {
+        info!(
+            "The account of customer {} got locked by transaction {}",
+            client_id, transaction_id
+        );
+    }
+
+    fn on_negative_available(&mut self, client_id: CustomerId, currency: &str, available: Decimal) {
+        warn!(
+            "The available funds of customer {} went negative: {} {}",
+            client_id, available, currency
+        );
+    }
+}
+
+// Keeps the events in their order. The clones share them, so a clone can read what the manager's one collected.
+#[derive(Clone, Default)]
+pub struct CollectingListener {
+    events: Rc<RefCell<Vec<TransactionEvent>>>,
+}
+
+impl CollectingListener {
+    pub fn events(&self) -> Vec<TransactionEvent> {
+        self.events.borrow().clone()
+    }
+
+    fn push(&mut self, event: TransactionEvent) {
+        self.events.borrow_mut().push(event);
+    }
+}
+
+impl TransactionEventListener for CollectingListener {
+    fn on_transaction_executed(
+        &mut self,
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+    ) {
+        self.push(TransactionEvent::Executed {
+            transaction_type: transaction_type.clone(),
+            transaction_id,
+            client: client_id,
+        });
+    }
+
+    fn on_transaction_skipped(
+        &mut self,
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+        reason: SkipReason,
+    ) {
+        self.push(TransactionEvent::Skipped {
+            transaction_type: transaction_type.clone(),
+            transaction_id,
+            client: client_id,
+            reason,
+        });
+    }
+
+    fn on_account_locked(&mut self, client_id: CustomerId, transaction_id: TransactionId) {
+        self.push(TransactionEvent::AccountLocked {
+            client: client_id,
+            transaction_id,
+        });
+    }
+
+    fn on_negative_available(&mut self, client_id: CustomerId, currency: &str, available: Decimal) {
+        self.push(TransactionEvent::NegativeAvailable {
+            client: client_id,
+            currency: currency.to_owned(),
+            available,
+        });
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::{HashMap, VecDeque};
+
+use rust_decimal::Decimal;
+
+use crate::common_types::CustomerId;
+
+// Limits of the withdrawals a client can execute within the window, the ones set to None are not checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FraudConfig {
+    pub max_withdrawals_per_window: Option<usize>,
+    pub max_amount_per_window: Option<Decimal>,
+    // Number of the latest requests handled by the manager, of any client, that make up the window
+    pub window_size: u64,
+}
+
+pub(crate) struct FraudMonitor {
+    config: FraudConfig,
+    // Number of requests handled so far, the position of the latest one
+    position: u64,
+    // Positions and amounts of the executed withdrawals of each client, the ones out of the window are dropped lazily
+    recent_withdrawals: HashMap<CustomerId, VecDeque<(u64, Decimal)>>,
+}
+
+impl FraudMonitor {
+    pub(crate) fn new(config: FraudConfig) -> Self {
+        FraudMonitor {
+            config,
+            position: 0,
+            recent_withdrawals: HashMap::new(),
+        }
+    }
+
+    // Called for every handled request, so the window moves with them whether they were executed or not
+    pub(crate) fn advance(&mut self) {
+        self.position += 1;
+    }
+
+    // Records the executed withdrawal, true when the client crossed a limit within the window. The client starts
+    // with an empty window afterwards, so unlocking the account gives them a clean slate.
+    pub(crate) fn record_withdrawal(&mut self, client_id: CustomerId, amount: Decimal) -> bool {
+        let recent_withdrawals = self.recent_withdrawals.entry(client_id).or_default();
+        recent_withdrawals.push_back((self.position, amount));
+        while let Some((position, _)) = recent_withdrawals.front() {
+            if position + self.config.window_size > self.position {
+                break;
+            }
+            recent_withdrawals.pop_front();
+        }
+        let too_many = self
+            .config
+            .max_withdrawals_per_window
+            .is_some_and(|max_withdrawals| recent_withdrawals.len() > max_withdrawals);
+        let too_much = self.config.max_amount_per_window.is_some_and(|max_amount| {
+            // An overflowing sum is way beyond any sensible limit
+            recent_withdrawals
+                .iter()
+                .try_fold(Decimal::ZERO, |sum, (_, amount)| sum.checked_add(*amount))
+                .is_none_or(|sum| sum > max_amount)
+        });
+        if too_many || too_much {
+            self.recent_withdrawals.remove(&client_id);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn withdrawals_leave_the_window_as_the_requests_move_on() {
+        let mut fraud_monitor = FraudMonitor::new(FraudConfig {
+            max_withdrawals_per_window: Some(2),
+            max_amount_per_window: Some(Decimal::new(100, 0)),
+            window_size: 3,
+        });
+        let mut withdraw = |client_id, amount| {
+            fraud_monitor.advance();
+            fraud_monitor.record_withdrawal(client_id, Decimal::new(amount, 0))
+        };
+        assert!(!withdraw(1, 10));
+        assert!(!withdraw(1, 10));
+        assert!(!withdraw(2, 10));
+        // The first withdrawal is out of the window by now
+        assert!(!withdraw(1, 10));
+        assert!(!withdraw(1, 10));
+        assert!(withdraw(1, 10));
+        // The window of the client is cleared once a rule fired
+        assert!(!withdraw(1, 10));
+        assert!(withdraw(2, 101));
+    }
+}
@@ -0,0 +1,150 @@
+//! Property-based checks of the invariants that must hold after every request, whatever the input looks like.
+
+use std::collections::{HashMap, HashSet};
+
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+
+use crate::{
+    common_types::{CustomerId, TransactionId},
+    customer_account_provider::{CustomerAccountReport, InMemoryCustomerAccountProvider},
+    transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+    transaction_outcome::TransactionOutcome,
+    transaction_request::{TransactionRequest, TransactionType},
+    transactions_manager::{DefaultTransactionsManager, TransactionsManager},
+};
+
+// Few clients and IDs, so the sequences are full of duplicates, cross-client disputes and repeated disputes
+fn transaction_request() -> impl Strategy<Value = TransactionRequest> {
+    (
+        prop_oneof![
+            4 => Just(TransactionType::Deposit),
+            3 => Just(TransactionType::Withdrawal),
+            2 => Just(TransactionType::Dispute),
+            1 => Just(TransactionType::Resolve),
+            1 => Just(TransactionType::Chargeback),
+            1 => Just(TransactionType::Unlock),
+        ],
+        1..4u16,
+        1..16u32,
+        prop::option::weighted(0.8, (-100..10_000i64, 0..5u32)),
+    )
+        .prop_map(
+            |(transaction_type, client_id, transaction_id, amount)| TransactionRequest {
+                amount: match transaction_type {
+                    // Mostly without amount, but sometimes with one to exercise the validation
+                    TransactionType::Deposit | TransactionType::Withdrawal => amount,
+                    _ => amount.filter(|(value, _)| value % 5 == 0),
+                }
+                .map(|(value, scale)| Decimal::new(value, scale)),
+                transaction_type,
+                client_id,
+                transaction_id,
+                timestamp: None,
+                currency: None,
+            },
+        )
+}
+
+fn accounts_by_client(
+    manager: &DefaultTransactionsManager,
+) -> HashMap<CustomerId, CustomerAccountReport> {
+    manager
+        .list_accounts()
+        .unwrap()
+        .into_iter()
+        .map(|account| (account.client, account))
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn invariants_hold_after_every_request(
+        requests in prop::collection::vec(transaction_request(), 0..60)
+    ) {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_allow_unlock(true)
+        .with_invariant_tracking();
+        // The executed deposits and withdrawals, to compute the totals independently from the manager
+        let mut executed: HashMap<TransactionId, TransactionRequest> = HashMap::new();
+        let mut charged_back: HashSet<TransactionId> = HashSet::new();
+        for request in requests {
+            if !manager.structure_validation(&request) {
+                continue;
+            }
+            let was_locked = accounts_by_client(&manager)
+                .get(&request.client_id)
+                .is_some_and(|account| account.locked);
+            let outcome = manager.handle_transaction(request.clone()).unwrap();
+
+            // Locked accounts never see a successful withdrawal
+            if request.transaction_type == TransactionType::Withdrawal {
+                prop_assert!(!(was_locked && outcome == TransactionOutcome::Executed));
+            }
+            if outcome == TransactionOutcome::Executed
+                && matches!(
+                    request.transaction_type,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                )
+            {
+                executed.insert(request.transaction_id, request);
+            }
+
+            // A charged back transaction stays charged back, it's never resolved or held again
+            for transaction_id in manager.known_transaction_ids().unwrap() {
+                let state = manager
+                    .read_transaction_state(transaction_id)
+                    .unwrap()
+                    .unwrap_or_default();
+                if charged_back.contains(&transaction_id) {
+                    prop_assert!(state.charged_back && !state.held);
+                }
+                if state.charged_back {
+                    charged_back.insert(transaction_id);
+                }
+            }
+
+            let mut expected_totals: HashMap<CustomerId, Decimal> = HashMap::new();
+            let mut charged_back_deposits_of: HashSet<CustomerId> = HashSet::new();
+            for transaction in executed.values() {
+                let state = manager
+                    .read_transaction_state(transaction.transaction_id)
+                    .unwrap()
+                    .unwrap_or_default();
+                let amount = transaction.amount.unwrap();
+                // Disputing a withdrawal holds its amount and charging it back returns it, while a charged back
+                // deposit leaves the account
+                let change = match transaction.transaction_type {
+                    TransactionType::Deposit if state.charged_back => {
+                        charged_back_deposits_of.insert(transaction.client_id);
+                        Decimal::ZERO
+                    }
+                    TransactionType::Deposit => amount,
+                    _ if state.held || state.charged_back => Decimal::ZERO,
+                    _ => -amount,
+                };
+                *expected_totals.entry(transaction.client_id).or_default() += change;
+            }
+            for (client, account) in accounts_by_client(&manager) {
+                // Held is never negative
+                prop_assert!(account.held >= Decimal::ZERO, "{:?}", account);
+                // The total always consists of available and held
+                prop_assert_eq!(account.total, account.available + account.held);
+                // Only a charged back deposit can take the total below zero, the withdrawals alone never do
+                if !charged_back_deposits_of.contains(&client) {
+                    prop_assert!(account.total >= Decimal::ZERO, "{:?}", account);
+                }
+                // The total follows the executed deposits and withdrawals, whatever their dispute state is
+                prop_assert_eq!(
+                    account.total,
+                    expected_totals.get(&client).copied().unwrap_or_default()
+                );
+            }
+            // The tracker of the manager agrees with the totals computed here
+            prop_assert_eq!(manager.verify_invariants().unwrap(), vec![]);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    common_types::{Currency, CustomerId},
+    customer_account_provider::CustomerAccountReport,
+    errors::PaymentEngineError,
+    transaction_request::TransactionType,
+};
+
+// An account whose total differs from the funds the executed requests brought in and took out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FundsDivergence {
+    pub client: CustomerId,
+    pub currency: Currency,
+    pub expected_total: Decimal,
+    pub actual_total: Decimal,
+}
+
+impl Display for FundsDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {} {}: total is {}, the executed requests give {}",
+            self.client, self.currency, self.actual_total, self.expected_total
+        )
+    }
+}
+
+// The totals every account should have, derived from the executed requests alone, so a provider or a handler losing
+// or creating money shows up as a divergence
+pub(crate) struct InvariantTracker {
+    expected_totals: BTreeMap<(CustomerId, Currency), Decimal>,
+    // The amounts of the open disputes of every account, which its held funds have to add up to
+    expected_held: BTreeMap<(CustomerId, Currency), Decimal>,
+}
+
+impl InvariantTracker {
+    // The accounts that existed before the tracking started, e.g. restored from a snapshot, keep their totals
+    pub(crate) fn new(accounts: impl Iterator<Item = CustomerAccountReport>) -> Self {
+        let mut expected_totals = BTreeMap::new();
+        let mut expected_held = BTreeMap::new();
+        for account in accounts {
+            let key = (account.client, account.currency);
+            expected_held.insert(key.clone(), account.held);
+            expected_totals.insert(key, account.total);
+        }
+        InvariantTracker {
+            expected_totals,
+            expected_held,
+        }
+    }
+
+    // Adds the change of the total made by the executed request. The amount is the one of the request itself for the
+    // deposits and withdrawals, and the one of the disputed transaction for the dispute family.
+    pub(crate) fn record(
+        &mut self,
+        transaction_type: &TransactionType,
+        disputed_type: Option<&TransactionType>,
+        client_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) {
+        let is_withdrawal = disputed_type == Some(&TransactionType::Withdrawal);
+        let change = match transaction_type {
+            TransactionType::Deposit => amount,
+            TransactionType::Withdrawal => -amount,
+            // The withdrawn amount is held on top of the available funds while disputed
+            TransactionType::Dispute if is_withdrawal => amount,
+            TransactionType::Resolve if is_withdrawal => -amount,
+            // A charged back withdrawal keeps the held amount as available funds
+            TransactionType::Chargeback if !is_withdrawal => -amount,
+            _ => Decimal::ZERO,
+        };
+        *self
+            .expected_totals
+            .entry((client_id, currency.to_owned()))
+            .or_default() += change;
+        // Disputing either type holds the amount, settling the dispute either way releases it
+        let held_change = match transaction_type {
+            TransactionType::Dispute => amount,
+            TransactionType::Resolve | TransactionType::Chargeback => -amount,
+            _ => return,
+        };
+        *self
+            .expected_held
+            .entry((client_id, currency.to_owned()))
+            .or_default() += held_change;
+    }
+
+    // The held funds can never be negative nor more than the amounts of the open disputes of the account, e.g. a
+    // dispute holding funds of a transaction that was already settled
+    pub(crate) fn check_held(
+        &self,
+        client_id: CustomerId,
+        currency: &str,
+        held: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let expected_held = self
+            .expected_held
+            .get(&(client_id, currency.to_owned()))
+            .copied()
+            .unwrap_or_default();
+        if held < Decimal::ZERO || held > expected_held {
+            return Err(PaymentEngineError::InvariantViolation(format!(
+                "Held funds of customer {} are {} {} while the open disputes only hold {}",
+                client_id, held, currency, expected_held
+            )));
+        }
+        Ok(())
+    }
+
+    // Compares the accounts with the expected totals, the missing accounts count as empty ones
+    pub(crate) fn divergences(
+        &self,
+        accounts: impl Iterator<Item = CustomerAccountReport>,
+    ) -> Vec<FundsDivergence> {
+        let mut actual_totals: BTreeMap<(CustomerId, Currency), Decimal> = accounts
+            .map(|account| ((account.client, account.currency), account.total))
+            .collect();
+        let mut divergences = vec![];
+        for (key, expected_total) in &self.expected_totals {
+            let actual_total = actual_totals.remove(key).unwrap_or_default();
+            if actual_total != *expected_total {
+                divergences.push(FundsDivergence {
+                    client: key.0,
+                    currency: key.1.clone(),
+                    expected_total: *expected_total,
+                    actual_total,
+                });
+            }
+        }
+        for ((client, currency), actual_total) in actual_totals {
+            if !actual_total.is_zero() {
+                divergences.push(FundsDivergence {
+                    client,
+                    currency,
+                    expected_total: Decimal::ZERO,
+                    actual_total,
+                });
+            }
+        }
+        divergences.sort_unstable_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+        divergences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(client: CustomerId, total: Decimal) -> CustomerAccountReport {
+        CustomerAccountReport {
+            client,
+            available: total,
+            held: Decimal::ZERO,
+            total,
+            locked: false,
+            last_activity: None,
+            currency: "USD".to_owned(),
+            total_charged_back: Decimal::ZERO,
+            open_disputes: 0,
+            total_deposited: Decimal::ZERO,
+            total_withdrawn: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn accounts_missing_on_either_side_count_as_empty() {
+        let mut invariant_tracker =
+            InvariantTracker::new(vec![account(1, Decimal::TEN)].into_iter());
+        invariant_tracker.record(&TransactionType::Deposit, None, 2, "USD", Decimal::ONE);
+        invariant_tracker.record(&TransactionType::Deposit, None, 3, "USD", Decimal::ONE);
+        invariant_tracker.record(&TransactionType::Withdrawal, None, 3, "USD", Decimal::ONE);
+        assert_eq!(
+            invariant_tracker
+                .divergences(vec![account(1, Decimal::TEN), account(4, Decimal::ONE)].into_iter()),
+            vec![
+                FundsDivergence {
+                    client: 2,
+                    currency: "USD".to_owned(),
+                    expected_total: Decimal::ONE,
+                    actual_total: Decimal::ZERO,
+                },
+                FundsDivergence {
+                    client: 4,
+                    currency: "USD".to_owned(),
+                    expected_total: Decimal::ZERO,
+                    actual_total: Decimal::ONE,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn held_funds_are_limited_to_the_open_disputes() {
+        let mut invariant_tracker = InvariantTracker::new(std::iter::empty());
+        let disputed = Some(&TransactionType::Withdrawal);
+        invariant_tracker.record(&TransactionType::Dispute, disputed, 1, "USD", Decimal::TEN);
+        assert_eq!(invariant_tracker.check_held(1, "USD", Decimal::TEN), Ok(()));
+        assert!(invariant_tracker
+            .check_held(1, "USD", Decimal::new(11, 0))
+            .is_err());
+        assert!(invariant_tracker
+            .check_held(1, "EUR", Decimal::ONE)
+            .is_err());
+        invariant_tracker.record(
+            &TransactionType::Chargeback,
+            disputed,
+            1,
+            "USD",
+            Decimal::TEN,
+        );
+        assert!(invariant_tracker
+            .check_held(1, "USD", Decimal::TEN)
+            .is_err());
+        assert!(invariant_tracker
+            .check_held(1, "USD", -Decimal::ONE)
+            .is_err());
+        assert_eq!(
+            invariant_tracker.check_held(1, "USD", Decimal::ZERO),
+            Ok(())
+        );
+    }
+}
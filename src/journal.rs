@@ -0,0 +1,308 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::PaymentEngineError,
+    transaction_outcome::{SkipReason, TransactionOutcome},
+    transaction_request::TransactionRequest,
+    transactions_manager::TransactionsManager,
+};
+
+// The records written between the fsyncs of the journal by default
+pub const DEFAULT_JOURNAL_SYNC_EVERY: usize = 1000;
+
+// A line of the journal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub request: TransactionRequest,
+    pub outcome: TransactionOutcome,
+}
+
+// Only the requests that changed the state are journaled, the executed ones and the follow-ups parked until their
+// transaction arrives. The skipped ones are left out, so replaying the journal doesn't reproduce the parts of the
+// state counting every request, e.g. the processing summary.
+fn is_journaled(outcome: &TransactionOutcome) -> bool {
+    matches!(
+        outcome,
+        TransactionOutcome::Executed | TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)
+    )
+}
+
+// Appends the requests handled by the wrapped manager to the journal file, so a crashed run can be recovered with
+// replay_journal. The records are fsynced in batches, a crash can lose the ones written since the last sync.
+pub struct JournalingTransactionsManager<M: TransactionsManager> {
+    inner: M,
+    writer: BufWriter<File>,
+    sync_every: usize,
+    unsynced: usize,
+}
+
+impl<M: TransactionsManager> JournalingTransactionsManager<M> {
+    // Appends to the journal when it exists already, e.g. after replaying it into the manager
+    pub fn open(inner: M, path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JournalingTransactionsManager {
+            inner,
+            writer: BufWriter::new(file),
+            sync_every: DEFAULT_JOURNAL_SYNC_EVERY,
+            unsynced: 0,
+        })
+    }
+
+    pub fn with_sync_every(mut self, sync_every: usize) -> Self {
+        self.sync_every = sync_every.max(1);
+        self
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    // Syncs the journal, so nothing journaled so far is lost
+    pub fn into_inner(mut self) -> Result<M, PaymentEngineError> {
+        self.sync()?;
+        Ok(self.inner)
+    }
+
+    pub fn sync(&mut self) -> Result<(), PaymentEngineError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> Result<(), PaymentEngineError> {
+        serde_json::to_writer(&mut self.writer, entry)
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))?;
+        self.writer.write_all(b"\n")?;
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl<M: TransactionsManager> TransactionsManager for JournalingTransactionsManager<M> {
+    fn structure_validation(&self, transaction_request: &TransactionRequest) -> bool {
+        self.inner.structure_validation(transaction_request)
+    }
+
+    // A request that can't be journaled fails the run, as the journal would no longer recover the state
+    fn handle_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        let outcome = self.inner.handle_transaction(transaction_request.clone())?;
+        if is_journaled(&outcome) {
+            self.append(&JournalEntry {
+                request: transaction_request,
+                outcome,
+            })?;
+        }
+        Ok(outcome)
+    }
+
+    fn write_report(&self, writer: &mut dyn Write) -> Result<(), PaymentEngineError> {
+        self.inner.write_report(writer)
+    }
+
+    fn print_report(&self) -> Result<(), PaymentEngineError> {
+        self.inner.print_report()
+    }
+}
+
+// Re-feeds the journaled requests into the manager, returning how many of them were replayed. A missing journal
+// replays nothing. The manager has to be configured like the one that wrote the journal, a request with another
+// outcome than the journaled one fails the replay.
+pub fn replay_journal(
+    path: impl AsRef<Path>,
+    transactions_manager: &mut dyn TransactionsManager,
+) -> Result<usize, PaymentEngineError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = BufReader::new(file).lines().enumerate().peekable();
+    let mut replayed = 0;
+    while let Some((index, line)) = lines.next() {
+        let entry: JournalEntry = match serde_json::from_str(&line?) {
+            Ok(entry) => entry,
+            // The process died while writing the last record, it was never acknowledged
+            Err(e) if lines.peek().is_none() => {
+                warn!("Ignoring the incomplete last line of the journal: {}", e);
+                break;
+            }
+            Err(e) => {
+                return Err(PaymentEngineError::Storage(format!(
+                    "Malformed journal line {}: {}",
+                    index + 1,
+                    e
+                )))
+            }
+        };
+        let transaction_id = entry.request.transaction_id;
+        let outcome = transactions_manager.handle_transaction(entry.request)?;
+        if outcome != entry.outcome {
+            return Err(PaymentEngineError::InvariantViolation(format!(
+                "Replaying transaction {} gave {:?} instead of the journaled {:?}",
+                transaction_id, outcome, entry.outcome
+            )));
+        }
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        customer_account_provider::InMemoryCustomerAccountProvider,
+        transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        transaction_request::TransactionType,
+        transaction_requests_reader::{SyntheticReader, TransactionRequestsReader},
+        transactions_manager::DefaultTransactionsManager,
+    };
+
+    use super::*;
+
+    fn new_manager() -> DefaultTransactionsManager {
+        DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+    }
+
+    fn report_of(transactions_manager: &dyn TransactionsManager) -> String {
+        let mut report = vec![];
+        transactions_manager.write_report(&mut report).unwrap();
+        String::from_utf8(report).unwrap()
+    }
+
+    fn requests() -> Vec<TransactionRequest> {
+        SyntheticReader::new(2000, 50, 42)
+            .with_withdrawal_percent(30)
+            .with_dispute_percent(10)
+            .read()
+            .collect()
+    }
+
+    #[test]
+    fn replayed_journal_continues_like_an_uninterrupted_run() {
+        let directory = tempfile::tempdir().unwrap();
+        let journal_path = directory.path().join("journal.jsonl");
+        let requests = requests();
+
+        let mut uninterrupted = new_manager();
+        for request in requests.clone() {
+            uninterrupted.handle_transaction(request).unwrap();
+        }
+
+        let mut journaling = JournalingTransactionsManager::open(new_manager(), &journal_path)
+            .unwrap()
+            .with_sync_every(1);
+        for request in requests[..1000].iter().cloned() {
+            journaling.handle_transaction(request).unwrap();
+        }
+        // Stopping without syncing or dropping anything, like a killed process
+        std::mem::forget(journaling);
+
+        let mut recovered = new_manager();
+        let replayed = replay_journal(&journal_path, &mut recovered).unwrap();
+        assert!(replayed > 0 && replayed < 1000);
+        let mut journaling = JournalingTransactionsManager::open(recovered, &journal_path).unwrap();
+        for request in requests[1000..].iter().cloned() {
+            journaling.handle_transaction(request).unwrap();
+        }
+        let recovered = journaling.into_inner().unwrap();
+        assert_eq!(report_of(&recovered), report_of(&uninterrupted));
+
+        // The journal now holds the whole run
+        let mut replayed_again = new_manager();
+        replay_journal(&journal_path, &mut replayed_again).unwrap();
+        assert_eq!(report_of(&replayed_again), report_of(&uninterrupted));
+    }
+
+    #[test]
+    fn records_after_the_last_sync_are_lost_with_the_process() {
+        let directory = tempfile::tempdir().unwrap();
+        let journal_path = directory.path().join("journal.jsonl");
+        let mut journaling = JournalingTransactionsManager::open(new_manager(), &journal_path)
+            .unwrap()
+            .with_sync_every(10);
+        for transaction_id in 1..=25 {
+            journaling
+                .handle_transaction(TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id: 1,
+                    transaction_id,
+                    amount: Some(Decimal::ONE),
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        // Skipped as a duplicate, so it's not journaled
+        journaling
+            .handle_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        std::mem::forget(journaling);
+        assert_eq!(
+            fs::read_to_string(&journal_path).unwrap().lines().count(),
+            20
+        );
+    }
+
+    #[test]
+    fn incomplete_last_line_is_ignored_but_a_diverging_replay_fails() {
+        let directory = tempfile::tempdir().unwrap();
+        let journal_path = directory.path().join("journal.jsonl");
+        assert_eq!(replay_journal(&journal_path, &mut new_manager()), Ok(0));
+
+        let entry = |transaction_id| JournalEntry {
+            request: TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id,
+                amount: Some(Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            },
+            outcome: TransactionOutcome::Executed,
+        };
+        let line = |transaction_id| serde_json::to_string(&entry(transaction_id)).unwrap();
+        fs::write(&journal_path, format!("{}\n{}\n{{\"requ", line(1), line(2))).unwrap();
+        assert_eq!(replay_journal(&journal_path, &mut new_manager()), Ok(2));
+
+        fs::write(&journal_path, format!("{{\"requ\n{}\n", line(1))).unwrap();
+        assert!(matches!(
+            replay_journal(&journal_path, &mut new_manager()),
+            Err(PaymentEngineError::Storage(_))
+        ));
+
+        // The second deposit with the same ID is a duplicate when replayed
+        fs::write(&journal_path, format!("{}\n{}\n", line(1), line(1))).unwrap();
+        assert!(matches!(
+            replay_journal(&journal_path, &mut new_manager()),
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+    }
+}
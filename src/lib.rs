@@ -0,0 +1,48 @@
+#[cfg(feature = "async")]
+pub mod async_customer_account_provider;
+#[cfg(feature = "async")]
+pub mod async_transactions_manager;
+pub mod audit;
+pub mod cli;
+pub mod common_types;
+pub mod customer_account_provider;
+pub mod dry_run;
+pub mod duplicate_detector;
+pub mod errors;
+pub mod events;
+pub mod fraud;
+pub mod invariant_tracker;
+pub mod journal;
+#[cfg(test)]
+mod invariant_tests;
+pub mod logging;
+pub mod manifest;
+pub mod pending_follow_ups;
+pub mod preview;
+pub mod profiling;
+pub mod report_formatter;
+pub mod runner;
+pub mod sharded_transactions_manager;
+pub mod sled_customer_account_provider;
+pub mod snapshot;
+pub mod transaction_history_provider;
+pub mod transaction_outcome;
+pub mod transaction_request;
+pub mod transaction_requests_reader;
+pub mod transactions_manager;
+pub mod validation;
+pub mod write_batch;
+
+// The types needed to embed the engine, so the users don't have to know the module layout
+pub use customer_account_provider::{
+    CustomerAccountProvider, CustomerAccountReport, InMemoryCustomerAccountProvider,
+};
+pub use errors::PaymentEngineError;
+pub use runner::process;
+pub use transaction_history_provider::{
+    in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+    transaction_history_provider::TransactionHistoryProvider,
+};
+pub use transaction_request::{TransactionRequest, TransactionState, TransactionType};
+pub use transaction_requests_reader::{DefaultTransactionRequestsReader, TransactionRequestsReader};
+pub use transactions_manager::{DefaultTransactionsManager, TransactionsManager};
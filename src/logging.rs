@@ -0,0 +1,375 @@
+//! Logger of the binary. The logs never go to stdout, as that's where the report is printed by default.
+
+use std::{
+    fs::File,
+    io::{stderr, LineWriter, Write},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, log, Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{transaction_outcome::SkipReason, transaction_request::TransactionRequest};
+
+// Target of the messages about the individual skipped requests, the quiet loggers drop them
+pub const SKIPPED_REQUESTS_TARGET: &str = "skipped_requests";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    // None means stderr
+    pub file: Option<String>,
+    pub quiet: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Warn,
+            file: None,
+            quiet: false,
+        }
+    }
+}
+
+// The line of a skipped request, in the same key=value format for every reason so the logs can be grepped by any of
+// the fields. The more a skip points to broken or malicious input, the higher its level.
+pub(crate) fn log_skip(reason: SkipReason, request: &TransactionRequest) {
+    let level = match reason {
+        SkipReason::CrossClientIdReuse | SkipReason::Overflow => Level::Error,
+        SkipReason::ConflictingDuplicate
+        | SkipReason::DisputeLimitReached
+        | SkipReason::TransactionLimitExceeded
+        | SkipReason::AccountTotalLimitExceeded => Level::Warn,
+        _ => Level::Info,
+    };
+    log!(
+        target: SKIPPED_REQUESTS_TARGET,
+        level,
+        "skip reason=\"{}\" tx={} client={} type={}",
+        reason,
+        request.transaction_id,
+        request.client_id,
+        request.transaction_type
+    );
+}
+
+pub(crate) fn log_executed(request: &TransactionRequest) {
+    debug!(
+        "executed tx={} client={} type={}",
+        request.transaction_id, request.client_id, request.transaction_type
+    );
+}
+
+// Writes every enabled record as a "LEVEL - message" line into the target, optionally prefixed with the UTC time
+pub struct WriterLogger {
+    level: LevelFilter,
+    quiet: bool,
+    timestamps: bool,
+    target: Mutex<Box<dyn Write + Send>>,
+}
+
+impl WriterLogger {
+    pub fn new(level: LevelFilter, target: Box<dyn Write + Send>) -> Self {
+        WriterLogger {
+            level,
+            quiet: false,
+            timestamps: false,
+            target: Mutex::new(target),
+        }
+    }
+
+    pub fn with_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    // Drops the messages about the individual skipped requests, whatever their level
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+}
+
+impl Log for WriterLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && !(self.quiet && metadata.target() == SKIPPED_REQUESTS_TARGET)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Ok(mut target) = self.target.lock() {
+            // Nowhere left to report a failing log target, so the line is dropped
+            let _ = if self.timestamps {
+                writeln!(
+                    target,
+                    "{} {} - {}",
+                    format_timestamp(SystemTime::now()),
+                    record.level(),
+                    record.args()
+                )
+            } else {
+                writeln!(target, "{} - {}", record.level(), record.args())
+            };
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut target) = self.target.lock() {
+            let _ = target.flush();
+        }
+    }
+}
+
+// RFC 3339 in UTC with milliseconds, e.g. 2024-02-29T13:05:09.123Z, without pulling in a date crate
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+    // The civil date of the days since the epoch, by Howard Hinnant's algorithm
+    let shifted_days = days + 719_468;
+    let era = shifted_days / 146_097;
+    let day_of_era = shifted_days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Installs the global logger, can be called only once per process
+pub fn init_logging(config: &LogConfig) -> Result<(), String> {
+    let target: Box<dyn Write + Send> = match &config.file {
+        Some(path) => {
+            Box::new(LineWriter::new(File::create(path).map_err(|e| {
+                format!("Failed creating the log file {}: {}", path, e)
+            })?))
+        }
+        None => Box::new(stderr()),
+    };
+    log::set_logger(Box::leak(Box::new(
+        WriterLogger::new(config.level, target)
+            .with_quiet(config.quiet)
+            .with_timestamps(true),
+    )))
+    .map_err(|e| format!("Failed setting the logger: {}", e))?;
+    log::set_max_level(config.level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use log::Level;
+
+    use super::*;
+
+    // Keeps the written bytes reachable after the logger took ownership of the writer
+    #[derive(Clone, Default)]
+    struct CapturingWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_at(logger: &WriterLogger, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    fn log_skipped_request(logger: &WriterLogger, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target(SKIPPED_REQUESTS_TARGET)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn only_the_records_within_the_level_are_written() {
+        let captured = CapturingWriter::default();
+        let logger = WriterLogger::new(LevelFilter::Info, Box::new(captured.clone()));
+        log_at(
+            &logger,
+            Level::Info,
+            "tx=42 client=7 skipped: insufficient funds",
+        );
+        log_at(&logger, Level::Debug, "not written");
+        log_at(&logger, Level::Error, "written");
+        assert_eq!(
+            String::from_utf8(captured.0.lock().unwrap().clone()).unwrap(),
+            "INFO - tx=42 client=7 skipped: insufficient funds\nERROR - written\n"
+        );
+    }
+
+    #[test]
+    fn quiet_logger_drops_only_the_skipped_requests() {
+        let captured = CapturingWriter::default();
+        let logger =
+            WriterLogger::new(LevelFilter::Info, Box::new(captured.clone())).with_quiet(true);
+        log_skipped_request(&logger, "tx=42 client=7 skipped: insufficient funds");
+        log_at(&logger, Level::Warn, "written");
+        assert_eq!(
+            String::from_utf8(captured.0.lock().unwrap().clone()).unwrap(),
+            "WARN - written\n"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_utc_with_milliseconds() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + std::time::Duration::from_millis(1_709_211_909_123)),
+            "2024-02-29T13:05:09.123Z"
+        );
+        let captured = CapturingWriter::default();
+        let logger =
+            WriterLogger::new(LevelFilter::Info, Box::new(captured.clone())).with_timestamps(true);
+        log_at(&logger, Level::Warn, "written");
+        let line = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(line.ends_with("Z WARN - written\n"));
+        assert_eq!(line.find(' '), Some(24));
+    }
+
+    // Keeps the records of the test's own thread, as the logger is global and the other tests log as well
+    struct ThreadCapturingLogger(Mutex<Vec<(std::thread::ThreadId, Level, String, String)>>);
+
+    impl Log for ThreadCapturingLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push((
+                std::thread::current().id(),
+                record.level(),
+                record.target().to_owned(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn skips_are_logged_with_their_fields() {
+        use rust_decimal::Decimal;
+
+        use crate::{
+            customer_account_provider::InMemoryCustomerAccountProvider,
+            transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+            transaction_request::TransactionType,
+            transactions_manager::{DefaultTransactionsManager, TransactionsManager},
+        };
+
+        static LOGGER: ThreadCapturingLogger = ThreadCapturingLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Debug);
+
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let request = |transaction_type, client_id, transaction_id, amount| TransactionRequest {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: Some(Decimal::new(amount, 0)),
+            timestamp: None,
+            currency: None,
+        };
+        for request in [
+            request(TransactionType::Deposit, 1, 1, 5),
+            request(TransactionType::Deposit, 1, 1, 5),
+            request(TransactionType::Withdrawal, 1, 2, 9),
+            request(TransactionType::Deposit, 2, 1, 5),
+        ] {
+            manager.handle_transaction(request).unwrap();
+        }
+
+        let thread_id = std::thread::current().id();
+        let records: Vec<(Level, String, String)> = LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.0 == thread_id)
+            .map(|record| (record.1, record.2.clone(), record.3.clone()))
+            .collect();
+        let skipped = |level, message: &str| {
+            (
+                level,
+                SKIPPED_REQUESTS_TARGET.to_owned(),
+                message.to_owned(),
+            )
+        };
+        assert_eq!(
+            records,
+            vec![
+                (
+                    Level::Debug,
+                    "simple_payment_engine::logging".to_owned(),
+                    "executed tx=1 client=1 type=deposit".to_owned()
+                ),
+                skipped(
+                    Level::Info,
+                    "skip reason=\"duplicate transaction\" tx=1 client=1 type=deposit"
+                ),
+                skipped(
+                    Level::Info,
+                    "skip reason=\"insufficient funds\" tx=2 client=1 type=withdrawal"
+                ),
+                skipped(
+                    Level::Error,
+                    "skip reason=\"transaction id of another client\" tx=1 client=2 type=deposit"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unwritable_log_file_is_reported() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("missing").join("engine.log");
+        let result = init_logging(&LogConfig {
+            level: LevelFilter::Info,
+            file: Some(path.to_str().unwrap().to_owned()),
+            quiet: false,
+        });
+        assert!(result
+            .unwrap_err()
+            .starts_with("Failed creating the log file"));
+    }
+}
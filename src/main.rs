@@ -1,66 +1,428 @@
-use std::env::args;
+use std::{
+    env::args,
+    fs::File,
+    io::{stderr, stdout, BufWriter, Write},
+    path::Path,
+    process::exit,
+    time::Instant,
+};
 
-use customer_account_provider::InMemoryCustomerAccountProvider;
-use log::{info, LevelFilter};
+use log::info;
+use simple_payment_engine::{
+    audit::{CsvAuditRecordSink, CsvAuditSink},
+    cli::{parse_cli, CliConfig, USAGE},
+    customer_account_provider::InMemoryCustomerAccountProvider,
+    dry_run::{DryRunValidator, FindingsFormat},
+    duplicate_detector::DEFAULT_BLOOM_CAPACITY,
+    errors::PaymentEngineError,
+    journal::{replay_journal, JournalingTransactionsManager},
+    logging::init_logging,
+    manifest::{InputHasher, Manifest},
+    profiling::{peak_rss_kib, stress_stats},
+    report_formatter::ReportFormat,
+    runner::{finish_reading, run, run_any, run_with_snapshots},
+    sharded_transactions_manager::ShardedTransactionsManager,
+    snapshot::Snapshot,
+    transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+    transaction_outcome::ProcessingSummary,
+    transaction_requests_reader::{
+        AsOfReader, ChainedTransactionRequestsReader, DefaultTransactionRequestsReader,
+        StdinTransactionRequestsReader, SyntheticReader, TransactionRequestsReader,
+    },
+    transactions_manager::{
+        DefaultTransactionsManager, Limits, TransactionsManager, ValidationConfig,
+    },
+};
 
-use log::{Level, Metadata, Record};
-use transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider;
-use transaction_requests_reader::{TransactionRequestsReader, DefaultTransactionRequestsReader};
+// The realistic mix of the --stress requests, the rest of them are deposits
+const STRESS_CLIENTS: u16 = 10_000;
+const STRESS_SEED: u64 = 42;
+const STRESS_DISPUTE_PERCENT: u64 = 5;
+const STRESS_WITHDRAWAL_PERCENT: u64 = 30;
 
-use crate::transactions_manager::{DefaultTransactionsManager, TransactionsManager};
+fn reader_for(config: &CliConfig) -> Box<dyn TransactionRequestsReader> {
+    let reader: Box<dyn TransactionRequestsReader> = match (config.stress, &config.inputs[..]) {
+        (Some(count), _) => Box::new(
+            SyntheticReader::new(count, STRESS_CLIENTS, STRESS_SEED)
+                .with_dispute_percent(STRESS_DISPUTE_PERCENT)
+                .with_withdrawal_percent(STRESS_WITHDRAWAL_PERCENT),
+        ),
+        (None, [path]) => Box::new(DefaultTransactionRequestsReader::new_with_scale(
+            path,
+            config.scale,
+            config.rounding,
+        )),
+        // Reading from stdin when no path or "-" is passed, so the input can be piped in
+        (None, []) => Box::new(StdinTransactionRequestsReader::new_with_scale(
+            config.scale,
+            config.rounding,
+        )),
+        (None, paths) => Box::new(ChainedTransactionRequestsReader::new_with_paths(
+            paths,
+            config.scale,
+            config.rounding,
+        )),
+    };
+    match config.as_of {
+        Some(timestamp) => Box::new(AsOfReader::new(reader, timestamp)),
+        None => reader,
+    }
+}
+
+fn validation_config(config: &CliConfig) -> ValidationConfig {
+    ValidationConfig {
+        ignore_follow_up_amounts: config.ignore_follow_up_amounts,
+        ..Default::default()
+    }
+}
 
-mod common_types;
-mod customer_account_provider;
-mod transaction_history_provider;
-mod transaction_request;
-mod transaction_requests_reader;
-mod transactions_manager;
+// The shortest possible row, e.g. "dispute,1,1,\n", so the estimate never falls short of the plain CSV inputs
+const MIN_ROW_BYTES: u64 = 13;
 
-struct SimpleLogger;
+// The number of IDs the --dedupe bloom filter is sized for, estimated from the size of the inputs. The gzipped inputs
+// are underestimated, which only means more history reads
+fn dedupe_capacity(config: &CliConfig) -> usize {
+    if let Some(count) = config.stress {
+        return count as usize;
+    }
+    if config.inputs.is_empty() {
+        return DEFAULT_BLOOM_CAPACITY;
+    }
+    let bytes: u64 = config
+        .inputs
+        .iter()
+        .filter_map(|input| std::fs::metadata(input).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    (bytes / MIN_ROW_BYTES) as usize
+}
 
-impl log::Log for SimpleLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+fn limits(config: &CliConfig) -> Limits {
+    Limits {
+        max_transaction_amount: config.max_tx_amount,
+        max_account_total: config.max_account_total,
     }
+}
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+// The statement of the --client, otherwise the account report
+fn write_output(
+    config: &CliConfig,
+    transactions_manager: &mut DefaultTransactionsManager,
+    writer: &mut dyn Write,
+) -> Result<(), PaymentEngineError> {
+    match config.client {
+        Some(client_id) => {
+            transactions_manager.generate_statement(client_id, &config.default_currency, writer)
         }
+        None => transactions_manager.write_report(writer),
     }
+}
 
-    fn flush(&self) {}
+// The content hash of the input when it's to be recorded into the --manifest. Exits with 3 when the manifest holds it
+// already, unless --force is passed.
+fn check_manifest(config: &CliConfig) -> Option<String> {
+    let (manifest_path, input) = (config.manifest.as_ref()?, config.inputs.first()?);
+    let manifest = Manifest::read_from(manifest_path)
+        .unwrap_or_else(|e| panic!("Failed reading the manifest {}: {}", manifest_path, e));
+    let hash = InputHasher::hash_file(input)
+        .unwrap_or_else(|e| panic!("Failed hashing the input {}: {}", input, e));
+    if let Some(entry) = manifest.find(&hash) {
+        if !config.force {
+            eprintln!(
+                "The input {} was already processed as {} (hash {}), pass --force to process it again",
+                input, entry.input, hash
+            );
+            exit(3);
+        }
+    }
+    Some(hash)
 }
 
-static LOGGER: SimpleLogger = SimpleLogger;
+// After the run completed, so a failed one can be repeated without --force
+fn record_run(config: &CliConfig, hash: Option<String>) {
+    if let (Some(manifest_path), Some(input), Some(hash)) =
+        (&config.manifest, config.inputs.first(), hash)
+    {
+        let mut manifest = Manifest::read_from(manifest_path)
+            .unwrap_or_else(|e| panic!("Failed reading the manifest {}: {}", manifest_path, e));
+        manifest.record(hash, input.clone());
+        manifest
+            .write_to(manifest_path)
+            .unwrap_or_else(|e| panic!("Failed writing the manifest {}: {}", manifest_path, e));
+    }
+}
 
-fn main() {
-    log::set_logger(&LOGGER)
-        // We can add a flag for verbose execution or redirect the logs to some file, but for now just turning off
-        .map(|()| log::set_max_level(LevelFilter::Warn))
-        .unwrap();
-
-    let path = match args().nth(1) {
-        Some(e) => e,
-        None => panic!("Path not passed for the input file!")
+// Replays the --journal into the manager first, then journals the requests of the input on top of it
+fn run_journaled(
+    mut transactions_manager: DefaultTransactionsManager,
+    reader: &dyn TransactionRequestsReader,
+    journal_path: &str,
+) -> (DefaultTransactionsManager, ProcessingSummary) {
+    let replayed = replay_journal(journal_path, &mut transactions_manager)
+        .unwrap_or_else(|e| panic!("Failed replaying the journal {}: {}", journal_path, e));
+    info!("Replayed {} requests of the journal", replayed);
+    let mut journaling_manager =
+        JournalingTransactionsManager::open(transactions_manager, journal_path)
+            .unwrap_or_else(|e| panic!("Failed opening the journal {}: {}", journal_path, e));
+    let summary = summary_or_exit(run_any(&mut journaling_manager, reader));
+    let transactions_manager = journaling_manager
+        .into_inner()
+        .expect("Syncing the journal failed.");
+    (transactions_manager, summary)
+}
+
+// Writes the findings of the input instead of the report, exiting with 1 when there are any
+fn run_dry(config: &CliConfig) {
+    let findings_format = match config.format {
+        ReportFormat::Json => FindingsFormat::Json,
+        _ => FindingsFormat::Csv,
     };
+    // The amounts above the limit are findings of their own in the dry run
+    let mut validator = DryRunValidator::new()
+        .with_validation_config(ValidationConfig {
+            max_amount: config.max_tx_amount,
+            ..validation_config(config)
+        })
+        .with_findings_format(findings_format);
+    validator
+        .validate_all(reader_for(config).read_fallible())
+        .unwrap_or_else(|e| panic!("Validating the input failed: {}", e));
+    match &config.output {
+        Some(output_path) => {
+            let file = File::create(output_path)
+                .unwrap_or_else(|_| panic!("Failed creating the output file {}", output_path));
+            validator
+                .write_report(&mut BufWriter::new(file))
+                .expect("Writing the findings failed.");
+        }
+        None => validator
+            .print_report()
+            .expect("Printing the findings failed."),
+    }
+    if !validator.findings().is_empty() {
+        eprintln!("The dry run found {} problems", validator.findings().len());
+        exit(1);
+    }
+}
+
+// Into stderr after processing the --stress requests, so stdout still holds only the account report
+fn print_stress_stats(config: &CliConfig, started: Instant) {
+    if let Some(count) = config.stress {
+        eprintln!("{}", stress_stats(count, started.elapsed(), peak_rss_kib()));
+    }
+}
+
+fn summary_or_exit(result: Result<ProcessingSummary, PaymentEngineError>) -> ProcessingSummary {
+    result.unwrap_or_else(|e| {
+        if let PaymentEngineError::StrictModeViolation { .. } = e {
+            eprintln!("{}", e);
+            exit(1);
+        }
+        panic!(
+            "Something went wrong while handling the transactions: {}",
+            e
+        )
+    })
+}
 
-    let reader = DefaultTransactionRequestsReader::new(&path);
-    // let reader = DummyReader {};
-    let iterator = reader.read();
-    let mut transactions_manager = DefaultTransactionsManager::new(
+// The manager of one of the config.threads shards
+fn shard_manager(config: &CliConfig, dedupe_capacity: usize) -> DefaultTransactionsManager {
+    let transactions_manager = DefaultTransactionsManager::new(
         InMemoryTransactionHistoryProvider::new(),
-        InMemoryCustomerAccountProvider::new(),
-    );
-    iterator
-        .filter(|request| DefaultTransactionsManager::structure_validation(request))
-        .for_each(|request| {
-            if !transactions_manager
-                .handle_transaction(request)
-                .expect("Something went wrong while handling the transaction")
-            {
-                info!("Request skipped");
-            }
-        });
-    transactions_manager.print_report().expect("Printing the report failed.");
+        InMemoryCustomerAccountProvider::new_with_scale(config.scale),
+    )
+    .with_allow_unlock(config.allow_unlock)
+    .with_validation_config(validation_config(config))
+    .with_limits(limits(config))
+    .with_scale(config.scale)
+    .with_default_currency(config.default_currency.clone());
+    let transactions_manager = match config.dedupe {
+        Some(dedupe_mode) => transactions_manager
+            .with_duplicate_detector(dedupe_mode.detector(dedupe_capacity))
+            .unwrap_or_else(|e| panic!("Failed filling the duplicate detector: {}", e)),
+        None => transactions_manager,
+    };
+    // Every shard parks the requests of its own clients, each with the full capacity
+    if config.out_of_order {
+        transactions_manager.with_out_of_order_tolerance(config.max_parked)
+    } else {
+        transactions_manager
+    }
+}
+
+// Runs the clients in config.threads parallel shards, the flags needing a single manager are rejected by parse_cli
+fn run_sharded(config: &CliConfig, reader: &dyn TransactionRequestsReader) {
+    // Every shard only gets the IDs of its own clients
+    let dedupe_capacity = dedupe_capacity(config).div_ceil(config.threads);
+    let shard_config = config.clone();
+    let mut sharded_manager =
+        ShardedTransactionsManager::new_with_factory(config.threads, move || {
+            shard_manager(&shard_config, dedupe_capacity)
+        })
+        .with_validation_config(validation_config(config))
+        .with_scale(config.scale)
+        .with_report_format(config.format)
+        .with_detailed_report(config.detailed)
+        .with_fixed_scale_report(config.fixed_scale);
+    let started = Instant::now();
+    let mut summary = summary_or_exit(sharded_manager.process_all(reader.read()));
+    finish_reading(reader, &mut summary);
+    print_stress_stats(config, started);
+    info!("Processing summary: {}", summary);
+    match &config.output {
+        Some(output_path) => {
+            let file = File::create(output_path)
+                .unwrap_or_else(|_| panic!("Failed creating the output file {}", output_path));
+            sharded_manager
+                .write_report(&mut BufWriter::new(file))
+                .expect("Writing the report failed.");
+        }
+        None => sharded_manager
+            .print_report()
+            .expect("Printing the report failed."),
+    }
+}
+
+fn main() {
+    let config = parse_cli(args().skip(1).collect()).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        exit(2);
+    });
+    init_logging(&config.log_config()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2);
+    });
+
+    if config.dry_run {
+        run_dry(&config);
+        return;
+    }
+
+    let input_hash = check_manifest(&config);
+
+    if config.threads > 1 {
+        run_sharded(&config, reader_for(&config).as_ref());
+        record_run(&config, input_hash);
+        return;
+    }
+
+    let mut transactions_manager = match (&config.resume_from, &config.resume) {
+        (Some(snapshot_path), _) => Snapshot::read_from(snapshot_path)
+            .and_then(|snapshot| DefaultTransactionsManager::import_state(snapshot, config.scale))
+            .unwrap_or_else(|e| panic!("Failed resuming from {}: {}", snapshot_path, e)),
+        (None, Some(checkpoint_dir)) => {
+            DefaultTransactionsManager::restore(Path::new(checkpoint_dir), config.scale)
+                .unwrap_or_else(|e| panic!("Failed resuming from {}: {}", checkpoint_dir, e))
+        }
+        (None, None) => DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_scale(config.scale),
+        ),
+    }
+    .with_strict_mode(config.strict)
+    .with_allow_unlock(config.allow_unlock)
+    .with_validation_config(validation_config(&config))
+    .with_limits(limits(&config))
+    .with_report_format(config.format)
+    .with_detailed_report(config.detailed)
+    .with_fixed_scale_report(config.fixed_scale)
+    .with_scale(config.scale)
+    .with_default_currency(config.default_currency.clone());
+    if config.out_of_order {
+        transactions_manager = transactions_manager.with_out_of_order_tolerance(config.max_parked);
+    }
+    if let Some(audit_path) = &config.audit_file {
+        let audit_sink = CsvAuditSink::create(audit_path)
+            .unwrap_or_else(|_| panic!("Failed creating the audit file {}", audit_path));
+        transactions_manager = transactions_manager.with_audit_sink(audit_sink);
+    }
+    if let Some(audit_path) = &config.audit_records {
+        let audit_record_sink = CsvAuditRecordSink::create(audit_path)
+            .unwrap_or_else(|_| panic!("Failed creating the audit file {}", audit_path));
+        transactions_manager = transactions_manager.with_audit_record_sink(audit_record_sink);
+    }
+
+    // Filled after resuming, so the restored transactions are still caught as duplicates
+    if let Some(dedupe_mode) = config.dedupe {
+        transactions_manager = transactions_manager
+            .with_duplicate_detector(dedupe_mode.detector(dedupe_capacity(&config)))
+            .unwrap_or_else(|e| panic!("Failed filling the duplicate detector: {}", e));
+    }
+
+    // Enabled after resuming, so the restored accounts are expected to keep their totals
+    if config.verify {
+        transactions_manager = transactions_manager.with_invariant_tracking();
+    }
+
+    let reader = reader_for(&config);
+    let started = Instant::now();
+    let summary = match &config.journal {
+        Some(journal_path) => {
+            let (journaled_manager, summary) =
+                run_journaled(transactions_manager, reader.as_ref(), journal_path);
+            transactions_manager = journaled_manager;
+            summary
+        }
+        None => summary_or_exit(match config.snapshot_every {
+            Some(snapshot_every) => run_with_snapshots(
+                reader.as_ref(),
+                &mut transactions_manager,
+                snapshot_every,
+                &config.snapshot_path,
+            ),
+            None => run(reader.as_ref(), &mut transactions_manager),
+        }),
+    };
+    print_stress_stats(&config, started);
+    info!("Processing summary: {}", summary);
+    if config.reconcile {
+        let discrepancies = transactions_manager
+            .reconcile(false)
+            .expect("Reconciling the accounts failed.");
+        // Printed regardless of the log level, as it's what the flag was passed for
+        eprintln!("Reconciliation found {} discrepancies", discrepancies.len());
+        for discrepancy in discrepancies {
+            eprintln!("{}", discrepancy);
+        }
+    }
+    if config.disputes {
+        // Into stderr like the reconciliation, so stdout still holds only the account report
+        transactions_manager
+            .write_disputes_report(&mut stderr().lock())
+            .expect("Writing the disputes report failed.");
+    }
+    let divergences = if config.verify {
+        transactions_manager
+            .verify_invariants()
+            .expect("Verifying the invariants failed.")
+    } else {
+        vec![]
+    };
+    for divergence in &divergences {
+        eprintln!("Funds not conserved, {}", divergence);
+    }
+    match &config.output {
+        Some(output_path) => {
+            let file = File::create(output_path)
+                .unwrap_or_else(|_| panic!("Failed creating the output file {}", output_path));
+            write_output(
+                &config,
+                &mut transactions_manager,
+                &mut BufWriter::new(file),
+            )
+            .expect("Writing the report failed.");
+        }
+        None => write_output(&config, &mut transactions_manager, &mut stdout().lock())
+            .expect("Printing the report failed."),
+    }
+    if let Some(checkpoint_dir) = &config.checkpoint {
+        transactions_manager
+            .checkpoint(Path::new(checkpoint_dir))
+            .unwrap_or_else(|e| panic!("Failed writing the checkpoint {}: {}", checkpoint_dir, e));
+    }
+    // After the report, which is still useful for finding the cause
+    if !divergences.is_empty() {
+        exit(1);
+    }
+    record_run(&config, input_hash);
 }
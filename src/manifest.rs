@@ -0,0 +1,162 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::PaymentEngineError;
+
+// FNV-1a over the bytes fed so far. Not a cryptographic hash, it only has to tell an accidental re-run of the same
+// input apart from a new one, and it's the same on every platform and Rust version, unlike the std hashers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputHasher {
+    state: u64,
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+impl Default for InputHasher {
+    fn default() -> Self {
+        InputHasher {
+            state: FNV_OFFSET_BASIS,
+        }
+    }
+}
+
+impl InputHasher {
+    // The hash doesn't depend on how the input is split into the fed chunks
+    pub fn feed_hash(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state = (self.state ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.state)
+    }
+
+    // The hash of the file content, so the same input under another name gives the same hash
+    pub fn hash_file(path: impl AsRef<Path>) -> Result<String, PaymentEngineError> {
+        let mut file = File::open(path)?;
+        let mut hasher = InputHasher::default();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(hasher.finish());
+            }
+            hasher.feed_hash(&buffer[..read]);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub hash: String,
+    // The path the input was processed from, for the operators reading the manifest
+    pub input: String,
+}
+
+// The inputs processed by the completed runs, see the --manifest flag
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub runs: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    // A missing manifest is an empty one, so the first run creates it
+    pub fn read_from(path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| PaymentEngineError::Storage(e.to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written next to the destination first like the snapshots, so a crash doesn't lose the earlier runs
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), PaymentEngineError> {
+        let path = path.as_ref();
+        let temporary_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        serde_json::to_writer_pretty(&mut writer, self)
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))?;
+        writer.flush()?;
+        fs::rename(temporary_path, path)?;
+        Ok(())
+    }
+
+    // The earlier run of the input with the hash, if there was one
+    pub fn find(&self, hash: &str) -> Option<&ManifestEntry> {
+        self.runs.iter().find(|entry| entry.hash == hash)
+    }
+
+    // A forced re-run doesn't add the hash again
+    pub fn record(&mut self, hash: String, input: String) {
+        if self.find(&hash).is_none() {
+            self.runs.push(ManifestEntry { hash, input });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_depends_only_on_the_content() {
+        let directory = tempfile::tempdir().unwrap();
+        let content = "type,client,tx,amount\ndeposit,1,1,2.0\n";
+        for name in ["a.csv", "b.csv"] {
+            fs::write(directory.path().join(name), content).unwrap();
+        }
+        fs::write(directory.path().join("c.csv"), "type,client,tx,amount\n").unwrap();
+
+        let hash = InputHasher::hash_file(directory.path().join("a.csv")).unwrap();
+        assert_eq!(
+            InputHasher::hash_file(directory.path().join("b.csv")).unwrap(),
+            hash
+        );
+        assert_ne!(
+            InputHasher::hash_file(directory.path().join("c.csv")).unwrap(),
+            hash
+        );
+        // Fed in chunks it's the same, and it stays the same across the releases
+        let mut hasher = InputHasher::default();
+        for chunk in content.as_bytes().chunks(5) {
+            hasher.feed_hash(chunk);
+        }
+        assert_eq!(hasher.finish(), hash);
+        assert_eq!(InputHasher::default().finish(), "cbf29ce484222325");
+        let mut hasher = InputHasher::default();
+        hasher.feed_hash(b"a");
+        assert_eq!(hasher.finish(), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn manifest_round_trips_and_starts_empty() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+        let mut manifest = Manifest::read_from(&path).unwrap();
+        assert_eq!(manifest, Manifest::default());
+
+        manifest.record("0123".to_owned(), "a.csv".to_owned());
+        manifest.record("0123".to_owned(), "b.csv".to_owned());
+        manifest.write_to(&path).unwrap();
+        let manifest = Manifest::read_from(&path).unwrap();
+        assert_eq!(
+            manifest.runs,
+            vec![ManifestEntry {
+                hash: "0123".to_owned(),
+                input: "a.csv".to_owned(),
+            }]
+        );
+        assert!(manifest.find("4567").is_none());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Manifest::read_from(&path).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{common_types::TransactionId, transaction_request::TransactionRequest};
+
+// Disputes, resolves and chargebacks that arrived before the transaction they reference, waiting for it
+pub(crate) struct PendingFollowUps {
+    capacity: usize,
+    // The parked requests by their arrival number, so the oldest one is the first
+    parked: BTreeMap<u64, TransactionRequest>,
+    // The arrival numbers of the requests parked for each referenced transaction, in their arrival order
+    arrivals: HashMap<TransactionId, Vec<u64>>,
+    next_arrival: u64,
+}
+
+impl PendingFollowUps {
+    pub(crate) fn new(capacity: usize) -> Self {
+        PendingFollowUps {
+            capacity,
+            parked: BTreeMap::new(),
+            arrivals: HashMap::new(),
+            next_arrival: 0,
+        }
+    }
+
+    // Evicts the oldest parked request when the capacity is used up and returns it, without any capacity the request
+    // itself is returned
+    pub(crate) fn park(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Option<TransactionRequest> {
+        if self.capacity == 0 {
+            return Some(transaction_request);
+        }
+        let evicted = if self.parked.len() >= self.capacity {
+            self.evict_oldest()
+        } else {
+            None
+        };
+        self.arrivals
+            .entry(transaction_request.transaction_id)
+            .or_default()
+            .push(self.next_arrival);
+        self.parked.insert(self.next_arrival, transaction_request);
+        self.next_arrival += 1;
+        evicted
+    }
+
+    pub(crate) fn take(&mut self, transaction_id: TransactionId) -> Vec<TransactionRequest> {
+        self.arrivals
+            .remove(&transaction_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|arrival| self.parked.remove(&arrival))
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    fn evict_oldest(&mut self) -> Option<TransactionRequest> {
+        let (arrival, evicted) = self.parked.pop_first()?;
+        if let Some(arrivals) = self.arrivals.get_mut(&evicted.transaction_id) {
+            // The oldest request of all is the oldest one of its transaction as well
+            arrivals.retain(|other| *other != arrival);
+            if arrivals.is_empty() {
+                self.arrivals.remove(&evicted.transaction_id);
+            }
+        }
+        Some(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_request::TransactionType;
+
+    use super::*;
+
+    fn follow_up(
+        transaction_type: TransactionType,
+        transaction_id: TransactionId,
+    ) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn parked_requests_are_taken_in_the_arrival_order_within_the_capacity() {
+        let mut pending_follow_ups = PendingFollowUps::new(3);
+        assert_eq!(
+            pending_follow_ups.park(follow_up(TransactionType::Dispute, 1)),
+            None
+        );
+        assert_eq!(
+            pending_follow_ups.park(follow_up(TransactionType::Dispute, 2)),
+            None
+        );
+        assert_eq!(
+            pending_follow_ups.park(follow_up(TransactionType::Chargeback, 1)),
+            None
+        );
+        assert_eq!(
+            pending_follow_ups.take(1),
+            vec![
+                follow_up(TransactionType::Dispute, 1),
+                follow_up(TransactionType::Chargeback, 1)
+            ]
+        );
+        assert_eq!(pending_follow_ups.len(), 1);
+        assert_eq!(pending_follow_ups.take(1), vec![]);
+    }
+
+    #[test]
+    fn the_oldest_parked_request_is_evicted_when_full() {
+        let mut pending_follow_ups = PendingFollowUps::new(2);
+        pending_follow_ups.park(follow_up(TransactionType::Dispute, 1));
+        pending_follow_ups.park(follow_up(TransactionType::Dispute, 2));
+        assert_eq!(
+            pending_follow_ups.park(follow_up(TransactionType::Resolve, 1)),
+            Some(follow_up(TransactionType::Dispute, 1))
+        );
+        assert_eq!(
+            pending_follow_ups.park(follow_up(TransactionType::Chargeback, 1)),
+            Some(follow_up(TransactionType::Dispute, 2))
+        );
+        assert_eq!(pending_follow_ups.len(), 2);
+        assert_eq!(pending_follow_ups.take(2), vec![]);
+        assert_eq!(
+            pending_follow_ups.take(1),
+            vec![
+                follow_up(TransactionType::Resolve, 1),
+                follow_up(TransactionType::Chargeback, 1)
+            ]
+        );
+
+        let mut pending_follow_ups = PendingFollowUps::new(0);
+        assert_eq!(
+            pending_follow_ups.park(follow_up(TransactionType::Dispute, 3)),
+            Some(follow_up(TransactionType::Dispute, 3))
+        );
+        assert_eq!(pending_follow_ups.len(), 0);
+    }
+}
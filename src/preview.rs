@@ -0,0 +1,330 @@
+//! Providers for previewing a transaction: the reads go to the real providers, while the writes are kept in memory
+//! and dropped with the preview, so the real providers never see them.
+
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    common_types::{CustomerId, TransactionId},
+    customer_account_provider::{
+        AccountMutation, CustomerAccountProvider, CustomerAccountReport,
+        InMemoryCustomerAccountProvider,
+    },
+    errors::PaymentEngineError,
+    transaction_history_provider::{
+        in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        transaction_history_provider::TransactionHistoryProvider,
+    },
+    transaction_outcome::SkipReason,
+    transaction_request::{TransactionRequest, TransactionState},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionPreview {
+    // The balances of the client after the transaction
+    WouldExecute { available: Decimal, held: Decimal },
+    WouldSkip(SkipReason),
+}
+
+// Shared with the manager, which takes the real provider back once the preview is over
+pub(crate) type SharedProvider<T> = Rc<RefCell<Box<T>>>;
+
+// Only succeeds once the preview providers holding the other references are dropped
+pub(crate) fn take_back<T: ?Sized>(shared: SharedProvider<T>) -> Box<T> {
+    match Rc::try_unwrap(shared) {
+        Ok(provider) => provider.into_inner(),
+        Err(_) => panic!("The provider is still used by a preview"),
+    }
+}
+
+pub(crate) struct PreviewTransactionHistoryProvider {
+    inner: SharedProvider<dyn TransactionHistoryProvider>,
+    written: InMemoryTransactionHistoryProvider,
+}
+
+impl PreviewTransactionHistoryProvider {
+    pub(crate) fn new(inner: SharedProvider<dyn TransactionHistoryProvider>) -> Self {
+        PreviewTransactionHistoryProvider {
+            inner,
+            written: InMemoryTransactionHistoryProvider::new(),
+        }
+    }
+}
+
+impl TransactionHistoryProvider for PreviewTransactionHistoryProvider {
+    fn write_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError> {
+        self.written.write_transaction(transaction_request)
+    }
+
+    fn read_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError> {
+        match self.written.read_transaction(transaction_id)? {
+            Some(transaction) => Ok(Some(transaction)),
+            None => self.inner.borrow_mut().read_transaction(transaction_id),
+        }
+    }
+
+    fn write_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction_state: TransactionState,
+    ) -> Result<(), PaymentEngineError> {
+        self.written
+            .write_transaction_state(transaction_id, transaction_state)
+    }
+
+    fn read_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionState>, PaymentEngineError> {
+        match self.written.read_transaction_state(transaction_id)? {
+            Some(state) => Ok(Some(state)),
+            None => self
+                .inner
+                .borrow_mut()
+                .read_transaction_state(transaction_id),
+        }
+    }
+
+    fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError> {
+        let mut transactions = self
+            .inner
+            .borrow_mut()
+            .read_client_transactions(client_id)?;
+        transactions.extend(self.written.read_client_transactions(client_id)?);
+        Ok(transactions)
+    }
+
+    // Collected, as the items can't borrow from the shared provider
+    fn iter_transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>> + '_> {
+        let transactions: Vec<_> = self.inner.borrow().iter_transactions().collect();
+        Box::new(
+            transactions
+                .into_iter()
+                .chain(self.written.iter_transactions()),
+        )
+    }
+
+    fn iter_states(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<(TransactionId, TransactionState), PaymentEngineError>> + '_>
+    {
+        let states: Vec<_> = self.inner.borrow().iter_states().collect();
+        Box::new(states.into_iter().chain(self.written.iter_states()))
+    }
+}
+
+pub(crate) struct PreviewCustomerAccountProvider {
+    inner: SharedProvider<dyn CustomerAccountProvider>,
+    // Copies of the accounts of the clients touched by the preview, with the preview's changes applied
+    accounts: InMemoryCustomerAccountProvider,
+    // Clients already copied from the inner provider, including the ones without any account there
+    loaded: HashSet<CustomerId>,
+}
+
+impl PreviewCustomerAccountProvider {
+    pub(crate) fn new(inner: SharedProvider<dyn CustomerAccountProvider>) -> Self {
+        PreviewCustomerAccountProvider {
+            inner,
+            accounts: InMemoryCustomerAccountProvider::new(),
+            loaded: HashSet::new(),
+        }
+    }
+
+    // Copies the accounts of the client on the first access, after that they're only served from the copies. All the
+    // currencies are copied at once, as the lock applies to all of them
+    fn accounts_of(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<&mut InMemoryCustomerAccountProvider, PaymentEngineError> {
+        if self.loaded.insert(customer_id) {
+            for account in self.inner.borrow().list_client_accounts(customer_id)? {
+                self.accounts.restore_account(account);
+            }
+        }
+        Ok(&mut self.accounts)
+    }
+}
+
+impl CustomerAccountProvider for PreviewCustomerAccountProvider {
+    fn get_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .get_available(customer_id, currency)
+    }
+
+    fn get_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .get_held_amount(customer_id, currency)
+    }
+
+    fn get_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<Option<bool>, PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .get_locked_status(customer_id)
+    }
+
+    fn get_total(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .get_total(customer_id, currency)
+    }
+
+    fn set_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .set_available(customer_id, currency, balance)
+    }
+
+    fn set_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .set_held_amount(customer_id, currency, balance)
+    }
+
+    fn set_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+        locked: bool,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .set_locked_status(customer_id, locked)
+    }
+
+    fn adjust_balances(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?.adjust_balances(
+            customer_id,
+            currency,
+            available_delta,
+            held_delta,
+        )
+    }
+
+    fn apply_mutation(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        mutation: AccountMutation,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .apply_mutation(customer_id, currency, mutation)
+    }
+
+    fn increment_charged_back(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .increment_charged_back(customer_id, currency, amount)
+    }
+
+    fn add_deposited(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .add_deposited(customer_id, currency, amount)
+    }
+
+    fn add_withdrawn(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .add_withdrawn(customer_id, currency, amount)
+    }
+
+    fn adjust_open_disputes(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        delta: i32,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .adjust_open_disputes(customer_id, currency, delta)
+    }
+
+    fn get_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<i64>, PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .get_last_activity(customer_id, currency)
+    }
+
+    fn set_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        timestamp: i64,
+    ) -> Result<(), PaymentEngineError> {
+        self.accounts_of(customer_id)?
+            .set_last_activity(customer_id, currency, timestamp)
+    }
+
+    fn list_client_accounts(
+        &self,
+        customer_id: CustomerId,
+    ) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        if self.loaded.contains(&customer_id) {
+            return self.accounts.list_client_accounts(customer_id);
+        }
+        self.inner.borrow().list_client_accounts(customer_id)
+    }
+
+    // The copies replace the inner accounts, collected as the items can't borrow from the shared provider
+    fn iter_accounts(&self) -> Box<dyn Iterator<Item = CustomerAccountReport> + '_> {
+        let accounts: Vec<CustomerAccountReport> = self
+            .inner
+            .borrow()
+            .iter_accounts()
+            .filter(|account| !self.loaded.contains(&account.client))
+            .chain(self.accounts.iter_accounts())
+            .collect();
+        Box::new(accounts.into_iter())
+    }
+}
@@ -0,0 +1,55 @@
+use std::{fs, time::Duration};
+
+// Peak resident set size of the process in KiB, None where /proc isn't available, e.g. outside of Linux
+pub fn peak_rss_kib() -> Option<u64> {
+    peak_rss_from_status(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+// VmHWM, the high water mark of the resident memory, from the content of /proc/self/status
+fn peak_rss_from_status(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// The line printed by the --stress mode after processing
+pub fn stress_stats(requests: u32, elapsed: Duration, peak_rss_kib: Option<u64>) -> String {
+    let throughput = requests as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let peak_rss = match peak_rss_kib {
+        Some(peak_rss_kib) => format!("{:.1} MiB", peak_rss_kib as f64 / 1024.0),
+        None => "unavailable".to_owned(),
+    };
+    format!(
+        "Processed {} requests in {:.3}s, {:.0} requests/s, peak RSS {}",
+        requests,
+        elapsed.as_secs_f64(),
+        throughput,
+        peak_rss
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peak_rss_is_read_from_the_status() {
+        assert_eq!(
+            peak_rss_from_status("Name:\tengine\nVmPeak:\t  20000 kB\nVmHWM:\t    5120 kB\n"),
+            Some(5120)
+        );
+        assert_eq!(peak_rss_from_status("Name:\tengine\n"), None);
+        assert_eq!(
+            stress_stats(1000, Duration::from_millis(500), Some(5120)),
+            "Processed 1000 requests in 0.500s, 2000 requests/s, peak RSS 5.0 MiB"
+        );
+        assert!(
+            stress_stats(1000, Duration::from_millis(500), None).ends_with("peak RSS unavailable")
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! Output formats of the account report. The amounts are written from their Decimal representation, never through
+//! f64, so the report holds exactly the balances the engine computed.
+
+use std::io::Write;
+
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    common_types::CustomerId, customer_account_provider::CustomerAccountReport,
+    errors::PaymentEngineError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    // One JSON object per account and line
+    Jsonl,
+    // A single JSON array of the accounts
+    Json,
+    // Padded columns for reading in a terminal
+    Table,
+}
+
+impl ReportFormat {
+    // The detailed report appends the lifetime deposited and withdrawn amounts to the standard columns
+    pub fn formatter(self, detailed: bool) -> Box<dyn ReportFormatter> {
+        match self {
+            ReportFormat::Csv => Box::new(CsvReportFormatter { detailed }),
+            ReportFormat::Jsonl => Box::new(JsonLinesReportFormatter { detailed }),
+            ReportFormat::Json => Box::new(JsonReportFormatter { detailed }),
+            ReportFormat::Table => Box::new(TableReportFormatter { detailed }),
+        }
+    }
+}
+
+// The columns of the report without the detailed ones
+#[derive(Serialize)]
+struct StandardReportRow<'a> {
+    client: CustomerId,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    last_activity: Option<i64>,
+    currency: &'a str,
+    total_charged_back: Decimal,
+    open_disputes: u32,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ReportRow<'a> {
+    Standard(StandardReportRow<'a>),
+    Detailed(&'a CustomerAccountReport),
+}
+
+impl<'a> ReportRow<'a> {
+    fn of(account: &'a CustomerAccountReport, detailed: bool) -> Self {
+        if detailed {
+            return ReportRow::Detailed(account);
+        }
+        ReportRow::Standard(StandardReportRow {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+            last_activity: account.last_activity,
+            currency: &account.currency,
+            total_charged_back: account.total_charged_back,
+            open_disputes: account.open_disputes,
+        })
+    }
+}
+
+pub trait ReportFormatter {
+    fn format(
+        &self,
+        accounts: &[CustomerAccountReport],
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError>;
+
+    // Formats the accounts as they come, the formats that can write them row by row override it so large reports
+    // are not held in memory
+    fn format_stream(
+        &self,
+        accounts: &mut dyn Iterator<Item = CustomerAccountReport>,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        self.format(&accounts.collect::<Vec<_>>(), writer)
+    }
+}
+
+// The header is written along with the first row, so an empty report stays empty
+#[derive(Default)]
+pub struct CsvReportFormatter {
+    pub detailed: bool,
+}
+
+impl ReportFormatter for CsvReportFormatter {
+    fn format(
+        &self,
+        accounts: &[CustomerAccountReport],
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        self.format_stream(&mut accounts.iter().cloned(), writer)
+    }
+
+    fn format_stream(
+        &self,
+        accounts: &mut dyn Iterator<Item = CustomerAccountReport>,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        let mut writer = WriterBuilder::new()
+            .has_headers(true)
+            .delimiter(b',')
+            .from_writer(writer);
+        for account in accounts {
+            writer.serialize(ReportRow::of(&account, self.detailed))?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+#[derive(Default)]
+pub struct JsonLinesReportFormatter {
+    pub detailed: bool,
+}
+
+impl ReportFormatter for JsonLinesReportFormatter {
+    fn format(
+        &self,
+        accounts: &[CustomerAccountReport],
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        self.format_stream(&mut accounts.iter().cloned(), writer)
+    }
+
+    fn format_stream(
+        &self,
+        accounts: &mut dyn Iterator<Item = CustomerAccountReport>,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        for account in accounts {
+            serde_json::to_writer(&mut *writer, &ReportRow::of(&account, self.detailed))
+                .map_err(|e| PaymentEngineError::Storage(e.to_string()))?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+// The amounts are JSON strings, as that's how Decimal is serialized, so the consumers don't lose precision
+#[derive(Default)]
+pub struct JsonReportFormatter {
+    pub detailed: bool,
+}
+
+impl ReportFormatter for JsonReportFormatter {
+    fn format(
+        &self,
+        accounts: &[CustomerAccountReport],
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        let rows: Vec<ReportRow> = accounts
+            .iter()
+            .map(|account| ReportRow::of(account, self.detailed))
+            .collect();
+        serde_json::to_writer(&mut *writer, &rows)
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))?;
+        writer.write_all(b"\n")?;
+        Ok(writer.flush()?)
+    }
+}
+
+// The numbers are aligned to the right and the text to the left, with two spaces between the columns
+#[derive(Default)]
+pub struct TableReportFormatter {
+    pub detailed: bool,
+}
+
+const TABLE_HEADER: [&str; 11] = [
+    "client",
+    "available",
+    "held",
+    "total",
+    "locked",
+    "last_activity",
+    "currency",
+    "total_charged_back",
+    "open_disputes",
+    "total_deposited",
+    "total_withdrawn",
+];
+
+// The detailed columns are the last ones of the header
+const TABLE_STANDARD_COLUMNS: usize = 9;
+
+// Columns of the text values, the rest are numbers
+const TABLE_TEXT_COLUMNS: [usize; 2] = [4, 6];
+
+impl ReportFormatter for TableReportFormatter {
+    fn format(
+        &self,
+        accounts: &[CustomerAccountReport],
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        let columns = if self.detailed {
+            TABLE_HEADER.len()
+        } else {
+            TABLE_STANDARD_COLUMNS
+        };
+        let rows: Vec<Vec<String>> = accounts
+            .iter()
+            .map(|account| {
+                let mut row = vec![
+                    account.client.to_string(),
+                    account.available.to_string(),
+                    account.held.to_string(),
+                    account.total.to_string(),
+                    account.locked.to_string(),
+                    account
+                        .last_activity
+                        .map(|timestamp| timestamp.to_string())
+                        .unwrap_or_default(),
+                    account.currency.clone(),
+                    account.total_charged_back.to_string(),
+                    account.open_disputes.to_string(),
+                    account.total_deposited.to_string(),
+                    account.total_withdrawn.to_string(),
+                ];
+                row.truncate(columns);
+                row
+            })
+            .collect();
+        let mut widths: Vec<usize> = TABLE_HEADER[..columns]
+            .iter()
+            .map(|name| name.len())
+            .collect();
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let header: Vec<String> = TABLE_HEADER[..columns]
+            .iter()
+            .map(|name| (*name).to_owned())
+            .collect();
+        for row in std::iter::once(&header).chain(&rows) {
+            let cells: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(column, cell)| {
+                    if TABLE_TEXT_COLUMNS.contains(&column) {
+                        format!("{:<width$}", cell, width = widths[column])
+                    } else {
+                        format!("{:>width$}", cell, width = widths[column])
+                    }
+                })
+                .collect();
+            writeln!(writer, "{}", cells.join("  "))?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    fn two_accounts() -> Vec<CustomerAccountReport> {
+        vec![
+            CustomerAccountReport {
+                client: 1,
+                available: Decimal::new(15, 1),
+                held: Decimal::new(2, 0),
+                total: Decimal::new(35, 1),
+                locked: false,
+                last_activity: Some(1700000000),
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::ZERO,
+                open_disputes: 1,
+                total_deposited: Decimal::ZERO,
+                total_withdrawn: Decimal::ZERO,
+            },
+            CustomerAccountReport {
+                client: 12,
+                available: Decimal::new(-1234, 4),
+                held: Decimal::ZERO,
+                total: Decimal::new(-1234, 4),
+                locked: true,
+                last_activity: None,
+                currency: "EUR".to_owned(),
+                total_charged_back: Decimal::new(10, 0),
+                open_disputes: 0,
+                total_deposited: Decimal::new(10, 0),
+                total_withdrawn: Decimal::new(101234, 4),
+            },
+        ]
+    }
+
+    fn formatted(format: ReportFormat) -> String {
+        let mut output = vec![];
+        format
+            .formatter(false)
+            .format(&two_accounts(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn csv_report() {
+        assert_eq!(
+            formatted(ReportFormat::Csv),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n\
+             1,1.5,2,3.5,false,1700000000,USD,0,1\n\
+             12,-0.1234,0,-0.1234,true,,EUR,10,0\n"
+        );
+    }
+
+    #[test]
+    fn json_report() {
+        assert_eq!(
+            formatted(ReportFormat::Json),
+            "[{\"client\":1,\"available\":\"1.5\",\"held\":\"2\",\"total\":\"3.5\",\"locked\":false,\
+             \"last_activity\":1700000000,\"currency\":\"USD\",\"total_charged_back\":\"0\",\"open_disputes\":1},\
+             {\"client\":12,\"available\":\"-0.1234\",\"held\":\"0\",\"total\":\"-0.1234\",\"locked\":true,\
+             \"last_activity\":null,\"currency\":\"EUR\",\"total_charged_back\":\"10\",\"open_disputes\":0}]\n"
+        );
+    }
+
+    #[test]
+    fn table_report() {
+        assert_eq!(
+            formatted(ReportFormat::Table),
+            "client  available  held    total  locked  last_activity  currency  total_charged_back  open_disputes\n\
+             \x20    1        1.5     2      3.5  false      1700000000  USD                        0              1\n\
+             \x20   12    -0.1234     0  -0.1234  true                   EUR                       10              0\n"
+        );
+    }
+
+    #[test]
+    fn detailed_reports_add_the_deposited_and_withdrawn_columns() {
+        let mut output = vec![];
+        ReportFormat::Csv
+            .formatter(true)
+            .format(&two_accounts(), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes,\
+             total_deposited,total_withdrawn\n\
+             1,1.5,2,3.5,false,1700000000,USD,0,1,0,0\n\
+             12,-0.1234,0,-0.1234,true,,EUR,10,0,10,10.1234\n"
+        );
+        let mut output = vec![];
+        ReportFormat::Table
+            .formatter(true)
+            .format(&two_accounts(), &mut output)
+            .unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .last()
+            .unwrap()
+            .ends_with("0               10          10.1234"));
+    }
+
+    #[test]
+    fn streaming_writes_the_same_bytes() {
+        for format in [
+            ReportFormat::Csv,
+            ReportFormat::Jsonl,
+            ReportFormat::Json,
+            ReportFormat::Table,
+        ] {
+            let mut output = vec![];
+            format
+                .formatter(false)
+                .format_stream(&mut two_accounts().into_iter(), &mut output)
+                .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), formatted(format));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! The processing loop of the binary, kept in the library so it can be driven by the benchmarks as well.
+
+use log::{info, warn};
+
+use crate::{
+    customer_account_provider::{CustomerAccountReport, InMemoryCustomerAccountProvider},
+    errors::PaymentEngineError,
+    logging::SKIPPED_REQUESTS_TARGET,
+    transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+    transaction_outcome::{ProcessingSummary, SkipReason, TransactionOutcome},
+    transaction_request::TransactionRequest,
+    transaction_requests_reader::TransactionRequestsReader,
+    transactions_manager::{DefaultTransactionsManager, TransactionsManager},
+};
+
+// Handles the requests of the reader that the manager didn't handle yet, e.g. the ones after a resumed snapshot
+pub fn run(
+    reader: &dyn TransactionRequestsReader,
+    transactions_manager: &mut DefaultTransactionsManager,
+) -> Result<ProcessingSummary, PaymentEngineError> {
+    let requests = reader
+        .read()
+        .skip(transactions_manager.processed_requests());
+    let mut summary = process_logged(transactions_manager, requests)?;
+    finish_reading(reader, &mut summary);
+    Ok(summary)
+}
+
+// Same as run, saving the state of the manager into the snapshot file after every snapshot_every requests
+pub fn run_with_snapshots(
+    reader: &dyn TransactionRequestsReader,
+    transactions_manager: &mut DefaultTransactionsManager,
+    snapshot_every: usize,
+    snapshot_path: &str,
+) -> Result<ProcessingSummary, PaymentEngineError> {
+    let mut requests = reader
+        .read()
+        .skip(transactions_manager.processed_requests());
+    let mut summary = loop {
+        let processed_before = transactions_manager.processed_requests();
+        let summary = process_logged(transactions_manager, requests.by_ref().take(snapshot_every))?;
+        if transactions_manager.processed_requests() - processed_before < snapshot_every {
+            break summary;
+        }
+        transactions_manager
+            .export_state()?
+            .write_to(snapshot_path)?;
+        info!(
+            "Snapshot of {} requests saved",
+            transactions_manager.processed_requests()
+        );
+    };
+    finish_reading(reader, &mut summary);
+    Ok(summary)
+}
+
+// Validates and handles the requests with any manager, e.g. a mocked one, stopping only on fatal errors. Unlike run,
+// there's no resuming and no strict mode, as those are features of DefaultTransactionsManager
+pub fn run_any(
+    transactions_manager: &mut dyn TransactionsManager,
+    reader: &dyn TransactionRequestsReader,
+) -> Result<ProcessingSummary, PaymentEngineError> {
+    let mut summary = ProcessingSummary::default();
+    for request in reader.read() {
+        let transaction_type = request.transaction_type.clone();
+        let result = if transactions_manager.structure_validation(&request) {
+            transactions_manager.handle_transaction(request)
+        } else {
+            Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure))
+        };
+        match result {
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(ref e) => warn!(target: SKIPPED_REQUESTS_TARGET, "Request skipped: {}", e),
+            Ok(_) => {}
+        }
+        summary.record(&transaction_type, &result);
+    }
+    finish_reading(reader, &mut summary);
+    Ok(summary)
+}
+
+// Processes the requests with the in-memory providers and returns the final accounts sorted by client and currency,
+// for embedding the engine without writing any report
+pub fn process(
+    reader: &dyn TransactionRequestsReader,
+) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+    let mut transactions_manager = DefaultTransactionsManager::new(
+        InMemoryTransactionHistoryProvider::new(),
+        InMemoryCustomerAccountProvider::new(),
+    );
+    run(reader, &mut transactions_manager)?;
+    let mut accounts = transactions_manager.list_accounts()?;
+    accounts.sort_unstable_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+    Ok(accounts)
+}
+
+fn process_logged(
+    transactions_manager: &mut DefaultTransactionsManager,
+    requests: impl Iterator<Item = TransactionRequest>,
+) -> Result<ProcessingSummary, PaymentEngineError> {
+    transactions_manager.process_all(
+        requests,
+        Some(&mut |processed| info!("Processed {} requests", processed)),
+    )
+}
+
+// Once the reader is read to the end, adds what it knows about the input to the summary
+pub fn finish_reading(reader: &dyn TransactionRequestsReader, summary: &mut ProcessingSummary) {
+    if reader.skipped_rows() > 0 {
+        warn!("Skipped {} malformed rows", reader.skipped_rows());
+    }
+    summary.rows_per_input = reader.rows_per_input().into_iter().collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rust_decimal::RoundingStrategy;
+
+    use crate::{
+        customer_account_provider::InMemoryCustomerAccountProvider,
+        transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        transaction_request::TransactionType,
+        transaction_requests_reader::{
+            ChainedTransactionRequestsReader, DefaultTransactionRequestsReader, DummyReader,
+        },
+        transactions_manager::{MockTransactionsManager, DEFAULT_SCALE},
+    };
+
+    use super::*;
+
+    fn new_manager() -> DefaultTransactionsManager {
+        DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+    }
+
+    #[test]
+    fn run_continues_after_the_already_handled_requests() {
+        let mut transactions_manager = new_manager();
+        let summary = run(&DummyReader::new(3), &mut transactions_manager).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 3);
+        // The first three requests are covered by the state of the manager, as after a resumed snapshot
+        let summary = run(&DummyReader::new(5), &mut transactions_manager).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 5);
+        assert_eq!(transactions_manager.processed_requests(), 5);
+    }
+
+    #[test]
+    fn run_any_works_with_a_mocked_manager() {
+        let mut mock_transactions_manager = MockTransactionsManager::new();
+        mock_transactions_manager
+            .expect_structure_validation()
+            .returning(|request| request.transaction_id != 2);
+        mock_transactions_manager
+            .expect_handle_transaction()
+            .times(3)
+            .returning(|request| match request.transaction_id {
+                3 => Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked)),
+                _ => Ok(TransactionOutcome::Executed),
+            });
+        let summary = run_any(&mut mock_transactions_manager, &DummyReader::new(4)).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped_because(SkipReason::InvalidStructure), 1);
+        assert_eq!(summary.skipped_because(SkipReason::AccountLocked), 1);
+    }
+
+    #[test]
+    fn run_any_stops_on_fatal_errors() {
+        let mut mock_transactions_manager = MockTransactionsManager::new();
+        mock_transactions_manager
+            .expect_structure_validation()
+            .return_const(true);
+        mock_transactions_manager
+            .expect_handle_transaction()
+            .times(1)
+            .returning(|_| Err(PaymentEngineError::Storage("Disk full".to_owned())));
+        assert_eq!(
+            run_any(&mut mock_transactions_manager, &DummyReader::new(4)),
+            Err(PaymentEngineError::Storage("Disk full".to_owned()))
+        );
+    }
+
+    #[test]
+    fn run_handles_the_rows_around_a_malformed_one() {
+        let directory = tempfile::tempdir().unwrap();
+        let input_path = directory.path().join("transactions.csv");
+        std::fs::write(
+            &input_path,
+            "type,client,tx,amount\ndeposit,1,1,2.0\n#garbage;;\ndeposit,1,2,3.0\n",
+        )
+        .unwrap();
+        let reader = DefaultTransactionRequestsReader::new(input_path.to_str().unwrap());
+        let mut transactions_manager = new_manager();
+        let summary = run(&reader, &mut transactions_manager).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(reader.skipped_rows(), 1);
+    }
+
+    #[test]
+    fn duplicates_across_the_chained_files_are_skipped() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut paths = vec![];
+        for (name, content) in [
+            ("a.csv", "type,client,tx,amount\ndeposit,1,1,2.0\n"),
+            (
+                "b.csv",
+                "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,3.0\n",
+            ),
+        ] {
+            let path = directory.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            paths.push(path.to_str().unwrap().to_owned());
+        }
+        let reader =
+            ChainedTransactionRequestsReader::new_with_paths(&paths, 4, RoundingStrategy::ToZero);
+        let summary = run(&reader, &mut new_manager()).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped_because(SkipReason::DuplicateTransaction), 1);
+        assert_eq!(
+            summary.rows_per_input,
+            BTreeMap::from([(paths[0].clone(), 1), (paths[1].clone(), 2)])
+        );
+    }
+
+    #[test]
+    fn snapshots_are_written_along_the_way() {
+        let directory = tempfile::tempdir().unwrap();
+        let snapshot_path = directory.path().join("snapshot.json");
+        let mut transactions_manager = new_manager();
+        run_with_snapshots(
+            &DummyReader::new(5),
+            &mut transactions_manager,
+            2,
+            snapshot_path.to_str().unwrap(),
+        )
+        .unwrap();
+        let resumed_manager = crate::snapshot::Snapshot::read_from(&snapshot_path)
+            .and_then(|snapshot| DefaultTransactionsManager::import_state(snapshot, DEFAULT_SCALE))
+            .unwrap();
+        assert_eq!(resumed_manager.processed_requests(), 4);
+    }
+}
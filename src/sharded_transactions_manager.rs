@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    common_types::TransactionId,
+    customer_account_provider::{
+        sort_accounts, CustomerAccountReport, InMemoryCustomerAccountProvider,
+    },
+    errors::PaymentEngineError,
+    report_formatter::ReportFormat,
+    transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+    transaction_outcome::{ProcessingSummary, TransactionOutcome},
+    transaction_request::{TransactionRequest, TransactionType},
+    transactions_manager::{
+        write_accounts, DefaultTransactionsManager, TransactionsManager, ValidationConfig,
+        DEFAULT_SCALE,
+    },
+};
+
+// The requests are sent in batches, as a channel message per request costs more than handling it
+const BATCH_SIZE: usize = 1024;
+// Number of batches that can wait in the channel of a shard before the reading is paused
+const SHARD_CHANNEL_CAPACITY: usize = 16;
+
+// The summary and the accounts of a shard
+type ShardState = (ProcessingSummary, Vec<CustomerAccountReport>);
+
+enum ShardMessage {
+    Request {
+        request: TransactionRequest,
+        // The answer of the shard that saw the ID of the deposit or withdrawal last, when it's another one
+        executed_elsewhere: Option<Receiver<bool>>,
+        // Set by handle_transaction, otherwise the request is validated and handled like in process_all
+        outcome: Option<SyncSender<Result<TransactionOutcome, PaymentEngineError>>>,
+    },
+    // Answered with whether a deposit or withdrawal with the ID was executed by the requests before the question
+    Query(TransactionId, SyncSender<bool>),
+    State(SyncSender<Result<ShardState, PaymentEngineError>>),
+}
+
+/**
+ * Processes the clients in parallel, as the transactions of different clients never affect each other.
+ * Each shard is a DefaultTransactionsManager running on its own thread and receiving the requests of the clients with
+ * client_id % shards == shard index, in the input order. The threads live as long as the manager, so the requests
+ * can be handled over several process_all and handle_transaction calls.
+ *
+ * The duplicate transaction IDs are detected globally, only an executed deposit or withdrawal uses up its ID. A shard
+ * checks its own clients' transactions itself. When the ID was last seen by another shard, that shard is asked whether
+ * it was executed by then, answering once it handled the requests before. So the report is the same as the serial
+ * one. The summary may differ in the reasons of the disputes, resolves and chargebacks of another client's
+ * transaction, skipped as not found by the shard instead of as a client mismatch.
+ */
+pub struct ShardedTransactionsManager {
+    senders: Vec<SyncSender<Vec<ShardMessage>>>,
+    workers: Vec<JoinHandle<()>>,
+    // The first fatal error of any shard, every shard stops after it
+    shard_failure: Arc<Mutex<Option<PaymentEngineError>>>,
+    // Set once the shards are stopped, returned by every call after
+    failure: Option<PaymentEngineError>,
+    batches: Vec<Vec<ShardMessage>>,
+    // The shard of the last deposit or withdrawal with the ID
+    last_shards: HashMap<TransactionId, usize>,
+    // Has to match the config of the managers created by the factory
+    validation_config: ValidationConfig,
+    // Has to match the scale of the managers created by the factory
+    scale: u32,
+    report_format: ReportFormat,
+    detailed_report: bool,
+    fixed_scale_report: bool,
+    summary: ProcessingSummary,
+}
+
+impl ShardedTransactionsManager {
+    pub fn new(shards: usize) -> Self {
+        Self::new_with_factory(shards, || {
+            DefaultTransactionsManager::new(
+                InMemoryTransactionHistoryProvider::new(),
+                InMemoryCustomerAccountProvider::new(),
+            )
+        })
+    }
+
+    // The managers are created on the shard threads, as the providers are not required to be Send
+    pub fn new_with_factory(
+        shards: usize,
+        manager_factory: impl Fn() -> DefaultTransactionsManager + Send + Sync + 'static,
+    ) -> Self {
+        let shards = shards.max(1);
+        let manager_factory = Arc::new(manager_factory);
+        let shard_failure = Arc::new(Mutex::new(None));
+        let (senders, workers) = (0..shards)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel(SHARD_CHANNEL_CAPACITY);
+                let manager_factory = Arc::clone(&manager_factory);
+                let shard_failure = Arc::clone(&shard_failure);
+                let worker =
+                    thread::spawn(move || run_shard(manager_factory(), receiver, &shard_failure));
+                (sender, worker)
+            })
+            .unzip();
+        ShardedTransactionsManager {
+            senders,
+            workers,
+            shard_failure,
+            failure: None,
+            batches: (0..shards).map(|_| Vec::new()).collect(),
+            last_shards: HashMap::new(),
+            validation_config: ValidationConfig::default(),
+            scale: DEFAULT_SCALE,
+            report_format: ReportFormat::default(),
+            detailed_report: false,
+            fixed_scale_report: false,
+            summary: ProcessingSummary::default(),
+        }
+    }
+
+    pub fn with_validation_config(mut self, validation_config: ValidationConfig) -> Self {
+        self.validation_config = validation_config;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_report_format(mut self, report_format: ReportFormat) -> Self {
+        self.report_format = report_format;
+        self
+    }
+
+    pub fn with_detailed_report(mut self, detailed_report: bool) -> Self {
+        self.detailed_report = detailed_report;
+        self
+    }
+
+    pub fn with_fixed_scale_report(mut self, fixed_scale_report: bool) -> Self {
+        self.fixed_scale_report = fixed_scale_report;
+        self
+    }
+
+    // Counters of everything handled so far, merged from all the shards
+    pub fn summary(&self) -> &ProcessingSummary {
+        &self.summary
+    }
+
+    // Validates and handles the requests in the shards, stopping on the first fatal error of any shard
+    pub fn process_all(
+        &mut self,
+        requests: impl Iterator<Item = TransactionRequest>,
+    ) -> Result<ProcessingSummary, PaymentEngineError> {
+        self.ensure_running()?;
+        for request in requests {
+            self.route(request, None)?;
+        }
+        for shard in 0..self.senders.len() {
+            self.send(shard)?;
+        }
+        let states = self.shard_states();
+        // Stopping all the shards after the failure of one, so every call after returns its error
+        if self.shard_failure.lock().unwrap().is_some() {
+            return Err(self.stop());
+        }
+        let mut summary = ProcessingSummary::default();
+        for (shard_summary, _) in &states? {
+            summary.merge(shard_summary);
+        }
+        self.summary = summary;
+        Ok(self.summary.clone())
+    }
+
+    fn ensure_running(&self) -> Result<(), PaymentEngineError> {
+        match &self.failure {
+            Some(failure) => Err(failure.clone()),
+            None => Ok(()),
+        }
+    }
+
+    // Puts the request into the batch of its shard, after asking the shard that saw its ID last, if it's another one
+    fn route(
+        &mut self,
+        request: TransactionRequest,
+        outcome: Option<SyncSender<Result<TransactionOutcome, PaymentEngineError>>>,
+    ) -> Result<(), PaymentEngineError> {
+        let shard = request.client_id as usize % self.senders.len();
+        let mut executed_elsewhere = None;
+        if matches!(
+            request.transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            if let Some(last_shard) = self
+                .last_shards
+                .insert(request.transaction_id, shard)
+                .filter(|last_shard| *last_shard != shard)
+            {
+                let (answer, receiver) = mpsc::sync_channel(1);
+                self.batches[last_shard].push(ShardMessage::Query(request.transaction_id, answer));
+                // Right away, as the shard of the request waits for the answer
+                self.send(last_shard)?;
+                executed_elsewhere = Some(receiver);
+            }
+        }
+        let awaited = outcome.is_some();
+        self.batches[shard].push(ShardMessage::Request {
+            request,
+            executed_elsewhere,
+            outcome,
+        });
+        if awaited || self.batches[shard].len() == BATCH_SIZE {
+            self.send(shard)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, shard: usize) -> Result<(), PaymentEngineError> {
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+        // The shard only hangs up after a fatal error
+        if self.senders[shard].send(batch).is_err() {
+            return Err(self.stop());
+        }
+        Ok(())
+    }
+
+    // The summaries and the accounts of all the shards, once they handled everything sent before
+    fn shard_states(&self) -> Result<Vec<ShardState>, PaymentEngineError> {
+        self.ensure_running()?;
+        let receivers = self
+            .senders
+            .iter()
+            .map(|sender| {
+                let (answer, receiver) = mpsc::sync_channel(1);
+                sender
+                    .send(vec![ShardMessage::State(answer)])
+                    .map(|()| receiver)
+                    .map_err(|_| self.shard_failure())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        receivers
+            .into_iter()
+            .map(|receiver| receiver.recv().map_err(|_| self.shard_failure())?)
+            .collect()
+    }
+
+    fn shard_failure(&self) -> PaymentEngineError {
+        self.shard_failure
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| PaymentEngineError::Storage("A shard stopped".to_owned()))
+    }
+
+    // Stops all the shards after one of them failed, so its error is in place once they're joined
+    fn stop(&mut self) -> PaymentEngineError {
+        self.senders.clear();
+        for worker in self.workers.drain(..) {
+            worker.join().expect("A shard panicked");
+        }
+        let failure = self.shard_failure();
+        self.failure = Some(failure.clone());
+        failure
+    }
+}
+
+impl TransactionsManager for ShardedTransactionsManager {
+    fn structure_validation(&self, transaction_request: &TransactionRequest) -> bool {
+        self.validation_config.is_valid(transaction_request)
+    }
+
+    // Waits for the shard of the client to handle the request, so it's slower than process_all
+    fn handle_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        self.ensure_running()?;
+        let transaction_type = transaction_request.transaction_type.clone();
+        let (outcome, receiver) = mpsc::sync_channel(1);
+        self.route(transaction_request, Some(outcome))?;
+        let result = match receiver.recv() {
+            Ok(result) => result,
+            Err(_) => return Err(self.stop()),
+        };
+        self.summary.record(&transaction_type, &result);
+        result
+    }
+
+    // Writes the report of the accounts of all shards, sorted by client
+    fn write_report(&self, writer: &mut dyn Write) -> Result<(), PaymentEngineError> {
+        let mut accounts: Vec<CustomerAccountReport> = self
+            .shard_states()?
+            .into_iter()
+            .flat_map(|(_, accounts)| accounts)
+            .collect();
+        sort_accounts(&mut accounts);
+        write_accounts(
+            writer,
+            accounts.into_iter(),
+            self.report_format.formatter(self.detailed_report).as_ref(),
+            self.scale,
+            self.fixed_scale_report,
+        )
+    }
+
+    fn print_report(&self) -> Result<(), PaymentEngineError> {
+        self.write_report(&mut std::io::stdout().lock())
+    }
+}
+
+impl Drop for ShardedTransactionsManager {
+    fn drop(&mut self) {
+        // Hanging up lets the shards finish, the panics of the shards were already reported by the thread
+        self.senders.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Handles the messages of one shard until the manager hangs up or a shard stops on a fatal error. The error is
+// recorded before the messages are dropped, so it's in place once the other shards notice the shard hung up.
+fn run_shard(
+    mut manager: DefaultTransactionsManager,
+    receiver: Receiver<Vec<ShardMessage>>,
+    shard_failure: &Mutex<Option<PaymentEngineError>>,
+) {
+    let fail = |e: PaymentEngineError| {
+        shard_failure.lock().unwrap().get_or_insert(e);
+    };
+    for message in receiver.iter().flatten() {
+        match message {
+            ShardMessage::Request {
+                request,
+                executed_elsewhere,
+                outcome,
+            } => {
+                if let Some(executed_elsewhere) = executed_elsewhere {
+                    match executed_elsewhere.recv() {
+                        Ok(true) => manager.mark_executed_elsewhere(request.transaction_id),
+                        Ok(false) => {}
+                        // The other shard dropped the question, it stopped on a fatal error
+                        Err(_) => return,
+                    }
+                }
+                match outcome {
+                    // The caller of handle_transaction decides what to do with a fatal error
+                    Some(outcome) => {
+                        let _ = outcome.send(manager.handle_transaction(request));
+                    }
+                    None => {
+                        if let Err(e) = manager.process_one(request) {
+                            fail(e);
+                            return;
+                        }
+                    }
+                }
+            }
+            ShardMessage::Query(transaction_id, answer) => {
+                match manager.knows_executed(transaction_id) {
+                    // The asking shard only hangs up after a fatal error of another one
+                    Ok(executed) => {
+                        let _ = answer.send(executed);
+                    }
+                    Err(e) => {
+                        fail(e);
+                        return;
+                    }
+                }
+            }
+            ShardMessage::State(answer) => {
+                let _ = answer.send(
+                    manager
+                        .list_accounts()
+                        .map(|accounts| (manager.summary().clone(), accounts)),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        common_types::CustomerId, customer_account_provider::MockCustomerAccountProvider,
+        transaction_history_provider::transaction_history_provider::MockTransactionHistoryProvider,
+        transaction_outcome::SkipReason,
+    };
+
+    use super::*;
+
+    // Pseudo-random mix of all the transaction types. Every twentieth deposit or withdrawal reuses an earlier ID, often
+    // of a request that was skipped, e.g. a withdrawal beyond the funds
+    fn randomized_requests(count: u32, seed: u64) -> Vec<TransactionRequest> {
+        let mut seed = seed;
+        let mut next = move |bound: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        (1..=count)
+            .map(|transaction_id| {
+                let client_id = next(50) as CustomerId;
+                let referenced_id = next(transaction_id as u64) as u32 + 1;
+                let amount = Some(Decimal::new(next(100_000) as i64 + 1, 2));
+                let new_id = if next(20) == 0 {
+                    referenced_id
+                } else {
+                    transaction_id
+                };
+                let (transaction_type, transaction_id, amount) = match next(10) {
+                    0..=4 => (TransactionType::Deposit, new_id, amount),
+                    5..=6 => (TransactionType::Withdrawal, new_id, amount),
+                    7 => (TransactionType::Dispute, referenced_id, None),
+                    8 => (TransactionType::Resolve, referenced_id, None),
+                    _ => (TransactionType::Chargeback, referenced_id, None),
+                };
+                TransactionRequest {
+                    transaction_type,
+                    client_id,
+                    transaction_id,
+                    amount,
+                    timestamp: None,
+                    currency: None,
+                }
+            })
+            .collect()
+    }
+
+    fn serial_report(requests: Vec<TransactionRequest>) -> Vec<u8> {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        manager.process_all(requests.into_iter(), None).unwrap();
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
+        output
+    }
+
+    fn sharded_report(shards: usize, requests: Vec<TransactionRequest>) -> Vec<u8> {
+        let mut sharded_manager = ShardedTransactionsManager::new(shards);
+        sharded_manager.process_all(requests.into_iter()).unwrap();
+        let mut output = vec![];
+        sharded_manager.write_report(&mut output).unwrap();
+        output
+    }
+
+    fn request(
+        transaction_type: TransactionType,
+        client_id: CustomerId,
+        transaction_id: TransactionId,
+        amount: Decimal,
+    ) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn sharded_report_matches_the_serial_one() {
+        for seed in [1, 2, 3] {
+            let requests = randomized_requests(5000, seed);
+            assert_eq!(
+                String::from_utf8(sharded_report(4, requests.clone())).unwrap(),
+                String::from_utf8(serial_report(requests)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn ids_of_skipped_requests_can_be_reused_in_any_shard() {
+        let requests = vec![
+            request(TransactionType::Withdrawal, 1, 1, Decimal::new(3, 0)),
+            request(TransactionType::Deposit, 1, 1, Decimal::new(5, 0)),
+            request(TransactionType::Deposit, 2, 2, Decimal::ONE),
+            request(TransactionType::Withdrawal, 2, 3, Decimal::new(3, 0)),
+            request(TransactionType::Deposit, 3, 3, Decimal::new(7, 0)),
+            // Executed by client 3 in another shard by now
+            request(TransactionType::Deposit, 2, 3, Decimal::new(9, 0)),
+        ];
+        let sharded_output = sharded_report(2, requests.clone());
+        assert_eq!(sharded_output, serial_report(requests));
+        assert_eq!(
+            String::from_utf8(sharded_output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n\
+             1,5,0,5,false,,USD,0,0\n\
+             2,1,0,1,false,,USD,0,0\n\
+             3,7,0,7,false,,USD,0,0\n"
+        );
+    }
+
+    #[test]
+    fn duplicate_ids_are_detected_across_shards() {
+        let deposit = |client_id: CustomerId| TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id: 1,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            currency: None,
+        };
+        let mut sharded_manager = ShardedTransactionsManager::new(2);
+        let summary = sharded_manager
+            .process_all(vec![deposit(1), deposit(2)].into_iter())
+            .unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 1);
+        assert_eq!(summary.skipped_because(SkipReason::CrossClientIdReuse), 1);
+        let mut output = vec![];
+        sharded_manager.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n1,1,0,1,false,,USD,0,0\n"
+        );
+    }
+
+    #[test]
+    fn handle_transaction_gives_the_outcomes_of_the_serial_run() {
+        let requests = randomized_requests(2000, 4);
+        let mut serial_manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let mut sharded_manager = ShardedTransactionsManager::new(4);
+        for request in requests.clone() {
+            let transaction_type = request.transaction_type.clone();
+            let outcome = sharded_manager.handle_transaction(request.clone());
+            // The disputes of another client's transaction are not found by the shard instead
+            if matches!(
+                transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            ) {
+                assert_eq!(outcome, serial_manager.handle_transaction(request));
+            } else {
+                serial_manager.handle_transaction(request).unwrap();
+            }
+        }
+        let mut sharded_output = vec![];
+        sharded_manager.write_report(&mut sharded_output).unwrap();
+        let mut serial_output = vec![];
+        serial_manager.write_report(&mut serial_output).unwrap();
+        assert_eq!(sharded_output, serial_output);
+        assert_eq!(sharded_output, sharded_report(1, requests));
+    }
+
+    #[test]
+    fn requests_are_handled_over_several_calls() {
+        let mut sharded_manager = ShardedTransactionsManager::new(2);
+        sharded_manager
+            .process_all(vec![request(TransactionType::Deposit, 1, 1, Decimal::ONE)].into_iter())
+            .unwrap();
+        assert_eq!(
+            sharded_manager.handle_transaction(request(
+                TransactionType::Deposit,
+                2,
+                1,
+                Decimal::ONE
+            )),
+            Ok(TransactionOutcome::Skipped(SkipReason::CrossClientIdReuse))
+        );
+        let summary = sharded_manager
+            .process_all(vec![request(TransactionType::Deposit, 2, 2, Decimal::ONE)].into_iter())
+            .unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped_because(SkipReason::CrossClientIdReuse), 1);
+        assert_eq!(sharded_manager.summary(), &summary);
+    }
+
+    #[test]
+    fn fatal_error_of_a_shard_stops_the_manager() {
+        let mut sharded_manager = ShardedTransactionsManager::new_with_factory(2, || {
+            let mut mock_history_provider = MockTransactionHistoryProvider::new();
+            mock_history_provider
+                .expect_read_transaction()
+                .return_const(Err(PaymentEngineError::Storage("disk failure".to_owned())));
+            DefaultTransactionsManager::new(
+                mock_history_provider,
+                MockCustomerAccountProvider::new(),
+            )
+        });
+        let requests = (1..=10).map(|transaction_id| {
+            request(
+                TransactionType::Deposit,
+                transaction_id as CustomerId,
+                transaction_id,
+                Decimal::ONE,
+            )
+        });
+        let failure = PaymentEngineError::Storage("disk failure".to_owned());
+        assert_eq!(sharded_manager.process_all(requests), Err(failure.clone()));
+        assert_eq!(
+            sharded_manager.handle_transaction(request(
+                TransactionType::Deposit,
+                1,
+                11,
+                Decimal::ONE
+            )),
+            Err(failure.clone())
+        );
+        assert_eq!(sharded_manager.write_report(&mut vec![]), Err(failure));
+    }
+}
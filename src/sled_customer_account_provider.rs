@@ -0,0 +1,623 @@
+//! Customer account provider keeping the accounts on disk, for the runs where the accounts don't fit into memory.
+
+use std::path::Path;
+
+use log::error;
+use rust_decimal::Decimal;
+use sled::{Batch, Tree};
+
+use crate::{
+    common_types::CustomerId,
+    customer_account_provider::{
+        AccountMutation, CustomerAccount, CustomerAccountProvider, CustomerAccountReport,
+    },
+    errors::PaymentEngineError,
+};
+
+// available, held and total as the 16 bytes of their Decimal representation, then the locked flag, then the
+// last activity presence flag and timestamp, then the total charged back and the open disputes count, then the total
+// deposited and withdrawn
+const RECORD_LENGTH: usize = DISPUTES_RECORD_LENGTH + 16 * 2;
+// Written before the deposited and withdrawn amounts were tracked, both of them are read as zero
+const DISPUTES_RECORD_LENGTH: usize = LEGACY_RECORD_LENGTH + 16 + 4;
+// Written before the charged back volume and the open disputes were tracked, both of them are read as zero
+const LEGACY_RECORD_LENGTH: usize = 16 * 3 + 1 + 1 + 8;
+
+pub struct SledCustomerAccountProvider {
+    tree: Tree,
+}
+
+fn err_to_string(e: impl ToString) -> String {
+    e.to_string()
+}
+
+impl SledCustomerAccountProvider {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        let db = sled::open(path).map_err(err_to_string)?;
+        Self::from_db(db)
+    }
+
+    // Removed from the disk once the provider is dropped
+    pub fn new_temporary() -> Result<Self, PaymentEngineError> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(err_to_string)?;
+        Self::from_db(db)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self, PaymentEngineError> {
+        let tree = db.open_tree("accounts").map_err(err_to_string)?;
+        Ok(SledCustomerAccountProvider { tree })
+    }
+
+    fn read_account(
+        &self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<CustomerAccount>, PaymentEngineError> {
+        self.tree
+            .get(account_key(customer_id, currency))
+            .map_err(err_to_string)?
+            .map(|record| decode_account(&record))
+            .transpose()
+    }
+
+    fn existing_account(
+        &self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<CustomerAccount, PaymentEngineError> {
+        self.read_account(customer_id, currency)?
+            .ok_or(PaymentEngineError::AccountNotFound(customer_id))
+    }
+
+    fn write_account(
+        &self,
+        customer_id: CustomerId,
+        currency: &str,
+        account: CustomerAccount,
+    ) -> Result<(), PaymentEngineError> {
+        self.tree
+            .insert(
+                account_key(customer_id, currency),
+                encode_account(&account).to_vec(),
+            )
+            .map_err(err_to_string)?;
+        Ok(())
+    }
+
+    // The accounts of the client in all the currencies, as the keys of a client share the prefix
+    fn client_accounts(
+        &self,
+        customer_id: CustomerId,
+    ) -> impl Iterator<Item = Result<CustomerAccountReport, PaymentEngineError>> + '_ {
+        self.tree
+            .scan_prefix(customer_id.to_be_bytes())
+            .map(|entry| decode_entry(entry.map_err(err_to_string)?))
+    }
+}
+
+// The big-endian client ID followed by the currency, so the accounts are ordered by client, then by currency
+fn account_key(customer_id: CustomerId, currency: &str) -> Vec<u8> {
+    let mut key = customer_id.to_be_bytes().to_vec();
+    key.extend_from_slice(currency.as_bytes());
+    key
+}
+
+// Using the binary representation of the decimals, so the stored values are exactly the ones that were written
+fn encode_account(account: &CustomerAccount) -> [u8; RECORD_LENGTH] {
+    let mut record = [0; RECORD_LENGTH];
+    record[0..16].copy_from_slice(&account.available.serialize());
+    record[16..32].copy_from_slice(&account.held.serialize());
+    record[32..48].copy_from_slice(&account.total.serialize());
+    record[48] = account.locked as u8;
+    if let Some(last_activity) = account.last_activity {
+        record[49] = 1;
+        record[50..58].copy_from_slice(&last_activity.to_be_bytes());
+    }
+    record[58..74].copy_from_slice(&account.total_charged_back.serialize());
+    record[74..78].copy_from_slice(&account.open_disputes.to_be_bytes());
+    record[78..94].copy_from_slice(&account.total_deposited.serialize());
+    record[94..110].copy_from_slice(&account.total_withdrawn.serialize());
+    record
+}
+
+fn decode_account(record: &[u8]) -> Result<CustomerAccount, PaymentEngineError> {
+    if ![RECORD_LENGTH, DISPUTES_RECORD_LENGTH, LEGACY_RECORD_LENGTH].contains(&record.len()) {
+        return Err(PaymentEngineError::Storage(format!(
+            "Account record of {} bytes, expected {}",
+            record.len(),
+            RECORD_LENGTH
+        )));
+    }
+    let decimal_at = |offset: usize| {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&record[offset..offset + 16]);
+        Decimal::deserialize(bytes)
+    };
+    let account = CustomerAccount {
+        available: decimal_at(0),
+        held: decimal_at(16),
+        total: decimal_at(32),
+        locked: record[48] != 0,
+        last_activity: (record[49] != 0).then(|| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&record[50..58]);
+            i64::from_be_bytes(bytes)
+        }),
+        ..Default::default()
+    };
+    if record.len() == LEGACY_RECORD_LENGTH {
+        return Ok(account);
+    }
+    let mut open_disputes = [0; 4];
+    open_disputes.copy_from_slice(&record[74..78]);
+    let account = CustomerAccount {
+        total_charged_back: decimal_at(58),
+        open_disputes: u32::from_be_bytes(open_disputes),
+        ..account
+    };
+    if record.len() == DISPUTES_RECORD_LENGTH {
+        return Ok(account);
+    }
+    Ok(CustomerAccount {
+        total_deposited: decimal_at(78),
+        total_withdrawn: decimal_at(94),
+        ..account
+    })
+}
+
+fn decode_key(key: &[u8]) -> Result<(CustomerId, &str), PaymentEngineError> {
+    let invalid_key = || PaymentEngineError::Storage(format!("Invalid account key {:?}", key));
+    if key.len() < 2 {
+        return Err(invalid_key());
+    }
+    let (customer_id, currency) = key.split_at(2);
+    Ok((
+        CustomerId::from_be_bytes([customer_id[0], customer_id[1]]),
+        std::str::from_utf8(currency).map_err(|_| invalid_key())?,
+    ))
+}
+
+fn decode_entry(
+    (key, record): (sled::IVec, sled::IVec),
+) -> Result<CustomerAccountReport, PaymentEngineError> {
+    let (customer_id, currency) = decode_key(&key)?;
+    Ok(decode_account(&record)?.report(customer_id, currency))
+}
+
+impl CustomerAccountProvider for SledCustomerAccountProvider {
+    fn get_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        Ok(self
+            .read_account(customer_id, currency)?
+            .map(|c| c.available))
+    }
+
+    fn get_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        Ok(self.read_account(customer_id, currency)?.map(|c| c.held))
+    }
+
+    fn get_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+    ) -> Result<Option<bool>, PaymentEngineError> {
+        self.client_accounts(customer_id)
+            .next()
+            .map(|account| account.map(|account| account.locked))
+            .transpose()
+    }
+
+    fn get_total(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<Decimal>, PaymentEngineError> {
+        Ok(self.read_account(customer_id, currency)?.map(|c| c.total))
+    }
+
+    fn set_available(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let account = match self.read_account(customer_id, currency)? {
+            Some(customer_account) => customer_account.with_available(customer_id, balance)?,
+            None => CustomerAccount::new(
+                balance,
+                Decimal::ZERO,
+                self.get_locked_status(customer_id)?.unwrap_or(false),
+            ),
+        };
+        self.write_account(customer_id, currency, account)
+    }
+
+    fn set_held_amount(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        balance: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.with_held(customer_id, balance)?,
+        )
+    }
+
+    fn set_locked_status(
+        &mut self,
+        customer_id: CustomerId,
+        locked: bool,
+    ) -> Result<(), PaymentEngineError> {
+        let accounts = self
+            .client_accounts(customer_id)
+            .collect::<Result<Vec<_>, _>>()?;
+        if accounts.is_empty() {
+            return Err(PaymentEngineError::AccountNotFound(customer_id));
+        }
+        for account in accounts {
+            let customer_account = self.existing_account(customer_id, &account.currency)?;
+            self.write_account(
+                customer_id,
+                &account.currency,
+                CustomerAccount {
+                    locked,
+                    ..customer_account
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn adjust_balances(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        available_delta: Decimal,
+        held_delta: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.adjusted(customer_id, available_delta, held_delta)?,
+        )
+    }
+
+    fn apply_mutation(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        mutation: AccountMutation,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self
+            .existing_account(customer_id, currency)?
+            .mutated(customer_id, mutation)?;
+        // Written as one batch, which sled applies atomically
+        let mut batch = Batch::default();
+        if mutation.lock {
+            for entry in self.tree.scan_prefix(customer_id.to_be_bytes()) {
+                let (key, record) = entry.map_err(err_to_string)?;
+                let account = CustomerAccount {
+                    locked: true,
+                    ..decode_account(&record)?
+                };
+                batch.insert(key, encode_account(&account).to_vec());
+            }
+        }
+        // Replaces the record of the same key inserted above
+        batch.insert(
+            account_key(customer_id, currency),
+            encode_account(&CustomerAccount {
+                locked: customer_account.locked || mutation.lock,
+                ..customer_account
+            })
+            .to_vec(),
+        );
+        self.tree.apply_batch(batch).map_err(err_to_string)?;
+        Ok(())
+    }
+
+    fn increment_charged_back(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.with_charged_back(customer_id, amount)?,
+        )
+    }
+
+    fn add_deposited(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.with_deposited(customer_id, amount)?,
+        )
+    }
+
+    fn add_withdrawn(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.with_withdrawn(customer_id, amount)?,
+        )
+    }
+
+    fn adjust_open_disputes(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        delta: i32,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.with_open_disputes_changed_by(delta),
+        )
+    }
+
+    fn get_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+    ) -> Result<Option<i64>, PaymentEngineError> {
+        Ok(self
+            .read_account(customer_id, currency)?
+            .and_then(|c| c.last_activity))
+    }
+
+    fn set_last_activity(
+        &mut self,
+        customer_id: CustomerId,
+        currency: &str,
+        timestamp: i64,
+    ) -> Result<(), PaymentEngineError> {
+        let customer_account = self.existing_account(customer_id, currency)?;
+        self.write_account(
+            customer_id,
+            currency,
+            customer_account.with_activity_at(timestamp),
+        )
+    }
+
+    // The keys start with the big-endian client IDs, so the accounts come out sorted by client and currency
+    fn iter_accounts(&self) -> Box<dyn Iterator<Item = CustomerAccountReport> + '_> {
+        Box::new(self.tree.iter().filter_map(|entry| {
+            let account = entry
+                .map_err(|e| PaymentEngineError::Storage(e.to_string()))
+                .and_then(decode_entry);
+            match account {
+                Ok(account) => Some(account),
+                Err(e) => {
+                    error!(
+                        "Failed reading an account, skipping it in the report: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        }))
+    }
+
+    fn list_client_accounts(
+        &self,
+        customer_id: CustomerId,
+    ) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        self.client_accounts(customer_id).collect()
+    }
+
+    fn list_accounts(&self) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        self.tree
+            .iter()
+            .map(|entry| decode_entry(entry.map_err(err_to_string)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod sled_customer_account_provider_tests {
+    use super::*;
+
+    #[test]
+    fn accounts_round_trip_without_losing_precision() {
+        let mut customer_account_provider = SledCustomerAccountProvider::new_temporary().unwrap();
+        let available = Decimal::new(123456789012345678, 4);
+        customer_account_provider
+            .set_available(1, "USD", available)
+            .unwrap();
+        customer_account_provider
+            .adjust_balances(1, "USD", Decimal::new(-1, 4), Decimal::new(1, 4))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        customer_account_provider
+            .set_last_activity(1, "USD", 1_650_000_000_000)
+            .unwrap();
+        customer_account_provider
+            .add_deposited(1, "USD", available)
+            .unwrap();
+        customer_account_provider
+            .add_withdrawn(1, "USD", Decimal::new(1, 4))
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.list_accounts(),
+            Ok(vec![CustomerAccountReport {
+                client: 1,
+                available: Decimal::new(123456789012345677, 4),
+                held: Decimal::new(1, 4),
+                total: available,
+                locked: true,
+                last_activity: Some(1_650_000_000_000),
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::ZERO,
+                open_disputes: 0,
+                total_deposited: available,
+                total_withdrawn: Decimal::new(1, 4),
+            }])
+        );
+    }
+
+    #[test]
+    fn set_held_amount_fails_when_no_account_found() {
+        let mut customer_account_provider = SledCustomerAccountProvider::new_temporary().unwrap();
+        assert_eq!(
+            customer_account_provider.set_held_amount(1, "USD", Decimal::ONE),
+            Err(PaymentEngineError::AccountNotFound(1))
+        );
+        assert_eq!(
+            customer_account_provider.get_held_amount(1, "USD"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn accounts_are_listed_by_client() {
+        let mut customer_account_provider = SledCustomerAccountProvider::new_temporary().unwrap();
+        for client in [300, 1, 20] {
+            customer_account_provider
+                .set_available(client, "USD", Decimal::ONE)
+                .unwrap();
+        }
+        let clients: Vec<CustomerId> = customer_account_provider
+            .iter_accounts()
+            .map(|account| account.client)
+            .collect();
+        assert_eq!(clients, vec![1, 20, 300]);
+    }
+
+    #[test]
+    fn currencies_of_a_client_share_the_lock() {
+        let mut customer_account_provider = SledCustomerAccountProvider::new_temporary().unwrap();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ONE)
+            .unwrap();
+        customer_account_provider
+            .set_available(1, "EUR", Decimal::TWO)
+            .unwrap();
+        customer_account_provider
+            .set_available(2, "USD", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        customer_account_provider
+            .set_available(1, "GBP", Decimal::ONE)
+            .unwrap();
+        let accounts: Vec<(CustomerId, String, bool)> = customer_account_provider
+            .iter_accounts()
+            .map(|account| (account.client, account.currency, account.locked))
+            .collect();
+        assert_eq!(
+            accounts,
+            vec![
+                (1, "EUR".to_owned(), true),
+                (1, "GBP".to_owned(), true),
+                (1, "USD".to_owned(), true),
+                (2, "USD".to_owned(), false),
+            ]
+        );
+        assert_eq!(
+            customer_account_provider.get_available(1, "EUR"),
+            Ok(Some(Decimal::TWO))
+        );
+    }
+
+    #[test]
+    fn mutation_moves_funds_and_locks_all_the_currencies() {
+        let mut customer_account_provider = SledCustomerAccountProvider::new_temporary().unwrap();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::TEN)
+            .unwrap();
+        customer_account_provider
+            .set_available(1, "EUR", Decimal::TWO)
+            .unwrap();
+        assert_eq!(
+            customer_account_provider.apply_mutation(
+                1,
+                "USD",
+                AccountMutation {
+                    available_delta: -Decimal::TWO,
+                    held_delta: Decimal::TWO,
+                    lock: true,
+                    ..Default::default()
+                },
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::TWO))
+        );
+        assert_eq!(
+            customer_account_provider.get_total(1, "USD"),
+            Ok(Some(Decimal::TEN))
+        );
+        assert_eq!(
+            customer_account_provider.get_available(1, "EUR"),
+            Ok(Some(Decimal::TWO))
+        );
+        assert!(customer_account_provider
+            .iter_accounts()
+            .all(|account| account.locked));
+    }
+
+    #[test]
+    fn accounts_persist_in_the_given_path() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let mut customer_account_provider =
+                SledCustomerAccountProvider::new(directory.path()).unwrap();
+            customer_account_provider
+                .set_available(1, "USD", Decimal::new(15, 1))
+                .unwrap();
+            customer_account_provider.tree.flush().unwrap();
+        }
+        // The flusher thread of sled releases the lock of the dropped db in the background, so the first attempts can
+        // still find it locked
+        let mut attempts = 0;
+        let mut customer_account_provider = loop {
+            match SledCustomerAccountProvider::new(directory.path()) {
+                Ok(customer_account_provider) => break customer_account_provider,
+                Err(_) if attempts < 100 => {
+                    attempts += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+                Err(e) => panic!("The db is still locked: {}", e),
+            }
+        };
+        assert_eq!(
+            customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(15, 1)))
+        );
+    }
+}
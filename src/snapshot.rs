@@ -0,0 +1,45 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common_types::{CustomerId, TransactionId},
+    customer_account_provider::CustomerAccountReport,
+    errors::PaymentEngineError,
+    transaction_request::{TransactionRequest, TransactionState},
+};
+
+// Everything the manager needs to continue a run where it stopped, see DefaultTransactionsManager::export_state
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    // Number of input requests handled before the snapshot was taken, resuming continues after them
+    pub processed_requests: usize,
+    pub accounts: Vec<CustomerAccountReport>,
+    pub transactions: Vec<TransactionRequest>,
+    pub transaction_states: Vec<(TransactionId, TransactionState)>,
+    // Needed for the dispute window, empty when it's not set
+    pub client_sequences: Vec<(CustomerId, u64)>,
+}
+
+impl Snapshot {
+    // Written next to the destination first, so a crash while writing doesn't corrupt the previous snapshot
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), PaymentEngineError> {
+        let path = path.as_ref();
+        let temporary_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        serde_json::to_writer(&mut writer, self)
+            .map_err(|e| PaymentEngineError::Storage(e.to_string()))?;
+        writer.flush()?;
+        fs::rename(temporary_path, path)?;
+        Ok(())
+    }
+
+    pub fn read_from(path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(|e| PaymentEngineError::Storage(e.to_string()))
+    }
+}
@@ -0,0 +1,91 @@
+use async_trait::async_trait;
+
+use crate::{
+    common_types::{CustomerId, TransactionId},
+    errors::PaymentEngineError,
+    transaction_request::{TransactionRequest, TransactionState},
+};
+
+use super::transaction_history_provider::TransactionHistoryProvider;
+
+/**
+ * Async counterpart of the TransactionHistoryProvider, for the providers backed by remote stores.
+ * The futures are not required to be Send, so that the sync providers can be wrapped without extra bounds.
+ */
+#[async_trait(?Send)]
+pub trait AsyncTransactionHistoryProvider {
+    async fn write_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError>;
+    async fn read_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError>;
+    async fn write_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction_state: TransactionState,
+    ) -> Result<(), PaymentEngineError>;
+    async fn read_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionState>, PaymentEngineError>;
+    async fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError>;
+}
+
+// Lets the existing sync providers, e.g. the in-memory one, be used in the async path
+pub struct SyncTransactionHistoryProviderAdapter<P: TransactionHistoryProvider> {
+    inner: P,
+}
+
+impl<P: TransactionHistoryProvider> SyncTransactionHistoryProviderAdapter<P> {
+    pub fn new(inner: P) -> Self {
+        SyncTransactionHistoryProviderAdapter { inner }
+    }
+}
+
+#[async_trait(?Send)]
+impl<P: TransactionHistoryProvider> AsyncTransactionHistoryProvider
+    for SyncTransactionHistoryProviderAdapter<P>
+{
+    async fn write_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner.write_transaction(transaction_request)
+    }
+
+    async fn read_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError> {
+        self.inner.read_transaction(transaction_id)
+    }
+
+    async fn write_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction_state: TransactionState,
+    ) -> Result<(), PaymentEngineError> {
+        self.inner
+            .write_transaction_state(transaction_id, transaction_state)
+    }
+
+    async fn read_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionState>, PaymentEngineError> {
+        self.inner.read_transaction_state(transaction_id)
+    }
+
+    async fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError> {
+        self.inner.read_client_transactions(client_id)
+    }
+}
@@ -1,14 +1,22 @@
 use crate::{
-    common_types::TransactionId,
+    common_types::{CustomerId, TransactionId},
+    errors::PaymentEngineError,
     transaction_request::{TransactionRequest, TransactionState},
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
 
 use super::transaction_history_provider::TransactionHistoryProvider;
 
+#[derive(Default, Serialize, Deserialize)]
 pub struct InMemoryTransactionHistoryProvider {
     history: HashMap<TransactionId, TransactionRequest>,
     state: HashMap<TransactionId, TransactionState>,
+    // Secondary index for looking up the transactions of a client without scanning the whole history
+    client_transactions: HashMap<CustomerId, Vec<TransactionId>>,
 }
 
 impl InMemoryTransactionHistoryProvider {
@@ -16,30 +24,61 @@ impl InMemoryTransactionHistoryProvider {
         InMemoryTransactionHistoryProvider {
             history: HashMap::new(),
             state: HashMap::new(),
+            client_transactions: HashMap::new(),
         }
     }
+
+    // Writes the transactions and their states as JSON, to be read back with load_snapshot
+    pub fn save_snapshot(&self, writer: impl Write) -> Result<(), PaymentEngineError> {
+        serde_json::to_writer(writer, self).map_err(|e| PaymentEngineError::Storage(e.to_string()))
+    }
+
+    pub fn load_snapshot(reader: impl Read) -> Result<Self, PaymentEngineError> {
+        serde_json::from_reader(reader).map_err(|e| PaymentEngineError::Storage(e.to_string()))
+    }
 }
 
 impl TransactionHistoryProvider for InMemoryTransactionHistoryProvider {
-    fn write_transaction(&mut self, transaction_request: TransactionRequest) -> Result<(), String> {
-        // Maybe we can add transaction_id check here, to make sure no overrides happen
-        self.history
-            .insert(transaction_request.transaction_id, transaction_request);
+    fn write_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError> {
+        // The manager checks for the duplicates before executing, so overwriting a transaction would be a bug in the caller
+        let transaction_id = transaction_request.transaction_id;
+        let client_id = transaction_request.client_id;
+        if self.history.contains_key(&transaction_id) {
+            return Err(PaymentEngineError::DuplicateTransaction(transaction_id));
+        }
+        self.history.insert(transaction_id, transaction_request);
+        self.client_transactions
+            .entry(client_id)
+            .or_default()
+            .push(transaction_id);
         Ok(())
     }
 
     fn read_transaction(
         &mut self,
         transaction_id: TransactionId,
-    ) -> Result<Option<&TransactionRequest>, String> {
-        Ok(self.history.get(&transaction_id))
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError> {
+        Ok(self.history.get(&transaction_id).cloned())
+    }
+
+    fn filter_new_ids(
+        &mut self,
+        transaction_ids: &[TransactionId],
+    ) -> Result<Vec<bool>, PaymentEngineError> {
+        Ok(transaction_ids
+            .iter()
+            .map(|transaction_id| !self.history.contains_key(transaction_id))
+            .collect())
     }
 
     fn write_transaction_state(
         &mut self,
         transaction_id: TransactionId,
         transaction_state: TransactionState,
-    ) -> Result<(), String> {
+    ) -> Result<(), PaymentEngineError> {
         self.state.insert(transaction_id, transaction_state);
         Ok(())
     }
@@ -47,8 +86,49 @@ impl TransactionHistoryProvider for InMemoryTransactionHistoryProvider {
     fn read_transaction_state(
         &mut self,
         transaction_id: TransactionId,
-    ) -> Result<Option<&TransactionState>, String> {
-        Ok(self.state.get(&transaction_id))
+    ) -> Result<Option<TransactionState>, PaymentEngineError> {
+        Ok(self.state.get(&transaction_id).cloned())
+    }
+
+    fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError> {
+        Ok(self
+            .client_transactions
+            .get(&client_id)
+            .map(|transaction_ids| {
+                transaction_ids
+                    .iter()
+                    .filter_map(|transaction_id| self.history.get(transaction_id).cloned())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    // Going through the client index, so the transactions of each client keep their order
+    fn iter_transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>> + '_> {
+        let mut client_ids: Vec<&CustomerId> = self.client_transactions.keys().collect();
+        client_ids.sort_unstable();
+        Box::new(
+            client_ids
+                .into_iter()
+                .flat_map(|client_id| &self.client_transactions[client_id])
+                .filter_map(|transaction_id| self.history.get(transaction_id).cloned())
+                .map(Ok),
+        )
+    }
+
+    fn iter_states(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<(TransactionId, TransactionState), PaymentEngineError>> + '_> {
+        Box::new(
+            self.state
+                .iter()
+                .map(|(transaction_id, state)| Ok((*transaction_id, state.clone()))),
+        )
     }
 }
 
@@ -70,6 +150,8 @@ mod in_memory_transaction_history_provider_tests {
             client_id,
             transaction_id,
             amount: Some(amount),
+            timestamp: None,
+            currency: None,
         };
         assert!(transaction_history_provider
             .write_transaction(transaction_request.clone())
@@ -90,16 +172,55 @@ mod in_memory_transaction_history_provider_tests {
             client_id,
             transaction_id,
             amount: Some(amount),
+            timestamp: None,
+            currency: None,
         };
         assert!(transaction_history_provider
             .write_transaction(transaction_request.clone())
             .is_ok());
         assert_eq!(
             transaction_history_provider.read_transaction(transaction_id),
-            Ok(Some(&transaction_request))
+            Ok(Some(transaction_request))
         );
     }
 
+    #[test]
+    fn snapshot_restores_the_transactions_and_their_states() {
+        let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
+        for transaction_id in [3, 1, 2] {
+            transaction_history_provider
+                .write_transaction(TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id: 1,
+                    transaction_id,
+                    amount: Some(Decimal::new(12345, 5)),
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        transaction_history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    dispute_count: 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut snapshot = vec![];
+        transaction_history_provider
+            .save_snapshot(&mut snapshot)
+            .unwrap();
+        let restored_provider =
+            InMemoryTransactionHistoryProvider::load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored_provider.history, transaction_history_provider.history);
+        assert_eq!(restored_provider.state, transaction_history_provider.state);
+        // The transactions of a client keep their order
+        assert_eq!(restored_provider.client_transactions[&1], vec![3, 1, 2]);
+    }
+
     #[test]
     fn write_transaction_state_works_as_expected() {
         let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
@@ -107,6 +228,9 @@ mod in_memory_transaction_history_provider_tests {
         let transaction_state = TransactionState {
             held: true,
             charged_back: false,
+            dispute_count: 1,
+            resolved: false,
+            sequence: None,
         };
         assert!(transaction_history_provider
             .write_transaction_state(transaction_id, transaction_state.clone())
@@ -124,13 +248,100 @@ mod in_memory_transaction_history_provider_tests {
         let transaction_state = TransactionState {
             held: true,
             charged_back: false,
+            dispute_count: 1,
+            resolved: false,
+            sequence: None,
         };
         assert!(transaction_history_provider
             .write_transaction_state(transaction_id, transaction_state.clone())
             .is_ok());
         assert_eq!(
             transaction_history_provider.read_transaction_state(transaction_id),
-            Ok(Some(&transaction_state))
+            Ok(Some(transaction_state))
+        );
+    }
+
+    #[test]
+    fn read_client_transactions_follows_the_writes() {
+        let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
+        let requests: Vec<TransactionRequest> = [(1, 1), (2, 2), (1, 3)]
+            .into_iter()
+            .map(|(client_id, transaction_id)| TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .collect();
+        for request in requests.iter() {
+            transaction_history_provider
+                .write_transaction(request.clone())
+                .unwrap();
+        }
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(1),
+            Ok(vec![requests[0].clone(), requests[2].clone()])
+        );
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(3),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn write_transaction_refuses_to_overwrite() {
+        let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
+        let mut request = TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(Decimal::new(10, 0)),
+            timestamp: None,
+            currency: None,
+        };
+        transaction_history_provider
+            .write_transaction(request.clone())
+            .unwrap();
+        assert_eq!(
+            transaction_history_provider.write_transaction(request.clone()),
+            Err(PaymentEngineError::DuplicateTransaction(1))
+        );
+        let stored = request.clone();
+        request.client_id = 2;
+        assert_eq!(
+            transaction_history_provider.write_transaction(request),
+            Err(PaymentEngineError::DuplicateTransaction(1))
+        );
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(1),
+            Ok(vec![stored])
+        );
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(2),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn list_disputed_transactions_returns_the_held_ones() {
+        let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
+        for (transaction_id, held) in [(7, true), (2, false), (3, true)] {
+            transaction_history_provider
+                .write_transaction_state(
+                    transaction_id,
+                    TransactionState {
+                        held,
+                        resolved: !held,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            transaction_history_provider.list_disputed_transactions(),
+            Ok(vec![3, 7])
         );
     }
 }
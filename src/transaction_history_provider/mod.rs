@@ -1,4 +1,7 @@
-
+#[allow(clippy::module_inception)]
 pub mod transaction_history_provider;
+#[cfg(feature = "async")]
+pub mod async_transaction_history_provider;
 pub mod in_memory_transaction_history_provider;
 pub mod sled_transaction_history_provider;
+pub mod tiered_transaction_history_provider;
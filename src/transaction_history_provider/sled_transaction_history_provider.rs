@@ -1,20 +1,25 @@
-/**
- * This is the future history provider that will be able to handle u32::MAX number of records without running out of memory.
- * It's not yet ready. This can be part of V2, but currently not planning to implement. The InMemoryTransactionHistoryProvider is the one used currently.
- */
+//! History provider keeping the transactions on disk, so it can handle u32::MAX number of records without running out of memory.
+//! Slower than the InMemoryTransactionHistoryProvider, because every access goes through serialization.
+
+use std::path::Path;
 
 use sled::{Db, Tree};
-use tempfile::NamedTempFile;
 
 use crate::{
-    common_types::TransactionId,
+    common_types::{CustomerId, TransactionId},
+    errors::PaymentEngineError,
     transaction_request::{TransactionRequest, TransactionState},
 };
 
 use super::transaction_history_provider::TransactionHistoryProvider;
 
 pub struct SledTransactionHistoryProvider {
-    tree: Tree,
+    db: Db,
+    history: Tree,
+    state: Tree,
+    // Keyed by the client ID followed by a write sequence number, so a prefix scan returns the transactions in the
+    // order they were written
+    client_transactions: Tree,
 }
 
 fn err_to_string(e: impl ToString) -> String {
@@ -22,60 +27,292 @@ fn err_to_string(e: impl ToString) -> String {
 }
 
 impl SledTransactionHistoryProvider {
-    pub fn new() -> Result<Self, String> {
-        let file = NamedTempFile::new().map_err(err_to_string)?;
-        let db = sled::open(file.path()).map_err(err_to_string)?;
-        let tree = db.open_tree("a").map_err(err_to_string)?;
-        Ok(SledTransactionHistoryProvider { tree })
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, PaymentEngineError> {
+        let db = sled::open(path).map_err(err_to_string)?;
+        Self::from_db(db)
+    }
+
+    // Removed from the disk once the provider is dropped
+    pub fn new_temporary() -> Result<Self, PaymentEngineError> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(err_to_string)?;
+        Self::from_db(db)
+    }
+
+    fn from_db(db: Db) -> Result<Self, PaymentEngineError> {
+        Ok(SledTransactionHistoryProvider {
+            history: db.open_tree("history").map_err(err_to_string)?,
+            state: db.open_tree("state").map_err(err_to_string)?,
+            client_transactions: db.open_tree("client_transactions").map_err(err_to_string)?,
+            db,
+        })
     }
+
+    fn remove_from_client_index(
+        &self,
+        client_id: CustomerId,
+        transaction_id: TransactionId,
+    ) -> Result<(), PaymentEngineError> {
+        for entry in self
+            .client_transactions
+            .scan_prefix(client_id.to_be_bytes())
+        {
+            let (key, value) = entry.map_err(err_to_string)?;
+            if value.as_ref() == transaction_id.to_be_bytes() {
+                self.client_transactions
+                    .remove(key)
+                    .map_err(err_to_string)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_transaction_id(bytes: &[u8]) -> Result<TransactionId, String> {
+    Ok(TransactionId::from_be_bytes(bytes.try_into().map_err(
+        |_| format!("Invalid transaction ID {:?}", bytes),
+    )?))
 }
+
 impl TransactionHistoryProvider for SledTransactionHistoryProvider {
-    fn write_transaction<'a>(
-        &'a mut self,
+    fn write_transaction(
+        &mut self,
         transaction_request: TransactionRequest,
-    ) -> Result<(), String> {
-        // // Expensive operations, can be improved with zerocopy
-        // let serialized: String = serde_json::to_string(&transaction_request).unwrap();
-        // self.tree
-        //     .insert(
-        //         transaction_request.transaction_id.to_be_bytes(),
-        //         serialized.as_bytes(),
-        //     )
-        //     .map_err(err_to_string)?;
-        todo!()
-    }
-
-    fn read_transaction<'a>(
-        &'a mut self,
+    ) -> Result<(), PaymentEngineError> {
+        let transaction_id = transaction_request.transaction_id;
+        let client_id = transaction_request.client_id;
+        // Expensive operations, can be improved with zerocopy
+        let serialized = serde_json::to_vec(&transaction_request).map_err(err_to_string)?;
+        if let Some(overridden) = self
+            .history
+            .insert(transaction_id.to_be_bytes(), serialized)
+            .map_err(err_to_string)?
+        {
+            let overridden: TransactionRequest =
+                serde_json::from_slice(&overridden).map_err(err_to_string)?;
+            if overridden.client_id == client_id {
+                return Ok(());
+            }
+            self.remove_from_client_index(overridden.client_id, transaction_id)?;
+        }
+        let sequence = self.db.generate_id().map_err(err_to_string)?;
+        let mut key = client_id.to_be_bytes().to_vec();
+        key.extend_from_slice(&sequence.to_be_bytes());
+        self.client_transactions
+            .insert(key, &transaction_id.to_be_bytes())
+            .map_err(err_to_string)?;
+        Ok(())
+    }
+
+    fn read_transaction(
+        &mut self,
         transaction_id: TransactionId,
-    ) -> Result<Option<&'a TransactionRequest>, String> {
-        // if let Some(val) = self
-        //     .tree
-        //     .get(transaction_id.to_be_bytes())
-        //     .map_err(err_to_string)?
-        // {
-        //     return Ok(Some(
-        //         serde_json::from_str(
-        //             &String::from_utf8_lossy(val.as_ref()).map_err(err_to_string)?,
-        //         )
-        //         .map_err(err_to_string)?,
-        //     ));
-        // }
-        todo!()
-    }
-
-    fn write_transaction_state<'a>(
-        &'a mut self,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError> {
+        self.history
+            .get(transaction_id.to_be_bytes())
+            .map_err(err_to_string)?
+            .map(|value| serde_json::from_slice(&value).map_err(|e| err_to_string(e).into()))
+            .transpose()
+    }
+
+    // Without deserializing the stored transactions
+    fn filter_new_ids(
+        &mut self,
+        transaction_ids: &[TransactionId],
+    ) -> Result<Vec<bool>, PaymentEngineError> {
+        transaction_ids
+            .iter()
+            .map(|transaction_id| {
+                Ok(!self
+                    .history
+                    .contains_key(transaction_id.to_be_bytes())
+                    .map_err(err_to_string)?)
+            })
+            .collect()
+    }
+
+    fn write_transaction_state(
+        &mut self,
         transaction_id: TransactionId,
         transaction_state: TransactionState,
-    ) -> Result<(), String> {
-        todo!()
+    ) -> Result<(), PaymentEngineError> {
+        let serialized = serde_json::to_vec(&transaction_state).map_err(err_to_string)?;
+        self.state
+            .insert(transaction_id.to_be_bytes(), serialized)
+            .map_err(err_to_string)?;
+        Ok(())
     }
 
-    fn read_transaction_state<'a>(
-        &'a mut self,
+    fn read_transaction_state(
+        &mut self,
         transaction_id: TransactionId,
-    ) -> Result<Option<&'a TransactionState>, String> {
-        todo!()
+    ) -> Result<Option<TransactionState>, PaymentEngineError> {
+        self.state
+            .get(transaction_id.to_be_bytes())
+            .map_err(err_to_string)?
+            .map(|value| serde_json::from_slice(&value).map_err(|e| err_to_string(e).into()))
+            .transpose()
+    }
+
+    fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError> {
+        let mut transactions = Vec::new();
+        for entry in self
+            .client_transactions
+            .scan_prefix(client_id.to_be_bytes())
+        {
+            let (_, value) = entry.map_err(err_to_string)?;
+            let transaction_id = TransactionId::from_be_bytes(
+                value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| err_to_string("Invalid transaction ID in the client index"))?,
+            );
+            if let Some(transaction) = self.read_transaction(transaction_id)? {
+                transactions.push(transaction);
+            }
+        }
+        Ok(transactions)
+    }
+
+    // The client index is sorted by client and write sequence, so it gives the transactions in the write order
+    fn iter_transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>> + '_> {
+        Box::new(
+            self.client_transactions
+                .iter()
+                .values()
+                .filter_map(|value| {
+                    let transaction = value.map_err(err_to_string).and_then(|value| {
+                        self.history
+                            .get(decode_transaction_id(&value)?.to_be_bytes())
+                            .map_err(err_to_string)
+                    });
+                    match transaction {
+                        Ok(serialized) => serialized.map(|serialized| {
+                            serde_json::from_slice(&serialized).map_err(|e| err_to_string(e).into())
+                        }),
+                        Err(e) => Some(Err(e.into())),
+                    }
+                }),
+        )
+    }
+
+    fn iter_states(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<(TransactionId, TransactionState), PaymentEngineError>> + '_>
+    {
+        Box::new(self.state.iter().map(|entry| {
+            let (key, value) = entry.map_err(err_to_string)?;
+            let state = serde_json::from_slice(&value).map_err(err_to_string)?;
+            Ok((decode_transaction_id(&key)?, state))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod sled_transaction_history_provider_tests {
+    use rust_decimal::Decimal;
+
+    use crate::transaction_request::TransactionType;
+
+    use super::*;
+
+    fn transaction(client_id: CustomerId, transaction_id: TransactionId) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(Decimal::new(12345, 4)),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn transactions_and_states_round_trip() {
+        let mut transaction_history_provider =
+            SledTransactionHistoryProvider::new_temporary().unwrap();
+        transaction_history_provider
+            .write_transaction(transaction(1, 1))
+            .unwrap();
+        let state = TransactionState {
+            held: true,
+            ..Default::default()
+        };
+        transaction_history_provider
+            .write_transaction_state(1, state.clone())
+            .unwrap();
+        assert_eq!(
+            transaction_history_provider.read_transaction(1),
+            Ok(Some(transaction(1, 1)))
+        );
+        assert_eq!(
+            transaction_history_provider.read_transaction_state(1),
+            Ok(Some(state))
+        );
+        assert_eq!(transaction_history_provider.read_transaction(2), Ok(None));
+    }
+
+    #[test]
+    fn client_transactions_are_in_write_order() {
+        let mut transaction_history_provider =
+            SledTransactionHistoryProvider::new_temporary().unwrap();
+        for (client_id, transaction_id) in [(1, 5), (2, 1), (1, 3), (1, 4)] {
+            transaction_history_provider
+                .write_transaction(transaction(client_id, transaction_id))
+                .unwrap();
+        }
+        // Overriding with another client moves the transaction out of the old client's index
+        transaction_history_provider
+            .write_transaction(transaction(2, 3))
+            .unwrap();
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(1),
+            Ok(vec![transaction(1, 5), transaction(1, 4)])
+        );
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(2),
+            Ok(vec![transaction(2, 1), transaction(2, 3)])
+        );
+    }
+
+    #[test]
+    fn filter_new_ids_sees_the_batched_writes() {
+        let mut transaction_history_provider =
+            SledTransactionHistoryProvider::new_temporary().unwrap();
+        transaction_history_provider
+            .write_transaction(transaction(1, 5))
+            .unwrap();
+        transaction_history_provider
+            .write_transactions_batch(vec![
+                transaction(2, 1),
+                transaction(1, 3),
+                transaction(2, 5),
+                transaction(1, 4),
+                transaction(2, 3),
+            ])
+            .unwrap();
+        assert_eq!(
+            transaction_history_provider.filter_new_ids(&[1, 2, 3, 4, 5]),
+            Ok(vec![false, true, false, false, false])
+        );
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(1),
+            Ok(vec![transaction(1, 4)])
+        );
+        assert_eq!(
+            transaction_history_provider.read_client_transactions(2),
+            Ok(vec![
+                transaction(2, 1),
+                transaction(2, 5),
+                transaction(2, 3)
+            ])
+        );
     }
 }
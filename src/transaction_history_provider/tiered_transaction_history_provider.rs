@@ -0,0 +1,380 @@
+//! History provider keeping the most recent transactions in memory and spilling the older ones to sled, so the memory
+//! stays bounded on long runs while the disputes of the recent transactions stay fast.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::{
+    common_types::{CustomerId, TransactionId},
+    errors::PaymentEngineError,
+    transaction_request::{TransactionRequest, TransactionState},
+};
+
+use super::{
+    sled_transaction_history_provider::SledTransactionHistoryProvider,
+    transaction_history_provider::TransactionHistoryProvider,
+};
+
+pub struct TieredTransactionHistoryProvider {
+    // Number of transactions kept in memory, the oldest one is spilled once it's exceeded
+    hot_capacity: usize,
+    hot_history: HashMap<TransactionId, TransactionRequest>,
+    // Only the states of the hot transactions, the rest live on disk
+    hot_state: HashMap<TransactionId, TransactionState>,
+    // Hot transactions in the write order, the front is the next one to spill
+    write_order: VecDeque<TransactionId>,
+    // Hot transactions of each client in the write order, all of them newer than the spilled ones of the client
+    hot_client_transactions: BTreeMap<CustomerId, VecDeque<TransactionId>>,
+    cold: SledTransactionHistoryProvider,
+    // Skips the disk lookups on writes until something was spilled
+    spilled: bool,
+}
+
+impl TieredTransactionHistoryProvider {
+    pub fn new(hot_capacity: usize, cold: SledTransactionHistoryProvider) -> Self {
+        TieredTransactionHistoryProvider {
+            hot_capacity: hot_capacity.max(1),
+            hot_history: HashMap::new(),
+            hot_state: HashMap::new(),
+            write_order: VecDeque::new(),
+            hot_client_transactions: BTreeMap::new(),
+            cold,
+            spilled: false,
+        }
+    }
+
+    // Spilled into a temporary sled tree, removed from the disk once the provider is dropped
+    pub fn new_temporary(hot_capacity: usize) -> Result<Self, PaymentEngineError> {
+        Ok(Self::new(
+            hot_capacity,
+            SledTransactionHistoryProvider::new_temporary()?,
+        ))
+    }
+
+    fn remove_from_hot_index(&mut self, client_id: CustomerId, transaction_id: TransactionId) {
+        if let Some(transaction_ids) = self.hot_client_transactions.get_mut(&client_id) {
+            // The spilled transaction is the oldest hot one of its client, unless its client was changed by a rewrite
+            if transaction_ids.front() == Some(&transaction_id) {
+                transaction_ids.pop_front();
+            } else {
+                transaction_ids.retain(|id| *id != transaction_id);
+            }
+            if transaction_ids.is_empty() {
+                self.hot_client_transactions.remove(&client_id);
+            }
+        }
+    }
+
+    // Moves the oldest hot transactions to the disk along with their states
+    fn spill_over_capacity(&mut self) -> Result<(), PaymentEngineError> {
+        while self.write_order.len() > self.hot_capacity {
+            let Some(transaction_id) = self.write_order.pop_front() else {
+                break;
+            };
+            let Some(transaction) = self.hot_history.remove(&transaction_id) else {
+                continue;
+            };
+            self.remove_from_hot_index(transaction.client_id, transaction_id);
+            self.cold.write_transaction(transaction)?;
+            if let Some(state) = self.hot_state.remove(&transaction_id) {
+                self.cold.write_transaction_state(transaction_id, state)?;
+            }
+            self.spilled = true;
+        }
+        Ok(())
+    }
+}
+
+impl TransactionHistoryProvider for TieredTransactionHistoryProvider {
+    fn write_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError> {
+        let transaction_id = transaction_request.transaction_id;
+        let client_id = transaction_request.client_id;
+        // The manager never rewrites an ID, but if something does, the spilled copy is rewritten in place so there's
+        // a single copy of every transaction
+        if !self.hot_history.contains_key(&transaction_id)
+            && self.spilled
+            && self.cold.read_transaction(transaction_id)?.is_some()
+        {
+            return self.cold.write_transaction(transaction_request);
+        }
+        if let Some(overridden) = self.hot_history.insert(transaction_id, transaction_request) {
+            if overridden.client_id == client_id {
+                return Ok(());
+            }
+            // Becomes the newest transaction of the new client, so it has to be spilled after the older ones of it
+            self.remove_from_hot_index(overridden.client_id, transaction_id);
+            self.write_order.retain(|id| *id != transaction_id);
+        }
+        self.write_order.push_back(transaction_id);
+        self.hot_client_transactions
+            .entry(client_id)
+            .or_default()
+            .push_back(transaction_id);
+        self.spill_over_capacity()
+    }
+
+    fn read_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError> {
+        match self.hot_history.get(&transaction_id) {
+            Some(transaction) => Ok(Some(transaction.clone())),
+            None => self.cold.read_transaction(transaction_id),
+        }
+    }
+
+    // Kept next to the transaction, so they're spilled together
+    fn write_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction_state: TransactionState,
+    ) -> Result<(), PaymentEngineError> {
+        if self.hot_history.contains_key(&transaction_id) {
+            self.hot_state.insert(transaction_id, transaction_state);
+            Ok(())
+        } else {
+            self.cold
+                .write_transaction_state(transaction_id, transaction_state)
+        }
+    }
+
+    fn read_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionState>, PaymentEngineError> {
+        match self.hot_state.get(&transaction_id) {
+            Some(state) => Ok(Some(state.clone())),
+            // A state written before its transaction stays on disk, so the hot transactions can have theirs there too
+            None => self.cold.read_transaction_state(transaction_id),
+        }
+    }
+
+    fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError> {
+        let mut transactions = self.cold.read_client_transactions(client_id)?;
+        if let Some(transaction_ids) = self.hot_client_transactions.get(&client_id) {
+            transactions.extend(
+                transaction_ids
+                    .iter()
+                    .map(|transaction_id| self.hot_history[transaction_id].clone()),
+            );
+        }
+        Ok(transactions)
+    }
+
+    // Merges the spilled transactions, sorted by client, with the hot ones of the same client coming after them
+    fn iter_transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>> + '_> {
+        let mut cold = self.cold.iter_transactions().peekable();
+        let mut hot = self.hot_client_transactions.iter().peekable();
+        let mut pending_hot = VecDeque::new().into_iter();
+        Box::new(std::iter::from_fn(move || loop {
+            if let Some(transaction_id) = pending_hot.next() {
+                return Some(Ok(self.hot_history[&transaction_id].clone()));
+            }
+            let next_hot_client = hot.peek().map(|(client_id, _)| **client_id);
+            match cold.peek() {
+                Some(Ok(transaction))
+                    if next_hot_client
+                        .is_none_or(|client_id| transaction.client_id <= client_id) =>
+                {
+                    return cold.next()
+                }
+                Some(Err(_)) => return cold.next(),
+                _ => {}
+            }
+            let (_, transaction_ids) = hot.next()?;
+            pending_hot = transaction_ids.clone().into_iter();
+        }))
+    }
+
+    fn iter_states(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<(TransactionId, TransactionState), PaymentEngineError>> + '_>
+    {
+        Box::new(
+            self.hot_state
+                .iter()
+                .map(|(transaction_id, state)| Ok((*transaction_id, state.clone())))
+                .chain(self.cold.iter_states().filter(|entry| {
+                    // Shadowed by the newer hot state
+                    !matches!(entry, Ok((transaction_id, _)) if self.hot_state.contains_key(transaction_id))
+                })),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tiered_transaction_history_provider_tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        customer_account_provider::InMemoryCustomerAccountProvider,
+        transaction_history_provider::in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        transaction_request::TransactionType,
+        transactions_manager::{DefaultTransactionsManager, TransactionsManager},
+    };
+
+    use super::*;
+
+    fn transaction(client_id: CustomerId, transaction_id: TransactionId) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(Decimal::new(transaction_id as i64, 1)),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    // Pseudo-random sequence, deterministic so the failures can be reproduced
+    fn generator(seed: u64) -> impl FnMut(u64) -> u64 {
+        let mut seed = seed;
+        move |bound: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        }
+    }
+
+    fn sorted_states(
+        transaction_history_provider: &dyn TransactionHistoryProvider,
+    ) -> Vec<(TransactionId, TransactionState)> {
+        let mut states: Vec<_> = transaction_history_provider
+            .iter_states()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        states.sort_unstable_by_key(|(transaction_id, _)| *transaction_id);
+        states
+    }
+
+    #[test]
+    fn reads_match_the_in_memory_provider() {
+        let mut next = generator(7);
+        let mut in_memory = InMemoryTransactionHistoryProvider::new();
+        let mut tiered = TieredTransactionHistoryProvider::new_temporary(16).unwrap();
+        for step in 1..=2000u32 {
+            let transaction_id = next(step as u64) as TransactionId + 1;
+            match next(10) {
+                0..=3 => {
+                    let transaction = transaction(next(8) as CustomerId, step);
+                    assert_eq!(
+                        in_memory.write_transaction(transaction.clone()),
+                        tiered.write_transaction(transaction)
+                    );
+                }
+                4..=5 => {
+                    let state = TransactionState {
+                        held: next(2) == 0,
+                        dispute_count: next(3) as u32,
+                        ..Default::default()
+                    };
+                    assert_eq!(
+                        in_memory.write_transaction_state(transaction_id, state.clone()),
+                        tiered.write_transaction_state(transaction_id, state)
+                    );
+                }
+                6 => {
+                    let client_id = next(8) as CustomerId;
+                    assert_eq!(
+                        in_memory.read_client_transactions(client_id),
+                        tiered.read_client_transactions(client_id)
+                    );
+                }
+                _ => {
+                    assert_eq!(
+                        in_memory.read_transaction(transaction_id),
+                        tiered.read_transaction(transaction_id)
+                    );
+                    assert_eq!(
+                        in_memory.read_transaction_state(transaction_id),
+                        tiered.read_transaction_state(transaction_id)
+                    );
+                }
+            }
+        }
+        assert!(tiered.hot_history.len() <= 16);
+        assert_eq!(
+            in_memory.iter_transactions().collect::<Vec<_>>(),
+            tiered.iter_transactions().collect::<Vec<_>>()
+        );
+        assert_eq!(sorted_states(&in_memory), sorted_states(&tiered));
+    }
+
+    #[test]
+    fn manager_outcomes_and_report_match_the_in_memory_provider() {
+        let mut next = generator(11);
+        let mut in_memory_manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let mut tiered_manager = DefaultTransactionsManager::new(
+            TieredTransactionHistoryProvider::new_temporary(32).unwrap(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        for transaction_id in 1..=3000u32 {
+            // Half of the requests dispute, resolve or charge back a random earlier transaction
+            let referenced_id = next(transaction_id as u64) as TransactionId + 1;
+            let client_id = next(20) as CustomerId;
+            let amount = Some(Decimal::new(next(10_000) as i64 + 1, 2));
+            let (transaction_type, transaction_id, amount) = match next(10) {
+                0..=3 => (TransactionType::Deposit, transaction_id, amount),
+                4 => (TransactionType::Withdrawal, transaction_id, amount),
+                5..=6 => (TransactionType::Dispute, referenced_id, None),
+                7..=8 => (TransactionType::Resolve, referenced_id, None),
+                _ => (TransactionType::Chargeback, referenced_id, None),
+            };
+            let request = TransactionRequest {
+                transaction_type,
+                client_id,
+                transaction_id,
+                amount,
+                timestamp: None,
+                currency: None,
+            };
+            assert_eq!(
+                in_memory_manager.handle_transaction(request.clone()),
+                tiered_manager.handle_transaction(request)
+            );
+        }
+        let mut in_memory_report = vec![];
+        in_memory_manager
+            .write_report(&mut in_memory_report)
+            .unwrap();
+        let mut tiered_report = vec![];
+        tiered_manager.write_report(&mut tiered_report).unwrap();
+        assert_eq!(
+            String::from_utf8(in_memory_report).unwrap(),
+            String::from_utf8(tiered_report).unwrap()
+        );
+    }
+
+    #[test]
+    fn spilled_transactions_keep_their_state() {
+        let mut tiered = TieredTransactionHistoryProvider::new_temporary(2).unwrap();
+        tiered.write_transaction(transaction(1, 1)).unwrap();
+        let state = TransactionState {
+            held: true,
+            dispute_count: 1,
+            ..Default::default()
+        };
+        tiered.write_transaction_state(1, state.clone()).unwrap();
+        tiered.write_transaction(transaction(1, 2)).unwrap();
+        tiered.write_transaction(transaction(2, 3)).unwrap();
+        assert!(!tiered.hot_history.contains_key(&1));
+        assert!(tiered.hot_state.is_empty());
+        assert_eq!(tiered.read_transaction(1), Ok(Some(transaction(1, 1))));
+        assert_eq!(tiered.read_transaction_state(1), Ok(Some(state)));
+        assert_eq!(
+            tiered.read_client_transactions(1),
+            Ok(vec![transaction(1, 1), transaction(1, 2)])
+        );
+    }
+}
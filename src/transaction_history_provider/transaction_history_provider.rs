@@ -2,35 +2,71 @@ use mockall::predicate::*;
 use mockall::*;
 
 use crate::{
-    common_types::TransactionId,
+    common_types::{CustomerId, TransactionId},
+    errors::PaymentEngineError,
     transaction_request::{TransactionRequest, TransactionState},
 };
 
 /**
  * This trait is supposed to abstract all history providers and many of them will contain network calls or storage reads.
  * So we can expect that in some cases this will include failures that are not related to the transaction/state existance or consistency. 
- * Hence we need to allow the future instances to use these Results. We can also add different types of Errors.
+ * Hence we need to allow the future instances to use these Results, reporting such failures as PaymentEngineError::Storage.
  * 
- * automock -> Doesn't support non-static references in the results, hence won't easily work with mocking the results.
+ * The read methods return owned values, so that providers backed by storage or network can hand out the data they just deserialized.
  */
 #[automock]
 pub trait TransactionHistoryProvider {
-    fn write_transaction<'a>(
-        &'a mut self,
-        transaction_request: TransactionRequest,
-    ) -> Result<(), String>;
-    fn read_transaction<'a>(
-        &'a mut self,
+    fn write_transaction(&mut self, transaction_request: TransactionRequest) -> Result<(), PaymentEngineError>;
+    // Writes the transactions in their order, the providers that can commit many of them at once should override it
+    fn write_transactions_batch(&mut self, transaction_requests: Vec<TransactionRequest>) -> Result<(), PaymentEngineError> {
+        for transaction_request in transaction_requests {
+            self.write_transaction(transaction_request)?;
+        }
+        Ok(())
+    }
+    fn read_transaction(
+        &mut self,
         transaction_id: TransactionId,
-    ) -> Result<Option<&'a TransactionRequest>, String>;
-    fn write_transaction_state<'a>(
-        &'a mut self,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError>;
+    // Whether each of the IDs is missing from the history, in the order of the IDs. Overridden by the providers that
+    // can check it without reading the whole transaction
+    fn filter_new_ids(&mut self, transaction_ids: &[TransactionId]) -> Result<Vec<bool>, PaymentEngineError> {
+        transaction_ids
+            .iter()
+            .map(|transaction_id| Ok(self.read_transaction(*transaction_id)?.is_none()))
+            .collect()
+    }
+    fn write_transaction_state(
+        &mut self,
         transaction_id: TransactionId,
         transaction_state: TransactionState,
-    ) -> Result<(), String>;
-    fn read_transaction_state<'a>(
-        &'a mut self,
+    ) -> Result<(), PaymentEngineError>;
+    fn read_transaction_state(
+        &mut self,
         transaction_id: TransactionId,
-    ) -> Result<Option<&'a TransactionState>, String>;
+    ) -> Result<Option<TransactionState>, PaymentEngineError>;
+    // All the transactions of the client, in the order they were written
+    fn read_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+    ) -> Result<Vec<TransactionRequest>, PaymentEngineError>;
+    // Every stored transaction, the ones of the same client in the order they were written. Used for the snapshots
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>> + 'a>;
+    fn iter_states<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(TransactionId, TransactionState), PaymentEngineError>> + 'a>;
+    // IDs of the transactions currently on hold, in ascending order
+    fn list_disputed_transactions(&self) -> Result<Vec<TransactionId>, PaymentEngineError> {
+        let mut transaction_ids = vec![];
+        for entry in self.iter_states() {
+            let (transaction_id, state) = entry?;
+            if state.held {
+                transaction_ids.push(transaction_id);
+            }
+        }
+        transaction_ids.sort_unstable();
+        Ok(transaction_ids)
+    }
 }
-
@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::PaymentEngineError, transaction_request::TransactionType};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SkipReason {
+    // Retry of an already executed transaction, identical to it
+    DuplicateTransaction,
+    // The ID of an already executed transaction reused for a request with different content
+    ConflictingDuplicate,
+    // The ID of another client's transaction reused by this client, a possible fraud attempt
+    CrossClientIdReuse,
+    AccountLocked,
+    AccountNotFound,
+    InsufficientFunds,
+    TransactionNotFound,
+    ClientMismatch,
+    // The dispute-family request names a different currency than the disputed transaction
+    CurrencyMismatch,
+    // The transaction is already on hold or was charged back
+    AlreadyDisputed,
+    // The transaction was resolved before and disputing it again is not allowed
+    RedisputeForbidden,
+    // The transaction was disputed as many times as the manager allows
+    DisputeLimitReached,
+    // Resolving or charging back a transaction that is not on hold
+    NotDisputed,
+    // Only deposits and withdrawals can be disputed
+    NotDisputable,
+    // The client executed more transactions since the disputed one than the dispute window allows
+    DisputeWindowExpired,
+    // Applying the transaction would go beyond the Decimal limits
+    Overflow,
+    // Unlock requests are not allowed by the manager
+    UnlockForbidden,
+    // Unlocking an account that is not locked
+    NotLocked,
+    // Unlocking an account with transactions still on hold in any currency
+    OpenDisputes,
+    // The request failed the structure validation, e.g. a deposit without amount
+    InvalidStructure,
+    // The amount of the deposit or withdrawal is above the max transaction amount of the limits
+    TransactionLimitExceeded,
+    // The deposit would bring the account total above the max account total of the limits
+    AccountTotalLimitExceeded,
+    // Parked until the referenced transaction arrives, counted again with the final outcome once it's handled
+    AwaitingTransaction,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            SkipReason::DuplicateTransaction => "duplicate transaction",
+            SkipReason::ConflictingDuplicate => "conflicting duplicate",
+            SkipReason::CrossClientIdReuse => "transaction id of another client",
+            SkipReason::AccountLocked => "account locked",
+            SkipReason::AccountNotFound => "account not found",
+            SkipReason::InsufficientFunds => "insufficient funds",
+            SkipReason::TransactionNotFound => "transaction not found",
+            SkipReason::ClientMismatch => "client mismatch",
+            SkipReason::CurrencyMismatch => "currency mismatch",
+            SkipReason::AlreadyDisputed => "already disputed",
+            SkipReason::RedisputeForbidden => "redispute forbidden",
+            SkipReason::DisputeLimitReached => "dispute limit reached",
+            SkipReason::NotDisputed => "not disputed",
+            SkipReason::NotDisputable => "not disputable",
+            SkipReason::DisputeWindowExpired => "dispute window expired",
+            SkipReason::Overflow => "overflow",
+            SkipReason::UnlockForbidden => "unlock forbidden",
+            SkipReason::NotLocked => "not locked",
+            SkipReason::OpenDisputes => "open disputes",
+            SkipReason::InvalidStructure => "invalid structure",
+            SkipReason::TransactionLimitExceeded => "transaction limit exceeded",
+            SkipReason::AccountTotalLimitExceeded => "account total limit exceeded",
+            SkipReason::AwaitingTransaction => "awaiting the referenced transaction",
+        };
+        f.write_str(reason)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    Executed,
+    Skipped(SkipReason),
+}
+
+impl TransactionOutcome {
+    pub fn is_executed(&self) -> bool {
+        *self == TransactionOutcome::Executed
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcessingSummary {
+    pub executed: BTreeMap<TransactionType, usize>,
+    pub skipped: BTreeMap<TransactionType, usize>,
+    pub skip_reasons: BTreeMap<SkipReason, usize>,
+    // Requests that failed with a non-fatal error
+    pub failed: BTreeMap<TransactionType, usize>,
+    // Accounts locked by the fraud rules
+    pub fraud_holds: usize,
+    // Requests reusing the transaction ID of another client, also counted in skip_reasons
+    pub cross_client_id_reuse: usize,
+    // Requests still parked for a transaction that didn't arrive, with the out-of-order tolerance
+    pub unresolved_references: usize,
+    // Parked requests evicted to make room for newer ones, they're never handled
+    pub evicted_references: usize,
+    // Requests read from each of the input files, when several of them are chained
+    pub rows_per_input: BTreeMap<String, usize>,
+}
+
+impl ProcessingSummary {
+    pub fn record(
+        &mut self,
+        transaction_type: &TransactionType,
+        result: &Result<TransactionOutcome, PaymentEngineError>,
+    ) {
+        let counter = match result {
+            Ok(TransactionOutcome::Executed) => &mut self.executed,
+            Ok(TransactionOutcome::Skipped(reason)) => {
+                if *reason == SkipReason::CrossClientIdReuse {
+                    self.cross_client_id_reuse += 1;
+                }
+                *self.skip_reasons.entry(*reason).or_default() += 1;
+                &mut self.skipped
+            }
+            Err(_) => &mut self.failed,
+        };
+        *counter.entry(transaction_type.clone()).or_default() += 1;
+    }
+
+    // Adds the counters of another summary, e.g. of a shard processing a part of the same input
+    pub fn merge(&mut self, other: &ProcessingSummary) {
+        for (counters, other_counters) in [
+            (&mut self.executed, &other.executed),
+            (&mut self.skipped, &other.skipped),
+            (&mut self.failed, &other.failed),
+        ] {
+            for (transaction_type, count) in other_counters {
+                *counters.entry(transaction_type.clone()).or_default() += count;
+            }
+        }
+        for (reason, count) in &other.skip_reasons {
+            *self.skip_reasons.entry(*reason).or_default() += count;
+        }
+        self.fraud_holds += other.fraud_holds;
+        self.cross_client_id_reuse += other.cross_client_id_reuse;
+        self.unresolved_references += other.unresolved_references;
+        self.evicted_references += other.evicted_references;
+        for (input, rows) in &other.rows_per_input {
+            *self.rows_per_input.entry(input.clone()).or_default() += rows;
+        }
+    }
+
+    pub fn executed(&self, transaction_type: &TransactionType) -> usize {
+        self.executed.get(transaction_type).copied().unwrap_or(0)
+    }
+
+    pub fn skipped(&self, transaction_type: &TransactionType) -> usize {
+        self.skipped.get(transaction_type).copied().unwrap_or(0)
+    }
+
+    pub fn skipped_because(&self, reason: SkipReason) -> usize {
+        self.skip_reasons.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn failed(&self, transaction_type: &TransactionType) -> usize {
+        self.failed.get(transaction_type).copied().unwrap_or(0)
+    }
+}
+
+impl Display for ProcessingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "executed {:?}, skipped {:?}",
+            self.executed, self.skipped
+        )?;
+        write!(f, ", skip reasons {:?}", self.skip_reasons)?;
+        write!(f, ", failed {:?}", self.failed)?;
+        write!(f, ", fraud holds {}", self.fraud_holds)?;
+        write!(f, ", cross client ID reuse {}", self.cross_client_id_reuse)?;
+        write!(f, ", unresolved references {}", self.unresolved_references)?;
+        write!(f, ", evicted references {}", self.evicted_references)?;
+        write!(f, ", rows per input {:?}", self.rows_per_input)
+    }
+}
+
+#[cfg(test)]
+mod processing_summary_tests {
+    use super::*;
+
+    #[test]
+    fn record_counts_per_type_and_reason() {
+        let mut summary = ProcessingSummary::default();
+        summary.record(&TransactionType::Deposit, &Ok(TransactionOutcome::Executed));
+        summary.record(&TransactionType::Deposit, &Ok(TransactionOutcome::Executed));
+        summary.record(
+            &TransactionType::Withdrawal,
+            &Ok(TransactionOutcome::Skipped(SkipReason::InsufficientFunds)),
+        );
+        summary.record(
+            &TransactionType::Dispute,
+            &Err(PaymentEngineError::AmountMissing(1)),
+        );
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped(&TransactionType::Deposit), 0);
+        assert_eq!(summary.skipped(&TransactionType::Withdrawal), 1);
+        assert_eq!(summary.skipped_because(SkipReason::InsufficientFunds), 1);
+        assert_eq!(summary.failed(&TransactionType::Dispute), 1);
+    }
+
+    #[test]
+    fn merge_adds_the_counters() {
+        let mut summary = ProcessingSummary::default();
+        summary.record(&TransactionType::Deposit, &Ok(TransactionOutcome::Executed));
+        let mut other = ProcessingSummary::default();
+        other.record(&TransactionType::Deposit, &Ok(TransactionOutcome::Executed));
+        other.record(
+            &TransactionType::Withdrawal,
+            &Ok(TransactionOutcome::Skipped(SkipReason::InsufficientFunds)),
+        );
+        summary.merge(&other);
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped(&TransactionType::Withdrawal), 1);
+        assert_eq!(summary.skipped_because(SkipReason::InsufficientFunds), 1);
+    }
+}
@@ -1,9 +1,11 @@
+use std::fmt::{self, Display};
+
 use rust_decimal::Decimal;
 use serde::{Serialize, Deserialize};
 
-use crate::common_types::{CustomerId, TransactionId};
+use crate::common_types::{Currency, CustomerId, TransactionId};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum TransactionType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -14,7 +16,25 @@ pub enum TransactionType {
     #[serde(rename = "resolve")]
     Resolve,
     #[serde(rename = "chargeback")]
-    Chargeback
+    Chargeback,
+    // Lifts the lock of the client's account, only handled when the manager allows it
+    #[serde(rename = "unlock")]
+    Unlock
+}
+
+// The same names as in the input
+impl Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Unlock => "unlock",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -25,11 +45,30 @@ pub struct TransactionRequest {
     pub client_id: CustomerId,
     #[serde(rename = "tx")]
     pub transaction_id: TransactionId,
-    pub amount: Option<Decimal>
+    pub amount: Option<Decimal>,
+    // Unix time in milliseconds, the older inputs don't have this column
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    // The default currency of the manager is used when missing, as the older inputs don't have this column
+    #[serde(default)]
+    pub currency: Option<Currency>
+}
+
+impl TransactionRequest {
+    pub fn currency_or<'a>(&'a self, default_currency: &'a str) -> &'a str {
+        self.currency.as_deref().unwrap_or(default_currency)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct TransactionState {
     pub held: bool,
     pub charged_back: bool,
+    // Number of times the transaction was put on hold
+    pub dispute_count: u32,
+    // Set once the transaction was resolved, even if it's disputed again later
+    pub resolved: bool,
+    // Position of the transaction among the executed transactions of its client, only recorded when a dispute window is set
+    #[serde(default)]
+    pub sequence: Option<u64>,
 }
\ No newline at end of file
@@ -1,81 +1,730 @@
-use csv::ReaderBuilder;
-use log::info;
-use rust_decimal::Decimal;
+use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read},
+    rc::Rc,
+    str::{self, FromStr},
+};
 
-use crate::transaction_request::{TransactionRequest, TransactionType};
+use csv::{ByteRecord, ReaderBuilder};
+use flate2::read::MultiGzDecoder;
+use log::{info, warn};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    common_types::CustomerId,
+    errors::PaymentEngineError,
+    transaction_request::{TransactionRequest, TransactionType},
+};
 
 pub trait TransactionRequestsReader {
-    fn read(self: &Self) -> Box<dyn Iterator<Item = TransactionRequest>>;
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>>;
+    // Same as read, but yields the malformed rows as PaymentEngineError::MalformedRow instead of skipping them. They
+    // are left to the caller, so they're not counted in skipped_rows
+    fn read_fallible(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> {
+        Box::new(self.read().map(Ok))
+    }
+    // Number of malformed rows dropped by the last read, readers that can't fail don't need to override this
+    fn skipped_rows(&self) -> usize {
+        0
+    }
+    // Number of requests the last read yielded from each of the input files, for the readers chaining several of them
+    fn rows_per_input(&self) -> Vec<(String, usize)> {
+        vec![]
+    }
+}
+
+// How the CSV rows are turned into requests, both give the same requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CsvParsing {
+    // Parses the fields straight from the raw bytes of the row, without the Strings serde allocates for them. Falls
+    // back to serde for the headers it doesn't recognize, e.g. without the type, client or tx column.
+    #[default]
+    Fast,
+    Serde,
 }
 
 pub struct DefaultTransactionRequestsReader {
     path: String,
     enforced_scale: u32,
+    rounding_strategy: RoundingStrategy,
+    // None detects it from the header line
+    delimiter: Option<u8>,
+    parsing: CsvParsing,
+    skipped_rows: Rc<Cell<usize>>,
 }
 
 impl DefaultTransactionRequestsReader {
+    // Truncating the amounts to 4 decimal places by default
     pub fn new(path: &str) -> DefaultTransactionRequestsReader {
+        Self::new_with_scale(path, 4, RoundingStrategy::ToZero)
+    }
+
+    pub fn new_with_scale(
+        path: &str,
+        enforced_scale: u32,
+        rounding_strategy: RoundingStrategy,
+    ) -> DefaultTransactionRequestsReader {
         DefaultTransactionRequestsReader {
             path: path.to_owned(),
-            enforced_scale: 4,
+            enforced_scale,
+            rounding_strategy,
+            delimiter: None,
+            parsing: CsvParsing::default(),
+            skipped_rows: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+
+    pub fn with_parsing(mut self, parsing: CsvParsing) -> Self {
+        self.parsing = parsing;
+        self
+    }
+
+    // Decompresses the gzipped files while reading them, recognized by their magic bytes rather than the extension
+    fn open(&self) -> io::Result<Box<dyn Read>> {
+        let mut file = BufReader::new(File::open(&self.path)?);
+        if file.fill_buf()?.starts_with(&GZIP_MAGIC_BYTES) {
+            return Ok(Box::new(MultiGzDecoder::new(file)));
         }
+        Ok(Box::new(file))
     }
 }
 
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
 impl TransactionRequestsReader for DefaultTransactionRequestsReader {
-    fn read(self: &Self) -> Box<dyn Iterator<Item = TransactionRequest>> {
-        let path = self.path.clone();
-        let enforced_scale = self.enforced_scale;
-        return Box::new(
-            ReaderBuilder::new()
-                .has_headers(true)
-                .delimiter(b',')
-                .trim(csv::Trim::All)
-                .from_path(path.clone())
-                .expect(&format!("Failed opening the file {}", path))
-                .into_deserialize::<TransactionRequest>()
-                .map(|record| record.expect("Failed extracting records"))
-                .map(move |record| {
-                    if let Some(mut amount) = record.amount {
-                        if amount.scale() > enforced_scale {
-                            info!("Scaling down the decimal - {}", amount);
-                            amount
-                                .set_scale(amount.scale() - enforced_scale)
-                                .expect("Couldn't change the amount scale.");
-                            let mut new_amount = amount.trunc();
-                            new_amount
-                                .set_scale(enforced_scale)
-                                .expect("Couldn't change the new amount scale.");
-                            return TransactionRequest {
-                                amount: Some(new_amount),
-                                ..record
-                            };
-                        }
-                    }
-                    record
-                }),
-        );
-    }
-}
-
-pub struct DummyReader;
-
-// For stress testing
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>> {
+        let file = self
+            .open()
+            .unwrap_or_else(|_| panic!("Failed opening the file {}", self.path));
+        self.skipped_rows.set(0);
+        read_parsed_transaction_requests(
+            file,
+            self.delimiter,
+            self.parsing,
+            self.enforced_scale,
+            self.rounding_strategy,
+            self.skipped_rows.clone(),
+        )
+    }
+
+    fn read_fallible(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> {
+        match self.open() {
+            Ok(file) => read_fallible_transaction_requests(
+                file,
+                self.delimiter,
+                self.parsing,
+                self.enforced_scale,
+                self.rounding_strategy,
+            ),
+            Err(e) => Box::new(std::iter::once(Err(PaymentEngineError::Storage(format!(
+                "Failed opening the file {}: {}",
+                self.path, e
+            ))))),
+        }
+    }
+
+    fn skipped_rows(&self) -> usize {
+        self.skipped_rows.get()
+    }
+}
+
+// Reads the requests piped into the process, e.g. `cat tx.csv | payment_engine`
+pub struct StdinTransactionRequestsReader {
+    enforced_scale: u32,
+    rounding_strategy: RoundingStrategy,
+    // None detects it from the header line
+    delimiter: Option<u8>,
+    skipped_rows: Rc<Cell<usize>>,
+}
+
+impl StdinTransactionRequestsReader {
+    pub fn new() -> StdinTransactionRequestsReader {
+        Self::new_with_scale(4, RoundingStrategy::ToZero)
+    }
+
+    pub fn new_with_scale(
+        enforced_scale: u32,
+        rounding_strategy: RoundingStrategy,
+    ) -> StdinTransactionRequestsReader {
+        StdinTransactionRequestsReader {
+            enforced_scale,
+            rounding_strategy,
+            delimiter: None,
+            skipped_rows: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+}
+
+impl Default for StdinTransactionRequestsReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransactionRequestsReader for StdinTransactionRequestsReader {
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>> {
+        self.skipped_rows.set(0);
+        read_delimited_transaction_requests(
+            io::stdin(),
+            self.delimiter,
+            self.enforced_scale,
+            self.rounding_strategy,
+            self.skipped_rows.clone(),
+        )
+    }
+
+    fn read_fallible(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> {
+        read_fallible_transaction_requests(
+            io::stdin(),
+            self.delimiter,
+            CsvParsing::default(),
+            self.enforced_scale,
+            self.rounding_strategy,
+        )
+    }
+
+    fn skipped_rows(&self) -> usize {
+        self.skipped_rows.get()
+    }
+}
+
+// Shared by the CSV based readers, so that the trimming, header handling and scale enforcement stay the same for any source
+pub fn read_transaction_requests(
+    source: impl Read + 'static,
+    enforced_scale: u32,
+    rounding_strategy: RoundingStrategy,
+    skipped_rows: Rc<Cell<usize>>,
+) -> Box<dyn Iterator<Item = TransactionRequest>> {
+    read_delimited_transaction_requests(
+        source,
+        None,
+        enforced_scale,
+        rounding_strategy,
+        skipped_rows,
+    )
+}
+
+// Same as read_transaction_requests with the given delimiter, detected from the header line when None
+pub fn read_delimited_transaction_requests(
+    source: impl Read + 'static,
+    delimiter: Option<u8>,
+    enforced_scale: u32,
+    rounding_strategy: RoundingStrategy,
+    skipped_rows: Rc<Cell<usize>>,
+) -> Box<dyn Iterator<Item = TransactionRequest>> {
+    read_parsed_transaction_requests(
+        source,
+        delimiter,
+        CsvParsing::default(),
+        enforced_scale,
+        rounding_strategy,
+        skipped_rows,
+    )
+}
+
+// Same as read_delimited_transaction_requests with the given way of parsing the rows
+pub fn read_parsed_transaction_requests(
+    source: impl Read + 'static,
+    delimiter: Option<u8>,
+    parsing: CsvParsing,
+    enforced_scale: u32,
+    rounding_strategy: RoundingStrategy,
+    skipped_rows: Rc<Cell<usize>>,
+) -> Box<dyn Iterator<Item = TransactionRequest>> {
+    Box::new(
+        read_fallible_transaction_requests(
+            source,
+            delimiter,
+            parsing,
+            enforced_scale,
+            rounding_strategy,
+        )
+        // A single malformed row shouldn't stop the whole batch, so logging and skipping it
+        .filter_map(move |record| match record {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("{}, skipping it", e);
+                skipped_rows.set(skipped_rows.get() + 1);
+                None
+            }
+        }),
+    )
+}
+
+// Same as read_parsed_transaction_requests, yielding the malformed rows as errors instead of skipping them
+pub fn read_fallible_transaction_requests(
+    source: impl Read + 'static,
+    delimiter: Option<u8>,
+    parsing: CsvParsing,
+    enforced_scale: u32,
+    rounding_strategy: RoundingStrategy,
+) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> {
+    let (delimiter, source): (u8, Box<dyn Read>) = match delimiter {
+        Some(delimiter) => (delimiter, Box::new(source)),
+        None => {
+            let mut source = BufReader::new(source);
+            let header = read_header_line(&mut source);
+            // The consumed header goes back in front of the rest, the CSV reader still needs it
+            (
+                detect_delimiter(&header),
+                Box::new(Cursor::new(header).chain(source)),
+            )
+        }
+    };
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .delimiter(delimiter)
+        .trim(csv::Trim::All)
+        // The dispute-family rows may omit the trailing empty amount, the missing fields deserialize to None
+        .flexible(true)
+        .from_reader(source);
+    let columns = match parsing {
+        CsvParsing::Fast => reader.byte_headers().ok().and_then(CsvColumns::of),
+        CsvParsing::Serde => None,
+    };
+    let records: Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> =
+        match columns {
+            Some(columns) => Box::new(reader.into_byte_records().map(move |record| {
+                let record = record.map_err(malformed_row)?;
+                columns
+                    .parse(&record)
+                    .map_err(|reason| PaymentEngineError::MalformedRow {
+                        line: record.position().map(|position| position.line()),
+                        reason,
+                    })
+            })),
+            None => Box::new(
+                reader
+                    .into_deserialize::<TransactionRequest>()
+                    .map(|record| record.map_err(malformed_row)),
+            ),
+        };
+    Box::new(records.map(move |record| {
+        record.map(|record| match record.amount {
+            Some(amount) if amount.scale() > enforced_scale => {
+                info!("Scaling down the decimal - {}", amount);
+                TransactionRequest {
+                    amount: Some(amount.round_dp_with_strategy(enforced_scale, rounding_strategy)),
+                    ..record
+                }
+            }
+            _ => record,
+        })
+    }))
+}
+
+fn malformed_row(e: csv::Error) -> PaymentEngineError {
+    PaymentEngineError::MalformedRow {
+        line: e.position().map(|position| position.line()),
+        reason: e.to_string(),
+    }
+}
+
+// Positions of the known columns in the header, for parsing the rows without serde
+#[derive(Clone, Copy)]
+struct CsvColumns {
+    transaction_type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    currency: Option<usize>,
+}
+
+impl CsvColumns {
+    // None when a required column is missing or any known one is repeated, serde reports those rows as it did before
+    fn of(headers: &ByteRecord) -> Option<Self> {
+        let mut positions: [Option<usize>; 6] = [None; 6];
+        for (position, header) in headers.iter().enumerate() {
+            let column = match header {
+                b"type" => 0,
+                b"client" => 1,
+                b"tx" => 2,
+                b"amount" => 3,
+                b"timestamp" => 4,
+                b"currency" => 5,
+                _ => continue,
+            };
+            if positions[column].replace(position).is_some() {
+                return None;
+            }
+        }
+        Some(CsvColumns {
+            transaction_type: positions[0]?,
+            client: positions[1]?,
+            tx: positions[2]?,
+            amount: positions[3],
+            timestamp: positions[4],
+            currency: positions[5],
+        })
+    }
+
+    // Mirrors what serde accepts, only the amounts are parsed exactly instead of going through f64
+    fn parse(&self, record: &ByteRecord) -> Result<TransactionRequest, String> {
+        // Like the String records serde reads, a row with invalid UTF-8 anywhere is malformed
+        str::from_utf8(record.as_slice()).map_err(|e| e.to_string())?;
+        let required = |position: usize, name: &str| {
+            record
+                .get(position)
+                .ok_or_else(|| format!("missing field `{}`", name))
+        };
+        // Missing and empty fields are both None
+        let optional = |position: Option<usize>| {
+            position
+                .and_then(|position| record.get(position))
+                .filter(|field| !field.is_empty())
+        };
+        let transaction_type = match required(self.transaction_type, "type")? {
+            b"deposit" => TransactionType::Deposit,
+            b"withdrawal" => TransactionType::Withdrawal,
+            b"dispute" => TransactionType::Dispute,
+            b"resolve" => TransactionType::Resolve,
+            b"chargeback" => TransactionType::Chargeback,
+            b"unlock" => TransactionType::Unlock,
+            other => {
+                return Err(format!(
+                    "unknown variant `{}`",
+                    String::from_utf8_lossy(other)
+                ))
+            }
+        };
+        Ok(TransactionRequest {
+            transaction_type,
+            client_id: parse_field(required(self.client, "client")?)?,
+            transaction_id: parse_field(required(self.tx, "tx")?)?,
+            amount: optional(self.amount).map(parse_amount).transpose()?,
+            timestamp: optional(self.timestamp).map(parse_field).transpose()?,
+            currency: optional(self.currency)
+                .map(|field| field_str(field).map(str::to_owned))
+                .transpose()?,
+        })
+    }
+}
+
+fn field_str(field: &[u8]) -> Result<&str, String> {
+    str::from_utf8(field).map_err(|e| e.to_string())
+}
+
+fn parse_field<T: FromStr>(field: &[u8]) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let field = field_str(field)?;
+    field
+        .parse()
+        .map_err(|e| format!("invalid value {}: {}", field, e))
+}
+
+// Accepts the same amounts as serde, which reads the ones that look like floats through f64, but keeps all their
+// digits. Normalized like the f64 round trip does, so "20.0" is still reported as 20.
+fn parse_amount(field: &[u8]) -> Result<Decimal, String> {
+    let field = field_str(field)?;
+    let invalid = || format!("invalid amount {}", field);
+    let amount = Decimal::from_str(field)
+        .or_else(|_| Decimal::from_scientific(field))
+        .or_else(|_| {
+            // The rare forms only f64 takes, e.g. "1." or ".5e1"
+            let value: f64 = field.parse().map_err(|_| invalid())?;
+            Decimal::from_str(&value.to_string()).map_err(|_| invalid())
+        })?;
+    Ok(amount.normalize())
+}
+
+// The bytes up to the end of the first non-blank line, the ones of the blank lines before it included
+fn read_header_line(source: &mut impl BufRead) -> Vec<u8> {
+    let mut consumed = Vec::new();
+    loop {
+        let line_start = consumed.len();
+        // A read error shows up again once the CSV reader gets to the same bytes, so it's not handled here
+        match source.read_until(b'\n', &mut consumed) {
+            Ok(0) | Err(_) => return consumed,
+            Ok(_) if consumed[line_start..].trim_ascii().is_empty() => continue,
+            Ok(_) => return consumed,
+        }
+    }
+}
+
+// Semicolon when the header has more of them than commas, comma otherwise
+fn detect_delimiter(header: &[u8]) -> u8 {
+    let count = |delimiter: u8| header.iter().filter(|byte| **byte == delimiter).count();
+    if count(b';') > count(b',') {
+        b';'
+    } else {
+        b','
+    }
+}
+
+// Replays the requests of the inner reader up to the timestamp, so processing them gives the accounts as they were at
+// that time. The requests without a timestamp are kept, as there's no telling when they happened.
+pub struct AsOfReader {
+    inner: Box<dyn TransactionRequestsReader>,
+    timestamp: i64,
+}
+
+impl AsOfReader {
+    pub fn new(inner: Box<dyn TransactionRequestsReader>, timestamp: i64) -> AsOfReader {
+        AsOfReader { inner, timestamp }
+    }
+}
+
+impl TransactionRequestsReader for AsOfReader {
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>> {
+        let timestamp = self.timestamp;
+        Box::new(
+            self.inner
+                .read()
+                .filter(move |request| request.timestamp.is_none_or(|t| t <= timestamp)),
+        )
+    }
+
+    fn read_fallible(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> {
+        let timestamp = self.timestamp;
+        Box::new(self.inner.read_fallible().filter(move |request| {
+            request.as_ref().map_or(true, |request| {
+                request.timestamp.is_none_or(|t| t <= timestamp)
+            })
+        }))
+    }
+
+    fn skipped_rows(&self) -> usize {
+        self.inner.skipped_rows()
+    }
+
+    fn rows_per_input(&self) -> Vec<(String, usize)> {
+        self.inner.rows_per_input()
+    }
+}
+
+// Reads the inputs one after the other, e.g. the daily exports of a month, opening each of them only once the
+// previous one is read to the end
+pub struct ChainedTransactionRequestsReader {
+    // The inputs with their names, in the order they're read
+    readers: Rc<[(String, Box<dyn TransactionRequestsReader>)]>,
+    rows: Rc<RefCell<Vec<usize>>>,
+}
+
+impl ChainedTransactionRequestsReader {
+    // Reads the inputs in the given order
+    pub fn new(
+        readers: Vec<(String, Box<dyn TransactionRequestsReader>)>,
+    ) -> ChainedTransactionRequestsReader {
+        ChainedTransactionRequestsReader {
+            rows: Rc::new(RefCell::new(vec![0; readers.len()])),
+            readers: readers.into(),
+        }
+    }
+
+    // Reads the CSV files in the lexicographic order of their paths, so the runs are reproducible whatever order the
+    // paths come in
+    pub fn new_with_paths(
+        paths: &[String],
+        enforced_scale: u32,
+        rounding_strategy: RoundingStrategy,
+    ) -> ChainedTransactionRequestsReader {
+        let mut paths = paths.to_vec();
+        paths.sort_unstable();
+        Self::new(
+            paths
+                .into_iter()
+                .map(|path| {
+                    let reader: Box<dyn TransactionRequestsReader> =
+                        Box::new(DefaultTransactionRequestsReader::new_with_scale(
+                            &path,
+                            enforced_scale,
+                            rounding_strategy,
+                        ));
+                    (path, reader)
+                })
+                .collect(),
+        )
+    }
+
+    // The items of all the readers, each of them counted for its input
+    fn chain<T: 'static>(
+        &self,
+        read: fn(&dyn TransactionRequestsReader) -> Box<dyn Iterator<Item = T>>,
+    ) -> Box<dyn Iterator<Item = T>> {
+        self.rows.borrow_mut().fill(0);
+        let readers = self.readers.clone();
+        let rows = self.rows.clone();
+        Box::new((0..readers.len()).flat_map(move |index| {
+            let rows = rows.clone();
+            read(readers[index].1.as_ref()).inspect(move |_| rows.borrow_mut()[index] += 1)
+        }))
+    }
+}
+
+impl TransactionRequestsReader for ChainedTransactionRequestsReader {
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>> {
+        self.chain(|reader| reader.read())
+    }
+
+    // The malformed rows are counted for their input along with the requests
+    fn read_fallible(
+        &self,
+    ) -> Box<dyn Iterator<Item = Result<TransactionRequest, PaymentEngineError>>> {
+        self.chain(|reader| reader.read_fallible())
+    }
+
+    fn skipped_rows(&self) -> usize {
+        self.readers
+            .iter()
+            .map(|(_, reader)| reader.skipped_rows())
+            .sum()
+    }
+
+    fn rows_per_input(&self) -> Vec<(String, usize)> {
+        self.readers
+            .iter()
+            .map(|(name, _)| name.clone())
+            .zip(self.rows.borrow().iter().copied())
+            .collect()
+    }
+}
+
+// For stress testing, deposits `count` times to the same client
+pub struct DummyReader {
+    count: u32,
+}
+
+impl DummyReader {
+    pub fn new(count: u32) -> DummyReader {
+        DummyReader { count }
+    }
+}
+
 impl TransactionRequestsReader for DummyReader {
-    fn read(self: &Self) -> Box<dyn Iterator<Item = TransactionRequest>> {
-        return Box::new((1..=1000000).map(|i| TransactionRequest {
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>> {
+        Box::new((1..=self.count).map(|i| TransactionRequest {
             transaction_type: TransactionType::Deposit,
             client_id: 1,
             transaction_id: i,
             amount: Some(Decimal::new(10, 0)),
-        }));
+            timestamp: None,
+            currency: None,
+        }))
+    }
+}
+
+// Deterministic pseudo-random workload for the benchmarks, the same seed generates the same requests on any machine.
+// Deposits spread over the clients, with the given percentages of withdrawals and of disputes and resolves of the
+// earlier requests.
+pub struct SyntheticReader {
+    count: u32,
+    clients: u16,
+    seed: u64,
+    dispute_percent: u64,
+    withdrawal_percent: u64,
+}
+
+impl SyntheticReader {
+    pub fn new(count: u32, clients: u16, seed: u64) -> SyntheticReader {
+        SyntheticReader {
+            count,
+            clients: clients.max(1),
+            seed,
+            dispute_percent: 0,
+            withdrawal_percent: 0,
+        }
+    }
+
+    // Half of them are disputes, half resolves, both referencing a random earlier request of the same client
+    pub fn with_dispute_percent(mut self, dispute_percent: u64) -> Self {
+        self.dispute_percent = dispute_percent.min(100);
+        self.withdrawal_percent = self.withdrawal_percent.min(100 - self.dispute_percent);
+        self
+    }
+
+    // Withdrawals of smaller amounts than the deposits, so most of them are executed like in a real workload. The
+    // disputes take precedence when the percentages add up to more than 100.
+    pub fn with_withdrawal_percent(mut self, withdrawal_percent: u64) -> Self {
+        self.withdrawal_percent = withdrawal_percent.min(100 - self.dispute_percent);
+        self
+    }
+}
+
+// SplitMix64, good enough for spreading the requests and much cheaper than a proper generator
+fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9E3779B97F4A7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+    value ^ (value >> 31)
+}
+
+impl TransactionRequestsReader for SyntheticReader {
+    fn read(&self) -> Box<dyn Iterator<Item = TransactionRequest>> {
+        let (clients, seed, dispute_percent) = (self.clients, self.seed, self.dispute_percent);
+        let withdrawal_percent = self.withdrawal_percent;
+        // The client is derived from the transaction ID, so the disputes can find it without keeping the requests
+        let client_of = move |transaction_id: u32| {
+            (mix(seed ^ transaction_id as u64) % clients as u64) as CustomerId
+        };
+        Box::new((1..=self.count).map(move |i| {
+            let random = mix(seed.wrapping_add(i as u64));
+            let bucket = random % 100;
+            if i > 1 && bucket < dispute_percent {
+                let referenced_id = ((random >> 8) % (i as u64 - 1)) as u32 + 1;
+                return TransactionRequest {
+                    transaction_type: if (random >> 40) & 1 == 0 {
+                        TransactionType::Dispute
+                    } else {
+                        TransactionType::Resolve
+                    },
+                    client_id: client_of(referenced_id),
+                    transaction_id: referenced_id,
+                    amount: None,
+                    timestamp: None,
+                    currency: None,
+                };
+            }
+            if bucket >= dispute_percent && bucket < dispute_percent + withdrawal_percent {
+                return TransactionRequest {
+                    transaction_type: TransactionType::Withdrawal,
+                    client_id: client_of(i),
+                    transaction_id: i,
+                    amount: Some(Decimal::new(((random >> 16) % 10_000) as i64 + 1, 2)),
+                    timestamp: None,
+                    currency: None,
+                };
+            }
+            TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: client_of(i),
+                transaction_id: i,
+                amount: Some(Decimal::new(((random >> 16) % 100_000) as i64 + 1, 2)),
+                timestamp: None,
+                currency: None,
+            }
+        }))
     }
 }
 
 #[cfg(test)]
 mod default_transaction_requests_reader {
-    use crate::transaction_request::TransactionType;
-    use std::io::Write;
+    use crate::{
+        common_types::TransactionId, transaction_request::TransactionType,
+        transactions_manager::DEFAULT_SCALE,
+    };
+    use std::io::{Cursor, Write};
 
     use super::*;
 
@@ -103,37 +752,49 @@ mod default_transaction_requests_reader {
                     transaction_type: TransactionType::Deposit,
                     client_id: 1,
                     transaction_id: 1,
-                    amount: Some(Decimal::new(102, 1))
+                    amount: Some(Decimal::new(102, 1)),
+                    timestamp: None,
+                    currency: None,
                 },
                 TransactionRequest {
                     transaction_type: TransactionType::Withdrawal,
                     client_id: 1,
                     transaction_id: 2,
-                    amount: Some(Decimal::new(103, 1))
+                    amount: Some(Decimal::new(103, 1)),
+                    timestamp: None,
+                    currency: None,
                 },
                 TransactionRequest {
                     transaction_type: TransactionType::Dispute,
                     client_id: 1,
                     transaction_id: 1,
-                    amount: None
+                    amount: None,
+                    timestamp: None,
+                    currency: None,
                 },
                 TransactionRequest {
                     transaction_type: TransactionType::Resolve,
                     client_id: 1,
                     transaction_id: 1,
-                    amount: None
+                    amount: None,
+                    timestamp: None,
+                    currency: None,
                 },
                 TransactionRequest {
                     transaction_type: TransactionType::Dispute,
                     client_id: 1,
                     transaction_id: 1,
-                    amount: None
+                    amount: None,
+                    timestamp: None,
+                    currency: None,
                 },
                 TransactionRequest {
                     transaction_type: TransactionType::Chargeback,
                     client_id: 1,
                     transaction_id: 1,
-                    amount: None
+                    amount: None,
+                    timestamp: None,
+                    currency: None,
                 }
             ]
         );
@@ -155,16 +816,721 @@ mod default_transaction_requests_reader {
                 transaction_type: TransactionType::Deposit,
                 client_id: 1,
                 transaction_id: 1,
-                amount: Some(Decimal::new(102345, 4))
+                amount: Some(Decimal::new(102345, 4)),
+                timestamp: None,
+                currency: None,
             }]
         );
         path.close().unwrap();
     }
 
+    #[test]
+    fn gzipped_files_read_like_the_plain_ones() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let content = "type,client,tx,amount
+deposit,1,1,10.23456
+withdrawal,1,two,1.0
+withdrawal,1,2,1.5
+";
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let gzipped_path = directory.path().join("transactions.csv.gz");
+        std::fs::write(&gzipped_path, encoder.finish().unwrap()).unwrap();
+        let plain_path = save_to_temp_file(content);
+
+        let gzipped_reader = DefaultTransactionRequestsReader::new(gzipped_path.to_str().unwrap());
+        let plain_reader = DefaultTransactionRequestsReader::new(plain_path.to_str().unwrap());
+        let records: Vec<TransactionRequest> = gzipped_reader.read().collect();
+        assert_eq!(records, plain_reader.read().collect::<Vec<_>>());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].amount, Some(Decimal::new(102345, 4)));
+        assert_eq!(gzipped_reader.skipped_rows(), 1);
+        assert_eq!(
+            gzipped_reader.read_fallible().collect::<Vec<_>>(),
+            plain_reader.read_fallible().collect::<Vec<_>>()
+        );
+        plain_path.close().unwrap();
+    }
+
+    #[test]
+    fn chained_files_are_read_in_the_order_of_their_paths() {
+        let directory = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            let path = directory.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path.to_str().unwrap().to_owned()
+        };
+        let paths = [
+            write(
+                "transactions-2024-01-03.csv",
+                "type,client,tx,amount\ndeposit,2,4,1.0\n",
+            ),
+            write("transactions-2024-01-02.csv", ""),
+            write(
+                "transactions-2024-01-01.csv",
+                "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,x\ndeposit,1,3,2.0\n",
+            ),
+        ];
+        let reader =
+            ChainedTransactionRequestsReader::new_with_paths(&paths, 4, RoundingStrategy::ToZero);
+        let transaction_ids: Vec<TransactionId> = reader
+            .read()
+            .map(|request| request.transaction_id)
+            .collect();
+        assert_eq!(transaction_ids, vec![1, 3, 4]);
+        assert_eq!(reader.skipped_rows(), 1);
+        assert_eq!(
+            reader.rows_per_input(),
+            vec![
+                (paths[2].clone(), 2),
+                (paths[1].clone(), 0),
+                (paths[0].clone(), 1)
+            ]
+        );
+        // The malformed row is counted for its input when yielded
+        assert_eq!(reader.read_fallible().count(), 4);
+        assert_eq!(reader.rows_per_input()[0].1, 3);
+    }
+
+    #[test]
+    fn read_truncates_tiny_amounts_to_zero() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 0.00009";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader =
+            DefaultTransactionRequestsReader::new(path.to_str().unwrap());
+        let records: Vec<TransactionRequest> = transaction_requests_reader.read().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].amount.unwrap().to_string(), "0.0000");
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_handles_huge_scales_and_negative_amounts() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 1.00000000000000000009
+        withdrawal, 1, 2, -3.99999";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader =
+            DefaultTransactionRequestsReader::new(path.to_str().unwrap());
+        let amounts: Vec<Decimal> = transaction_requests_reader
+            .read()
+            .map(|record| record.amount.unwrap())
+            .collect();
+        assert_eq!(amounts, vec![Decimal::ONE, Decimal::new(-39999, 4)]);
+        assert!(amounts.iter().all(|amount| amount.scale() <= 4));
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_uses_the_configured_scale_and_rounding_strategy() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 10.23456
+        deposit, 1, 2, 10.2";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader = DefaultTransactionRequestsReader::new_with_scale(
+            path.to_str().unwrap(),
+            2,
+            RoundingStrategy::MidpointAwayFromZero,
+        );
+        let amounts: Vec<String> = transaction_requests_reader
+            .read()
+            .map(|record| record.amount.unwrap().to_string())
+            .collect();
+        assert_eq!(amounts, vec!["10.23", "10.2"]);
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_truncates_to_the_default_scale() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 10.23456
+        deposit, 1, 2, 10.2";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader = DefaultTransactionRequestsReader::new_with_scale(
+            path.to_str().unwrap(),
+            DEFAULT_SCALE,
+            RoundingStrategy::ToZero,
+        );
+        let amounts: Vec<String> = transaction_requests_reader
+            .read()
+            .map(|record| record.amount.unwrap().to_string())
+            .collect();
+        assert_eq!(amounts, vec!["10.2345", "10.2"]);
+        assert_eq!(
+            transaction_requests_reader.read().collect::<Vec<_>>(),
+            DefaultTransactionRequestsReader::new(path.to_str().unwrap())
+                .read()
+                .collect::<Vec<_>>()
+        );
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_rounds_the_midpoints_with_each_strategy() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 10.23455
+        deposit, 1, 2, 10.23465";
+        let path = save_to_temp_file(content);
+        for (rounding_strategy, expected) in [
+            (RoundingStrategy::ToZero, ["10.2345", "10.2346"]),
+            (
+                RoundingStrategy::MidpointAwayFromZero,
+                ["10.2346", "10.2347"],
+            ),
+            (
+                RoundingStrategy::MidpointNearestEven,
+                ["10.2346", "10.2346"],
+            ),
+        ] {
+            let amounts: Vec<String> = DefaultTransactionRequestsReader::new_with_scale(
+                path.to_str().unwrap(),
+                4,
+                rounding_strategy,
+            )
+            .read()
+            .map(|record| record.amount.unwrap().to_string())
+            .collect();
+            assert_eq!(amounts, expected, "{:?}", rounding_strategy);
+        }
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_keeps_the_amounts_within_the_maximal_scale() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 0.1234567890123456789012345678
+        deposit, 1, 2, 1.000000000000000000000000000000009";
+        let path = save_to_temp_file(content);
+        let amounts: Vec<Decimal> = DefaultTransactionRequestsReader::new_with_scale(
+            path.to_str().unwrap(),
+            28,
+            RoundingStrategy::ToZero,
+        )
+        .read()
+        .map(|record| record.amount.unwrap())
+        .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                Decimal::from_i128_with_scale(1234567890123456789012345678, 28),
+                Decimal::ONE
+            ]
+        );
+        assert!(amounts.iter().all(|amount| amount.scale() <= 28));
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_skips_malformed_rows() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 10.2
+        deposit, 1, 2, abc
+        deposit, 1
+        transfer, 1, 3, 5.0
+        withdrawal, 1, 4, 1.5
+        deposit, 1, -5, 1.0
+        dispute, 1, 1, ";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader =
+            DefaultTransactionRequestsReader::new(path.to_str().unwrap());
+        let records: Vec<TransactionRequest> = transaction_requests_reader.read().collect();
+        assert_eq!(
+            records,
+            vec![
+                TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id: 1,
+                    transaction_id: 1,
+                    amount: Some(Decimal::new(102, 1)),
+                    timestamp: None,
+                    currency: None,
+                },
+                TransactionRequest {
+                    transaction_type: TransactionType::Withdrawal,
+                    client_id: 1,
+                    transaction_id: 4,
+                    amount: Some(Decimal::new(15, 1)),
+                    timestamp: None,
+                    currency: None,
+                },
+                TransactionRequest {
+                    transaction_type: TransactionType::Dispute,
+                    client_id: 1,
+                    transaction_id: 1,
+                    amount: None,
+                    timestamp: None,
+                    currency: None,
+                }
+            ]
+        );
+        assert_eq!(transaction_requests_reader.skipped_rows(), 4);
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_fallible_yields_the_malformed_rows_with_their_line() {
+        let content = "type,client,tx,amount
+deposit,1,1,10.2
+deposit,1,2,abc
+withdrawal,1,3,1.5";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader =
+            DefaultTransactionRequestsReader::new(path.to_str().unwrap());
+        let records: Vec<Result<TransactionRequest, PaymentEngineError>> =
+            transaction_requests_reader.read_fallible().collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].as_ref().unwrap().transaction_id, 1);
+        assert!(matches!(
+            records[1],
+            Err(PaymentEngineError::MalformedRow { line: Some(3), .. })
+        ));
+        assert_eq!(records[2].as_ref().unwrap().transaction_id, 3);
+        assert_eq!(transaction_requests_reader.skipped_rows(), 0);
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn unknown_types_are_malformed_rows_naming_the_type_and_line() {
+        let content = "type,client,tx,amount
+deposit,1,1,10
+transfer,1,2,5
+depositt,1,3,5
+withdrawal,1,4,2.5
+dispute,1,1,";
+        let path = save_to_temp_file(content);
+        for parsing in [CsvParsing::Fast, CsvParsing::Serde] {
+            let transaction_requests_reader =
+                DefaultTransactionRequestsReader::new(path.to_str().unwrap()).with_parsing(parsing);
+            let errors: Vec<String> = transaction_requests_reader
+                .read_fallible()
+                .filter_map(Result::err)
+                .map(|e| e.to_string())
+                .collect();
+            assert_eq!(errors.len(), 2, "{:?}", parsing);
+            // Serde adds the record position and the expected variants to the reason
+            assert!(errors[0].starts_with("Malformed row at line 3: "));
+            assert!(errors[0].contains("unknown variant `transfer`"));
+            assert!(errors[1].starts_with("Malformed row at line 4: "));
+            assert!(errors[1].contains("unknown variant `depositt`"));
+
+            let transaction_ids: Vec<u32> = transaction_requests_reader
+                .read()
+                .map(|request| request.transaction_id)
+                .collect();
+            assert_eq!(transaction_ids, vec![1, 4, 1]);
+            assert_eq!(transaction_requests_reader.skipped_rows(), 2);
+        }
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn as_of_reader_leaves_out_the_later_requests() {
+        let content = "type,client,tx,amount,timestamp
+deposit,1,1,10,100
+deposit,1,2,5,200
+dispute,1,1,,200
+resolve,1,1,,300
+deposit,1,3,1,301
+deposit,2,4,2,
+deposit,1,5,abc,100";
+        let path = save_to_temp_file(content);
+        let as_of = |timestamp| {
+            let reader = AsOfReader::new(
+                Box::new(DefaultTransactionRequestsReader::new(
+                    path.to_str().unwrap(),
+                )),
+                timestamp,
+            );
+            let accounts = crate::runner::process(&reader).unwrap();
+            assert_eq!(reader.skipped_rows(), 1);
+            accounts
+                .iter()
+                .map(|account| (account.client, account.available, account.held))
+                .collect::<Vec<_>>()
+        };
+        // The dispute is still open at 299 and resolved at 300, the deposit without a timestamp is always there
+        assert_eq!(as_of(99), vec![(2, Decimal::new(2, 0), Decimal::ZERO)]);
+        assert_eq!(
+            as_of(299),
+            vec![
+                (1, Decimal::new(5, 0), Decimal::new(10, 0)),
+                (2, Decimal::new(2, 0), Decimal::ZERO)
+            ]
+        );
+        assert_eq!(
+            as_of(300),
+            vec![
+                (1, Decimal::new(15, 0), Decimal::ZERO),
+                (2, Decimal::new(2, 0), Decimal::ZERO)
+            ]
+        );
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_fallible_reports_a_missing_file() {
+        let transaction_requests_reader = DefaultTransactionRequestsReader::new("missing.csv");
+        let records: Vec<Result<TransactionRequest, PaymentEngineError>> =
+            transaction_requests_reader.read_fallible().collect();
+        assert!(matches!(records[..], [Err(PaymentEngineError::Storage(_))]));
+    }
+
+    #[test]
+    fn skipped_rows_is_reset_on_every_read() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 2, abc";
+        let path = save_to_temp_file(content);
+        let transaction_requests_reader =
+            DefaultTransactionRequestsReader::new(path.to_str().unwrap());
+        assert_eq!(transaction_requests_reader.read().count(), 0);
+        assert_eq!(transaction_requests_reader.read().count(), 0);
+        assert_eq!(transaction_requests_reader.skipped_rows(), 1);
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn read_transaction_requests_works_with_any_source() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 10.23456
+        deposit, 1, abc
+        withdrawal, 1, 2, 1.5";
+        let skipped_rows = Rc::new(Cell::new(0));
+        let records: Vec<TransactionRequest> = read_transaction_requests(
+            Cursor::new(content.as_bytes().to_vec()),
+            4,
+            RoundingStrategy::ToZero,
+            skipped_rows.clone(),
+        )
+        .collect();
+        assert_eq!(
+            records,
+            vec![
+                TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id: 1,
+                    transaction_id: 1,
+                    amount: Some(Decimal::new(102345, 4)),
+                    timestamp: None,
+                    currency: None,
+                },
+                TransactionRequest {
+                    transaction_type: TransactionType::Withdrawal,
+                    client_id: 1,
+                    transaction_id: 2,
+                    amount: Some(Decimal::new(15, 1)),
+                    timestamp: None,
+                    currency: None,
+                }
+            ]
+        );
+        assert_eq!(skipped_rows.get(), 1);
+    }
+
+    #[test]
+    fn read_works_with_and_without_the_timestamp_column() {
+        let without_timestamps = "
+        type, client, tx, amount
+        deposit, 1, 1, 1.5
+        dispute, 1, 1, ";
+        let with_timestamps = "
+        type, client, tx, amount, timestamp
+        deposit, 1, 1, 1.5, 1650000000000
+        dispute, 1, 1, , ";
+        let read = |content: &str| -> Vec<TransactionRequest> {
+            let path = save_to_temp_file(content);
+            let records = DefaultTransactionRequestsReader::new(path.to_str().unwrap())
+                .read()
+                .collect();
+            path.close().unwrap();
+            records
+        };
+        let deposit = TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(Decimal::new(15, 1)),
+            timestamp: None,
+            currency: None,
+        };
+        let dispute = TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        assert_eq!(
+            read(without_timestamps),
+            vec![deposit.clone(), dispute.clone()]
+        );
+        assert_eq!(
+            read(with_timestamps),
+            vec![
+                TransactionRequest {
+                    timestamp: Some(1_650_000_000_000),
+                    currency: None,
+                    ..deposit
+                },
+                dispute
+            ]
+        );
+    }
+
+    #[test]
+    fn read_accepts_the_csv_variants_of_the_partners() {
+        let expected = vec![
+            TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(15, 1)),
+                timestamp: None,
+                currency: None,
+            },
+            TransactionRequest {
+                transaction_type: TransactionType::Dispute,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            },
+            TransactionRequest {
+                transaction_type: TransactionType::Resolve,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            },
+        ];
+        let read = |reader: DefaultTransactionRequestsReader| -> Vec<TransactionRequest> {
+            let records = reader.read().collect();
+            assert_eq!(reader.skipped_rows(), 0);
+            records
+        };
+        for content in [
+            // Without the spaces after the delimiters
+            "type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1,\nresolve,1,1,\n",
+            // Semicolons, detected from the header
+            "type;client;tx;amount\ndeposit;1;1;1.5\ndispute;1;1;\nresolve;1;1;\n",
+            // Dispute-family rows without the trailing empty amount
+            "type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1\nresolve, 1, 1\n",
+            // Windows line endings, after a blank line
+            "\r\ntype, client, tx, amount\r\ndeposit, 1, 1, 1.5\r\ndispute, 1, 1\r\nresolve, 1, 1,\r\n",
+        ] {
+            let path = save_to_temp_file(content);
+            assert_eq!(
+                read(DefaultTransactionRequestsReader::new(path.to_str().unwrap())),
+                expected
+            );
+            path.close().unwrap();
+        }
+        // Any other delimiter has to be configured
+        let path = save_to_temp_file(
+            "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.5\ndispute\t1\t1\nresolve\t1\t1\t\n",
+        );
+        assert_eq!(
+            read(
+                DefaultTransactionRequestsReader::new(path.to_str().unwrap()).with_delimiter(b'\t')
+            ),
+            expected
+        );
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn fast_parsing_reads_the_same_requests_as_serde() {
+        let mut content = "type, client, tx, amount, timestamp, currency\n".to_owned();
+        let types = [
+            "deposit",
+            "withdrawal",
+            "dispute",
+            "resolve",
+            "chargeback",
+            "unlock",
+        ];
+        let amounts = [
+            "1.5",
+            "  2.25  ",
+            "",
+            "0",
+            "-3.99999",
+            "10.23456",
+            "0.00009",
+            "1e3",
+            "1.5E-2",
+            "+4",
+            ".5",
+            "7.",
+            "1_000",
+            "abc",
+            "inf",
+            "NaN",
+            "1.2.3",
+            "--1",
+            ".",
+            "e5",
+            "1e",
+            "99999999999999",
+        ];
+        let ids = [
+            "1",
+            " 42 ",
+            "4294967295",
+            "4294967296",
+            "-5",
+            "",
+            "x",
+            "+7",
+            "65535",
+            "65536",
+        ];
+        let mut seed: u64 = 7;
+        let mut next = move |bound: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+        for _ in 0..5000 {
+            let row = [
+                types[next(types.len())].to_owned(),
+                ids[next(ids.len())].to_owned(),
+                ids[next(ids.len())].to_owned(),
+                amounts[next(amounts.len())].to_owned(),
+                ["", "1650000000000", "-1", "t"][next(4)].to_owned(),
+                ["", "USD", " eur "][next(3)].to_owned(),
+            ];
+            // Some rows are cut short, as the dispute-family ones often are, or have an extra field
+            let fields = match next(10) {
+                0 => 3,
+                1 => 4,
+                _ => 6,
+            };
+            content.push_str(&row[..fields].join(", "));
+            if next(20) == 0 {
+                content.push_str(", extra");
+            }
+            content.push('\n');
+        }
+        content.push_str("transfer, 1, 1, 1.0\nDeposit, 1, 1, 1.0\n");
+        let path = save_to_temp_file(&content);
+        let read = |parsing: CsvParsing| {
+            let reader =
+                DefaultTransactionRequestsReader::new(path.to_str().unwrap()).with_parsing(parsing);
+            let records: Vec<TransactionRequest> = reader.read().collect();
+            (records, reader.skipped_rows())
+        };
+        let (fast, fast_skipped) = read(CsvParsing::Fast);
+        let (serde, serde_skipped) = read(CsvParsing::Serde);
+        assert!(fast.len() > 500);
+        assert!(fast_skipped > 1000);
+        // Through Debug, as comparing the Decimals ignores their scale
+        assert_eq!(format!("{:?}", fast), format!("{:?}", serde));
+        assert_eq!(fast_skipped, serde_skipped);
+        path.close().unwrap();
+
+        // Serde reads this one through f64, which doesn't have enough digits for it
+        let path =
+            save_to_temp_file("type, client, tx, amount\ndeposit, 1, 1, 1234567890123.4567\n");
+        let amounts: Vec<Decimal> = DefaultTransactionRequestsReader::new(path.to_str().unwrap())
+            .read()
+            .map(|record| record.amount.unwrap())
+            .collect();
+        assert_eq!(amounts, vec![Decimal::new(12345678901234567, 4)]);
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn fast_parsing_falls_back_to_serde_for_unknown_headers() {
+        // Without a tx column every row is malformed, the same way as before
+        let path = save_to_temp_file("type, client, id, amount\ndeposit, 1, 1, 1.0\n");
+        let reader = DefaultTransactionRequestsReader::new(path.to_str().unwrap());
+        assert_eq!(reader.read().count(), 0);
+        assert_eq!(reader.skipped_rows(), 1);
+        path.close().unwrap();
+    }
+
+    #[test]
+    fn synthetic_reader_is_deterministic() {
+        let reader = SyntheticReader::new(1000, 10, 42).with_dispute_percent(10);
+        let requests: Vec<TransactionRequest> = reader.read().collect();
+        assert_eq!(requests, reader.read().collect::<Vec<_>>());
+        assert_ne!(
+            requests,
+            SyntheticReader::new(1000, 10, 43)
+                .with_dispute_percent(10)
+                .read()
+                .collect::<Vec<_>>()
+        );
+        let deposits: Vec<&TransactionRequest> = requests
+            .iter()
+            .filter(|request| request.transaction_type == TransactionType::Deposit)
+            .collect();
+        assert!((850..950).contains(&deposits.len()));
+        // The disputes and resolves reference the earlier requests, on behalf of the client that made them
+        for request in &requests {
+            if let Some(deposit) = deposits
+                .iter()
+                .find(|deposit| deposit.transaction_id == request.transaction_id)
+            {
+                assert_eq!(request.client_id, deposit.client_id);
+            }
+        }
+    }
+
+    #[test]
+    fn synthetic_reader_follows_the_mix_ratios() {
+        let requests: Vec<TransactionRequest> = SyntheticReader::new(100_000, 100, 7)
+            .with_dispute_percent(10)
+            .with_withdrawal_percent(30)
+            .read()
+            .collect();
+        let count_of = |transaction_types: &[TransactionType]| {
+            requests
+                .iter()
+                .filter(|request| transaction_types.contains(&request.transaction_type))
+                .count()
+        };
+        assert!((59_000..61_000).contains(&count_of(&[TransactionType::Deposit])));
+        assert!((29_000..31_000).contains(&count_of(&[TransactionType::Withdrawal])));
+        assert!((9_500..10_500).contains(&count_of(&[
+            TransactionType::Dispute,
+            TransactionType::Resolve
+        ])));
+        assert!((4_500..5_500).contains(&count_of(&[TransactionType::Dispute])));
+        assert_eq!(count_of(&[TransactionType::Chargeback]), 0);
+
+        // The disputes take precedence over the withdrawals, only the first request may be a deposit as there's nothing
+        // to dispute before it
+        let requests: Vec<TransactionRequest> = SyntheticReader::new(1000, 10, 7)
+            .with_withdrawal_percent(80)
+            .with_dispute_percent(50)
+            .read()
+            .collect();
+        assert!(requests[1..]
+            .iter()
+            .all(|request| request.transaction_type != TransactionType::Deposit));
+    }
+
     fn save_to_temp_file(content: &str) -> TempPath {
         let mut file = NamedTempFile::new().expect("Couldn't create a temporary file for testing");
         file.write_all(content.as_bytes())
             .expect("Couldn't write into the temp file for unit-testing");
-        return file.into_temp_path();
+        file.into_temp_path()
     }
 }
@@ -1,31 +1,248 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    rc::Rc,
+};
+
 use csv::WriterBuilder;
 use mockall::predicate::*;
 use mockall::*;
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Serialize;
 
 use crate::{
-    common_types::TransactionId,
-    customer_account_provider::CustomerAccountProvider,
-    transaction_history_provider::transaction_history_provider::TransactionHistoryProvider,
+    audit::{
+        AuditDecision, AuditEvent, AuditField, AuditRecord, AuditRecordSink, AuditSink, AuditValue,
+    },
+    common_types::{Currency, CustomerId, TransactionId},
+    customer_account_provider::{
+        AccountMutation, CustomerAccountProvider, CustomerAccountReport,
+        InMemoryCustomerAccountProvider,
+    },
+    duplicate_detector::DuplicateDetector,
+    errors::PaymentEngineError,
+    events::TransactionEventListener,
+    fraud::{FraudConfig, FraudMonitor},
+    invariant_tracker::{FundsDivergence, InvariantTracker},
+    logging::{log_executed, log_skip, SKIPPED_REQUESTS_TARGET},
+    pending_follow_ups::PendingFollowUps,
+    preview::{
+        take_back, PreviewCustomerAccountProvider, PreviewTransactionHistoryProvider,
+        SharedProvider, TransactionPreview,
+    },
+    report_formatter::{ReportFormat, ReportFormatter},
+    snapshot::Snapshot,
+    transaction_history_provider::{
+        in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+        transaction_history_provider::TransactionHistoryProvider,
+    },
+    transaction_outcome::{ProcessingSummary, SkipReason, TransactionOutcome},
     transaction_request::{TransactionRequest, TransactionState, TransactionType},
+    validation::references_transaction,
+    write_batch::WriteBatch,
 };
 
-use log::info;
+// Risk limits of the deposits and withdrawals, the ones set to None are not checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    // Largest amount of a single deposit or withdrawal
+    pub max_transaction_amount: Option<Decimal>,
+    // Largest total, the available plus the held funds, a deposit can bring an account to. Each currency of the
+    // client has its own account, so it's checked per currency.
+    pub max_account_total: Option<Decimal>,
+}
+
+// Re-exported, so the callers importing it from the manager keep working
+pub use crate::validation::ValidationConfig;
+
+use log::warn;
 
 #[automock]
 pub trait TransactionsManager {
-    fn structure_validation(transaction_request: &TransactionRequest) -> bool;
-    // Returning bool for showing if the transaction was executed
+    fn structure_validation(&self, transaction_request: &TransactionRequest) -> bool;
+    // Returning the outcome for showing if the transaction was executed or why it was skipped, errors that are not fatal only affect the given request
     fn handle_transaction(
         &mut self,
         transaction_request: TransactionRequest,
-    ) -> Result<bool, String>;
-    fn print_report(&self) -> Result<(), String>;
+    ) -> Result<TransactionOutcome, PaymentEngineError>;
+    // Writes the report of all accounts in the configured format into the given writer
+    fn write_report(&self, writer: &mut dyn Write) -> Result<(), PaymentEngineError>;
+    fn print_report(&self) -> Result<(), PaymentEngineError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportOrder {
+    #[default]
+    SortedByClient,
+    // Whatever order the account provider lists the accounts in, skips sorting for very large reports
+    ProviderOrder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    // Locked accounts can still receive deposits and go through disputes, protecting the customers from malicious vendors
+    #[default]
+    BlockWithdrawalsOnly,
+    // Any activity on a locked account is skipped, for fraud-frozen accounts
+    BlockAll,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccountBalances {
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+// A field of the account provider that differs from the value derived from the transaction history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub client: CustomerId,
+    // None for the locked flag, as it's shared by all the accounts of the client
+    pub currency: Option<Currency>,
+    pub field: AuditField,
+    pub expected: AuditValue,
+    pub actual: AuditValue,
+}
+
+impl Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.client)?;
+        if let Some(currency) = &self.currency {
+            write!(f, " {}", currency)?;
+        }
+        write!(
+            f,
+            ": {:?} is {}, the history gives {}",
+            self.field, self.actual, self.expected
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StatementState {
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl StatementState {
+    // Transactions that were never disputed have no state in the statement
+    fn of(state: &TransactionState) -> Option<Self> {
+        if state.charged_back {
+            Some(StatementState::ChargedBack)
+        } else if state.held {
+            Some(StatementState::Disputed)
+        } else if state.resolved {
+            Some(StatementState::Resolved)
+        } else {
+            None
+        }
+    }
 }
 
+#[derive(Debug, Serialize)]
+struct StatementRow {
+    #[serde(rename = "tx")]
+    transaction_id: TransactionId,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    amount: Option<Decimal>,
+    running_available: Decimal,
+    state: Option<StatementState>,
+}
+
+#[derive(Debug, Serialize)]
+struct DisputeRow {
+    #[serde(rename = "tx")]
+    transaction_id: TransactionId,
+    client: CustomerId,
+    amount: Option<Decimal>,
+    currency: Currency,
+}
+
+// The fields of an account watched by the audit sink and the event listeners, missing accounts count as empty ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct AuditedFields {
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+}
+
+// Decimal places of the amounts and balances, unless configured otherwise
+pub const DEFAULT_SCALE: u32 = 4;
+
+// Currency of the requests without one, unless configured otherwise
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+// Files of the account and the history stores in the directory of a checkpoint
+const CHECKPOINT_ACCOUNTS_FILE: &str = "accounts.json";
+const CHECKPOINT_HISTORY_FILE: &str = "history.json";
+
+// Times a transaction can be disputed, resolving it in between, unless configured otherwise
+pub const DEFAULT_MAX_DISPUTES: u32 = 2;
+
+// Dispute-family requests parked for a missing transaction at once with the out-of-order tolerance, unless configured
+// otherwise
+pub const DEFAULT_MAX_PARKED: usize = 10_000;
+
+// Used by the manager for bringing amounts and balances to the configured scale. Truncating like the readers do, so
+// neither the balances nor the report can show funds the client doesn't have. The in-memory account provider given a
+// scale normalizes the balances it writes with MidpointNearestEven instead, so it should be given the scale of the
+// manager, otherwise it rounds away digits the manager kept.
+const SCALE_ROUNDING: RoundingStrategy = RoundingStrategy::ToZero;
+
 pub struct DefaultTransactionsManager {
     transaction_history_provider: Box<dyn TransactionHistoryProvider>,
     customer_account_provider: Box<dyn CustomerAccountProvider>,
+    report_order: ReportOrder,
+    report_format: ReportFormat,
+    // Appends the lifetime deposited and withdrawn amounts to the report
+    detailed_report: bool,
+    fixed_scale_report: bool,
+    scale: u32,
+    default_currency: Currency,
+    lock_policy: LockPolicy,
+    progress_interval: usize,
+    validation_config: ValidationConfig,
+    limits: Limits,
+    allow_redispute: bool,
+    max_disputes: Option<u32>,
+    allow_unlock: bool,
+    strict_mode: bool,
+    dispute_window: Option<u64>,
+    // Number of executed transactions of each client, the sequence of the latest one
+    client_sequences: HashMap<CustomerId, u64>,
+    // Requests passed to process_all so far, over all its calls
+    processed_requests: usize,
+    // Receives a change event for every field of an account modified by an executed transaction
+    audit_sink: Option<Box<dyn AuditSink>>,
+    // Receives the decision of every handled request with the balances after it, costing a few provider reads each
+    audit_record_sink: Option<Box<dyn AuditRecordSink>>,
+    // Called with the outcome of every handled request, in the order they were added
+    event_listeners: Vec<Box<dyn TransactionEventListener>>,
+    // Locks the accounts with suspicious withdrawals, only set when a fraud config is given
+    fraud_monitor: Option<FraudMonitor>,
+    // Dispute-family requests waiting for the transaction they reference, only set with the out-of-order tolerance
+    pending_follow_ups: Option<PendingFollowUps>,
+    // Expected totals of the accounts for verify_invariants, only set with the invariant tracking
+    invariant_tracker: Option<InvariantTracker>,
+    // Number of consecutive deposits and withdrawals process_all writes to the history together, None writes each
+    write_batch_size: Option<usize>,
+    // The batch process_all is executing, only set while it runs
+    write_batch: Option<WriteBatch>,
+    // Answers the duplicate check of the new IDs without reading the history, None reads it for every ID
+    duplicate_detector: Option<Box<dyn DuplicateDetector>>,
+    // IDs executed by the other shards of a ShardedTransactionsManager, the ones this shard was told about
+    executed_elsewhere: HashSet<TransactionId>,
+    // Sum of the amounts of the open disputes of every account touched by a dispute-family request, which its held
+    // funds can never exceed. An account is added with its held funds when first touched, e.g. restored ones.
+    disputed_amounts: HashMap<(CustomerId, Currency), Decimal>,
+    summary: ProcessingSummary,
 }
 impl DefaultTransactionsManager {
     pub fn new(
@@ -35,1529 +252,7087 @@ impl DefaultTransactionsManager {
         DefaultTransactionsManager {
             transaction_history_provider: Box::new(transaction_history_provider),
             customer_account_provider: Box::new(customer_account_provider),
+            report_order: ReportOrder::default(),
+            report_format: ReportFormat::default(),
+            detailed_report: false,
+            fixed_scale_report: false,
+            scale: DEFAULT_SCALE,
+            default_currency: DEFAULT_CURRENCY.to_owned(),
+            lock_policy: LockPolicy::default(),
+            progress_interval: 100_000,
+            validation_config: ValidationConfig::default(),
+            limits: Limits::default(),
+            allow_redispute: true,
+            max_disputes: Some(DEFAULT_MAX_DISPUTES),
+            allow_unlock: false,
+            strict_mode: false,
+            dispute_window: None,
+            client_sequences: HashMap::new(),
+            processed_requests: 0,
+            audit_sink: None,
+            audit_record_sink: None,
+            event_listeners: Vec::new(),
+            fraud_monitor: None,
+            pending_follow_ups: None,
+            invariant_tracker: None,
+            write_batch_size: None,
+            write_batch: None,
+            duplicate_detector: None,
+            executed_elsewhere: HashSet::new(),
+            disputed_amounts: HashMap::new(),
+            summary: ProcessingSummary::default(),
+        }
+    }
+
+    pub fn with_lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    pub fn with_validation_config(mut self, validation_config: ValidationConfig) -> Self {
+        self.validation_config = validation_config;
+        self
+    }
+
+    // The deposits and withdrawals beyond the limits are skipped with a reason of their own, so they stand out in the
+    // logs and the summary
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // When not allowed, a transaction that was resolved once can never be disputed again
+    pub fn with_allow_redispute(mut self, allow_redispute: bool) -> Self {
+        self.allow_redispute = allow_redispute;
+        self
+    }
+
+    // Stops the dispute and resolve loops on one transaction, None allows any number of disputes
+    pub fn with_max_disputes(mut self, max_disputes: Option<u32>) -> Self {
+        self.max_disputes = max_disputes;
+        self
+    }
+
+    // When allowed, unlock requests lift the lock set by a chargeback, e.g. when the chargeback was a mistake
+    pub fn with_allow_unlock(mut self, allow_unlock: bool) -> Self {
+        self.allow_unlock = allow_unlock;
+        self
+    }
+
+    // In strict mode inconsistent requests are returned as errors and process_all stops on the first of them
+    pub fn with_strict_mode(mut self, strict_mode: bool) -> Self {
+        self.strict_mode = strict_mode;
+        self
+    }
+
+    // Disputes are skipped when the client executed more than dispute_window transactions since the disputed one
+    pub fn with_dispute_window(mut self, dispute_window: u64) -> Self {
+        self.dispute_window = Some(dispute_window);
+        self
+    }
+
+    // Parks the disputes, resolves and chargebacks of transactions that didn't arrive yet, up to max_parked of them,
+    // and handles them right after the referenced deposit or withdrawal is executed. For the inputs that are not
+    // strictly ordered, otherwise such requests are dropped as referencing a missing transaction. When full, the
+    // oldest parked request is evicted, see DEFAULT_MAX_PARKED.
+    pub fn with_out_of_order_tolerance(mut self, max_parked: usize) -> Self {
+        self.pending_follow_ups = Some(PendingFollowUps::new(max_parked));
+        self
+    }
+
+    // Tracks the total funds every account should have from the executed requests, for verify_invariants. The
+    // existing accounts, e.g. restored from a snapshot, are expected to keep their totals, so it should be enabled
+    // after the state is in place.
+    pub fn with_invariant_tracking(mut self) -> Self {
+        self.invariant_tracker = Some(InvariantTracker::new(
+            self.customer_account_provider.iter_accounts(),
+        ));
+        self
+    }
+
+    // The deposits and withdrawals the detector hasn't seen are executed without reading the history for a duplicate.
+    // It's filled with the IDs of the history first, so it should be set after the state is in place. The IDs an
+    // approximate detector mistakes for seen ones are still read from the history, so they're executed all the same.
+    pub fn with_duplicate_detector(
+        mut self,
+        duplicate_detector: impl DuplicateDetector + 'static,
+    ) -> Result<Self, PaymentEngineError> {
+        let mut duplicate_detector = Box::new(duplicate_detector);
+        for transaction in self.transaction_history_provider.iter_transactions() {
+            duplicate_detector.insert(transaction?.transaction_id);
+        }
+        self.duplicate_detector = Some(duplicate_detector);
+        Ok(self)
+    }
+
+    // Locks the account of a client as soon as their withdrawals cross a limit of the config, until it's unlocked
+    pub fn with_fraud_config(mut self, fraud_config: FraudConfig) -> Self {
+        self.fraud_monitor = Some(FraudMonitor::new(fraud_config));
+        self
+    }
+
+    pub fn with_audit_sink(mut self, audit_sink: impl AuditSink + 'static) -> Self {
+        self.audit_sink = Some(Box::new(audit_sink));
+        self
+    }
+
+    pub fn with_audit_record_sink(
+        mut self,
+        audit_record_sink: impl AuditRecordSink + 'static,
+    ) -> Self {
+        self.audit_record_sink = Some(Box::new(audit_record_sink));
+        self
+    }
+
+    // Can be called several times, every listener gets all the events
+    pub fn with_event_listener(
+        mut self,
+        event_listener: impl TransactionEventListener + 'static,
+    ) -> Self {
+        self.event_listeners.push(Box::new(event_listener));
+        self
+    }
+
+    // process_all executes up to batch_size consecutive deposits and withdrawals with one duplicate check read and
+    // writes them to the history together. Any other request writes the batch first, as it may read its transactions.
+    pub fn with_write_batch_size(mut self, batch_size: usize) -> Self {
+        self.write_batch_size = Some(batch_size.max(1));
+        self
+    }
+
+    // Number of processed requests between the progress callback invocations of process_all
+    pub fn with_progress_interval(mut self, progress_interval: usize) -> Self {
+        self.progress_interval = progress_interval.max(1);
+        self
+    }
+
+    // Validates and handles the requests one by one, stopping only on fatal errors
+    pub fn process_all(
+        &mut self,
+        requests: impl Iterator<Item = TransactionRequest>,
+        mut progress: Option<&mut dyn FnMut(usize)>,
+    ) -> Result<ProcessingSummary, PaymentEngineError> {
+        let mut batch = vec![];
+        for request in requests {
+            if let Some(batch_size) = self.write_batch_size {
+                if matches!(
+                    request.transaction_type,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                ) {
+                    batch.push(request);
+                    if batch.len() == batch_size {
+                        self.process_batch(std::mem::take(&mut batch), &mut progress)?;
+                    }
+                    continue;
+                }
+                self.process_batch(std::mem::take(&mut batch), &mut progress)?;
+            }
+            self.process_request(request, &mut progress)?;
         }
+        self.process_batch(batch, &mut progress)?;
+        Ok(self.summary.clone())
     }
 
-    fn is_duplicate_transaction_id(
+    // Executes the deposits and withdrawals with the duplicate check answered by one filter_new_ids call, then writes
+    // them to the history together
+    fn process_batch(
         &mut self,
-        transaction_id: TransactionId,
-    ) -> Result<bool, String> {
-        Ok(self
+        batch: Vec<TransactionRequest>,
+        progress: &mut Option<&mut dyn FnMut(usize)>,
+    ) -> Result<(), PaymentEngineError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let transaction_ids: Vec<TransactionId> =
+            batch.iter().map(|request| request.transaction_id).collect();
+        let new_ids = self
             .transaction_history_provider
-            .as_mut()
-            .read_transaction(transaction_id)?
-            .is_some())
+            .filter_new_ids(&transaction_ids)?;
+        self.write_batch = Some(WriteBatch::new(
+            transaction_ids
+                .into_iter()
+                .zip(new_ids)
+                .filter_map(|(transaction_id, new)| new.then_some(transaction_id))
+                .collect(),
+        ));
+        let result = batch
+            .into_iter()
+            .try_for_each(|request| self.process_request(request, progress));
+        // Written even when a request failed, as the executed ones are already applied to the accounts
+        self.flush_write_batch()?;
+        self.write_batch = None;
+        result
     }
 
-    fn deposit(&mut self, transaction_request: TransactionRequest) -> Result<bool, String> {
-        if self.is_duplicate_transaction_id(transaction_request.transaction_id)? {
-            info!("Transaction with duplicate ID, skipping");
-            return Ok(false);
-        }
-        let existing_amount = self
-            .customer_account_provider
-            .as_mut()
-            .get_available(transaction_request.client_id)?
-            .unwrap_or(Decimal::ZERO);
-        self.customer_account_provider.as_mut().set_available(
-            transaction_request.client_id,
-            existing_amount
-                + transaction_request
-                    .amount
-                    .expect("Transaction amount not present when depositing!"),
-        )?;
+    fn flush_write_batch(&mut self) -> Result<(), PaymentEngineError> {
+        let Some(write_batch) = self.write_batch.as_mut() else {
+            return Ok(());
+        };
+        let pending = write_batch.take_pending();
         self.transaction_history_provider
-            .as_mut()
-            .write_transaction(transaction_request)?;
-        Ok(true)
+            .write_transactions_batch(pending)
     }
 
-    fn withdraw(&mut self, transaction_request: TransactionRequest) -> Result<bool, String> {
-        if self.is_duplicate_transaction_id(transaction_request.transaction_id)? {
-            info!("Transaction with duplicate ID, skipping");
-            return Ok(false);
-        }
-        if let Some(locked) = self
-            .customer_account_provider
-            .as_mut()
-            .get_locked_status(transaction_request.client_id)?
-        {
-            if locked {
-                // We should not allow to withdraw money when the account is locked.
-                // TODO Would be better if we could elaborate this with some errors.
-                info!(
-                    "The account of customer {} is locked, skipping withdrawal request.",
-                    transaction_request.client_id
-                );
-                return Ok(false);
+    // Validates and handles the request, logging the skipped ones
+    fn process_request(
+        &mut self,
+        request: TransactionRequest,
+        progress: &mut Option<&mut dyn FnMut(usize)>,
+    ) -> Result<(), PaymentEngineError> {
+        self.processed_requests += 1;
+        let processed = self.processed_requests;
+        if self.structure_validation(&request) {
+            let transaction_id = request.transaction_id;
+            let client_id = request.client_id;
+            match self.handle_transaction(request) {
+                Ok(_) => {}
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) if self.strict_mode => {
+                    return Err(PaymentEngineError::StrictModeViolation {
+                        row: processed,
+                        transaction_id,
+                        reason: Box::new(e),
+                    })
+                }
+                Err(e) => warn!(
+                    target: SKIPPED_REQUESTS_TARGET,
+                    "tx={} client={} skipped: {}",
+                    transaction_id,
+                    client_id,
+                    e
+                ),
             }
+        } else {
+            log_skip(SkipReason::InvalidStructure, &request);
+            let result = Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure));
+            self.summary.record(&request.transaction_type, &result);
+            self.record_decision(&request, &result)?;
         }
-        // If the amount is not present, we just skip. Maybe we can add some logging later.
-        if let Some(existing_amount) = self
-            .customer_account_provider
-            .as_mut()
-            .get_available(transaction_request.client_id)?
-        {
-            let transaction_amount = transaction_request
-                .amount
-                .expect("Transaction amount not present when withdrawing!");
-            if existing_amount >= transaction_amount {
-                self.customer_account_provider.as_mut().set_available(
-                    transaction_request.client_id,
-                    existing_amount - transaction_amount,
-                )?;
-                self.transaction_history_provider
-                    .write_transaction(transaction_request)?;
-                return Ok(true);
-            } else {
-                info!(
-                    "The customer {} doesn't have enough available funds to withdraw {}",
-                    transaction_request.client_id, transaction_amount
-                );
+        if processed.is_multiple_of(self.progress_interval) {
+            if let Some(progress) = progress.as_mut() {
+                progress(processed);
             }
-        } else {
-            info!(
-                "The customer {} doens't have any available funds, skipping the withdraw request.",
-                transaction_request.client_id
-            );
         }
-        Ok(false)
+        Ok(())
     }
 
-    fn dispute(&mut self, transaction_request: TransactionRequest) -> Result<bool, String> {
-        let existing_amount: Decimal = self
-            .customer_account_provider
-            .as_mut()
-            .get_available(transaction_request.client_id)?
-            .unwrap_or(Decimal::ZERO);
-        if let Some(disputed_transaction) = self
+    // Copies the accounts, the transaction history and the state of the manager, so the run can be resumed with
+    // import_state. The summary is not part of it, after resuming it only counts the newly handled requests
+    pub fn export_state(&self) -> Result<Snapshot, PaymentEngineError> {
+        let mut transaction_states = self
             .transaction_history_provider
-            .as_mut()
-            .read_transaction(transaction_request.transaction_id)?
-        {
-            if disputed_transaction.client_id != transaction_request.client_id {
-                info!("Client ID of the disputed transaction doesn't match the client ID of the request, possibly malicious client");
-                return Ok(false);
-            }
-
-            let disputed_amount = disputed_transaction
-                .amount
-                .expect("Disputed transaction doesn't have amount");
-
-            let disputed_transaction_state = self
+            .iter_states()
+            .collect::<Result<Vec<_>, _>>()?;
+        transaction_states.sort_unstable_by_key(|(transaction_id, _)| *transaction_id);
+        let mut client_sequences: Vec<(CustomerId, u64)> = self
+            .client_sequences
+            .iter()
+            .map(|(client_id, sequence)| (*client_id, *sequence))
+            .collect();
+        client_sequences.sort_unstable();
+        Ok(Snapshot {
+            processed_requests: self.processed_requests,
+            accounts: self.customer_account_provider.list_accounts_sorted()?,
+            transactions: self
                 .transaction_history_provider
-                .as_mut()
-                .read_transaction_state(transaction_request.transaction_id)?;
-            // Skipping if the transaction was already disputed or charged back
-            if Some(true)
-                == disputed_transaction_state.map(|state| state.held || state.charged_back)
-            {
-                info!(
-                    "Transaction {} already on hold or charged back, not holding again",
-                    transaction_request.transaction_id
-                );
-                return Ok(false);
-            }
-            // Allowing disputes even if they will create negative available funds. Customers first!
+                .iter_transactions()
+                .collect::<Result<_, _>>()?,
+            transaction_states,
+            client_sequences,
+        })
+    }
 
-            // TODO: with ? failing at random moment, while this might break the consistency of the system. Think if some guarantee system can be implemented. Transactions?
-            self.customer_account_provider.as_mut().set_available(
-                transaction_request.client_id,
-                existing_amount - disputed_amount,
-            )?;
-            let existing_held_amount = self
-                .customer_account_provider
-                .as_mut()
-                .get_held_amount(transaction_request.client_id)?
-                .unwrap_or(Decimal::ZERO);
-            self.customer_account_provider.as_mut().set_held_amount(
-                transaction_request.client_id,
-                existing_held_amount + disputed_amount,
-            )?;
-            let new_transaction_state = disputed_transaction_state
-                .map(|existing_state| {
-                    let mut new_state = existing_state.clone();
-                    new_state.held = true;
-                    new_state
-                })
-                .unwrap_or_else(|| TransactionState {
-                    held: true,
-                    ..Default::default()
-                });
-            self.transaction_history_provider
-                .as_mut()
-                .write_transaction_state(
-                    transaction_request.transaction_id,
-                    new_transaction_state,
-                )?;
-            return Ok(true);
+    // Creates a manager with in-memory providers holding the snapshot, the options have to be set again. The scale is
+    // the one of the interrupted run, the restored accounts keep their balances and the new ones are rounded to it.
+    pub fn import_state(snapshot: Snapshot, scale: u32) -> Result<Self, PaymentEngineError> {
+        let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
+        for transaction in snapshot.transactions {
+            transaction_history_provider.write_transaction(transaction)?;
+        }
+        for (transaction_id, state) in snapshot.transaction_states {
+            transaction_history_provider.write_transaction_state(transaction_id, state)?;
+        }
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new_with_scale(scale);
+        for account in snapshot.accounts {
+            customer_account_provider.restore_account(account);
         }
-        Ok(false)
+        let mut manager =
+            Self::new(transaction_history_provider, customer_account_provider).with_scale(scale);
+        manager.processed_requests = snapshot.processed_requests;
+        manager.client_sequences = snapshot.client_sequences.into_iter().collect();
+        Ok(manager)
     }
 
-    fn resolve(&mut self, transaction_request: TransactionRequest) -> Result<bool, String> {
-        let existing_amount = self
-            .customer_account_provider
-            .as_mut()
-            .get_available(transaction_request.client_id)?
-            .unwrap_or(Decimal::ZERO);
-        if let Some(disputed_transaction) = self
+    // Writes the accounts and the transaction history into the directory, a file each, so a later run can continue
+    // with new requests after restore. Both are written next to their destination and only renamed once both are
+    // complete, so a crash while writing keeps the previous checkpoint.
+    pub fn checkpoint(&self, dir: &Path) -> Result<(), PaymentEngineError> {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        for account in self.customer_account_provider.iter_accounts() {
+            customer_account_provider.restore_account(account);
+        }
+        let mut transaction_history_provider = InMemoryTransactionHistoryProvider::new();
+        for transaction in self.transaction_history_provider.iter_transactions() {
+            transaction_history_provider.write_transaction(transaction?)?;
+        }
+        for state in self.transaction_history_provider.iter_states() {
+            let (transaction_id, state) = state?;
+            transaction_history_provider.write_transaction_state(transaction_id, state)?;
+        }
+        fs::create_dir_all(dir)?;
+        let accounts_path = dir.join(CHECKPOINT_ACCOUNTS_FILE);
+        let history_path = dir.join(CHECKPOINT_HISTORY_FILE);
+        let mut writer = BufWriter::new(File::create(accounts_path.with_extension("tmp"))?);
+        customer_account_provider.save_snapshot(&mut writer)?;
+        writer.flush()?;
+        let mut writer = BufWriter::new(File::create(history_path.with_extension("tmp"))?);
+        transaction_history_provider.save_snapshot(&mut writer)?;
+        writer.flush()?;
+        fs::rename(accounts_path.with_extension("tmp"), accounts_path)?;
+        fs::rename(history_path.with_extension("tmp"), history_path)?;
+        Ok(())
+    }
+
+    // Creates a manager with in-memory providers holding the checkpoint, for handling new requests. The options have
+    // to be set again and the scale is the one of the checkpointed run, like with import_state.
+    pub fn restore(dir: &Path, scale: u32) -> Result<Self, PaymentEngineError> {
+        let customer_account_provider = InMemoryCustomerAccountProvider::load_snapshot(
+            BufReader::new(File::open(dir.join(CHECKPOINT_ACCOUNTS_FILE))?),
+        )?
+        .with_scale(scale);
+        let transaction_history_provider = InMemoryTransactionHistoryProvider::load_snapshot(
+            BufReader::new(File::open(dir.join(CHECKPOINT_HISTORY_FILE))?),
+        )?;
+        Ok(Self::new(transaction_history_provider, customer_account_provider).with_scale(scale))
+    }
+
+    // Replays the client's transactions and their states from the history, so the result can be compared with the
+    // account provider for consistency checking
+    pub fn recompute_account(
+        &mut self,
+        client_id: CustomerId,
+        currency: &str,
+    ) -> Result<AccountBalances, PaymentEngineError> {
+        Ok(self
+            .replay_client_transactions(client_id, currency)?
+            .last()
+            .map(|(_, _, balances)| *balances)
+            .unwrap_or_default())
+    }
+
+    // The transactions of the client in the currency, in the processing order, with their states and the balances
+    // after each of them
+    fn replay_client_transactions(
+        &mut self,
+        client_id: CustomerId,
+        currency: &str,
+    ) -> Result<Vec<(TransactionRequest, TransactionState, AccountBalances)>, PaymentEngineError>
+    {
+        let mut balances = AccountBalances::default();
+        let mut replayed = Vec::new();
+        for transaction in self
             .transaction_history_provider
             .as_mut()
-            .read_transaction(transaction_request.transaction_id)?
+            .read_client_transactions(client_id)?
         {
-            if disputed_transaction.client_id != transaction_request.client_id {
-                info!("Client ID of the disputed transaction doesn't match the client ID of the request, possibly malicious client");
-                return Ok(false);
+            if transaction.currency_or(&self.default_currency) != currency {
+                continue;
             }
-
-            let disputed_amount = disputed_transaction
-                .amount
-                .expect("Disputed transaction doesn't have amount");
-
-            if let Some(disputed_transaction_state) = self
+            let amount = transaction.amount.ok_or(PaymentEngineError::AmountMissing(
+                transaction.transaction_id,
+            ))?;
+            let state = self
                 .transaction_history_provider
                 .as_mut()
-                .read_transaction_state(transaction_request.transaction_id)?
-            {
-                // Skipping if the transaction was not held or was already charged_back
-                if !disputed_transaction_state.held || disputed_transaction_state.charged_back {
-                    info!(
-                        "Transaction {} is not on hold or was charged back, not resolving",
-                        transaction_request.transaction_id
-                    );
-                    return Ok(false);
-                }
-                let existing_held_amount = self
+                .read_transaction_state(transaction.transaction_id)?
+                .unwrap_or_default();
+            let (available_change, held_change) = match transaction.transaction_type {
+                TransactionType::Deposit if state.held => (Decimal::ZERO, amount),
+                TransactionType::Deposit if state.charged_back => (Decimal::ZERO, Decimal::ZERO),
+                TransactionType::Deposit => (amount, Decimal::ZERO),
+                TransactionType::Withdrawal if state.held => (-amount, amount),
+                TransactionType::Withdrawal if state.charged_back => (Decimal::ZERO, Decimal::ZERO),
+                TransactionType::Withdrawal => (-amount, Decimal::ZERO),
+                _ => continue,
+            };
+            balances = AccountBalances {
+                available: balances
+                    .available
+                    .checked_add(available_change)
+                    .ok_or(PaymentEngineError::Overflow(client_id))?,
+                held: balances
+                    .held
+                    .checked_add(held_change)
+                    .ok_or(PaymentEngineError::Overflow(client_id))?,
+            };
+            replayed.push((transaction, state, balances));
+        }
+        Ok(replayed)
+    }
+
+    // Compares every account with the balances replayed from the history, including the clients that have only one
+    // of the two. A charged back transaction means the client has to be locked, while the other locks can't be
+    // derived from the history, e.g. the fraud holds, so only the missing ones are reported. With unlock requests
+    // allowed the locks are not checked at all. With repair the derived values are written into the account provider.
+    pub fn reconcile(&mut self, repair: bool) -> Result<Vec<Discrepancy>, PaymentEngineError> {
+        let mut currencies: BTreeMap<CustomerId, BTreeSet<Currency>> = BTreeMap::new();
+        for transaction in self.transaction_history_provider.iter_transactions() {
+            let transaction = transaction?;
+            currencies
+                .entry(transaction.client_id)
+                .or_default()
+                .insert(transaction.currency_or(&self.default_currency).to_owned());
+        }
+        for account in self.customer_account_provider.iter_accounts() {
+            currencies
+                .entry(account.client)
+                .or_default()
+                .insert(account.currency);
+        }
+        let mut discrepancies = Vec::new();
+        for (client_id, currencies) in currencies {
+            let mut charged_back = false;
+            for currency in currencies {
+                let replayed = self.replay_client_transactions(client_id, &currency)?;
+                charged_back |= replayed.iter().any(|(_, state, _)| state.charged_back);
+                let expected = replayed
+                    .last()
+                    .map(|(_, _, balances)| *balances)
+                    .unwrap_or_default();
+                let available = self
                     .customer_account_provider
-                    .as_mut()
-                    .get_held_amount(transaction_request.client_id)?
-                    .unwrap_or(Decimal::ZERO);
-                if existing_held_amount < disputed_amount {
-                    panic!("Something went wrong, disputed transaction funds are not held");
+                    .get_available(client_id, &currency)?
+                    .unwrap_or_default();
+                let held = self
+                    .customer_account_provider
+                    .get_held_amount(client_id, &currency)?
+                    .unwrap_or_default();
+                for (field, expected, actual) in [
+                    (AuditField::Available, expected.available, available),
+                    (AuditField::Held, expected.held, held),
+                ] {
+                    if expected != actual {
+                        discrepancies.push(Discrepancy {
+                            client: client_id,
+                            currency: Some(currency.clone()),
+                            field,
+                            expected: AuditValue::Amount(expected),
+                            actual: AuditValue::Amount(actual),
+                        });
+                    }
                 }
-                self.customer_account_provider.as_mut().set_available(
-                    transaction_request.client_id,
-                    existing_amount + disputed_amount,
-                )?;
-                self.customer_account_provider.as_mut().set_held_amount(
-                    transaction_request.client_id,
-                    existing_held_amount - disputed_amount,
-                )?;
-                let mut new_transaction_state = disputed_transaction_state.clone();
-                new_transaction_state.held = false;
-                self.transaction_history_provider
-                    .as_mut()
-                    .write_transaction_state(
-                        transaction_request.transaction_id,
-                        new_transaction_state,
-                    )?;
-                return Ok(true);
+            }
+            let locked = self
+                .customer_account_provider
+                .get_locked_status(client_id)?
+                .unwrap_or(false);
+            if charged_back && !locked && !self.allow_unlock {
+                discrepancies.push(Discrepancy {
+                    client: client_id,
+                    currency: None,
+                    field: AuditField::Locked,
+                    expected: AuditValue::Flag(true),
+                    actual: AuditValue::Flag(false),
+                });
+            }
+        }
+        if repair {
+            for discrepancy in &discrepancies {
+                self.repair(discrepancy)?;
             }
         }
-        Ok(false)
+        Ok(discrepancies)
     }
 
-    fn chargeback(&mut self, transaction_request: TransactionRequest) -> Result<bool, String> {
-        if let Some(disputed_transaction) = self
-            .transaction_history_provider
-            .as_mut()
-            .read_transaction(transaction_request.transaction_id)?
-        {
-            if disputed_transaction.client_id != transaction_request.client_id {
-                info!("Client ID of the disputed transaction doesn't match the client ID of the request, possibly malicious client");
-                return Ok(false);
+    // The available funds go first, as setting them creates the account when it's missing
+    fn repair(&mut self, discrepancy: &Discrepancy) -> Result<(), PaymentEngineError> {
+        // The repaired held funds are taken as the disputed ones again when the account is next touched
+        if let (Some(currency), AuditField::Held) = (&discrepancy.currency, discrepancy.field) {
+            self.disputed_amounts
+                .remove(&(discrepancy.client, currency.clone()));
+        }
+        let provider = self.customer_account_provider.as_mut();
+        match (
+            &discrepancy.currency,
+            discrepancy.field,
+            discrepancy.expected,
+        ) {
+            (Some(currency), AuditField::Available, AuditValue::Amount(amount)) => {
+                provider.set_available(discrepancy.client, currency, amount)
             }
-
-            let disputed_amount = disputed_transaction
-                .amount
-                .expect("Disputed transaction doesn't have amount");
-
-            if let Some(disputed_transaction_state) = self
-                .transaction_history_provider
-                .as_mut()
-                .read_transaction_state(transaction_request.transaction_id)?
-            {
-                // Skipping if the transaction was not held or was already charged_back
-                if !disputed_transaction_state.held || disputed_transaction_state.charged_back {
-                    info!(
-                        "Transaction {} is not on hold or was charged back, not charging back",
-                        transaction_request.transaction_id
-                    );
-                    return Ok(false);
-                }
-                let existing_held_amount = self
-                    .customer_account_provider
-                    .as_mut()
-                    .get_held_amount(transaction_request.client_id)?
-                    .unwrap_or(Decimal::ZERO);
-                if existing_held_amount < disputed_amount {
-                    panic!("Something went wrong, disputed transaction funds are not held");
+            (Some(currency), AuditField::Held, AuditValue::Amount(amount)) => {
+                if provider
+                    .get_available(discrepancy.client, currency)?
+                    .is_none()
+                {
+                    provider.set_available(discrepancy.client, currency, Decimal::ZERO)?;
                 }
-                self.customer_account_provider.as_mut().set_held_amount(
-                    transaction_request.client_id,
-                    existing_held_amount - disputed_amount,
-                )?;
-                self.customer_account_provider
-                    .as_mut()
-                    .set_locked_status(transaction_request.client_id, true)?;
-                let mut new_transaction_state = disputed_transaction_state.clone();
-                new_transaction_state.held = false;
-                new_transaction_state.charged_back = true;
-                self.transaction_history_provider
-                    .as_mut()
-                    .write_transaction_state(
-                        transaction_request.transaction_id,
-                        new_transaction_state,
-                    )?;
-                return Ok(true);
+                provider.set_held_amount(discrepancy.client, currency, amount)
+            }
+            (_, AuditField::Locked, AuditValue::Flag(locked)) => {
+                provider.set_locked_status(discrepancy.client, locked)
             }
+            _ => Err(PaymentEngineError::InvariantViolation(format!(
+                "Unexpected discrepancy {}",
+                discrepancy
+            ))),
         }
-        Ok(false)
     }
 
-    fn has_positive_amount(transaction_request: &TransactionRequest) -> bool {
-        transaction_request
-            .amount
-            .map_or(false, |amount| amount.gt(&Decimal::ZERO))
+    // Writes the CSV statement of the client's account in the currency, a row per transaction with the available
+    // balance recomputed from the history. A differing balance in the account provider is reported in a warning line
+    // after the rows.
+    pub fn generate_statement(
+        &mut self,
+        client_id: CustomerId,
+        currency: &str,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        let replayed = self.replay_client_transactions(client_id, currency)?;
+        let recomputed_available = replayed
+            .last()
+            .map_or(Decimal::ZERO, |(_, _, balances)| balances.available);
+        {
+            let mut csv_writer = WriterBuilder::new()
+                .has_headers(true)
+                .from_writer(&mut *writer);
+            for (transaction, state, balances) in replayed {
+                csv_writer.serialize(StatementRow {
+                    transaction_id: transaction.transaction_id,
+                    transaction_type: transaction.transaction_type,
+                    amount: transaction.amount,
+                    running_available: balances.available,
+                    state: StatementState::of(&state),
+                })?;
+            }
+            csv_writer.flush()?;
+        }
+        let available = self
+            .customer_account_provider
+            .get_available(client_id, currency)?
+            .unwrap_or_default();
+        if available != recomputed_available {
+            writeln!(
+                writer,
+                "warning: the recomputed available balance {} differs from the account's {}",
+                recomputed_available, available
+            )?;
+        }
+        Ok(())
     }
 
-    fn has_no_amount(transaction_request: &TransactionRequest) -> bool {
-        transaction_request.amount.is_none()
+    // Writes a CSV row with the transaction, client, amount and currency of every open dispute, ordered by the
+    // transaction ID. The resolved and charged back transactions are not listed.
+    pub fn write_disputes_report(
+        &mut self,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        let transaction_ids = self
+            .transaction_history_provider
+            .list_disputed_transactions()?;
+        let mut csv_writer = WriterBuilder::new().has_headers(true).from_writer(writer);
+        for transaction_id in transaction_ids {
+            let transaction = self
+                .transaction_history_provider
+                .read_transaction(transaction_id)?
+                .ok_or_else(|| {
+                    PaymentEngineError::InvariantViolation(format!(
+                        "Transaction {} is on hold but missing from the history",
+                        transaction_id
+                    ))
+                })?;
+            csv_writer.serialize(DisputeRow {
+                transaction_id,
+                client: transaction.client_id,
+                currency: transaction.currency_or(&self.default_currency).to_owned(),
+                amount: transaction.amount,
+            })?;
+        }
+        Ok(csv_writer.flush()?)
     }
-}
 
-impl TransactionsManager for DefaultTransactionsManager {
-    fn structure_validation(transaction_request: &TransactionRequest) -> bool {
-        match &transaction_request.transaction_type {
-            TransactionType::Deposit => Self::has_positive_amount(transaction_request),
-            TransactionType::Withdrawal => Self::has_positive_amount(transaction_request),
-            TransactionType::Dispute => Self::has_no_amount(transaction_request),
-            TransactionType::Resolve => Self::has_no_amount(transaction_request),
-            TransactionType::Chargeback => Self::has_no_amount(transaction_request),
-        }
+    pub fn print_disputes_report(&mut self) -> Result<(), PaymentEngineError> {
+        self.write_disputes_report(&mut std::io::stdout().lock())
     }
 
-    fn handle_transaction(
+    fn record_activity(
         &mut self,
-        transaction_request: TransactionRequest,
-    ) -> Result<bool, String> {
-        // TODO think about the system consistency if something goes wrong
-        // Maybe instead of thinking about current available amount, check the recent transactions and recalculate it? That will let us
-        // fix the consistency issue.
-        // Also maybe update the available and held funds at the same time instead of separate API calls?
-        //
-        match &transaction_request.transaction_type {
-            TransactionType::Deposit => self.deposit(transaction_request),
-            TransactionType::Withdrawal => self.withdraw(transaction_request),
-            TransactionType::Dispute => self.dispute(transaction_request),
-            TransactionType::Resolve => self.resolve(transaction_request),
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+        currency: &str,
+        timestamp: Option<i64>,
+    ) -> Result<(), PaymentEngineError> {
+        let sequence = self.client_sequences.entry(client_id).or_default();
+        *sequence += 1;
+        let sequence = *sequence;
+        // The sequence is only needed for the dispute window, saving a state write per transaction otherwise
+        if self.dispute_window.is_some()
+            && matches!(
+                transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            self.transaction_history_provider
+                .as_mut()
+                .write_transaction_state(
+                    transaction_id,
+                    TransactionState {
+                        sequence: Some(sequence),
+                        ..Default::default()
+                    },
+                )?;
+        }
+        if let Some(timestamp) = timestamp {
+            self.customer_account_provider
+                .as_mut()
+                .set_last_activity(client_id, currency, timestamp)?;
+        }
+        Ok(())
+    }
+
+    // Records the executed request in the invariant tracker, reading the disputed transaction for the dispute family
+    fn track_invariants(
+        &mut self,
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+        currency: &str,
+        amount: Option<Decimal>,
+    ) -> Result<(), PaymentEngineError> {
+        if self.invariant_tracker.is_none() {
+            return Ok(());
+        }
+        let (disputed_type, amount) = match transaction_type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                match self
+                    .transaction_history_provider
+                    .read_transaction(transaction_id)?
+                {
+                    Some(disputed) => (Some(disputed.transaction_type), disputed.amount),
+                    None => (None, None),
+                }
+            }
+            _ => (None, amount),
+        };
+        let amount = self.normalized(amount.unwrap_or_default());
+        if let Some(invariant_tracker) = self.invariant_tracker.as_mut() {
+            invariant_tracker.record(
+                transaction_type,
+                disputed_type.as_ref(),
+                client_id,
+                currency,
+                amount,
+            );
+        }
+        if disputed_type.is_none() {
+            return Ok(());
+        }
+        let held = self
+            .customer_account_provider
+            .get_held_amount(client_id, currency)?
+            .unwrap_or_default();
+        match &self.invariant_tracker {
+            Some(invariant_tracker) => invariant_tracker.check_held(client_id, currency, held),
+            None => Ok(()),
+        }
+    }
+
+    // The accounts whose total differs from what the executed requests since enabling the invariant tracking give.
+    // The accounts are expected to change only through the handled requests, so the check doesn't hold when they are
+    // modified around the manager, e.g. by reconcile with repair.
+    pub fn verify_invariants(&self) -> Result<Vec<FundsDivergence>, PaymentEngineError> {
+        match &self.invariant_tracker {
+            Some(invariant_tracker) => {
+                Ok(invariant_tracker.divergences(self.customer_account_provider.iter_accounts()))
+            }
+            None => Err(PaymentEngineError::InvariantViolation(
+                "The invariants are verified only with the invariant tracking enabled".to_owned(),
+            )),
+        }
+    }
+
+    // Puts the account on hold when the executed withdrawal crossed a limit of the fraud config
+    fn apply_fraud_rules(
+        &mut self,
+        transaction_type: &TransactionType,
+        client_id: CustomerId,
+        amount: Option<Decimal>,
+    ) -> Result<(), PaymentEngineError> {
+        if *transaction_type != TransactionType::Withdrawal {
+            return Ok(());
+        }
+        let amount = self.normalized(amount.unwrap_or_default());
+        let triggered = match self.fraud_monitor.as_mut() {
+            Some(fraud_monitor) => fraud_monitor.record_withdrawal(client_id, amount),
+            None => false,
+        };
+        if triggered {
+            warn!(
+                "The withdrawals of customer {} crossed the fraud limits, locking the account",
+                client_id
+            );
+            self.customer_account_provider
+                .as_mut()
+                .set_locked_status(client_id, true)?;
+            self.summary.fraud_holds += 1;
+        }
+        Ok(())
+    }
+
+    fn audited_fields(
+        &mut self,
+        client_id: CustomerId,
+        currency: &str,
+    ) -> Result<AuditedFields, PaymentEngineError> {
+        let customer_account_provider = self.customer_account_provider.as_mut();
+        Ok(AuditedFields {
+            available: customer_account_provider
+                .get_available(client_id, currency)?
+                .unwrap_or_default(),
+            held: customer_account_provider
+                .get_held_amount(client_id, currency)?
+                .unwrap_or_default(),
+            locked: customer_account_provider
+                .get_locked_status(client_id)?
+                .unwrap_or_default(),
+        })
+    }
+
+    // Records an event per field that differs from the snapshot taken before the transaction, returns the fields after
+    // it
+    fn audit_changes(
+        &mut self,
+        before: AuditedFields,
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+        currency: &str,
+    ) -> Result<AuditedFields, PaymentEngineError> {
+        let after = self.audited_fields(client_id, currency)?;
+        let changes = [
+            (
+                AuditField::Available,
+                AuditValue::Amount(before.available),
+                AuditValue::Amount(after.available),
+            ),
+            (
+                AuditField::Held,
+                AuditValue::Amount(before.held),
+                AuditValue::Amount(after.held),
+            ),
+            (
+                AuditField::Locked,
+                AuditValue::Flag(before.locked),
+                AuditValue::Flag(after.locked),
+            ),
+        ];
+        if let Some(audit_sink) = self.audit_sink.as_mut() {
+            for (field, old_value, new_value) in changes {
+                if old_value != new_value {
+                    audit_sink.record(AuditEvent {
+                        client: client_id,
+                        field,
+                        old_value,
+                        new_value,
+                        transaction_id,
+                        transaction_type: transaction_type.clone(),
+                        currency: currency.to_owned(),
+                    })?;
+                }
+            }
+        }
+        Ok(after)
+    }
+
+    // Only reads the balances when an audit record sink is set. The fatal errors stop the run, so they're not recorded
+    fn record_decision(
+        &mut self,
+        transaction_request: &TransactionRequest,
+        result: &Result<TransactionOutcome, PaymentEngineError>,
+    ) -> Result<(), PaymentEngineError> {
+        if self.audit_record_sink.is_none() || result.as_ref().is_err_and(|e| e.is_fatal()) {
+            return Ok(());
+        }
+        let currency = transaction_request
+            .currency_or(&self.default_currency)
+            .to_owned();
+        let after = self.audited_fields(transaction_request.client_id, &currency)?;
+        let (decision, reason) = AuditDecision::of(result);
+        match self.audit_record_sink.as_mut() {
+            Some(audit_record_sink) => audit_record_sink.record(AuditRecord {
+                transaction_id: transaction_request.transaction_id,
+                client: transaction_request.client_id,
+                transaction_type: transaction_request.transaction_type.clone(),
+                decision,
+                reason,
+                available_after: after.available,
+                held_after: after.held,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Tells the listeners the outcome of the request, then the lock and the negative balance it caused. The
+    // non-fatal errors are not reported, as the request neither executed nor got a skip reason.
+    fn notify_listeners(
+        &mut self,
+        transaction_type: &TransactionType,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+        currency: &str,
+        result: &Result<TransactionOutcome, PaymentEngineError>,
+        changes: Option<(AuditedFields, AuditedFields)>,
+    ) {
+        for event_listener in self.event_listeners.iter_mut() {
+            match result {
+                Ok(TransactionOutcome::Executed) => {
+                    event_listener.on_transaction_executed(
+                        transaction_type,
+                        transaction_id,
+                        client_id,
+                    );
+                }
+                Ok(TransactionOutcome::Skipped(reason)) => {
+                    event_listener.on_transaction_skipped(
+                        transaction_type,
+                        transaction_id,
+                        client_id,
+                        *reason,
+                    );
+                }
+                Err(_) => {}
+            }
+            if let Some((before, after)) = changes {
+                if after.locked && !before.locked {
+                    event_listener.on_account_locked(client_id, transaction_id);
+                }
+                if after.available < Decimal::ZERO && before.available >= Decimal::ZERO {
+                    event_listener.on_negative_available(client_id, currency, after.available);
+                }
+            }
+        }
+    }
+
+    // Runs the checks and the provider calls of the transaction, without the bookkeeping of handle_transaction
+    fn execute(
+        &mut self,
+        mut transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        // Normalized before anything else, so the history and the balances always agree on the amount
+        transaction_request.amount = transaction_request
+            .amount
+            .map(|amount| self.normalized(amount));
+        match transaction_request.transaction_type {
+            TransactionType::Deposit => self.deposit(transaction_request),
+            TransactionType::Withdrawal => self.withdraw(transaction_request),
+            TransactionType::Dispute => self.dispute(transaction_request),
+            TransactionType::Resolve => self.resolve(transaction_request),
             TransactionType::Chargeback => self.chargeback(transaction_request),
+            TransactionType::Unlock => self.unlock(transaction_request),
+        }
+    }
+
+    // Answers what handle_transaction would do with the request, without changing anything. It takes &mut self as the
+    // providers read through &mut self, but the transaction runs against preview providers that keep its writes in
+    // memory and are dropped afterwards, so the same checks apply without duplicating them. Strict mode, the summary,
+    // the activity and the audit sink are not involved.
+    pub fn preview_transaction(
+        &mut self,
+        transaction_request: &TransactionRequest,
+    ) -> Result<TransactionPreview, PaymentEngineError> {
+        if !self.structure_validation(transaction_request) {
+            return Ok(TransactionPreview::WouldSkip(SkipReason::InvalidStructure));
+        }
+        let transaction_history_provider: SharedProvider<dyn TransactionHistoryProvider> =
+            Rc::new(RefCell::new(std::mem::replace(
+                &mut self.transaction_history_provider,
+                Box::new(InMemoryTransactionHistoryProvider::new()),
+            )));
+        let customer_account_provider: SharedProvider<dyn CustomerAccountProvider> =
+            Rc::new(RefCell::new(std::mem::replace(
+                &mut self.customer_account_provider,
+                Box::new(InMemoryCustomerAccountProvider::new()),
+            )));
+        self.transaction_history_provider = Box::new(PreviewTransactionHistoryProvider::new(
+            transaction_history_provider.clone(),
+        ));
+        self.customer_account_provider = Box::new(PreviewCustomerAccountProvider::new(
+            customer_account_provider.clone(),
+        ));
+        // Taken out, so the previewed ID isn't inserted into it. The duplicate check reads the history meanwhile
+        let duplicate_detector = self.duplicate_detector.take();
+        let client_id = transaction_request.client_id;
+        let currency = transaction_request
+            .currency_or(&self.default_currency)
+            .to_owned();
+        let preview = self
+            .execute(transaction_request.clone())
+            .and_then(|outcome| match outcome {
+                TransactionOutcome::Executed => Ok(TransactionPreview::WouldExecute {
+                    available: self
+                        .customer_account_provider
+                        .get_available(client_id, &currency)?
+                        .unwrap_or_default(),
+                    held: self
+                        .customer_account_provider
+                        .get_held_amount(client_id, &currency)?
+                        .unwrap_or_default(),
+                }),
+                TransactionOutcome::Skipped(reason) => Ok(TransactionPreview::WouldSkip(reason)),
+            });
+        // Dropping the preview providers, so the manager holds the only references to the real ones again
+        self.transaction_history_provider = Box::new(InMemoryTransactionHistoryProvider::new());
+        self.customer_account_provider = Box::new(InMemoryCustomerAccountProvider::new());
+        self.transaction_history_provider = take_back(transaction_history_provider);
+        self.customer_account_provider = take_back(customer_account_provider);
+        self.duplicate_detector = duplicate_detector;
+        preview
+    }
+
+    // The lock policy in one place, every handler but unlock calls it before touching the account. Withdrawals are
+    // blocked on any locked account, the other requests only under LockPolicy::BlockAll. Unlocks are meant for the
+    // locked accounts, so they don't call it.
+    fn ensure_account_active(
+        &mut self,
+        transaction_request: &TransactionRequest,
+    ) -> Result<Option<SkipReason>, PaymentEngineError> {
+        if self.lock_policy != LockPolicy::BlockAll
+            && transaction_request.transaction_type != TransactionType::Withdrawal
+        {
+            return Ok(None);
+        }
+        let locked = self
+            .customer_account_provider
+            .as_mut()
+            .get_locked_status(transaction_request.client_id)?
+            .unwrap_or(false);
+        Ok(locked.then_some(SkipReason::AccountLocked))
+    }
+
+    // Counters of everything handled so far
+    pub fn summary(&self) -> &ProcessingSummary {
+        &self.summary
+    }
+
+    // Includes the requests handled before the snapshot the manager was restored from
+    pub fn processed_requests(&self) -> usize {
+        self.processed_requests
+    }
+
+    pub fn list_accounts(&self) -> Result<Vec<CustomerAccountReport>, PaymentEngineError> {
+        self.customer_account_provider.list_accounts()
+    }
+
+    pub fn read_transaction_state(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionState>, PaymentEngineError> {
+        self.transaction_history_provider
+            .as_mut()
+            .read_transaction_state(transaction_id)
+    }
+
+    // The account of the client in the default currency, the same as its row in the report
+    pub fn account(
+        &self,
+        client_id: CustomerId,
+    ) -> Result<Option<CustomerAccountReport>, PaymentEngineError> {
+        self.customer_account_provider
+            .get_account(client_id, &self.default_currency)
+    }
+
+    // The executed deposit or withdrawal with the ID along with its state. None for the IDs never executed, e.g.
+    // the skipped ones.
+    pub fn transaction(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<(TransactionRequest, Option<TransactionState>)>, PaymentEngineError> {
+        let Some(transaction) = self
+            .transaction_history_provider
+            .read_transaction(transaction_id)?
+        else {
+            return Ok(None);
+        };
+        let state = self
+            .transaction_history_provider
+            .read_transaction_state(transaction_id)?;
+        Ok(Some((transaction, state)))
+    }
+
+    // Validates and handles a single request like process_all, for the shards handling their requests one by one
+    pub(crate) fn process_one(
+        &mut self,
+        request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError> {
+        self.process_request(request, &mut None)
+    }
+
+    // Whether a deposit or withdrawal with the ID was executed, by this manager or by another shard it was told about
+    pub(crate) fn knows_executed(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<bool, PaymentEngineError> {
+        Ok(self.executed_elsewhere.contains(&transaction_id)
+            || self.read_stored_transaction(transaction_id)?.is_some())
+    }
+
+    // The deposits and withdrawals reusing the ID are skipped as CrossClientIdReuse from now on
+    pub(crate) fn mark_executed_elsewhere(&mut self, transaction_id: TransactionId) {
+        self.executed_elsewhere.insert(transaction_id);
+    }
+
+    // IDs of all the executed deposits and withdrawals, for checking the invariants of their states in the tests
+    #[cfg(test)]
+    pub(crate) fn known_transaction_ids(&self) -> Result<Vec<TransactionId>, PaymentEngineError> {
+        self.transaction_history_provider
+            .iter_transactions()
+            .map(|transaction| transaction.map(|transaction| transaction.transaction_id))
+            .collect()
+    }
+
+    pub fn with_report_order(mut self, report_order: ReportOrder) -> Self {
+        self.report_order = report_order;
+        self
+    }
+
+    pub fn with_report_format(mut self, report_format: ReportFormat) -> Self {
+        self.report_format = report_format;
+        self
+    }
+
+    pub fn with_detailed_report(mut self, detailed_report: bool) -> Self {
+        self.detailed_report = detailed_report;
+        self
+    }
+
+    // Pads the amounts of the report with zeros to exactly the scale, e.g. 1.5000, for the consumers expecting a
+    // fixed number of decimal places
+    pub fn with_fixed_scale_report(mut self, fixed_scale_report: bool) -> Self {
+        self.fixed_scale_report = fixed_scale_report;
+        self
+    }
+
+    // Should match the scale of the reader, the amounts with more decimal places are truncated to it
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    // Used for the requests without a currency, so the inputs without the currency column keep working
+    pub fn with_default_currency(mut self, default_currency: impl Into<Currency>) -> Self {
+        self.default_currency = default_currency.into();
+        self
+    }
+
+    fn normalized(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.scale, SCALE_ROUNDING)
+    }
+
+    // Some(reason) when a transaction with the same ID was already executed
+    fn duplicate_skip_reason(
+        &mut self,
+        transaction_request: &TransactionRequest,
+    ) -> Result<Option<SkipReason>, PaymentEngineError> {
+        // Executed by a client of another shard, so it can't be the same client
+        if self
+            .executed_elsewhere
+            .contains(&transaction_request.transaction_id)
+        {
+            return Ok(Some(SkipReason::CrossClientIdReuse));
+        }
+        if let Some(write_batch) = &self.write_batch {
+            if write_batch.is_new(transaction_request.transaction_id) {
+                return Ok(None);
+            }
+        }
+        let Some(duplicate_detector) = &self.duplicate_detector else {
+            return Ok(self
+                .read_stored_transaction(transaction_request.transaction_id)?
+                .map(|stored| duplicate_skip_reason(&stored, transaction_request)));
+        };
+        if !duplicate_detector.contains(transaction_request.transaction_id) {
+            return Ok(None);
+        }
+        // Missing from the history when the detector mistook the ID for a seen one
+        Ok(self
+            .read_stored_transaction(transaction_request.transaction_id)?
+            .map(|stored| duplicate_skip_reason(&stored, transaction_request)))
+    }
+
+    // Reads the executed transaction from the write batch if it's not written yet, otherwise from the history
+    fn read_stored_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRequest>, PaymentEngineError> {
+        if let Some(pending) = self
+            .write_batch
+            .as_ref()
+            .and_then(|write_batch| write_batch.pending(transaction_id))
+        {
+            return Ok(Some(pending.clone()));
+        }
+        self.transaction_history_provider
+            .as_mut()
+            .read_transaction(transaction_id)
+    }
+
+    // Into the write batch while process_all executes one
+    fn write_executed_transaction(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<(), PaymentEngineError> {
+        if let Some(duplicate_detector) = self.duplicate_detector.as_mut() {
+            duplicate_detector.insert(transaction_request.transaction_id);
+        }
+        match self.write_batch.as_mut() {
+            Some(write_batch) => {
+                write_batch.push(transaction_request);
+                Ok(())
+            }
+            None => self
+                .transaction_history_provider
+                .write_transaction(transaction_request),
+        }
+    }
+
+    fn deposit(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(skip_reason) = self.ensure_account_active(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(skip_reason) = self.duplicate_skip_reason(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(skip_reason) = self.limit_skip_reason(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        let currency = transaction_request.currency_or(&self.default_currency);
+        let (existing_amount, total_deposited) = self
+            .customer_account_provider
+            .get_account(transaction_request.client_id, currency)?
+            .map_or((Decimal::ZERO, Decimal::ZERO), |account| {
+                (account.available, account.total_deposited)
+            });
+        let transaction_amount =
+            transaction_request
+                .amount
+                .ok_or(PaymentEngineError::AmountMissing(
+                    transaction_request.transaction_id,
+                ))?;
+        // The lifetime sum is checked before any write as well, so the balance is never credited without the
+        // transaction being recorded
+        let new_amount = match (
+            existing_amount.checked_add(transaction_amount),
+            total_deposited.checked_add(transaction_amount),
+        ) {
+            (Some(new_amount), Some(_)) => self.normalized(new_amount),
+            _ => return Ok(TransactionOutcome::Skipped(SkipReason::Overflow)),
+        };
+        if let Some(skip_reason) =
+            Self::skip_reason_of(self.customer_account_provider.as_mut().set_available(
+                transaction_request.client_id,
+                transaction_request.currency_or(&self.default_currency),
+                new_amount,
+            ))?
+        {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        self.customer_account_provider.as_mut().add_deposited(
+            transaction_request.client_id,
+            transaction_request.currency_or(&self.default_currency),
+            transaction_amount,
+        )?;
+        self.write_executed_transaction(transaction_request)?;
+        Ok(TransactionOutcome::Executed)
+    }
+
+    // Checked before any change, so a request beyond the limits leaves the account as it was. The account total is
+    // only read when that limit is set.
+    fn limit_skip_reason(
+        &mut self,
+        transaction_request: &TransactionRequest,
+    ) -> Result<Option<SkipReason>, PaymentEngineError> {
+        let Some(amount) = transaction_request.amount else {
+            return Ok(None);
+        };
+        if self
+            .limits
+            .max_transaction_amount
+            .is_some_and(|max_transaction_amount| amount > max_transaction_amount)
+        {
+            return Ok(Some(SkipReason::TransactionLimitExceeded));
+        }
+        let Some(max_account_total) = self.limits.max_account_total else {
+            return Ok(None);
+        };
+        if transaction_request.transaction_type != TransactionType::Deposit {
+            return Ok(None);
+        }
+        let total = self
+            .customer_account_provider
+            .get_total(
+                transaction_request.client_id,
+                transaction_request.currency_or(&self.default_currency),
+            )?
+            .unwrap_or_default();
+        Ok(total
+            .checked_add(amount)
+            .is_none_or(|new_total| new_total > max_account_total)
+            .then_some(SkipReason::AccountTotalLimitExceeded))
+    }
+
+    fn withdraw(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(skip_reason) = self.ensure_account_active(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(skip_reason) = self.duplicate_skip_reason(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(skip_reason) = self.limit_skip_reason(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
         }
+        if let Some(account) = self.customer_account_provider.get_account(
+            transaction_request.client_id,
+            transaction_request.currency_or(&self.default_currency),
+        )? {
+            let existing_amount = account.available;
+            let transaction_amount =
+                transaction_request
+                    .amount
+                    .ok_or(PaymentEngineError::AmountMissing(
+                        transaction_request.transaction_id,
+                    ))?;
+            if existing_amount >= transaction_amount {
+                // The lifetime sum is checked before any write as well, so the balance is never debited without the
+                // transaction being recorded
+                let new_amount = match (
+                    existing_amount.checked_sub(transaction_amount),
+                    account.total_withdrawn.checked_add(transaction_amount),
+                ) {
+                    (Some(new_amount), Some(_)) => self.normalized(new_amount),
+                    _ => return Ok(TransactionOutcome::Skipped(SkipReason::Overflow)),
+                };
+                if let Some(skip_reason) =
+                    Self::skip_reason_of(self.customer_account_provider.as_mut().set_available(
+                        transaction_request.client_id,
+                        transaction_request.currency_or(&self.default_currency),
+                        new_amount,
+                    ))?
+                {
+                    return Ok(TransactionOutcome::Skipped(skip_reason));
+                }
+                self.customer_account_provider.as_mut().add_withdrawn(
+                    transaction_request.client_id,
+                    transaction_request.currency_or(&self.default_currency),
+                    transaction_amount,
+                )?;
+                self.write_executed_transaction(transaction_request)?;
+                return Ok(TransactionOutcome::Executed);
+            } else {
+                return Ok(TransactionOutcome::Skipped(SkipReason::InsufficientFunds));
+            }
+        }
+        Ok(TransactionOutcome::Skipped(SkipReason::AccountNotFound))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(skip_reason) = self.ensure_account_active(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(disputed_transaction) = self
+            .transaction_history_provider
+            .as_mut()
+            .read_transaction(transaction_request.transaction_id)?
+        {
+            if disputed_transaction.client_id != transaction_request.client_id {
+                return Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch));
+            }
+            if let Some(skip_reason) =
+                self.currency_mismatch(&disputed_transaction, &transaction_request)
+            {
+                return Ok(TransactionOutcome::Skipped(skip_reason));
+            }
+
+            let disputed_amount = self.normalized(disputed_transaction.amount.ok_or(
+                PaymentEngineError::AmountMissing(disputed_transaction.transaction_id),
+            )?);
+
+            let disputed_transaction_state = self
+                .transaction_history_provider
+                .as_mut()
+                .read_transaction_state(transaction_request.transaction_id)?;
+            // Skipping if the transaction was already disputed or charged back
+            if Some(true)
+                == disputed_transaction_state
+                    .as_ref()
+                    .map(|state| state.held || state.charged_back)
+            {
+                return Ok(TransactionOutcome::Skipped(SkipReason::AlreadyDisputed));
+            }
+            if !self.allow_redispute
+                && Some(true)
+                    == disputed_transaction_state
+                        .as_ref()
+                        .map(|state| state.resolved)
+            {
+                return Ok(TransactionOutcome::Skipped(SkipReason::RedisputeForbidden));
+            }
+            if let (Some(max_disputes), Some(state)) =
+                (self.max_disputes, disputed_transaction_state.as_ref())
+            {
+                if state.dispute_count >= max_disputes {
+                    return Ok(TransactionOutcome::Skipped(SkipReason::DisputeLimitReached));
+                }
+            }
+            if let (Some(dispute_window), Some(sequence)) = (
+                self.dispute_window,
+                disputed_transaction_state
+                    .as_ref()
+                    .and_then(|state| state.sequence),
+            ) {
+                let executed_since = self
+                    .client_sequences
+                    .get(&transaction_request.client_id)
+                    .map_or(0, |latest| latest.saturating_sub(sequence));
+                if executed_since > dispute_window {
+                    return Ok(TransactionOutcome::Skipped(
+                        SkipReason::DisputeWindowExpired,
+                    ));
+                }
+            }
+            let available_change = match disputed_transaction.transaction_type {
+                // Allowing disputes even if they will create negative available funds. Customers first!
+                TransactionType::Deposit => -disputed_amount,
+                // The funds already left the account, so only holding the amount pending its return
+                TransactionType::Withdrawal => Decimal::ZERO,
+                _ => return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputable)),
+            };
+
+            let previous_transaction_state = disputed_transaction_state.unwrap_or_default();
+            let mut new_transaction_state = previous_transaction_state.clone();
+            new_transaction_state.held = true;
+            new_transaction_state.dispute_count += 1;
+            return self.settle_dispute(
+                &transaction_request,
+                previous_transaction_state,
+                new_transaction_state,
+                AccountMutation {
+                    available_delta: available_change,
+                    held_delta: disputed_amount,
+                    lock: false,
+                    charged_back: Decimal::ZERO,
+                    open_disputes_delta: 1,
+                },
+            );
+        }
+        Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound))
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(skip_reason) = self.ensure_account_active(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(disputed_transaction) = self
+            .transaction_history_provider
+            .as_mut()
+            .read_transaction(transaction_request.transaction_id)?
+        {
+            if disputed_transaction.client_id != transaction_request.client_id {
+                return Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch));
+            }
+            if let Some(skip_reason) =
+                self.currency_mismatch(&disputed_transaction, &transaction_request)
+            {
+                return Ok(TransactionOutcome::Skipped(skip_reason));
+            }
+
+            let disputed_amount = self.normalized(disputed_transaction.amount.ok_or(
+                PaymentEngineError::AmountMissing(disputed_transaction.transaction_id),
+            )?);
+
+            if let Some(disputed_transaction_state) = self
+                .transaction_history_provider
+                .as_mut()
+                .read_transaction_state(transaction_request.transaction_id)?
+            {
+                // Skipping if the transaction was not held or was already charged_back
+                if !disputed_transaction_state.held || disputed_transaction_state.charged_back {
+                    return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed));
+                }
+                self.ensure_disputed_funds_held(&transaction_request, disputed_amount)?;
+                // Disputed deposits get their funds back to available, while for withdrawals the hold is just released
+                let available_change =
+                    if disputed_transaction.transaction_type == TransactionType::Deposit {
+                        disputed_amount
+                    } else {
+                        Decimal::ZERO
+                    };
+                let mut new_transaction_state = disputed_transaction_state.clone();
+                new_transaction_state.held = false;
+                new_transaction_state.resolved = true;
+                return self.settle_dispute(
+                    &transaction_request,
+                    disputed_transaction_state,
+                    new_transaction_state,
+                    AccountMutation {
+                        available_delta: available_change,
+                        held_delta: -disputed_amount,
+                        lock: false,
+                        charged_back: Decimal::ZERO,
+                        open_disputes_delta: -1,
+                    },
+                );
+            }
+            return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed));
+        }
+        Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound))
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if let Some(skip_reason) = self.ensure_account_active(&transaction_request)? {
+            return Ok(TransactionOutcome::Skipped(skip_reason));
+        }
+        if let Some(disputed_transaction) = self
+            .transaction_history_provider
+            .as_mut()
+            .read_transaction(transaction_request.transaction_id)?
+        {
+            if disputed_transaction.client_id != transaction_request.client_id {
+                return Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch));
+            }
+            if let Some(skip_reason) =
+                self.currency_mismatch(&disputed_transaction, &transaction_request)
+            {
+                return Ok(TransactionOutcome::Skipped(skip_reason));
+            }
+
+            let disputed_amount = self.normalized(disputed_transaction.amount.ok_or(
+                PaymentEngineError::AmountMissing(disputed_transaction.transaction_id),
+            )?);
+
+            if let Some(disputed_transaction_state) = self
+                .transaction_history_provider
+                .as_mut()
+                .read_transaction_state(transaction_request.transaction_id)?
+            {
+                // Skipping if the transaction was not held or was already charged_back
+                if !disputed_transaction_state.held || disputed_transaction_state.charged_back {
+                    return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed));
+                }
+                self.ensure_disputed_funds_held(&transaction_request, disputed_amount)?;
+                // Charging back a withdrawal returns the withdrawn amount to the client
+                let available_change =
+                    if disputed_transaction.transaction_type == TransactionType::Withdrawal {
+                        disputed_amount
+                    } else {
+                        Decimal::ZERO
+                    };
+                let mut new_transaction_state = disputed_transaction_state.clone();
+                new_transaction_state.held = false;
+                new_transaction_state.charged_back = true;
+                return self.settle_dispute(
+                    &transaction_request,
+                    disputed_transaction_state,
+                    new_transaction_state,
+                    AccountMutation {
+                        available_delta: available_change,
+                        held_delta: -disputed_amount,
+                        lock: true,
+                        charged_back: disputed_amount,
+                        open_disputes_delta: -1,
+                    },
+                );
+            }
+            return Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed));
+        }
+        Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound))
+    }
+
+    // A transaction on hold means its amount is held in the client's account. Anything else is an inconsistency
+    // between the providers, so it fails the run instead of skipping the request.
+    fn ensure_disputed_funds_held(
+        &mut self,
+        transaction_request: &TransactionRequest,
+        disputed_amount: Decimal,
+    ) -> Result<(), PaymentEngineError> {
+        let currency = transaction_request.currency_or(&self.default_currency);
+        match self
+            .customer_account_provider
+            .as_mut()
+            .get_held_amount(transaction_request.client_id, currency)?
+        {
+            Some(held_amount) if held_amount >= disputed_amount => Ok(()),
+            Some(held_amount) => Err(PaymentEngineError::InvariantViolation(format!(
+                "Transaction {} of {} is on hold, but customer {} only has {} held",
+                transaction_request.transaction_id,
+                disputed_amount,
+                transaction_request.client_id,
+                held_amount
+            ))),
+            None => Err(PaymentEngineError::InvariantViolation(format!(
+                "Transaction {} is on hold, but customer {} doesn't have an account in {}",
+                transaction_request.transaction_id, transaction_request.client_id, currency
+            ))),
+        }
+    }
+
+    // Writes the new state of the disputed transaction, then applies the account mutation in one provider call. A
+    // failed state write leaves the account untouched, while a rejected mutation gets the previous state restored, so
+    // the state and the balances never disagree
+    fn settle_dispute(
+        &mut self,
+        transaction_request: &TransactionRequest,
+        previous_transaction_state: TransactionState,
+        new_transaction_state: TransactionState,
+        mutation: AccountMutation,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        // Every dispute-family request holds or releases exactly the disputed amount
+        let disputed_after = self.check_held_after(transaction_request, mutation.held_delta)?;
+        self.transaction_history_provider
+            .as_mut()
+            .write_transaction_state(transaction_request.transaction_id, new_transaction_state)?;
+        let result = self.customer_account_provider.as_mut().apply_mutation(
+            transaction_request.client_id,
+            transaction_request.currency_or(&self.default_currency),
+            mutation,
+        );
+        if result.is_err() {
+            self.transaction_history_provider
+                .as_mut()
+                .write_transaction_state(
+                    transaction_request.transaction_id,
+                    previous_transaction_state,
+                )?;
+        }
+        Ok(match Self::skip_reason_of(result)? {
+            Some(skip_reason) => TransactionOutcome::Skipped(skip_reason),
+            None => {
+                self.disputed_amounts.insert(
+                    (
+                        transaction_request.client_id,
+                        transaction_request
+                            .currency_or(&self.default_currency)
+                            .to_owned(),
+                    ),
+                    disputed_after,
+                );
+                TransactionOutcome::Executed
+            }
+        })
+    }
+
+    // The held funds can never be negative nor more than the amounts of the open disputes of the account, checked
+    // whether the invariant tracking is on or not. Gives the disputed amount after the request.
+    fn check_held_after(
+        &mut self,
+        transaction_request: &TransactionRequest,
+        held_delta: Decimal,
+    ) -> Result<Decimal, PaymentEngineError> {
+        let currency = transaction_request.currency_or(&self.default_currency);
+        let held = self
+            .customer_account_provider
+            .as_mut()
+            .get_held_amount(transaction_request.client_id, currency)?
+            .unwrap_or_default();
+        let disputed = *self
+            .disputed_amounts
+            .entry((transaction_request.client_id, currency.to_owned()))
+            .or_insert(held);
+        // Overflows are left to the provider, which skips the mutation
+        let (Some(held_after), Some(disputed_after)) = (
+            held.checked_add(held_delta),
+            disputed.checked_add(held_delta),
+        ) else {
+            return Ok(disputed);
+        };
+        if held_after < Decimal::ZERO || held_after > disputed_after {
+            return Err(PaymentEngineError::HeldFundsOutOfBounds {
+                client_id: transaction_request.client_id,
+                currency: currency.to_owned(),
+                held: held_after,
+                disputed: disputed_after,
+            });
+        }
+        Ok(disputed_after)
+    }
+
+    // The dispute-family requests only apply to the disputed transaction in its own currency
+    fn currency_mismatch(
+        &self,
+        disputed_transaction: &TransactionRequest,
+        transaction_request: &TransactionRequest,
+    ) -> Option<SkipReason> {
+        let disputed_currency = disputed_transaction.currency_or(&self.default_currency);
+        let currency = transaction_request.currency_or(&self.default_currency);
+        (disputed_currency != currency).then_some(SkipReason::CurrencyMismatch)
+    }
+
+    fn park(&mut self, follow_up: TransactionRequest) -> TransactionOutcome {
+        let Some(pending_follow_ups) = self.pending_follow_ups.as_mut() else {
+            return TransactionOutcome::Skipped(SkipReason::TransactionNotFound);
+        };
+        let evicted = pending_follow_ups.park(follow_up);
+        self.summary.unresolved_references = pending_follow_ups.len();
+        let Some(evicted) = evicted else {
+            return TransactionOutcome::Skipped(SkipReason::AwaitingTransaction);
+        };
+        self.summary.evicted_references += 1;
+        warn!(
+            "No room left for parking, evicted the {} of transaction {} by client {}",
+            evicted.transaction_type, evicted.transaction_id, evicted.client_id
+        );
+        // Nothing is parked only when there's no capacity at all, the request itself was evicted then
+        if self.summary.unresolved_references == 0 {
+            return TransactionOutcome::Skipped(SkipReason::TransactionNotFound);
+        }
+        TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)
+    }
+
+    // Handles the requests parked for the just executed transaction in their arrival order, each of them counted in
+    // the summary again with its final outcome
+    fn handle_parked_follow_ups(
+        &mut self,
+        transaction_id: TransactionId,
+    ) -> Result<(), PaymentEngineError> {
+        let Some(pending_follow_ups) = self.pending_follow_ups.as_mut() else {
+            return Ok(());
+        };
+        let follow_ups = pending_follow_ups.take(transaction_id);
+        self.summary.unresolved_references = pending_follow_ups.len();
+        if !follow_ups.is_empty() {
+            // They read the transaction they reference
+            self.flush_write_batch()?;
+        }
+        for follow_up in follow_ups {
+            match self.handle_transaction(follow_up) {
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => warn!(
+                    "Parked request of transaction {} failed: {}",
+                    transaction_id, e
+                ),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Only flips the locked flag, the balances and the transaction states stay as they are
+    fn unlock(
+        &mut self,
+        transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        if !self.allow_unlock {
+            return Ok(TransactionOutcome::Skipped(SkipReason::UnlockForbidden));
+        }
+        match self
+            .customer_account_provider
+            .as_mut()
+            .get_locked_status(transaction_request.client_id)?
+        {
+            Some(true) => {
+                // The open disputes still have to be settled, a chargeback among them would lock the account again
+                if self
+                    .customer_account_provider
+                    .list_client_accounts(transaction_request.client_id)?
+                    .iter()
+                    .any(|account| account.open_disputes > 0 || !account.held.is_zero())
+                {
+                    return Ok(TransactionOutcome::Skipped(SkipReason::OpenDisputes));
+                }
+                self.customer_account_provider
+                    .as_mut()
+                    .set_locked_status(transaction_request.client_id, false)?;
+                Ok(TransactionOutcome::Executed)
+            }
+            Some(false) => Ok(TransactionOutcome::Skipped(SkipReason::NotLocked)),
+            None => Ok(TransactionOutcome::Skipped(SkipReason::AccountNotFound)),
+        }
+    }
+
+    // The skips that point to inconsistent input are turned into errors, the rest are kept as they are
+    fn strict_result(
+        result: Result<TransactionOutcome, PaymentEngineError>,
+        transaction_id: TransactionId,
+        client_id: CustomerId,
+        stored_client_id: Option<CustomerId>,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        match result {
+            Ok(TransactionOutcome::Skipped(SkipReason::DuplicateTransaction)) => {
+                Err(PaymentEngineError::DuplicateTransaction(transaction_id))
+            }
+            Ok(TransactionOutcome::Skipped(SkipReason::ConflictingDuplicate)) => {
+                Err(PaymentEngineError::ConflictingDuplicate(transaction_id))
+            }
+            Ok(TransactionOutcome::Skipped(SkipReason::CrossClientIdReuse)) => {
+                Err(PaymentEngineError::CrossClientIdReuse {
+                    transaction_id,
+                    stored_client_id: stored_client_id.unwrap_or_default(),
+                    client_id,
+                })
+            }
+            Ok(TransactionOutcome::Skipped(SkipReason::InsufficientFunds)) => {
+                Err(PaymentEngineError::InsufficientFunds(client_id))
+            }
+            Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch)) => {
+                Err(PaymentEngineError::ClientMismatch {
+                    transaction_id,
+                    client_id,
+                })
+            }
+            result => result,
+        }
+    }
+
+    // Account provider failures that only affect the given request are skipped, the rest are propagated
+    fn skip_reason_of(
+        result: Result<(), PaymentEngineError>,
+    ) -> Result<Option<SkipReason>, PaymentEngineError> {
+        match result {
+            Ok(()) => Ok(None),
+            Err(PaymentEngineError::AccountNotFound(_)) => Ok(Some(SkipReason::AccountNotFound)),
+            Err(PaymentEngineError::Overflow(_)) => Ok(Some(SkipReason::Overflow)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl TransactionsManager for DefaultTransactionsManager {
+    fn structure_validation(&self, transaction_request: &TransactionRequest) -> bool {
+        self.validation_config.is_valid(transaction_request)
+    }
+
+    fn handle_transaction(
+        &mut self,
+        mut transaction_request: TransactionRequest,
+    ) -> Result<TransactionOutcome, PaymentEngineError> {
+        self.validation_config
+            .drop_ignored_amount(&mut transaction_request);
+        // The library callers may skip structure_validation, so the handlers never see e.g. a negative amount
+        if !self.structure_validation(&transaction_request) {
+            log_skip(SkipReason::InvalidStructure, &transaction_request);
+            let result = Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure));
+            self.summary
+                .record(&transaction_request.transaction_type, &result);
+            let currency = transaction_request
+                .currency_or(&self.default_currency)
+                .to_owned();
+            self.notify_listeners(
+                &transaction_request.transaction_type,
+                transaction_request.transaction_id,
+                transaction_request.client_id,
+                &currency,
+                &result,
+                None,
+            );
+            self.record_decision(&transaction_request, &result)?;
+            return result;
+        }
+        // If the accounts drift from the history after a failure, reconcile recomputes them from it
+        let logged_request = transaction_request.clone();
+        let transaction_type = transaction_request.transaction_type.clone();
+        let (transaction_id, client_id, amount, timestamp) = (
+            transaction_request.transaction_id,
+            transaction_request.client_id,
+            transaction_request.amount,
+            transaction_request.timestamp,
+        );
+        if let Some(fraud_monitor) = self.fraud_monitor.as_mut() {
+            fraud_monitor.advance();
+        }
+        let currency = transaction_request
+            .currency_or(&self.default_currency)
+            .to_owned();
+        // Reading the fields only when auditing or notifying, as it costs a few extra provider calls per request
+        let audited_before = if self.audit_sink.is_some() || !self.event_listeners.is_empty() {
+            Some(self.audited_fields(client_id, &currency)?)
+        } else {
+            None
+        };
+        // Kept for parking, in case the referenced transaction didn't arrive yet
+        let follow_up = (self.pending_follow_ups.is_some()
+            && references_transaction(&transaction_type))
+        .then(|| transaction_request.clone());
+        let result = match (self.execute(transaction_request), follow_up) {
+            (Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound)), Some(follow_up)) => {
+                Ok(self.park(follow_up))
+            }
+            (result, _) => result,
+        };
+        let mut changes = None;
+        // Only the executed transactions count as activity of the client
+        let result = match result {
+            Ok(TransactionOutcome::Executed) => self
+                .record_activity(
+                    &transaction_type,
+                    transaction_id,
+                    client_id,
+                    &currency,
+                    timestamp,
+                )
+                .and_then(|()| self.apply_fraud_rules(&transaction_type, client_id, amount))
+                .and_then(|()| {
+                    self.track_invariants(
+                        &transaction_type,
+                        transaction_id,
+                        client_id,
+                        &currency,
+                        amount,
+                    )
+                })
+                .and_then(|()| match audited_before {
+                    Some(before) => self
+                        .audit_changes(
+                            before,
+                            &transaction_type,
+                            transaction_id,
+                            client_id,
+                            &currency,
+                        )
+                        .map(|after| changes = Some((before, after))),
+                    None => Ok(()),
+                })
+                .map(|()| TransactionOutcome::Executed),
+            result => result,
+        };
+        // Before the strict mode turns the skips into errors, so they're recorded with their reason
+        self.record_decision(&logged_request, &result)?;
+        let result = if self.strict_mode {
+            // The owner of the reused ID is only needed for the error
+            let stored_client_id = match result {
+                Ok(TransactionOutcome::Skipped(SkipReason::CrossClientIdReuse)) => self
+                    .read_stored_transaction(transaction_id)?
+                    .map(|stored| stored.client_id),
+                _ => None,
+            };
+            Self::strict_result(result, transaction_id, client_id, stored_client_id)
+        } else {
+            result
+        };
+        self.summary.record(&transaction_type, &result);
+        self.notify_listeners(
+            &transaction_type,
+            transaction_id,
+            client_id,
+            &currency,
+            &result,
+            changes,
+        );
+        match &result {
+            Ok(TransactionOutcome::Executed) => log_executed(&logged_request),
+            Ok(TransactionOutcome::Skipped(reason)) => log_skip(*reason, &logged_request),
+            Err(_) => {}
+        }
+        if result == Ok(TransactionOutcome::Executed)
+            && matches!(
+                transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            self.handle_parked_follow_ups(transaction_id)?;
+        }
+        result
+    }
+
+    fn write_report(&self, writer: &mut dyn Write) -> Result<(), PaymentEngineError> {
+        self.write_report_with(
+            self.report_format.formatter(self.detailed_report).as_ref(),
+            writer,
+        )
+    }
+
+    fn print_report(&self) -> Result<(), PaymentEngineError> {
+        self.write_report(&mut std::io::stdout().lock())
+    }
+}
+
+impl DefaultTransactionsManager {
+    // Writes the report in the format of the given formatter instead of the configured one
+    pub fn write_report_with(
+        &self,
+        formatter: &dyn ReportFormatter,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentEngineError> {
+        // Only sorting needs all the accounts at once, otherwise streaming them straight into the writer
+        let accounts: Box<dyn Iterator<Item = CustomerAccountReport>> = match self.report_order {
+            ReportOrder::SortedByClient => Box::new(
+                self.customer_account_provider
+                    .list_accounts_sorted()?
+                    .into_iter(),
+            ),
+            ReportOrder::ProviderOrder => self.customer_account_provider.iter_accounts(),
+        };
+        write_accounts(
+            writer,
+            accounts,
+            formatter,
+            self.scale,
+            self.fixed_scale_report,
+        )
+    }
+}
+
+// An identical request is a retry of the stored one, while any difference means the ID was reused for another
+// transaction, which points to a problem in the source data. Reusing the ID of another client is flagged separately,
+// as it can be an attempt to tamper with their transaction.
+pub(crate) fn duplicate_skip_reason(
+    stored: &TransactionRequest,
+    transaction_request: &TransactionRequest,
+) -> SkipReason {
+    if stored.client_id != transaction_request.client_id {
+        SkipReason::CrossClientIdReuse
+    } else if stored == transaction_request {
+        SkipReason::DuplicateTransaction
+    } else {
+        SkipReason::ConflictingDuplicate
+    }
+}
+
+// Shared by the managers, so the report format stays the same whichever of them produced it. The balances are
+// truncated to the scale, which only changes the ones that were not produced by a manager of the same scale
+pub(crate) fn write_accounts(
+    writer: &mut dyn Write,
+    accounts: impl Iterator<Item = CustomerAccountReport>,
+    formatter: &dyn ReportFormatter,
+    scale: u32,
+    fixed_scale: bool,
+) -> Result<(), PaymentEngineError> {
+    let to_scale = |amount: Decimal| {
+        let mut amount = amount.round_dp_with_strategy(scale, SCALE_ROUNDING);
+        if fixed_scale {
+            amount.rescale(scale);
+        }
+        amount
+    };
+    let mut accounts = accounts.map(|account| CustomerAccountReport {
+        available: to_scale(account.available),
+        held: to_scale(account.held),
+        total: to_scale(account.total),
+        total_charged_back: to_scale(account.total_charged_back),
+        total_deposited: to_scale(account.total_deposited),
+        total_withdrawn: to_scale(account.total_withdrawn),
+        ..account
+    });
+    formatter.format_stream(&mut accounts, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audit::CsvAuditSink,
+        duplicate_detector::{BloomDuplicateDetector, DuplicateDetector, ExactDuplicateDetector},
+        events::{CollectingListener, LoggingListener, TransactionEvent},
+        preview::TransactionPreview,
+        snapshot::Snapshot,
+    };
+    use crate::{
+        customer_account_provider::{InMemoryCustomerAccountProvider, MockCustomerAccountProvider},
+        sled_customer_account_provider::SledCustomerAccountProvider,
+        transaction_history_provider::{
+            in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
+            sled_transaction_history_provider::SledTransactionHistoryProvider,
+            transaction_history_provider::MockTransactionHistoryProvider,
+        },
+        transaction_requests_reader::read_transaction_requests,
+    };
+    use rust_decimal::RoundingStrategy;
+    use std::{
+        cell::{Cell, RefCell},
+        io::Cursor,
+        rc::Rc,
+    };
+
+    use super::*;
+    fn account_with_available(client_id: CustomerId, available: Decimal) -> CustomerAccountReport {
+        CustomerAccountReport {
+            client: client_id,
+            available,
+            held: Decimal::ZERO,
+            total: available,
+            locked: false,
+            last_activity: None,
+            currency: "USD".to_owned(),
+            total_charged_back: Decimal::ZERO,
+            open_disputes: 0,
+            total_deposited: available,
+            total_withdrawn: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn deposit_works_as_expected_as_first_transaction() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(10, 4);
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        mock_history_provider
+            .expect_write_transaction()
+            .with(eq(transaction_request.clone()))
+            .times(1)
+            .return_const(Ok(()));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_account()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(None));
+        mock_customer_account_provider
+            .expect_set_available()
+            .with(eq(client_id), predicate::str::diff("USD"), eq(amount))
+            .times(1)
+            .return_const(Ok(()));
+        mock_customer_account_provider
+            .expect_add_deposited()
+            .with(eq(client_id), predicate::str::diff("USD"), eq(amount))
+            .times(1)
+            .return_const(Ok(()));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.deposit(transaction_request);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_executed());
+    }
+
+    #[test]
+    fn deposit_works_as_expected_when_some_funds_already_present() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(10, 0);
+        let existing_amount = Decimal::new(5, 0);
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        mock_history_provider
+            .expect_write_transaction()
+            .with(eq(transaction_request.clone()))
+            .times(1)
+            .return_const(Ok(()));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_account()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(Some(account_with_available(client_id, existing_amount))));
+        mock_customer_account_provider
+            .expect_set_available()
+            .with(
+                eq(client_id),
+                predicate::str::diff("USD"),
+                eq(amount + existing_amount),
+            )
+            .times(1)
+            .return_const(Ok(()));
+        mock_customer_account_provider
+            .expect_add_deposited()
+            .with(eq(client_id), predicate::str::diff("USD"), eq(amount))
+            .times(1)
+            .return_const(Ok(()));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.deposit(transaction_request);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_executed());
+    }
+
+    #[test]
+    fn deposit_fails_with_amount_missing_when_no_amount() {
+        let mut transactions_manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        // handle_transaction skips it as invalid before reaching the handler
+        let result = transactions_manager.deposit(TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(result, Err(PaymentEngineError::AmountMissing(1)));
+        assert!(!result.unwrap_err().is_fatal());
+    }
+
+    #[test]
+    fn withdraw_works_as_expected_in_happy_case() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(5, 0);
+        let existing_amount = Decimal::new(10, 0);
+        let locked = false;
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Withdrawal,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        mock_history_provider
+            .expect_write_transaction()
+            .with(eq(transaction_request.clone()))
+            .times(1)
+            .return_const(Ok(()));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_account()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(Some(account_with_available(client_id, existing_amount))));
+        mock_customer_account_provider
+            .expect_get_locked_status()
+            .with(eq(client_id))
+            .times(1)
+            .return_const(Ok(Some(locked)));
+        mock_customer_account_provider
+            .expect_set_available()
+            .with(
+                eq(client_id),
+                predicate::str::diff("USD"),
+                eq(existing_amount - amount),
+            )
+            .times(1)
+            .return_const(Ok(()));
+        mock_customer_account_provider
+            .expect_add_withdrawn()
+            .with(eq(client_id), predicate::str::diff("USD"), eq(amount))
+            .times(1)
+            .return_const(Ok(()));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.withdraw(transaction_request);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_executed());
+    }
+
+    #[test]
+    fn withdraw_skips_when_no_enough_funds_present() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(10, 0);
+        let existing_amount = Decimal::new(5, 0);
+        let locked = false;
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Withdrawal,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_account()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(Some(account_with_available(client_id, existing_amount))));
+        mock_customer_account_provider
+            .expect_get_locked_status()
+            .with(eq(client_id))
+            .times(1)
+            .return_const(Ok(Some(locked)));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.withdraw(transaction_request);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            TransactionOutcome::Skipped(SkipReason::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn withdraw_skips_when_no_account_state_found() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(5, 0);
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Withdrawal,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        };
+        let locked = false;
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_account()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(None));
+        mock_customer_account_provider
+            .expect_get_locked_status()
+            .with(eq(client_id))
+            .times(1)
+            .return_const(Ok(Some(locked)));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.withdraw(transaction_request);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            TransactionOutcome::Skipped(SkipReason::AccountNotFound)
+        );
+    }
+
+    #[test]
+    fn withdraw_skips_when_account_locked() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(5, 0);
+        let locked = true;
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Withdrawal,
+            client_id,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        };
+        // The lock is checked first, so the history isn't read for the duplicate check
+        let mock_history_provider = MockTransactionHistoryProvider::new();
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_locked_status()
+            .with(eq(client_id))
+            .times(1)
+            .return_const(Ok(Some(locked)));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.withdraw(transaction_request);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            TransactionOutcome::Skipped(SkipReason::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn deposit_and_withdrawal_check_the_lock_before_the_duplicates() {
+        let mut manager = in_memory_manager(None).with_lock_policy(LockPolicy::BlockAll);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+            ],
+        );
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                    (TransactionType::Withdrawal, 1, Some(Decimal::new(10, 0))),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked)),
+                Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked)),
+            ]
+        );
+    }
+
+    #[test]
+    fn dispute_does_nothing_when_transaction_not_found() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        let mock_customer_account_provider = MockCustomerAccountProvider::new();
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.dispute(transaction_request);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            TransactionOutcome::Skipped(SkipReason::TransactionNotFound)
+        );
+    }
+
+    #[test]
+    fn resolve_does_nothing_when_transaction_not_found() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Resolve,
+            client_id,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        let mock_customer_account_provider = MockCustomerAccountProvider::new();
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.resolve(transaction_request);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            TransactionOutcome::Skipped(SkipReason::TransactionNotFound)
+        );
+    }
+
+    #[test]
+    fn chargeback_does_nothing_when_transaction_not_found() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        let mock_customer_account_provider = MockCustomerAccountProvider::new();
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.chargeback(transaction_request);
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            TransactionOutcome::Skipped(SkipReason::TransactionNotFound)
+        );
+    }
+
+    #[test]
+    fn dispute_works_as_expected_with_mocked_history() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(10, 0);
+        let transaction_request = TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(Some(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id,
+                amount: Some(amount),
+                timestamp: None,
+                currency: None,
+            })));
+        mock_history_provider
+            .expect_read_transaction_state()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        mock_history_provider
+            .expect_write_transaction_state()
+            .with(
+                eq(transaction_id),
+                eq(TransactionState {
+                    held: true,
+                    dispute_count: 1,
+                    ..Default::default()
+                }),
+            )
+            .times(1)
+            .return_const(Ok(()));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_held_amount()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(None));
+        mock_customer_account_provider
+            .expect_apply_mutation()
+            .with(
+                eq(client_id),
+                predicate::str::diff("USD"),
+                eq(AccountMutation {
+                    available_delta: -amount,
+                    held_delta: amount,
+                    lock: false,
+                    charged_back: Decimal::ZERO,
+                    open_disputes_delta: 1,
+                }),
+            )
+            .times(1)
+            .return_const(Ok(()));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.dispute(transaction_request);
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_executed());
+    }
+
+    #[test]
+    fn chargeback_restores_the_state_when_the_account_mutation_fails() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(10, 0);
+        let held_state = TransactionState {
+            held: true,
+            dispute_count: 1,
+            ..Default::default()
+        };
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(Some(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id,
+                amount: Some(amount),
+                timestamp: None,
+                currency: None,
+            })));
+        mock_history_provider
+            .expect_read_transaction_state()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(Some(held_state.clone())));
+        mock_history_provider
+            .expect_write_transaction_state()
+            .with(
+                eq(transaction_id),
+                eq(TransactionState {
+                    held: false,
+                    charged_back: true,
+                    ..held_state.clone()
+                }),
+            )
+            .times(1)
+            .return_const(Ok(()));
+        // The state written above is reverted once the account rejected the chargeback
+        mock_history_provider
+            .expect_write_transaction_state()
+            .with(eq(transaction_id), eq(held_state))
+            .times(1)
+            .return_const(Ok(()));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_held_amount()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(2)
+            .return_const(Ok(Some(amount)));
+        mock_customer_account_provider
+            .expect_apply_mutation()
+            .with(
+                eq(client_id),
+                predicate::str::diff("USD"),
+                eq(AccountMutation {
+                    available_delta: Decimal::ZERO,
+                    held_delta: -amount,
+                    lock: true,
+                    charged_back: amount,
+                    open_disputes_delta: -1,
+                }),
+            )
+            .times(1)
+            .return_const(Err(PaymentEngineError::Storage(
+                "Connection lost".to_owned(),
+            )));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.chargeback(TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            result,
+            Err(PaymentEngineError::Storage("Connection lost".to_owned()))
+        );
+    }
+
+    #[test]
+    fn dispute_restores_the_state_when_the_account_mutation_fails() {
+        let transaction_id = 1;
+        let client_id = 1;
+        let amount = Decimal::new(10, 0);
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(Some(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id,
+                amount: Some(amount),
+                timestamp: None,
+                currency: None,
+            })));
+        mock_history_provider
+            .expect_read_transaction_state()
+            .with(eq(transaction_id))
+            .times(1)
+            .return_const(Ok(None));
+        mock_history_provider
+            .expect_write_transaction_state()
+            .with(
+                eq(transaction_id),
+                eq(TransactionState {
+                    held: true,
+                    dispute_count: 1,
+                    ..Default::default()
+                }),
+            )
+            .times(1)
+            .return_const(Ok(()));
+        // Neither the available nor the held funds moved, so the transaction goes back to not being disputed
+        mock_history_provider
+            .expect_write_transaction_state()
+            .with(eq(transaction_id), eq(TransactionState::default()))
+            .times(1)
+            .return_const(Ok(()));
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_held_amount()
+            .with(eq(client_id), predicate::str::diff("USD"))
+            .times(1)
+            .return_const(Ok(None));
+        mock_customer_account_provider
+            .expect_apply_mutation()
+            .with(
+                eq(client_id),
+                predicate::str::diff("USD"),
+                eq(AccountMutation {
+                    available_delta: -amount,
+                    held_delta: amount,
+                    lock: false,
+                    charged_back: Decimal::ZERO,
+                    open_disputes_delta: 1,
+                }),
+            )
+            .times(1)
+            .return_const(Err(PaymentEngineError::Storage(
+                "Connection lost".to_owned(),
+            )));
+        let mut transactions_manager =
+            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
+        let result = transactions_manager.dispute(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            result,
+            Err(PaymentEngineError::Storage("Connection lost".to_owned()))
+        );
+    }
+
+    // Using actual instances from here onwards, they make the longer state transitions easier to follow
+    #[test]
+    fn dispute_does_nothing_when_original_transaction_client_id_is_different() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        let customer_account_provider = InMemoryCustomerAccountProvider::new();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 2,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(None)
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn dispute_does_nothing_when_transaction_was_already_held() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let customer_account_provider = InMemoryCustomerAccountProvider::new();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::AlreadyDisputed))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(None)
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn dispute_does_nothing_when_transaction_was_already_charged_back() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: false,
+                    charged_back: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let customer_account_provider = InMemoryCustomerAccountProvider::new();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::AlreadyDisputed))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(None)
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn dispute_works_as_expected_in_happy_case() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: false,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert!(
+            manager
+                .transaction_history_provider
+                .as_mut()
+                .read_transaction_state(1)
+                .unwrap()
+                .unwrap()
+                .held
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn dispute_works_as_expected_if_transaction_state_doesnt_exist() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert!(
+            manager
+                .transaction_history_provider
+                .as_mut()
+                .read_transaction_state(1)
+                .unwrap()
+                .unwrap()
+                .held
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn dispute_works_as_expected_even_if_account_is_locked() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert!(
+            manager
+                .transaction_history_provider
+                .as_mut()
+                .read_transaction_state(1)
+                .unwrap()
+                .unwrap()
+                .held
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+    }
+
+    #[test]
+    fn resolve_does_nothing_if_client_ids_dont_match() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Resolve,
+            client_id: 2,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn resolve_does_nothing_if_already_charged_back() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: false,
+                    charged_back: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Resolve,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+    }
+
+    #[test]
+    fn resolve_does_nothing_if_not_held() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: false,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Resolve,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn resolve_fails_if_not_enough_held_funds() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::ZERO)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        assert!(matches!(
+            manager.resolve(TransactionRequest {
+                transaction_type: TransactionType::Resolve,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            }),
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_works_as_expected() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Resolve,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn resolve_works_as_expected_even_if_locked() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Resolve,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+    }
+
+    #[test]
+    fn chargeback_does_nothing_if_account_ids_dont_match() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 2,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::ClientMismatch))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn chargeback_does_nothing_if_not_held() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: false,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(false))
+        );
+    }
+
+    #[test]
+    fn chargeback_does_nothing_if_already_charged_back() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: false,
+                    charged_back: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            res,
+            Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::new(10, 0)))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+    }
+
+    #[test]
+    fn chargeback_fails_if_not_enough_held_funds() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::ZERO)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        assert!(matches!(
+            manager.handle_transaction(TransactionRequest {
+                transaction_type: TransactionType::Chargeback,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            }),
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn chargeback_works_as_expected() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+    }
+
+    // chargeback
+    // Works as expected, even if locked
+    #[test]
+    fn chargeback_works_as_expected_even_if_already_locked() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
+        customer_account_provider
+            .set_available(1, "USD", Decimal::ZERO)
+            .unwrap();
+        customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(10, 0))
+            .unwrap();
+        customer_account_provider
+            .set_locked_status(1, true)
+            .unwrap();
+        let mut manager =
+            DefaultTransactionsManager::new(history_provider, customer_account_provider);
+        let res = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(res, Ok(TransactionOutcome::Executed));
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(Decimal::ZERO))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(Some(true))
+        );
+    }
+
+    #[test]
+    fn dispute_skips_when_client_has_no_account() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Withdrawal,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        let mut manager = DefaultTransactionsManager::new(
+            history_provider,
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let result = manager.handle_transaction(TransactionRequest {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            result,
+            Ok(TransactionOutcome::Skipped(SkipReason::AccountNotFound))
+        );
+        assert_eq!(
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(None)
+        );
+        // The state written ahead of the rejected mutation is reverted to the default one
+        assert_eq!(
+            manager
+                .transaction_history_provider
+                .read_transaction_state(1),
+            Ok(Some(TransactionState::default()))
+        );
+    }
+
+    #[test]
+    fn resolve_and_chargeback_fail_when_client_has_no_account() {
+        let mut history_provider = InMemoryTransactionHistoryProvider::new();
+        history_provider
+            .write_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        history_provider
+            .write_transaction_state(
+                1,
+                TransactionState {
+                    held: true,
+                    charged_back: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut manager = DefaultTransactionsManager::new(
+            history_provider,
+            InMemoryCustomerAccountProvider::new(),
+        );
+        for transaction_type in [TransactionType::Resolve, TransactionType::Chargeback] {
+            let result = manager.handle_transaction(TransactionRequest {
+                transaction_type,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            });
+            // The transaction is on hold, so the account has to exist
+            assert!(result.unwrap_err().is_fatal());
+        }
+        assert_eq!(
+            manager.customer_account_provider.get_locked_status(1),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn funds_not_held_for_a_held_transaction_fail_the_run() {
+        for (transaction_type, held_amount) in [
+            (TransactionType::Resolve, None),
+            (TransactionType::Resolve, Some(Decimal::new(9, 0))),
+            (TransactionType::Chargeback, None),
+            (TransactionType::Chargeback, Some(Decimal::new(9, 0))),
+        ] {
+            let mut mock_history_provider = MockTransactionHistoryProvider::new();
+            mock_history_provider
+                .expect_read_transaction()
+                .with(eq(1))
+                .times(1)
+                .return_const(Ok(Some(TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id: 1,
+                    transaction_id: 1,
+                    amount: Some(Decimal::new(10, 0)),
+                    timestamp: None,
+                    currency: None,
+                })));
+            mock_history_provider
+                .expect_read_transaction_state()
+                .with(eq(1))
+                .times(1)
+                .return_const(Ok(Some(TransactionState {
+                    held: true,
+                    dispute_count: 1,
+                    ..Default::default()
+                })));
+            let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+            mock_customer_account_provider
+                .expect_get_held_amount()
+                .with(eq(1), predicate::str::diff("USD"))
+                .times(1)
+                .return_const(Ok(held_amount));
+            let mut manager = DefaultTransactionsManager::new(
+                mock_history_provider,
+                mock_customer_account_provider,
+            );
+            let requests = vec![TransactionRequest {
+                transaction_type,
+                client_id: 1,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            }];
+            assert!(matches!(
+                manager.process_all(requests.into_iter(), None),
+                Err(PaymentEngineError::InvariantViolation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn account_lookup_gives_the_report_row_of_the_client() {
+        let mut manager = in_memory_manager(None);
+        assert_eq!(manager.account(1).unwrap(), None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(4, 0))),
+                (TransactionType::Dispute, 2, None),
+            ],
+        );
+        let account = manager.account(1).unwrap().unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (Decimal::new(10, 0), Decimal::new(4, 0), Decimal::new(14, 0))
+        );
+        assert_eq!(account.open_disputes, 1);
+        assert_eq!(manager.account(2).unwrap(), None);
+        assert_eq!(
+            manager.list_accounts().unwrap(),
+            vec![account],
+            "The lookup should give the same row as the report"
+        );
+    }
+
+    #[test]
+    fn account_lookup_asks_the_provider_once() {
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_get_account()
+            .with(eq(7), predicate::str::diff("EUR"))
+            .times(1)
+            .return_const(Ok(None));
+        let manager = DefaultTransactionsManager::new(
+            MockTransactionHistoryProvider::new(),
+            mock_customer_account_provider,
+        )
+        .with_default_currency("EUR");
+        assert_eq!(manager.account(7).unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_lookup_joins_the_request_and_its_state() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(50, 0))),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        let (transaction, state) = manager.transaction(1).unwrap().unwrap();
+        assert_eq!(transaction.transaction_type, TransactionType::Deposit);
+        assert_eq!(transaction.amount, Some(Decimal::new(10, 0)));
+        let state = state.unwrap();
+        assert!(state.held);
+        assert_eq!(state.dispute_count, 1);
+        // The withdrawal was skipped for the missing funds, so it's not in the history
+        assert_eq!(manager.transaction(2).unwrap(), None);
+        assert_eq!(manager.transaction(99).unwrap(), None);
+    }
+
+    #[test]
+    fn summary_counts_a_mixed_batch() {
+        let content = "
+        type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 5.0
+        deposit, 1, 1, 3.0
+        withdrawal, 1, 3, 4.0
+        withdrawal, 2, 4, 50.0
+        withdrawal, 3, 5, 1.0
+        dispute, 1, 1,
+        dispute, 1, 1,
+        dispute, 2, 1,
+        dispute, 1, 99,
+        chargeback, 1, 1,
+        withdrawal, 1, 6, 1.0
+        resolve, 1, 1,";
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        read_transaction_requests(
+            Cursor::new(content.as_bytes().to_vec()),
+            4,
+            RoundingStrategy::ToZero,
+            Rc::new(Cell::new(0)),
+        )
+        .for_each(|request| {
+            manager.handle_transaction(request).unwrap();
+        });
+        let summary = manager.summary();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped(&TransactionType::Deposit), 1);
+        assert_eq!(summary.executed(&TransactionType::Withdrawal), 1);
+        assert_eq!(summary.skipped(&TransactionType::Withdrawal), 3);
+        assert_eq!(summary.executed(&TransactionType::Dispute), 1);
+        assert_eq!(summary.skipped(&TransactionType::Dispute), 3);
+        assert_eq!(summary.executed(&TransactionType::Chargeback), 1);
+        assert_eq!(summary.skipped(&TransactionType::Resolve), 1);
+        assert_eq!(summary.skipped_because(SkipReason::ConflictingDuplicate), 1);
+        assert_eq!(summary.skipped_because(SkipReason::InsufficientFunds), 1);
+        assert_eq!(summary.skipped_because(SkipReason::AccountNotFound), 1);
+        assert_eq!(summary.skipped_because(SkipReason::AccountLocked), 1);
+        assert_eq!(summary.skipped_because(SkipReason::AlreadyDisputed), 1);
+        assert_eq!(summary.skipped_because(SkipReason::ClientMismatch), 1);
+        assert_eq!(summary.skipped_because(SkipReason::TransactionNotFound), 1);
+        assert_eq!(summary.skipped_because(SkipReason::NotDisputed), 1);
+    }
+
+    #[test]
+    fn deposit_after_chargeback_is_executed_when_only_withdrawals_are_blocked() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+            ],
+        );
+        assert_eq!(results[3], Ok(TransactionOutcome::Executed));
+        assert_account(&mut manager, Decimal::new(5, 0), Decimal::ZERO, true);
+    }
+
+    #[test]
+    fn deposit_after_chargeback_is_skipped_when_all_activity_is_blocked() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_lock_policy(LockPolicy::BlockAll);
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Deposit, 3, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 2, None),
+            ],
+        );
+        assert_eq!(
+            results[4..],
+            [
+                Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked)),
+                Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked))
+            ]
+        );
+        assert_account(&mut manager, Decimal::new(5, 0), Decimal::ZERO, true);
+        assert_eq!(
+            manager.transaction_history_provider.read_transaction(3),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn every_request_type_against_a_locked_account_follows_the_lock_policy() {
+        let executed = Ok(TransactionOutcome::Executed);
+        let locked = Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked));
+        for (lock_policy, expected) in [
+            (
+                LockPolicy::BlockWithdrawalsOnly,
+                vec![
+                    executed.clone(),
+                    locked.clone(),
+                    executed.clone(),
+                    executed.clone(),
+                    executed.clone(),
+                ],
+            ),
+            (LockPolicy::BlockAll, vec![locked.clone(); 5]),
+        ] {
+            let mut manager = DefaultTransactionsManager::new(
+                InMemoryTransactionHistoryProvider::new(),
+                InMemoryCustomerAccountProvider::new(),
+            )
+            .with_lock_policy(lock_policy);
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                    (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                    (TransactionType::Deposit, 3, Some(Decimal::new(5, 0))),
+                    (TransactionType::Dispute, 2, None),
+                    (TransactionType::Dispute, 3, None),
+                ],
+            );
+            manager
+                .customer_account_provider
+                .set_locked_status(1, true)
+                .unwrap();
+            let results = run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 4, Some(Decimal::new(1, 0))),
+                    (TransactionType::Withdrawal, 5, Some(Decimal::new(1, 0))),
+                    (TransactionType::Dispute, 1, None),
+                    (TransactionType::Resolve, 2, None),
+                    (TransactionType::Chargeback, 3, None),
+                ],
+            );
+            assert_eq!(results, expected, "{:?}", lock_policy);
+        }
+    }
+
+    #[test]
+    fn invariants_hold_through_disputes_and_chargebacks() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_invariant_tracking();
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Withdrawal, 3, Some(Decimal::new(4, 0))),
+                (TransactionType::Withdrawal, 4, Some(Decimal::new(1, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Dispute, 3, None),
+                (TransactionType::Dispute, 4, None),
+                (TransactionType::Resolve, 4, None),
+                (TransactionType::Chargeback, 3, None),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Chargeback, 2, None),
+            ],
+        );
+        assert_account(&mut manager, Decimal::new(-1, 0), Decimal::new(10, 0), true);
+        assert_eq!(manager.verify_invariants(), Ok(vec![]));
+    }
+
+    #[test]
+    fn invariants_report_an_account_changed_around_the_manager() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_invariant_tracking();
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        // A provider losing some of the held funds
+        manager
+            .customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(7, 0))
+            .unwrap();
+        assert_eq!(
+            manager.verify_invariants(),
+            Ok(vec![FundsDivergence {
+                client: 1,
+                currency: "USD".to_owned(),
+                expected_total: Decimal::new(10, 0),
+                actual_total: Decimal::new(7, 0),
+            }])
+        );
+    }
+
+    #[test]
+    fn invariants_are_verified_only_when_tracked() {
+        let manager = in_memory_manager(None);
+        assert!(matches!(
+            manager.verify_invariants(),
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn deposit_beyond_decimal_limits_is_skipped() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::MAX)),
+                (TransactionType::Deposit, 2, Some(Decimal::ONE)),
+            ],
+        );
+        assert_eq!(
+            results,
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(SkipReason::Overflow))
+            ]
+        );
+        assert_account(&mut manager, Decimal::MAX, Decimal::ZERO, false);
+        assert_eq!(
+            manager.transaction_history_provider.read_transaction(2),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn transactions_above_the_amount_limit_are_skipped() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_limits(Limits {
+            max_transaction_amount: Some(Decimal::new(100, 0)),
+            ..Default::default()
+        });
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::new(100, 0))),
+                    (TransactionType::Deposit, 2, Some(Decimal::new(10001, 2))),
+                    (TransactionType::Deposit, 3, Some(Decimal::new(50, 0))),
+                    (TransactionType::Withdrawal, 4, Some(Decimal::new(10001, 2))),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::TransactionLimitExceeded
+                )),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::TransactionLimitExceeded
+                )),
+            ]
+        );
+        assert_account(&mut manager, Decimal::new(150, 0), Decimal::ZERO, false);
+        assert_eq!(
+            manager
+                .summary()
+                .skipped_because(SkipReason::TransactionLimitExceeded),
+            2
+        );
+    }
+
+    #[test]
+    fn account_total_limit_counts_the_held_funds() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_limits(Limits {
+            max_account_total: Some(Decimal::new(100, 0)),
+            ..Default::default()
+        });
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::new(60, 0))),
+                    (TransactionType::Deposit, 2, Some(Decimal::new(30, 0))),
+                    (TransactionType::Dispute, 2, None),
+                    // Would only bring the available funds to 80, but the total to 110
+                    (TransactionType::Deposit, 3, Some(Decimal::new(20, 0))),
+                    (TransactionType::Deposit, 4, Some(Decimal::new(10, 0))),
+                    (TransactionType::Deposit, 5, Some(Decimal::new(1, 2))),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::AccountTotalLimitExceeded
+                )),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::AccountTotalLimitExceeded
+                )),
+            ]
+        );
+        assert_account(
+            &mut manager,
+            Decimal::new(70, 0),
+            Decimal::new(30, 0),
+            false,
+        );
+    }
+
+    #[test]
+    fn handle_transaction_skips_the_invalid_amounts_itself() {
+        let mut manager = in_memory_manager(None);
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::TEN)),
+                (TransactionType::Withdrawal, 2, Some(-Decimal::TEN)),
+                (TransactionType::Deposit, 3, Some(-Decimal::MAX)),
+                (TransactionType::Deposit, 4, None),
+                (TransactionType::Dispute, 1, Some(Decimal::ONE)),
+            ],
+        );
+        assert_eq!(
+            results[1..],
+            vec![Ok(TransactionOutcome::Skipped(SkipReason::InvalidStructure)); 4]
+        );
+        assert_account(&mut manager, Decimal::TEN, Decimal::ZERO, false);
+        assert_eq!(
+            manager
+                .summary()
+                .skipped_because(SkipReason::InvalidStructure),
+            4
+        );
+    }
+
+    #[test]
+    fn dispute_beyond_decimal_limits_is_skipped() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::MAX)),
+                (TransactionType::Withdrawal, 2, Some(Decimal::MAX)),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Dispute, 2, None),
+            ],
+        );
+        assert_eq!(
+            results[2..],
+            [
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(SkipReason::Overflow))
+            ]
+        );
+        assert_account(&mut manager, -Decimal::MAX, Decimal::MAX, false);
+    }
+
+    #[test]
+    fn deposit_overflowing_the_deposited_sum_leaves_the_account_untouched() {
+        let mut manager = in_memory_manager(None);
+        let amount = Decimal::from_i128_with_scale(5 * 10i128.pow(28), 0);
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(amount)),
+                (TransactionType::Withdrawal, 2, Some(amount)),
+                (TransactionType::Deposit, 3, Some(amount)),
+            ],
+        );
+        assert_eq!(
+            results[2],
+            Ok(TransactionOutcome::Skipped(SkipReason::Overflow))
+        );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ZERO, false);
+        assert_eq!(manager.transaction(3), Ok(None));
+        let account = manager.account(1).unwrap().unwrap();
+        assert_eq!(account.total_deposited, amount);
+        assert_eq!(account.total_withdrawn, amount);
+    }
+
+    #[test]
+    fn process_all_reports_progress_at_the_configured_interval() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_progress_interval(3);
+        let requests = (1..=10).map(|transaction_id| TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            currency: None,
+        });
+        let mut reported = vec![];
+        let summary = manager
+            .process_all(requests, Some(&mut |processed| reported.push(processed)))
+            .unwrap();
+        assert_eq!(reported, vec![3, 6, 9]);
+        assert_eq!(summary.executed(&TransactionType::Deposit), 10);
+    }
+
+    #[test]
+    fn process_all_skips_requests_with_invalid_structure() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let requests = vec![
+            TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            },
+            TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 2,
+                amount: Some(-Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            },
+            TransactionRequest {
+                transaction_type: TransactionType::Dispute,
+                client_id: 1,
+                transaction_id: 1,
+                amount: Some(Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            },
+        ];
+        let summary = manager.process_all(requests.into_iter(), None).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 1);
+        assert_eq!(summary.skipped(&TransactionType::Deposit), 1);
+        assert_eq!(summary.skipped(&TransactionType::Dispute), 1);
+        assert_eq!(summary.skipped_because(SkipReason::InvalidStructure), 2);
+        assert_account(&mut manager, Decimal::ONE, Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn process_all_stops_on_fatal_errors() {
+        let mut mock_history_provider = MockTransactionHistoryProvider::new();
+        mock_history_provider
+            .expect_read_transaction()
+            .times(1)
+            .return_const(Err(PaymentEngineError::Storage("disk failure".to_owned())));
+        let mut manager = DefaultTransactionsManager::new(
+            mock_history_provider,
+            MockCustomerAccountProvider::new(),
+        );
+        let requests = (1..=2).map(|transaction_id| TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(
+            manager.process_all(requests, None),
+            Err(PaymentEngineError::Storage("disk failure".to_owned()))
+        );
+    }
+
+    const INCONSISTENT_CSV: &str = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,8.0
+dispute,2,1,
+deposit,1,1,2.0
+";
+
+    fn process_csv(
+        manager: &mut DefaultTransactionsManager,
+    ) -> Result<ProcessingSummary, PaymentEngineError> {
+        let requests = read_transaction_requests(
+            Cursor::new(INCONSISTENT_CSV),
+            4,
+            RoundingStrategy::ToZero,
+            Rc::new(Cell::new(0)),
+        );
+        manager.process_all(requests, None)
+    }
+
+    #[test]
+    fn lenient_mode_skips_inconsistent_requests() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let summary = process_csv(&mut manager).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 2);
+        assert_eq!(summary.skipped_because(SkipReason::InsufficientFunds), 1);
+        assert_eq!(summary.skipped_because(SkipReason::ClientMismatch), 1);
+        // Same ID with another amount
+        assert_eq!(summary.skipped_because(SkipReason::ConflictingDuplicate), 1);
+    }
+
+    #[test]
+    fn strict_mode_stops_at_the_first_inconsistent_row() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_strict_mode(true);
+        assert_eq!(
+            process_csv(&mut manager),
+            Err(PaymentEngineError::StrictModeViolation {
+                row: 3,
+                transaction_id: 3,
+                reason: Box::new(PaymentEngineError::InsufficientFunds(1)),
+            })
+        );
+        assert_eq!(manager.summary().executed(&TransactionType::Deposit), 2);
+        assert_eq!(manager.summary().failed(&TransactionType::Withdrawal), 1);
+    }
+
+    #[test]
+    fn strict_mode_reports_the_specific_reason() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_strict_mode(true);
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::ONE)),
+                    (TransactionType::Deposit, 1, Some(Decimal::ONE)),
+                ],
+            )[1],
+            Err(PaymentEngineError::DuplicateTransaction(1))
+        );
+        assert_eq!(
+            manager.handle_transaction(TransactionRequest {
+                transaction_type: TransactionType::Dispute,
+                client_id: 2,
+                transaction_id: 1,
+                amount: None,
+                timestamp: None,
+                currency: None,
+            }),
+            Err(PaymentEngineError::ClientMismatch {
+                transaction_id: 1,
+                client_id: 2
+            })
+        );
+        // Skips that don't point to inconsistent input stay skips
+        assert_eq!(
+            run_requests(&mut manager, vec![(TransactionType::Resolve, 1, None)])[0],
+            Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed))
+        );
+    }
+
+    #[test]
+    fn recompute_account_matches_the_account_provider() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(7, 0))),
+                (TransactionType::Deposit, 3, Some(Decimal::new(3, 0))),
+                (TransactionType::Withdrawal, 4, Some(Decimal::new(4, 0))),
+                (TransactionType::Withdrawal, 5, Some(Decimal::new(2, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Resolve, 2, None),
+                (TransactionType::Dispute, 3, None),
+                (TransactionType::Chargeback, 3, None),
+                (TransactionType::Dispute, 4, None),
+                (TransactionType::Dispute, 5, None),
+                (TransactionType::Chargeback, 5, None),
+            ],
+        );
+        let recomputed = manager.recompute_account(1, "USD").unwrap();
+        assert_eq!(
+            Ok(Some(recomputed.available)),
+            manager.customer_account_provider.get_available(1, "USD")
+        );
+        assert_eq!(
+            Ok(Some(recomputed.held)),
+            manager.customer_account_provider.get_held_amount(1, "USD")
+        );
+        assert_eq!(
+            recomputed,
+            AccountBalances {
+                available: Decimal::new(3, 0),
+                held: Decimal::new(14, 0),
+            }
+        );
+    }
+
+    #[test]
+    fn identical_retries_and_conflicting_duplicates_are_told_apart() {
+        for transaction_type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            let mut manager = DefaultTransactionsManager::new(
+                InMemoryTransactionHistoryProvider::new(),
+                InMemoryCustomerAccountProvider::new(),
+            );
+            run_requests(
+                &mut manager,
+                vec![(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)))],
+            );
+            let request = TransactionRequest {
+                transaction_type: transaction_type.clone(),
+                client_id: 1,
+                transaction_id: 2,
+                amount: Some(Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            };
+            assert_eq!(
+                manager.handle_transaction(request.clone()),
+                Ok(TransactionOutcome::Executed)
+            );
+            assert_eq!(
+                manager.handle_transaction(request.clone()),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::DuplicateTransaction
+                ))
+            );
+            for conflicting in [
+                TransactionRequest {
+                    amount: Some(Decimal::TWO),
+                    ..request.clone()
+                },
+                TransactionRequest {
+                    transaction_type: if transaction_type == TransactionType::Deposit {
+                        TransactionType::Withdrawal
+                    } else {
+                        TransactionType::Deposit
+                    },
+                    ..request.clone()
+                },
+            ] {
+                assert_eq!(
+                    manager.handle_transaction(conflicting),
+                    Ok(TransactionOutcome::Skipped(
+                        SkipReason::ConflictingDuplicate
+                    ))
+                );
+            }
+            let summary = manager.summary();
+            assert_eq!(summary.skipped_because(SkipReason::DuplicateTransaction), 1);
+            assert_eq!(summary.skipped_because(SkipReason::ConflictingDuplicate), 2);
+        }
+    }
+
+    #[test]
+    fn reusing_the_id_of_another_client_is_flagged() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        let deposit = TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 10,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            currency: None,
+        };
+        manager.handle_transaction(deposit.clone()).unwrap();
+        // The same client reusing its own ID is a plain duplicate
+        assert_eq!(
+            manager.handle_transaction(TransactionRequest {
+                amount: Some(Decimal::TWO),
+                ..deposit.clone()
+            }),
+            Ok(TransactionOutcome::Skipped(
+                SkipReason::ConflictingDuplicate
+            ))
+        );
+        for transaction_type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            assert_eq!(
+                manager.handle_transaction(TransactionRequest {
+                    transaction_type,
+                    client_id: 2,
+                    ..deposit.clone()
+                }),
+                Ok(TransactionOutcome::Skipped(SkipReason::CrossClientIdReuse))
+            );
+        }
+        let summary = manager.summary();
+        assert_eq!(summary.cross_client_id_reuse, 2);
+        assert_eq!(summary.skipped_because(SkipReason::ConflictingDuplicate), 1);
+        assert_eq!(
+            manager.customer_account_provider.get_available(2, "USD"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn reusing_the_id_of_another_client_is_an_error_in_strict_mode() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_strict_mode(true);
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 10, Some(Decimal::ONE))],
+        );
+        assert_eq!(
+            manager.handle_transaction(TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 2,
+                transaction_id: 10,
+                amount: Some(Decimal::ONE),
+                timestamp: None,
+                currency: None,
+            }),
+            Err(PaymentEngineError::CrossClientIdReuse {
+                transaction_id: 10,
+                stored_client_id: 1,
+                client_id: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn conflicting_duplicates_are_errors_in_strict_mode() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_strict_mode(true);
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::ONE)),
+                    (TransactionType::Deposit, 1, Some(Decimal::TWO)),
+                    (TransactionType::Withdrawal, 1, Some(Decimal::ONE)),
+                ],
+            )[1..],
+            [
+                Err(PaymentEngineError::ConflictingDuplicate(1)),
+                Err(PaymentEngineError::ConflictingDuplicate(1)),
+            ]
+        );
+    }
+
+    fn disputes_report_of(manager: &mut DefaultTransactionsManager) -> String {
+        let mut output = vec![];
+        manager.write_disputes_report(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn disputes_report_lists_the_open_disputes() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Withdrawal, 3, Some(Decimal::new(3, 0))),
+                (TransactionType::Dispute, 3, None),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        assert_eq!(
+            disputes_report_of(&mut manager),
+            "tx,client,amount,currency\n1,1,10,USD\n3,1,3,USD\n"
+        );
+    }
+
+    #[test]
+    fn resolved_dispute_leaves_the_disputes_report() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Resolve, 1, None),
+            ],
+        );
+        assert_eq!(disputes_report_of(&mut manager), "");
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn charged_back_dispute_leaves_the_disputes_report_and_locks_the_account() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Chargeback, 1, None),
+            ],
+        );
+        assert_eq!(
+            disputes_report_of(&mut manager),
+            "tx,client,amount,currency\n2,1,5,USD\n"
+        );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::new(5, 0), true);
+    }
+
+    fn statement_of(manager: &mut DefaultTransactionsManager, client_id: CustomerId) -> String {
+        let mut output = vec![];
+        manager
+            .generate_statement(client_id, "USD", &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn statement_lists_the_transactions_with_the_running_balance() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(3, 0))),
+                (TransactionType::Deposit, 3, Some(Decimal::new(5, 0))),
+                (TransactionType::Deposit, 4, Some(Decimal::new(2, 0))),
+                (TransactionType::Dispute, 3, None),
+                (TransactionType::Chargeback, 3, None),
+                (TransactionType::Dispute, 4, None),
+                (TransactionType::Resolve, 4, None),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        assert_eq!(
+            statement_of(&mut manager, 1),
+            "tx,type,amount,running_available,state
+1,deposit,10,0,disputed
+2,withdrawal,3,-3,
+3,deposit,5,-3,charged_back
+4,deposit,2,-1,resolved
+"
+        );
+        assert_eq!(statement_of(&mut manager, 2), "");
+    }
+
+    #[test]
+    fn statement_skips_the_duplicate_ids_and_ends_at_the_account_balance() {
+        let mut manager = in_memory_manager(None);
+        let deposit = |client_id, transaction_id, amount| TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(Decimal::new(amount, 0)),
+            timestamp: None,
+            currency: None,
+        };
+        let summary = manager
+            .process_all(
+                vec![
+                    deposit(1, 1, 10),
+                    // A retry, then the same ID reused by another client
+                    deposit(1, 1, 10),
+                    deposit(2, 1, 4),
+                    deposit(2, 2, 4),
+                    deposit(1, 3, 5),
+                ]
+                .into_iter(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(summary.skipped_because(SkipReason::CrossClientIdReuse), 1);
+        run_requests(&mut manager, vec![(TransactionType::Dispute, 3, None)]);
+        assert_eq!(
+            statement_of(&mut manager, 1),
+            "tx,type,amount,running_available,state
+1,deposit,10,10,
+3,deposit,5,10,disputed
+"
+        );
+        assert_eq!(
+            statement_of(&mut manager, 2),
+            "tx,type,amount,running_available,state\n2,deposit,4,4,\n"
+        );
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::new(5, 0), false);
+    }
+
+    #[test]
+    fn statement_warns_when_the_account_differs_from_the_history() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)))],
+        );
+        manager
+            .customer_account_provider
+            .set_available(1, "USD", Decimal::new(12, 0))
+            .unwrap();
+        assert_eq!(
+            statement_of(&mut manager, 1),
+            "tx,type,amount,running_available,state
+1,deposit,10,10,
+warning: the recomputed available balance 10 differs from the account's 12
+"
+        );
+    }
+
+    const UNLOCK_CSV: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,2,
+chargeback,1,2,
+unlock,1,3,
+withdrawal,1,4,4.0
+";
+
+    fn process_unlock_csv(manager: &mut DefaultTransactionsManager) -> ProcessingSummary {
+        let requests = read_transaction_requests(
+            Cursor::new(UNLOCK_CSV),
+            4,
+            RoundingStrategy::ToZero,
+            Rc::new(Cell::new(0)),
+        );
+        manager.process_all(requests, None).unwrap()
+    }
+
+    #[test]
+    fn unlock_after_chargeback_allows_withdrawals_again() {
+        let mut manager = in_memory_manager(None).with_allow_unlock(true);
+        let summary = process_unlock_csv(&mut manager);
+        assert_eq!(summary.executed(&TransactionType::Unlock), 1);
+        assert_eq!(summary.executed(&TransactionType::Withdrawal), 1);
+        assert_account(&mut manager, Decimal::new(6, 0), Decimal::ZERO, false);
+        // The chargeback itself stays in place
+        assert_eq!(
+            manager
+                .transaction_history_provider
+                .read_transaction_state(2),
+            Ok(Some(TransactionState {
+                charged_back: true,
+                dispute_count: 1,
+                ..Default::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn unlock_is_skipped_unless_allowed() {
+        let mut manager = in_memory_manager(None);
+        let summary = process_unlock_csv(&mut manager);
+        assert_eq!(summary.skipped_because(SkipReason::UnlockForbidden), 1);
+        assert_eq!(summary.skipped_because(SkipReason::AccountLocked), 1);
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, true);
+    }
+
+    #[test]
+    fn unlock_is_skipped_while_a_dispute_is_open() {
+        let mut manager = in_memory_manager(None).with_allow_unlock(true);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::TEN)),
+                (TransactionType::Deposit, 2, Some(Decimal::ONE)),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Dispute, 2, None),
+            ],
+        );
+        assert_eq!(
+            run_requests(&mut manager, vec![(TransactionType::Unlock, 3, None)]),
+            vec![Ok(TransactionOutcome::Skipped(SkipReason::OpenDisputes))]
+        );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ONE, true);
+        assert!(run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Resolve, 2, None),
+                (TransactionType::Unlock, 4, None),
+                (TransactionType::Withdrawal, 5, Some(Decimal::ONE)),
+            ],
+        )
+        .iter()
+        .all(|result| result == &Ok(TransactionOutcome::Executed)));
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn unlocking_an_unlocked_or_missing_account_is_skipped() {
+        let mut manager = in_memory_manager(None).with_allow_unlock(true);
+        let unlock = |client_id| TransactionRequest {
+            transaction_type: TransactionType::Unlock,
+            client_id,
+            transaction_id: 2,
+            amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 1, Some(Decimal::ONE))],
+        );
+        assert_eq!(
+            manager.handle_transaction(unlock(1)),
+            Ok(TransactionOutcome::Skipped(SkipReason::NotLocked))
+        );
+        assert_eq!(
+            manager.handle_transaction(unlock(2)),
+            Ok(TransactionOutcome::Skipped(SkipReason::AccountNotFound))
+        );
+        assert!(!manager.structure_validation(&TransactionRequest {
+            amount: Some(Decimal::ONE),
+            ..unlock(1)
+        }));
     }
 
-    fn print_report(&self) -> Result<(), String> {
-        let mut writer = WriterBuilder::new()
-            .has_headers(true)
-            .delimiter(b',')
-            .from_writer(vec![]);
-        if let Some(err) = self
-            .customer_account_provider
-            .list_accounts()?
-            .iter()
-            .inspect(|account| {
-                if account.available.scale() > 4 || account.held.scale() > 4 {
-                    panic!(
-                        "Some available/held values have > 4 scale! {}, {}",
-                        account.available, account.held
-                    )
-                }
-            })
-            .map(|account| writer.serialize(account))
-            .filter(|e| e.is_err())
-            .nth(0)
-        {
-            return err.map_err(|e| e.to_string());
+    fn fraud_config() -> FraudConfig {
+        FraudConfig {
+            max_withdrawals_per_window: Some(3),
+            max_amount_per_window: Some(Decimal::new(100, 0)),
+            window_size: 10,
         }
-        println!(
-            "{}",
-            String::from_utf8(writer.into_inner().map_err(|e| e.to_string())?)
-                .map_err(|e| e.to_string())?
-        );
-        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        customer_account_provider::{InMemoryCustomerAccountProvider, MockCustomerAccountProvider},
-        transaction_history_provider::{
-            in_memory_transaction_history_provider::InMemoryTransactionHistoryProvider,
-            transaction_history_provider::MockTransactionHistoryProvider,
-        },
-    };
+    #[test]
+    fn burst_of_withdrawals_puts_the_account_on_hold() {
+        let mut manager = in_memory_manager(None).with_fraud_config(fraud_config());
+        let mut requests = vec![(TransactionType::Deposit, 1, Some(Decimal::new(50, 0)))];
+        requests.extend((2..=6).map(|transaction_id| {
+            (
+                TransactionType::Withdrawal,
+                transaction_id,
+                Some(Decimal::ONE),
+            )
+        }));
+        let outcomes = run_requests(&mut manager, requests);
+        // The fourth withdrawal is executed and crosses the limit, the fifth one hits the lock
+        assert_eq!(
+            outcomes[1..],
+            [
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked)),
+            ]
+        );
+        assert_account(&mut manager, Decimal::new(46, 0), Decimal::ZERO, true);
+        assert_eq!(manager.summary().fraud_holds, 1);
+    }
 
-    use super::*;
     #[test]
-    fn deposit_works_as_expected_as_first_transaction() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let amount = Decimal::new(10, 4);
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Deposit,
-            client_id,
-            transaction_id,
-            amount: Some(amount),
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        mock_history_provider
-            .expect_write_transaction()
-            .with(eq(transaction_request.clone()))
-            .times(1)
-            .return_const(Ok(()));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(None));
-        mock_customer_account_provider
-            .expect_set_available()
-            .with(eq(client_id), eq(amount))
-            .times(1)
-            .return_const(Ok(()));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.deposit(transaction_request);
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+    fn withdrawn_amount_over_the_limit_puts_the_account_on_hold() {
+        let mut manager = in_memory_manager(None).with_fraud_config(fraud_config());
+        let outcomes = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(500, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(60, 0))),
+                (TransactionType::Withdrawal, 3, Some(Decimal::new(60, 0))),
+                (TransactionType::Withdrawal, 4, Some(Decimal::ONE)),
+            ],
+        );
+        assert_eq!(
+            outcomes[3],
+            Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked))
+        );
+        assert_eq!(manager.summary().fraud_holds, 1);
     }
 
     #[test]
-    fn deposit_works_as_expected_when_some_funds_already_present() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let amount = Decimal::new(10, 0);
-        let existing_amount = Decimal::new(5, 0);
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Deposit,
-            client_id,
-            transaction_id,
-            amount: Some(amount),
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        mock_history_provider
-            .expect_write_transaction()
-            .with(eq(transaction_request.clone()))
-            .times(1)
-            .return_const(Ok(()));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(existing_amount)));
-        mock_customer_account_provider
-            .expect_set_available()
-            .with(eq(client_id), eq(amount + existing_amount))
-            .times(1)
-            .return_const(Ok(()));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.deposit(transaction_request);
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+    fn deposits_never_trigger_the_fraud_rules() {
+        let mut manager = in_memory_manager(None).with_fraud_config(fraud_config());
+        run_requests(
+            &mut manager,
+            (1..=20)
+                .map(|transaction_id| {
+                    (
+                        TransactionType::Deposit,
+                        transaction_id,
+                        Some(Decimal::new(1000, 0)),
+                    )
+                })
+                .collect(),
+        );
+        assert_account(&mut manager, Decimal::new(20_000, 0), Decimal::ZERO, false);
+        assert_eq!(manager.summary().fraud_holds, 0);
+    }
+
+    const CURRENCIES_CSV: &str = "type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,20.0,EUR
+deposit,1,3,5.0,
+dispute,1,2,,USD
+dispute,1,2,,EUR
+";
+
+    fn process_currencies_csv(manager: &mut DefaultTransactionsManager) -> ProcessingSummary {
+        let requests = read_transaction_requests(
+            Cursor::new(CURRENCIES_CSV),
+            4,
+            RoundingStrategy::ToZero,
+            Rc::new(Cell::new(0)),
+        );
+        manager.process_all(requests, None).unwrap()
     }
 
     #[test]
-    fn withdraw_works_as_expected_in_happy_case() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let amount = Decimal::new(5, 0);
-        let existing_amount = Decimal::new(10, 0);
-        let locked = false;
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id,
-            amount: Some(amount),
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        mock_history_provider
-            .expect_write_transaction()
-            .with(eq(transaction_request.clone()))
-            .times(1)
-            .return_const(Ok(()));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(existing_amount)));
-        mock_customer_account_provider
-            .expect_get_locked_status()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(locked)));
-        mock_customer_account_provider
-            .expect_set_available()
-            .with(eq(client_id), eq(existing_amount - amount))
-            .times(1)
-            .return_const(Ok(()));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.withdraw(transaction_request);
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+    fn balances_are_kept_per_currency() {
+        let mut manager = in_memory_manager(None);
+        let summary = process_currencies_csv(&mut manager);
+        // The dispute in USD doesn't match the EUR deposit, only the one in EUR holds its funds
+        assert_eq!(summary.skipped_because(SkipReason::CurrencyMismatch), 1);
+        assert_eq!(summary.executed(&TransactionType::Dispute), 1);
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,0,20,20,false,,EUR,0,1
+1,15,0,15,false,,USD,0,0
+"
+        );
     }
 
     #[test]
-    fn withdraw_skips_when_no_enough_funds_present() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let amount = Decimal::new(10, 0);
-        let existing_amount = Decimal::new(5, 0);
-        let locked = false;
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id,
-            amount: Some(amount),
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(existing_amount)));
-        mock_customer_account_provider
-            .expect_get_locked_status()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(locked)));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.withdraw(transaction_request);
-        assert!(result.is_ok());
-        assert!(!result.unwrap());
+    fn requests_without_currency_use_the_default_one() {
+        let mut manager = in_memory_manager(None).with_default_currency("EUR");
+        process_currencies_csv(&mut manager);
+        // Now the deposit without currency goes to EUR and the dispute in USD is the mismatched one
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,5,20,25,false,,EUR,0,1
+1,10,0,10,false,,USD,0,0
+"
+        );
     }
 
     #[test]
-    fn withdraw_skips_when_no_account_state_found() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let amount = Decimal::new(5, 0);
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id,
-            amount: Some(amount),
-        };
-        let locked = false;
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(None));
-        mock_customer_account_provider
-            .expect_get_locked_status()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(locked)));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.withdraw(transaction_request);
-        assert!(result.is_ok());
-        assert!(!result.unwrap());
+    fn chargeback_in_one_currency_locks_all_of_them() {
+        let mut manager = in_memory_manager(None);
+        process_currencies_csv(&mut manager);
+        let request =
+            |transaction_type, transaction_id, amount, currency: &str| TransactionRequest {
+                transaction_type,
+                client_id: 1,
+                transaction_id,
+                amount,
+                timestamp: None,
+                currency: Some(currency.to_owned()),
+            };
+        assert_eq!(
+            manager.handle_transaction(request(TransactionType::Chargeback, 2, None, "EUR")),
+            Ok(TransactionOutcome::Executed)
+        );
+        assert_eq!(
+            manager.handle_transaction(request(
+                TransactionType::Withdrawal,
+                4,
+                Some(Decimal::ONE),
+                "USD"
+            )),
+            Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked))
+        );
+        // An account opened after the lock is locked as well
+        assert_eq!(
+            manager.handle_transaction(request(
+                TransactionType::Deposit,
+                5,
+                Some(Decimal::ONE),
+                "GBP"
+            )),
+            Ok(TransactionOutcome::Executed)
+        );
+        assert!(manager
+            .list_accounts()
+            .unwrap()
+            .iter()
+            .all(|account| account.locked));
     }
 
     #[test]
-    fn withdraw_skips_when_account_locked() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let amount = Decimal::new(5, 0);
-        let locked = true;
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id,
-            amount: Some(amount),
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_locked_status()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(Some(locked)));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.withdraw(transaction_request);
-        assert!(result.is_ok());
-        assert!(!result.unwrap());
+    fn recompute_account_ignores_rejected_duplicates() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        for client_id in [1, 2] {
+            manager
+                .handle_transaction(TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id,
+                    transaction_id: 1,
+                    amount: Some(Decimal::new(10, 0)),
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            manager
+                .transaction_history_provider
+                .read_client_transactions(1)
+                .map(|transactions| transactions.len()),
+            Ok(1)
+        );
+        assert_eq!(
+            manager
+                .transaction_history_provider
+                .read_client_transactions(2),
+            Ok(vec![])
+        );
+        assert_eq!(
+            manager.recompute_account(2, "USD"),
+            Ok(AccountBalances::default())
+        );
     }
 
     #[test]
-    fn dispute_does_nothing_when_transaction_not_found() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id,
-            transaction_id,
-            amount: None,
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.dispute(transaction_request);
-        assert!(result.is_ok());
-        assert!(!result.unwrap());
+    fn parked_dispute_holds_the_funds_once_the_deposit_arrives() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_out_of_order_tolerance(10);
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Dispute, 1, None),
+                    (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)),
+                Ok(TransactionOutcome::Executed),
+            ]
+        );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::new(10, 0), false);
+        let summary = manager.summary();
+        assert_eq!(summary.executed(&TransactionType::Dispute), 1);
+        assert_eq!(summary.unresolved_references, 0);
     }
 
     #[test]
-    fn resolve_does_nothing_when_transaction_not_found() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Resolve,
-            client_id,
-            transaction_id,
-            amount: None,
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
-        mock_customer_account_provider
-            .expect_get_available()
-            .with(eq(client_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.resolve(transaction_request);
-        assert!(result.is_ok());
-        assert!(!result.unwrap());
+    fn parked_chargeback_locks_the_account_once_the_deposit_arrives() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_out_of_order_tolerance(10);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+            ],
+        );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ZERO, true);
+        // The deposit of the second dispute never arrived
+        assert_eq!(manager.summary().unresolved_references, 1);
     }
 
     #[test]
-    fn chargeback_does_nothing_when_transaction_not_found() {
-        let transaction_id = 1;
-        let client_id = 1;
-        let transaction_request = TransactionRequest {
-            transaction_type: TransactionType::Chargeback,
-            client_id,
-            transaction_id,
-            amount: None,
-        };
-        let mut mock_history_provider = MockTransactionHistoryProvider::new();
-        mock_history_provider
-            .expect_read_transaction()
-            .with(eq(transaction_id))
-            .times(1)
-            .return_const(Ok(None));
-        let mock_customer_account_provider = MockCustomerAccountProvider::new();
-        let mut transactions_manager =
-            DefaultTransactionsManager::new(mock_history_provider, mock_customer_account_provider);
-        let result = transactions_manager.chargeback(transaction_request);
-        assert!(result.is_ok());
-        assert!(!result.unwrap());
+    fn parking_evicts_the_oldest_request_when_full() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_out_of_order_tolerance(1);
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Dispute, 1, None),
+                    (TransactionType::Dispute, 2, None),
+                    (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                    (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)),
+                Ok(TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+            ]
+        );
+        // Only the dispute of the second deposit was still parked when it arrived
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::new(5, 0), false);
+        let summary = manager.summary();
+        assert_eq!(summary.executed(&TransactionType::Dispute), 1);
+        assert_eq!(summary.evicted_references, 1);
+        assert_eq!(summary.unresolved_references, 0);
+
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_out_of_order_tolerance(0);
+        assert_eq!(
+            run_requests(&mut manager, vec![(TransactionType::Dispute, 1, None)]),
+            vec![Ok(TransactionOutcome::Skipped(
+                SkipReason::TransactionNotFound
+            ))]
+        );
+        assert_eq!(manager.summary().evicted_references, 1);
     }
 
-    // Using actual instances from here onwards, because of the issue with mocking
     #[test]
-    fn dispute_does_nothing_when_original_transaction_client_id_is_different() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        let customer_account_provider = InMemoryCustomerAccountProvider::new();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id: 2,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
-        assert_eq!(manager.customer_account_provider.get_available(1), Ok(None));
+    fn resolve_before_the_dispute_stays_parked_and_is_reported() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_out_of_order_tolerance(DEFAULT_MAX_PARKED);
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(None)
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Resolve, 1, None),
+                    (TransactionType::Dispute, 1, None),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)),
+                Ok(TransactionOutcome::Skipped(SkipReason::AwaitingTransaction)),
+            ]
         );
+        let summary = manager.summary();
+        assert_eq!(summary.unresolved_references, 2);
+        assert_eq!(summary.evicted_references, 0);
+        assert!(summary.to_string().contains("unresolved references 2"));
     }
 
     #[test]
-    fn dispute_does_nothing_when_transaction_was_already_held() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let customer_account_provider = InMemoryCustomerAccountProvider::new();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
-        assert_eq!(manager.customer_account_provider.get_available(1), Ok(None));
+    fn disputes_before_the_deposit_are_dropped_without_the_tolerance() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(None)
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Dispute, 1, None),
+                    (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Skipped(SkipReason::TransactionNotFound)),
+                Ok(TransactionOutcome::Executed),
+            ]
         );
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, false);
+        assert_eq!(manager.summary().unresolved_references, 0);
     }
 
     #[test]
-    fn dispute_does_nothing_when_transaction_was_already_charged_back() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
+    fn reconcile_finds_and_repairs_corrupted_balances() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 2, None),
+            ],
+        );
+        assert_eq!(manager.reconcile(false), Ok(vec![]));
+        manager
+            .customer_account_provider
+            .set_available(1, "USD", Decimal::new(3, 0))
             .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: false,
-                    charged_back: true,
-                },
-            )
+        let expected = vec![Discrepancy {
+            client: 1,
+            currency: Some("USD".to_owned()),
+            field: AuditField::Available,
+            expected: AuditValue::Amount(Decimal::new(10, 0)),
+            actual: AuditValue::Amount(Decimal::new(3, 0)),
+        }];
+        assert_eq!(manager.reconcile(false), Ok(expected.clone()));
+        // Only reported so far
+        assert_account(&mut manager, Decimal::new(3, 0), Decimal::new(5, 0), false);
+        assert_eq!(manager.reconcile(true), Ok(expected));
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::new(5, 0), false);
+        assert_eq!(manager.reconcile(false), Ok(vec![]));
+    }
+
+    #[test]
+    fn reconcile_restores_the_lock_of_a_charged_back_client() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+            ],
+        );
+        manager
+            .customer_account_provider
+            .set_locked_status(1, false)
             .unwrap();
-        let customer_account_provider = InMemoryCustomerAccountProvider::new();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
-        assert_eq!(manager.customer_account_provider.get_available(1), Ok(None));
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(None)
+            manager.reconcile(true),
+            Ok(vec![Discrepancy {
+                client: 1,
+                currency: None,
+                field: AuditField::Locked,
+                expected: AuditValue::Flag(true),
+                actual: AuditValue::Flag(false),
+            }])
         );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ZERO, true);
     }
 
     #[test]
-    fn dispute_works_as_expected_in_happy_case() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: false,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::new(10, 0))
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(true));
-        assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+    fn reconcile_finds_nothing_after_a_regular_run() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        for request in generated_requests(2000) {
+            manager.handle_transaction(request).unwrap();
+        }
+        assert_eq!(manager.reconcile(false), Ok(vec![]));
+    }
+
+    #[test]
+    fn disputes_within_the_window_are_accepted() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_dispute_window(2);
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 3, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        assert_eq!(results[3], Ok(TransactionOutcome::Executed));
+        assert_account(&mut manager, Decimal::new(5, 0), Decimal::new(10, 0), false);
+    }
+
+    #[test]
+    fn disputes_outside_of_the_window_are_skipped() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_dispute_window(2);
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Deposit, 3, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                // Already held transactions can still be settled, whatever their age
+                (TransactionType::Deposit, 4, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 5, Some(Decimal::new(10, 0))),
+                (TransactionType::Resolve, 2, None),
+            ],
         );
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+            results[4],
+            Ok(TransactionOutcome::Skipped(
+                SkipReason::DisputeWindowExpired
+            ))
         );
-        assert!(
+        assert_eq!(results[7], Ok(TransactionOutcome::Executed));
+        assert_account(&mut manager, Decimal::new(50, 0), Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn other_clients_transactions_dont_age_the_disputed_one() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_dispute_window(2);
+        manager
+            .handle_transaction(deposit_request(1, Decimal::ONE))
+            .unwrap();
+        for transaction_id in 2..10 {
             manager
-                .transaction_history_provider
-                .as_mut()
-                .read_transaction_state(1)
-                .unwrap()
-                .unwrap()
-                .held
+                .handle_transaction(TransactionRequest {
+                    client_id: 2,
+                    ..deposit_request(transaction_id, Decimal::ONE)
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            run_requests(&mut manager, vec![(TransactionType::Dispute, 1, None)])[0],
+            Ok(TransactionOutcome::Executed)
+        );
+    }
+
+    fn deposit_request(transaction_id: TransactionId, amount: Decimal) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id,
+            amount: Some(amount),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn structure_validation_rejects_non_positive_amounts_by_default() {
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
         );
+        assert!(manager.structure_validation(&deposit_request(1, Decimal::ONE)));
+        assert!(!manager.structure_validation(&deposit_request(1, Decimal::ZERO)));
+        assert!(!manager.structure_validation(&deposit_request(1, -Decimal::ONE)));
+        assert!(manager.structure_validation(&deposit_request(1, Decimal::new(1, 10))));
+    }
+
+    #[test]
+    fn structure_validation_allows_zero_amounts_when_configured() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_validation_config(ValidationConfig {
+            allow_zero_amount: true,
+            ..Default::default()
+        });
+        assert!(!manager.structure_validation(&deposit_request(1, -Decimal::ONE)));
+        let summary = manager
+            .process_all(vec![deposit_request(1, Decimal::ZERO)].into_iter(), None)
+            .unwrap();
+        assert_eq!(summary.executed(&TransactionType::Deposit), 1);
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ZERO, false);
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            manager.transaction_history_provider.read_transaction(1),
+            Ok(Some(deposit_request(1, Decimal::ZERO)))
         );
     }
 
     #[test]
-    fn dispute_works_as_expected_if_transaction_state_doesnt_exist() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
+    fn structure_validation_enforces_the_max_amount() {
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_validation_config(ValidationConfig {
+            max_amount: Some(Decimal::new(100, 0)),
+            ..Default::default()
+        });
+        assert!(manager.structure_validation(&deposit_request(1, Decimal::new(100, 0))));
+        assert!(!manager.structure_validation(&deposit_request(1, Decimal::new(1001, 1))));
+    }
+
+    #[test]
+    fn structure_validation_enforces_the_amount_scale() {
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_validation_config(ValidationConfig {
+            require_amount_scale_le: Some(2),
+            ..Default::default()
+        });
+        assert!(manager.structure_validation(&deposit_request(1, Decimal::new(101, 2))));
+        assert!(!manager.structure_validation(&deposit_request(1, Decimal::new(1001, 3))));
+    }
+
+    #[test]
+    fn follow_up_amounts_are_rejected_or_ignored_as_configured() {
+        let requests = || {
+            [
+                (TransactionType::Deposit, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, Some(Decimal::new(3, 0))),
+                (TransactionType::Chargeback, Some(Decimal::new(3, 0))),
+            ]
+            .into_iter()
+            .map(|(transaction_type, amount)| TransactionRequest {
+                transaction_type,
                 client_id: 1,
                 transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
+                amount,
+                timestamp: None,
+                currency: None,
             })
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::new(10, 0))
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
+        };
+        let mut manager = in_memory_manager(None);
+        let summary = manager.process_all(requests(), None).unwrap();
+        assert_eq!(summary.skipped_because(SkipReason::InvalidStructure), 2);
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, false);
+
+        let mut manager = in_memory_manager(None).with_validation_config(ValidationConfig {
+            ignore_follow_up_amounts: true,
+            ..Default::default()
         });
-        assert_eq!(res, Ok(true));
+        let summary = manager.process_all(requests(), None).unwrap();
+        assert_eq!(summary.executed(&TransactionType::Chargeback), 1);
+        // The whole deposit is charged back, not the amount on the follow-up rows
+        assert_account(&mut manager, Decimal::ZERO, Decimal::ZERO, true);
+    }
+
+    fn run_dispute_matrix(
+        allow_redispute: bool,
+    ) -> Vec<Result<TransactionOutcome, PaymentEngineError>> {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        )
+        .with_allow_redispute(allow_redispute);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Resolve, 1, None),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+            ],
+        )
+    }
+
+    #[test]
+    fn redispute_after_resolve_is_allowed_by_default() {
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            run_dispute_matrix(true),
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(SkipReason::AlreadyDisputed)),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+            ]
         );
+    }
+
+    #[test]
+    fn redispute_after_resolve_is_skipped_when_forbidden() {
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+            run_dispute_matrix(false),
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(SkipReason::AlreadyDisputed)),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(SkipReason::RedisputeForbidden)),
+                Ok(TransactionOutcome::Skipped(SkipReason::NotDisputed)),
+            ]
         );
-        assert!(
-            manager
-                .transaction_history_provider
-                .as_mut()
-                .read_transaction_state(1)
-                .unwrap()
-                .unwrap()
-                .held
+    }
+
+    #[test]
+    fn disputes_beyond_the_limit_are_skipped() {
+        let requests = vec![
+            (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Resolve, 1, None),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Resolve, 1, None),
+            (TransactionType::Dispute, 1, None),
+        ];
+        let mut manager = in_memory_manager(None);
+        assert_eq!(
+            run_requests(&mut manager, requests.clone()).last(),
+            Some(&Ok(TransactionOutcome::Skipped(
+                SkipReason::DisputeLimitReached
+            )))
         );
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, false);
+
+        let mut manager = in_memory_manager(None).with_max_disputes(None);
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            run_requests(&mut manager, requests).last(),
+            Some(&Ok(TransactionOutcome::Executed))
         );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::new(10, 0), false);
     }
 
     #[test]
-    fn dispute_works_as_expected_even_if_account_is_locked() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::new(10, 0))
-            .unwrap();
-        customer_account_provider
-            .set_locked_status(1, true)
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Dispute,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(true));
-        assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+    fn dispute_count_and_resolved_flag_are_tracked() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
         );
-        assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Resolve, 1, None),
+                (TransactionType::Dispute, 1, None),
+            ],
         );
-        assert!(
+        assert_eq!(
             manager
                 .transaction_history_provider
-                .as_mut()
-                .read_transaction_state(1)
-                .unwrap()
-                .unwrap()
-                .held
+                .read_transaction_state(1),
+            Ok(Some(TransactionState {
+                held: true,
+                charged_back: false,
+                dispute_count: 2,
+                resolved: true,
+                sequence: None,
+            }))
+        );
+    }
+
+    // Keeps the events in memory, so they can be checked after the manager used them
+    struct RecordingAuditSink(Rc<RefCell<Vec<AuditEvent>>>);
+
+    impl AuditSink for RecordingAuditSink {
+        fn record(&mut self, event: AuditEvent) -> Result<(), PaymentEngineError> {
+            self.0.borrow_mut().push(event);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn audit_sink_receives_every_changed_field() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_audit_sink(RecordingAuditSink(events.clone()));
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                // Skipped for insufficient funds, so not audited
+                (TransactionType::Withdrawal, 3, Some(Decimal::new(20, 0))),
+                (TransactionType::Withdrawal, 4, Some(Decimal::new(3, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Resolve, 1, None),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Chargeback, 2, None),
+            ],
         );
+        let mut csv_audit_sink = CsvAuditSink::new(vec![]);
+        for event in events.borrow().iter() {
+            csv_audit_sink.record(event.clone()).unwrap();
+        }
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(true))
+            String::from_utf8(csv_audit_sink.into_inner().unwrap()).unwrap(),
+            "client,field,old_value,new_value,tx,type,currency
+1,available,0,10,1,deposit,USD
+1,available,10,15,2,deposit,USD
+1,available,15,12,4,withdrawal,USD
+1,available,12,2,1,dispute,USD
+1,held,0,10,1,dispute,USD
+1,available,2,12,1,resolve,USD
+1,held,10,0,1,resolve,USD
+1,available,12,7,2,dispute,USD
+1,held,0,5,2,dispute,USD
+1,held,5,0,2,chargeback,USD
+1,locked,false,true,2,chargeback,USD
+"
         );
     }
 
     #[test]
-    fn resolve_does_nothing_if_client_ids_dont_match() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
+    fn skipped_transactions_are_not_audited() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_audit_sink(RecordingAuditSink(events.clone()));
+        let outcomes = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Withdrawal, 1, Some(Decimal::ONE)),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Deposit, 2, Some(Decimal::ONE)),
+                (TransactionType::Deposit, 2, Some(Decimal::ONE)),
+                (TransactionType::Resolve, 2, None),
+            ],
+        );
+        assert_eq!(
+            outcomes
+                .iter()
+                .filter(|outcome| **outcome == Ok(TransactionOutcome::Executed))
+                .count(),
+            1
+        );
+        assert_eq!(events.borrow().len(), 1);
+        assert_eq!(events.borrow()[0].transaction_id, 2);
+    }
+
+    struct RecordingAuditRecordSink(Rc<RefCell<Vec<AuditRecord>>>);
+
+    impl AuditRecordSink for RecordingAuditRecordSink {
+        fn record(&mut self, record: AuditRecord) -> Result<(), PaymentEngineError> {
+            self.0.borrow_mut().push(record);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn strict_and_invalid_requests_get_an_audit_record() {
+        let records = Rc::new(RefCell::new(vec![]));
+        let mut manager = in_memory_manager(None)
+            .with_strict_mode(true)
+            .with_audit_record_sink(RecordingAuditRecordSink(records.clone()));
+        let request = |transaction_type, transaction_id, amount| TransactionRequest {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount,
+            timestamp: None,
+            currency: None,
+        };
+        assert!(manager
+            .process_all(
+                vec![
+                    request(TransactionType::Deposit, 1, Some(Decimal::TWO)),
+                    request(TransactionType::Deposit, 2, None),
+                    request(TransactionType::Withdrawal, 3, Some(Decimal::TEN)),
+                ]
+                .into_iter(),
+                None,
+            )
+            .is_err());
+        let decisions: Vec<_> = records
+            .borrow()
+            .iter()
+            .map(|record| {
+                (
+                    record.transaction_id,
+                    record.decision,
+                    record.reason.clone(),
+                    record.available_after,
+                )
             })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                (1, AuditDecision::Executed, None, Decimal::TWO),
+                (
+                    2,
+                    AuditDecision::Skipped,
+                    Some("invalid structure".to_owned()),
+                    Decimal::TWO
+                ),
+                (
+                    3,
+                    AuditDecision::Skipped,
+                    Some("insufficient funds".to_owned()),
+                    Decimal::TWO
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn listeners_get_the_events_of_a_dispute_then_chargeback() {
+        let listener = CollectingListener::default();
+        let mut manager = in_memory_manager(None)
+            .with_event_listener(listener.clone())
+            .with_event_listener(LoggingListener);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::TEN)),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(8, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Withdrawal, 3, Some(Decimal::ONE)),
+            ],
+        );
+        let executed = |transaction_type, transaction_id| TransactionEvent::Executed {
+            transaction_type,
+            transaction_id,
+            client: 1,
+        };
+        assert_eq!(
+            listener.events(),
+            vec![
+                executed(TransactionType::Deposit, 1),
+                executed(TransactionType::Withdrawal, 2),
+                executed(TransactionType::Dispute, 1),
+                TransactionEvent::NegativeAvailable {
+                    client: 1,
+                    currency: "USD".to_owned(),
+                    available: Decimal::new(-8, 0),
                 },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Resolve,
-            client_id: 2,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
+                // Still negative, so not reported again
+                executed(TransactionType::Chargeback, 1),
+                TransactionEvent::AccountLocked {
+                    client: 1,
+                    transaction_id: 1,
+                },
+                TransactionEvent::Skipped {
+                    transaction_type: TransactionType::Withdrawal,
+                    transaction_id: 3,
+                    client: 1,
+                    reason: SkipReason::AccountLocked,
+                },
+            ]
+        );
+    }
+
+    fn run_requests(
+        manager: &mut DefaultTransactionsManager,
+        requests: Vec<(TransactionType, TransactionId, Option<Decimal>)>,
+    ) -> Vec<Result<TransactionOutcome, PaymentEngineError>> {
+        requests
+            .into_iter()
+            .map(|(transaction_type, transaction_id, amount)| {
+                manager.handle_transaction(TransactionRequest {
+                    transaction_type,
+                    client_id: 1,
+                    transaction_id,
+                    amount,
+                    timestamp: None,
+                    currency: None,
+                })
+            })
+            .collect()
+    }
+
+    fn assert_account(
+        manager: &mut DefaultTransactionsManager,
+        available: Decimal,
+        held: Decimal,
+        locked: bool,
+    ) {
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            manager.customer_account_provider.get_available(1, "USD"),
+            Ok(Some(available))
         );
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+            manager.customer_account_provider.get_held_amount(1, "USD"),
+            Ok(Some(held))
         );
         assert_eq!(
             manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            Ok(Some(locked))
         );
     }
 
     #[test]
-    fn resolve_does_nothing_if_already_charged_back() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: false,
-                    charged_back: true,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
-        customer_account_provider
-            .set_locked_status(1, true)
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Resolve,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
+    fn deposit_dispute_then_resolve_returns_funds_to_available() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            results,
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed)
+            ]
+        );
+        assert_account(&mut manager, Decimal::ZERO, Decimal::new(10, 0), false);
+        let results = run_requests(&mut manager, vec![(TransactionType::Resolve, 1, None)]);
+        assert_eq!(results, vec![Ok(TransactionOutcome::Executed)]);
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn deposit_dispute_then_chargeback_removes_funds_and_locks() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+            ],
         );
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+            results,
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed)
+            ]
+        );
+        assert_account(&mut manager, Decimal::new(5, 0), Decimal::ZERO, true);
+    }
+
+    #[test]
+    fn withdrawal_after_a_chargeback_is_skipped_on_the_locked_account() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Withdrawal, 3, Some(Decimal::new(1, 0))),
+            ],
         );
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(true))
+            results.last(),
+            Some(&Ok(TransactionOutcome::Skipped(SkipReason::AccountLocked)))
+        );
+        assert_eq!(
+            manager.account(1),
+            Ok(Some(CustomerAccountReport {
+                client: 1,
+                available: Decimal::new(5, 0),
+                held: Decimal::ZERO,
+                total: Decimal::new(5, 0),
+                locked: true,
+                last_activity: None,
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::new(10, 0),
+                open_disputes: 0,
+                total_deposited: Decimal::new(15, 0),
+                total_withdrawn: Decimal::ZERO,
+            }))
         );
     }
 
     #[test]
-    fn resolve_does_nothing_if_not_held() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: false,
-                    charged_back: false,
-                },
-            )
+    fn withdrawal_dispute_after_the_deposit_was_charged_back_holds_only_its_amount() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        )
+        .with_invariant_tracking();
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+                (TransactionType::Chargeback, 1, None),
+                (TransactionType::Dispute, 2, None),
+            ],
+        );
+        assert!(results
+            .iter()
+            .all(|result| *result == Ok(TransactionOutcome::Executed)));
+        // The withdrawn funds already left the account, so the available funds stay where the chargeback left them
+        assert_account(
+            &mut manager,
+            Decimal::new(-10, 0),
+            Decimal::new(10, 0),
+            true,
+        );
+        assert_eq!(manager.verify_invariants(), Ok(vec![]));
+        let results = run_requests(&mut manager, vec![(TransactionType::Resolve, 2, None)]);
+        assert_eq!(results, vec![Ok(TransactionOutcome::Executed)]);
+        assert_account(&mut manager, Decimal::new(-10, 0), Decimal::ZERO, true);
+        assert_eq!(manager.verify_invariants(), Ok(vec![]));
+    }
+
+    #[test]
+    fn held_funds_beyond_the_open_disputes_fail_the_dispute() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_invariant_tracking();
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 1, Some(Decimal::new(10, 0)))],
+        );
+        // A provider holding funds no dispute accounts for
+        manager
+            .customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(5, 0))
             .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
+        assert!(matches!(
+            run_requests(&mut manager, vec![(TransactionType::Dispute, 1, None)])[0],
+            Err(PaymentEngineError::InvariantViolation(_))
+        ));
+    }
+
+    #[test]
+    fn held_funds_beyond_the_open_disputes_fail_the_resolve_without_invariant_tracking() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        // A provider holding more than the open dispute accounts for
+        manager
+            .customer_account_provider
+            .set_held_amount(1, "USD", Decimal::new(15, 0))
             .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Resolve,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            run_requests(&mut manager, vec![(TransactionType::Resolve, 1, None)]),
+            vec![Err(PaymentEngineError::HeldFundsOutOfBounds {
+                client_id: 1,
+                currency: "USD".to_owned(),
+                held: Decimal::new(5, 0),
+                disputed: Decimal::ZERO,
+            })]
         );
+        // Nothing is settled, the dispute stays open
+        assert_account(&mut manager, Decimal::ZERO, Decimal::new(15, 0), false);
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::ZERO))
+            manager
+                .transaction_history_provider
+                .read_transaction_state(1)
+                .unwrap()
+                .map(|state| state.held),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn withdrawal_dispute_holds_without_reducing_available() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(4, 0))),
+                (TransactionType::Dispute, 2, None),
+            ],
         );
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            results,
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed)
+            ]
         );
+        assert_account(&mut manager, Decimal::new(6, 0), Decimal::new(4, 0), false);
     }
 
     #[test]
-    #[should_panic]
-    fn resolve_panics_if_not_enough_held_funds() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::ZERO)
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        manager
-            .resolve(TransactionRequest {
-                transaction_type: TransactionType::Resolve,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            })
-            .unwrap();
+    fn withdrawal_dispute_then_resolve_releases_the_hold() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
+        );
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(4, 0))),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Resolve, 2, None),
+            ],
+        );
+        assert_eq!(
+            results,
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed)
+            ]
+        );
+        assert_account(&mut manager, Decimal::new(6, 0), Decimal::ZERO, false);
     }
 
     #[test]
-    fn resolve_works_as_expected() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Resolve,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(true));
-        assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::new(10, 0)))
+    fn withdrawal_dispute_then_chargeback_credits_the_client_and_locks() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new_with_invariant_checks(true),
         );
-        assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::ZERO))
+        let results = run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(4, 0))),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Chargeback, 2, None),
+            ],
         );
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            results,
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed)
+            ]
         );
+        assert_account(&mut manager, Decimal::new(10, 0), Decimal::ZERO, true);
     }
 
     #[test]
-    fn resolve_works_as_expected_even_if_locked() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
+    fn write_report_writes_the_header_and_rows() {
         let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
         customer_account_provider
-            .set_available(1, Decimal::ZERO)
+            .set_available(1, "USD", Decimal::new(15, 1))
             .unwrap();
         customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
+            .set_held_amount(1, "USD", Decimal::new(2, 0))
             .unwrap();
         customer_account_provider
             .set_locked_status(1, true)
             .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Resolve,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(true));
-        assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::new(10, 0)))
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            customer_account_provider,
         );
+        let mut output: Vec<u8> = vec![];
+        assert_eq!(manager.write_report(&mut output), Ok(()));
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::ZERO))
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n1,1.5,2,3.5,true,,USD,0,0\n"
+        );
+    }
+
+    #[test]
+    fn write_report_writes_nothing_without_accounts() {
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
         );
+        let mut output: Vec<u8> = vec![];
+        assert_eq!(manager.write_report(&mut output), Ok(()));
+        assert_eq!(String::from_utf8(output).unwrap(), "");
+    }
+
+    #[test]
+    fn write_report_sorts_the_rows_by_client() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        for (client_id, transaction_id) in [(5, 1), (1, 2), (3, 3)] {
+            assert_eq!(
+                manager.handle_transaction(TransactionRequest {
+                    transaction_type: TransactionType::Deposit,
+                    client_id,
+                    transaction_id,
+                    amount: Some(Decimal::new(10, 0)),
+                    timestamp: None,
+                    currency: None,
+                }),
+                Ok(TransactionOutcome::Executed)
+            );
+        }
+        let mut output: Vec<u8> = vec![];
+        assert_eq!(manager.write_report(&mut output), Ok(()));
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(true))
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n1,10,0,10,false,,USD,0,0\n3,10,0,10,false,,USD,0,0\n5,10,0,10,false,,USD,0,0\n"
         );
     }
 
     #[test]
-    fn chargeback_does_nothing_if_account_ids_dont_match() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Chargeback,
-            client_id: 2,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
+    fn write_report_keeps_the_provider_order_when_asked() {
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_iter_accounts()
+            .times(1)
+            .returning(|| {
+                Box::new([5, 1].into_iter().map(|client| CustomerAccountReport {
+                    client,
+                    available: Decimal::ONE,
+                    held: Decimal::ZERO,
+                    total: Decimal::ONE,
+                    locked: false,
+                    last_activity: None,
+                    currency: "USD".to_owned(),
+                    total_charged_back: Decimal::ZERO,
+                    open_disputes: 0,
+                    total_deposited: Decimal::ZERO,
+                    total_withdrawn: Decimal::ZERO,
+                }))
+            });
+        mock_customer_account_provider
+            .expect_list_accounts_sorted()
+            .times(0);
+        let manager = DefaultTransactionsManager::new(
+            MockTransactionHistoryProvider::new(),
+            mock_customer_account_provider,
+        )
+        .with_report_order(ReportOrder::ProviderOrder);
+        let mut output: Vec<u8> = vec![];
+        assert_eq!(manager.write_report(&mut output), Ok(()));
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n5,1,0,1,false,,USD,0,0\n1,1,0,1,false,,USD,0,0\n"
+        );
+    }
+
+    // Deterministic mix of all the transaction types over a few clients, referencing earlier transactions for disputes
+    fn generated_requests(count: u32) -> Vec<TransactionRequest> {
+        let mut seed: u64 = 42;
+        let mut next = move |bound: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        (1..=count)
+            .map(|transaction_id| {
+                let client_id = next(20) as CustomerId + 1;
+                let (transaction_type, transaction_id, amount) = match next(10) {
+                    0..=4 => (
+                        TransactionType::Deposit,
+                        transaction_id,
+                        Some(Decimal::new(next(1_000_000) as i64, 4)),
+                    ),
+                    5..=6 => (
+                        TransactionType::Withdrawal,
+                        transaction_id,
+                        Some(Decimal::new(next(1_000_000) as i64, 4)),
+                    ),
+                    7 => (
+                        TransactionType::Dispute,
+                        next(transaction_id as u64) as TransactionId + 1,
+                        None,
+                    ),
+                    8 => (
+                        TransactionType::Resolve,
+                        next(transaction_id as u64) as TransactionId + 1,
+                        None,
+                    ),
+                    _ => (
+                        TransactionType::Chargeback,
+                        next(transaction_id as u64) as TransactionId + 1,
+                        None,
+                    ),
+                };
+                TransactionRequest {
+                    transaction_type,
+                    client_id,
+                    transaction_id,
+                    amount,
+                    timestamp: None,
+                    currency: None,
+                }
+            })
+            .collect()
+    }
+
+    fn report_after(
+        mut manager: DefaultTransactionsManager,
+        requests: Vec<TransactionRequest>,
+    ) -> Vec<u8> {
+        manager.process_all(requests.into_iter(), None).unwrap();
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
+        output
+    }
+
+    fn in_memory_manager(dispute_window: Option<u64>) -> DefaultTransactionsManager {
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
         );
+        match dispute_window {
+            Some(dispute_window) => manager.with_dispute_window(dispute_window),
+            None => manager,
+        }
+    }
+
+    #[test]
+    fn resuming_from_a_snapshot_matches_an_uninterrupted_run() {
+        let requests = generated_requests(3000);
+        let directory = tempfile::tempdir().unwrap();
+        let snapshot_path = directory.path().join("snapshot.json");
+        for dispute_window in [None, Some(5)] {
+            let uninterrupted_report =
+                report_after(in_memory_manager(dispute_window), requests.clone());
+            let mut manager = in_memory_manager(dispute_window);
+            manager
+                .process_all(requests[..1500].iter().cloned(), None)
+                .unwrap();
+            manager
+                .export_state()
+                .unwrap()
+                .write_to(&snapshot_path)
+                .unwrap();
+            let snapshot = Snapshot::read_from(&snapshot_path).unwrap();
+            assert_eq!(snapshot.processed_requests, 1500);
+            let mut restored_manager =
+                DefaultTransactionsManager::import_state(snapshot, DEFAULT_SCALE).unwrap();
+            if let Some(dispute_window) = dispute_window {
+                restored_manager = restored_manager.with_dispute_window(dispute_window);
+            }
+            assert_eq!(
+                report_after(restored_manager, requests[1500..].to_vec()),
+                uninterrupted_report
+            );
+        }
+    }
+
+    #[test]
+    fn restoring_a_checkpoint_matches_an_uninterrupted_run() {
+        let requests = generated_requests(3000);
+        let directory = tempfile::tempdir().unwrap();
+        let uninterrupted_report = report_after(in_memory_manager(None), requests.clone());
+        let mut manager = in_memory_manager(None);
+        manager
+            .process_all(requests[..1500].iter().cloned(), None)
+            .unwrap();
+        manager.checkpoint(directory.path()).unwrap();
+        let restored_manager =
+            DefaultTransactionsManager::restore(directory.path(), DEFAULT_SCALE).unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+            report_after(restored_manager, requests[1500..].to_vec()),
+            uninterrupted_report
         );
+        // The restored history still catches the repeated IDs
+        let mut restored_manager =
+            DefaultTransactionsManager::restore(directory.path(), DEFAULT_SCALE).unwrap();
+        let deposit = requests
+            .iter()
+            .find(|request| request.transaction_type == TransactionType::Deposit)
+            .unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            restored_manager.handle_transaction(deposit.clone()),
+            Ok(TransactionOutcome::Skipped(
+                SkipReason::DuplicateTransaction
+            ))
         );
     }
 
     #[test]
-    fn chargeback_does_nothing_if_not_held() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
+    fn restoring_a_missing_checkpoint_fails() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            DefaultTransactionsManager::restore(directory.path(), DEFAULT_SCALE),
+            Err(PaymentEngineError::Storage(_))
+        ));
+    }
+
+    #[test]
+    fn resuming_keeps_the_scale_of_the_interrupted_run() {
+        let scaled_manager = || {
+            DefaultTransactionsManager::new(
+                InMemoryTransactionHistoryProvider::new(),
+                InMemoryCustomerAccountProvider::new_with_scale(2),
+            )
+            .with_scale(2)
+        };
+        let requests = vec![
+            TransactionRequest {
                 transaction_type: TransactionType::Deposit,
                 client_id: 1,
                 transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: false,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
+                amount: Some(Decimal::new(123456, 5)),
+                timestamp: None,
+                currency: None,
+            },
+            TransactionRequest {
+                transaction_type: TransactionType::Deposit,
+                client_id: 1,
+                transaction_id: 2,
+                amount: Some(Decimal::new(234567, 5)),
+                timestamp: None,
+                currency: None,
+            },
+        ];
+        let uninterrupted_report = report_after(scaled_manager(), requests.clone());
+        let mut manager = scaled_manager();
+        manager
+            .process_all(requests[..1].iter().cloned(), None)
             .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
+        let mut restored_manager =
+            DefaultTransactionsManager::import_state(manager.export_state().unwrap(), 2).unwrap();
+        restored_manager
+            .process_all(requests[1..].iter().cloned(), None)
             .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Chargeback,
+        // Truncated to 1.23 and 2.34 like in the uninterrupted run
+        assert_account(
+            &mut restored_manager,
+            Decimal::new(357, 2),
+            Decimal::ZERO,
+            false,
+        );
+        let mut restored_report = vec![];
+        restored_manager.write_report(&mut restored_report).unwrap();
+        assert_eq!(restored_report, uninterrupted_report);
+    }
+
+    #[test]
+    fn write_batches_give_the_same_state_as_single_writes() {
+        let mut requests = vec![];
+        for (position, request) in generated_requests(3000).into_iter().enumerate() {
+            requests.push(request.clone());
+            if request.transaction_type == TransactionType::Deposit && position % 40 == 0 {
+                // A retry and a reuse by another client, mostly within the same batch
+                requests.push(request.clone());
+                requests.push(TransactionRequest {
+                    client_id: request.client_id % 20 + 1,
+                    ..request
+                });
+            }
+        }
+        // Repeating transactions of the batches written long before
+        requests.extend(requests[..200].to_vec());
+        let state_after = |manager: DefaultTransactionsManager| {
+            let mut manager = manager.with_out_of_order_tolerance(10);
+            let summary = manager.process_all(requests.iter().cloned(), None).unwrap();
+            (summary, manager.export_state().unwrap())
+        };
+        let (unbatched_summary, unbatched_state) = state_after(in_memory_manager(Some(50)));
+        assert!(unbatched_summary.skipped_because(SkipReason::CrossClientIdReuse) > 0);
+        for batch_size in [1, 7, 500] {
+            let (summary, state) =
+                state_after(in_memory_manager(Some(50)).with_write_batch_size(batch_size));
+            assert_eq!(summary, unbatched_summary);
+            assert_eq!(state, unbatched_state);
+        }
+    }
+
+    #[test]
+    fn write_batch_is_written_before_a_dispute_reads_it() {
+        let mut manager = in_memory_manager(None).with_write_batch_size(100);
+        let request = |transaction_type, amount| TransactionRequest {
+            transaction_type,
             client_id: 1,
             transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
-        assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            amount,
+            timestamp: None,
+            currency: None,
+        };
+        let summary = manager
+            .process_all(
+                vec![
+                    request(TransactionType::Deposit, Some(Decimal::TEN)),
+                    request(TransactionType::Dispute, None),
+                    request(TransactionType::Deposit, Some(Decimal::TEN)),
+                ]
+                .into_iter(),
+                None,
+            )
+            .unwrap();
+        assert_eq!(summary.executed(&TransactionType::Dispute), 1);
+        assert_eq!(summary.skipped_because(SkipReason::DuplicateTransaction), 1);
+        assert_account(&mut manager, Decimal::ZERO, Decimal::TEN, false);
+    }
+
+    #[test]
+    fn exact_duplicate_detector_gives_the_same_state_as_the_history_check() {
+        let mut requests = vec![];
+        for (position, request) in generated_requests(3000).into_iter().enumerate() {
+            requests.push(request.clone());
+            if position % 30 == 0 {
+                requests.push(request.clone());
+                requests.push(TransactionRequest {
+                    client_id: request.client_id % 20 + 1,
+                    ..request
+                });
+            }
+        }
+        let state_after = |manager: DefaultTransactionsManager| {
+            let mut manager = manager.with_out_of_order_tolerance(10);
+            let summary = manager.process_all(requests.iter().cloned(), None).unwrap();
+            (summary, manager.export_state().unwrap())
+        };
+        let (history_summary, history_state) = state_after(in_memory_manager(Some(50)));
+        assert!(history_summary.skipped_because(SkipReason::DuplicateTransaction) > 0);
+        assert!(history_summary.skipped_because(SkipReason::CrossClientIdReuse) > 0);
+        for manager in [
+            in_memory_manager(Some(50)),
+            in_memory_manager(Some(50)).with_write_batch_size(7),
+        ] {
+            let manager = manager
+                .with_duplicate_detector(ExactDuplicateDetector::default())
+                .unwrap();
+            assert_eq!(
+                state_after(manager),
+                (history_summary.clone(), history_state.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_detector_is_filled_from_the_restored_history() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 1, Some(Decimal::ONE))],
         );
+        let mut restored_manager = DefaultTransactionsManager::import_state(
+            manager.export_state().unwrap(),
+            DEFAULT_SCALE,
+        )
+        .unwrap()
+        .with_duplicate_detector(ExactDuplicateDetector::default())
+        .unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+            run_requests(
+                &mut restored_manager,
+                vec![(TransactionType::Deposit, 1, Some(Decimal::ONE))]
+            ),
+            vec![Ok(TransactionOutcome::Skipped(
+                SkipReason::DuplicateTransaction
+            ))]
         );
+    }
+
+    // Mistakes every ID for a seen one, like a full bloom filter
+    struct AlwaysSeenDetector;
+
+    impl DuplicateDetector for AlwaysSeenDetector {
+        fn contains(&self, _transaction_id: TransactionId) -> bool {
+            true
+        }
+
+        fn insert(&mut self, _transaction_id: TransactionId) {}
+    }
+
+    #[test]
+    fn detector_false_positives_are_still_executed() {
+        let mut manager = in_memory_manager(None)
+            .with_duplicate_detector(AlwaysSeenDetector)
+            .unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(false))
+            run_requests(
+                &mut manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::TEN)),
+                    (TransactionType::Withdrawal, 2, Some(Decimal::ONE)),
+                    (TransactionType::Deposit, 1, Some(Decimal::TEN)),
+                    (TransactionType::Deposit, 2, Some(Decimal::TEN)),
+                ]
+            ),
+            vec![
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Executed),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::DuplicateTransaction
+                )),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::ConflictingDuplicate
+                )),
+            ]
         );
+        assert_account(&mut manager, Decimal::new(9, 0), Decimal::ZERO, false);
     }
 
     #[test]
-    fn chargeback_does_nothing_if_already_charged_back() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
+    fn bloom_detector_executes_the_same_requests_as_the_history_check() {
+        // Sized for a tenth of the deposits, so a share of the new IDs pass for seen ones
+        let mut expected = BloomDuplicateDetector::new_with_seed(10, 0.1, 3);
+        let false_positives = (1..=100)
+            .filter(|transaction_id| {
+                let seen = expected.contains(*transaction_id);
+                expected.insert(*transaction_id);
+                seen
             })
+            .count();
+        assert!(false_positives > 0);
+        let mut manager = in_memory_manager(None)
+            .with_duplicate_detector(BloomDuplicateDetector::new_with_seed(10, 0.1, 3))
             .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: false,
-                    charged_back: true,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
-        customer_account_provider
-            .set_locked_status(1, true)
+        let results = run_requests(
+            &mut manager,
+            (1..=100)
+                .map(|transaction_id| {
+                    (TransactionType::Deposit, transaction_id, Some(Decimal::ONE))
+                })
+                .collect(),
+        );
+        assert!(results
+            .iter()
+            .all(|result| *result == Ok(TransactionOutcome::Executed)));
+        assert_eq!(
+            run_requests(
+                &mut manager,
+                vec![(TransactionType::Deposit, 1, Some(Decimal::ONE))]
+            ),
+            vec![Ok(TransactionOutcome::Skipped(
+                SkipReason::DuplicateTransaction
+            ))]
+        );
+        assert_account(&mut manager, Decimal::new(100, 0), Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn preview_leaves_the_duplicate_detector_untouched() {
+        let mut manager = in_memory_manager(None)
+            .with_duplicate_detector(ExactDuplicateDetector::default())
             .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Chargeback,
+        let deposit = TransactionRequest {
+            transaction_type: TransactionType::Deposit,
             client_id: 1,
             transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(false));
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            currency: None,
+        };
+        assert!(matches!(
+            manager.preview_transaction(&deposit),
+            Ok(TransactionPreview::WouldExecute { .. })
+        ));
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            manager.handle_transaction(deposit),
+            Ok(TransactionOutcome::Executed)
         );
-        assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::new(10, 0)))
+        assert_account(&mut manager, Decimal::ONE, Decimal::ZERO, false);
+    }
+
+    #[test]
+    fn restored_manager_still_detects_duplicates() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 1, Some(Decimal::ONE))],
         );
+        let mut restored_manager = DefaultTransactionsManager::import_state(
+            manager.export_state().unwrap(),
+            DEFAULT_SCALE,
+        )
+        .unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(true))
+            run_requests(
+                &mut restored_manager,
+                vec![
+                    (TransactionType::Deposit, 1, Some(Decimal::ONE)),
+                    (TransactionType::Withdrawal, 1, Some(Decimal::TWO)),
+                ],
+            ),
+            vec![
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::DuplicateTransaction
+                )),
+                Ok(TransactionOutcome::Skipped(
+                    SkipReason::ConflictingDuplicate
+                )),
+            ]
         );
     }
 
     #[test]
-    #[should_panic]
-    fn chargeback_panics_if_not_enough_held_funds() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::ZERO)
+    fn sled_providers_export_the_same_state_as_in_memory_ones() {
+        let requests = generated_requests(1000);
+        let mut in_memory_manager = in_memory_manager(Some(5));
+        in_memory_manager
+            .process_all(requests.clone().into_iter(), None)
             .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        manager
-            .handle_transaction(TransactionRequest {
-                transaction_type: TransactionType::Chargeback,
-                client_id: 1,
-                transaction_id: 1,
-                amount: None,
-            })
+        let mut sled_manager = DefaultTransactionsManager::new(
+            SledTransactionHistoryProvider::new_temporary().unwrap(),
+            SledCustomerAccountProvider::new_temporary().unwrap(),
+        )
+        .with_dispute_window(5);
+        sled_manager
+            .process_all(requests.into_iter(), None)
             .unwrap();
+        assert_eq!(
+            sled_manager.export_state(),
+            in_memory_manager.export_state()
+        );
     }
 
     #[test]
-    fn chargeback_works_as_expected() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
-            )
-            .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Chargeback,
+    fn preview_agrees_with_handle_transaction_and_changes_nothing() {
+        let mut manager = in_memory_manager(Some(5));
+        for request in generated_requests(500) {
+            if !manager.structure_validation(&request) {
+                continue;
+            }
+            let state_before = manager.export_state().unwrap();
+            let preview = manager.preview_transaction(&request).unwrap();
+            assert_eq!(manager.export_state().unwrap(), state_before);
+            let client_id = request.client_id;
+            match manager.handle_transaction(request).unwrap() {
+                TransactionOutcome::Executed => assert_eq!(
+                    preview,
+                    TransactionPreview::WouldExecute {
+                        available: manager
+                            .customer_account_provider
+                            .get_available(client_id, "USD")
+                            .unwrap()
+                            .unwrap(),
+                        held: manager
+                            .customer_account_provider
+                            .get_held_amount(client_id, "USD")
+                            .unwrap()
+                            .unwrap(),
+                    }
+                ),
+                TransactionOutcome::Skipped(reason) => {
+                    assert_eq!(preview, TransactionPreview::WouldSkip(reason))
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn preview_of_a_dispute_shows_the_resulting_balances() {
+        let mut manager = in_memory_manager(None);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(10, 0))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(5, 0))),
+            ],
+        );
+        let dispute = |transaction_id| TransactionRequest {
+            transaction_type: TransactionType::Dispute,
             client_id: 1,
-            transaction_id: 1,
+            transaction_id,
             amount: None,
-        });
-        assert_eq!(res, Ok(true));
+            timestamp: None,
+            currency: None,
+        };
+        let accounts_before = manager.list_accounts();
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            manager.preview_transaction(&dispute(1)),
+            Ok(TransactionPreview::WouldExecute {
+                available: Decimal::new(5, 0),
+                held: Decimal::new(10, 0),
+            })
         );
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::ZERO))
+            manager.preview_transaction(&dispute(3)),
+            Ok(TransactionPreview::WouldSkip(
+                SkipReason::TransactionNotFound
+            ))
         );
+        assert_eq!(manager.list_accounts(), accounts_before);
+        // Previews are not counted as handled requests
+        assert_eq!(manager.summary().executed(&TransactionType::Dispute), 0);
+        assert_eq!(manager.summary().skipped(&TransactionType::Dispute), 0);
+    }
+
+    #[test]
+    fn report_tracks_the_charged_back_volume_and_open_disputes() {
+        let content = "type,client,tx,amount
+deposit,1,1,10.5
+deposit,1,2,3.25
+dispute,1,1,
+dispute,1,2,
+resolve,1,1,
+chargeback,1,2,
+";
+        let mut manager = in_memory_manager(None).with_scale(2);
+        manager
+            .process_all(
+                read_transaction_requests(
+                    Cursor::new(content),
+                    2,
+                    RoundingStrategy::ToZero,
+                    Rc::new(Cell::new(0)),
+                ),
+                None,
+            )
+            .unwrap();
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(true))
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,10.5,0.00,10.50,true,,USD,3.25,0
+"
         );
     }
 
     #[test]
-    fn chargeback_works_as_expected_even_if_already_locked() {
-        let mut history_provider = InMemoryTransactionHistoryProvider::new();
-        history_provider
-            .write_transaction(TransactionRequest {
-                transaction_type: TransactionType::Deposit,
-                client_id: 1,
-                transaction_id: 1,
-                amount: Some(Decimal::new(10, 0)),
-            })
-            .unwrap();
-        history_provider
-            .write_transaction_state(
-                1,
-                TransactionState {
-                    held: true,
-                    charged_back: false,
-                },
+    fn fixed_scale_report_pads_the_amounts_to_the_scale() {
+        let mut manager = in_memory_manager(None)
+            .with_report_format(ReportFormat::Json)
+            .with_fixed_scale_report(true);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(15, 1))),
+                (TransactionType::Deposit, 2, Some(Decimal::new(123456, 5))),
+                (TransactionType::Dispute, 2, None),
+                (TransactionType::Chargeback, 2, None),
+            ],
+        );
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
+        // The amounts stay JSON strings, while locked is a boolean
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":true,\
+             \"last_activity\":null,\"currency\":\"USD\",\"total_charged_back\":\"1.2345\",\"open_disputes\":0}]\n"
+        );
+    }
+
+    #[test]
+    fn detailed_report_adds_the_deposited_and_withdrawn_amounts() {
+        let content = "type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5.5
+withdrawal,1,3,3
+withdrawal,1,4,100
+dispute,1,2,
+chargeback,1,2,
+";
+        let report = |detailed_report: bool| {
+            let mut manager = in_memory_manager(None)
+                .with_scale(2)
+                .with_detailed_report(detailed_report);
+            manager
+                .process_all(
+                    read_transaction_requests(
+                        Cursor::new(content),
+                        2,
+                        RoundingStrategy::ToZero,
+                        Rc::new(Cell::new(0)),
+                    ),
+                    None,
+                )
+                .unwrap();
+            let mut output = vec![];
+            manager.write_report(&mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        // The failed withdrawal is not counted, and the charged back deposit stays in the deposited amount
+        assert_eq!(
+            report(true),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes,total_deposited,total_withdrawn
+1,7.0,0.0,7.0,true,,USD,5.5,0,15.5,3
+"
+        );
+        assert_eq!(
+            report(false),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,7.0,0.0,7.0,true,,USD,5.5,0
+"
+        );
+    }
+
+    fn report_with_scale(scale: u32) -> String {
+        let content = "type,client,tx,amount
+deposit,1,1,1.123456789
+deposit,1,2,2.5
+withdrawal,1,3,0.333333333
+deposit,2,4,0.999999999
+dispute,2,4,
+";
+        let mut manager = in_memory_manager(None).with_scale(scale);
+        manager
+            .process_all(
+                read_transaction_requests(
+                    Cursor::new(content),
+                    scale,
+                    RoundingStrategy::ToZero,
+                    Rc::new(Cell::new(0)),
+                ),
+                None,
             )
             .unwrap();
-        let mut customer_account_provider = InMemoryCustomerAccountProvider::new();
-        customer_account_provider
-            .set_available(1, Decimal::ZERO)
-            .unwrap();
-        customer_account_provider
-            .set_held_amount(1, Decimal::new(10, 0))
-            .unwrap();
+        let mut output = vec![];
+        manager.write_report(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn report_keeps_the_configured_scale() {
+        assert_eq!(
+            report_with_scale(2),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,3.29,0,3.29,false,,USD,0,0
+2,0.00,0.99,0.99,false,,USD,0,1
+"
+        );
+        assert_eq!(
+            report_with_scale(8),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,3.29012345,0,3.29012345,false,,USD,0,0
+2,0.00000000,0.99999999,0.99999999,false,,USD,0,1
+"
+        );
+    }
+
+    #[test]
+    fn balances_beyond_the_scale_are_normalized_by_the_provider() {
+        let mut customer_account_provider = InMemoryCustomerAccountProvider::new_with_scale(4);
         customer_account_provider
-            .set_locked_status(1, true)
+            .set_available(1, "USD", Decimal::new(1000000, 5))
             .unwrap();
-        let mut manager =
-            DefaultTransactionsManager::new(history_provider, customer_account_provider);
-        let res = manager.handle_transaction(TransactionRequest {
-            transaction_type: TransactionType::Chargeback,
-            client_id: 1,
-            transaction_id: 1,
-            amount: None,
-        });
-        assert_eq!(res, Ok(true));
+        let manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            customer_account_provider,
+        );
+        let mut report = vec![];
+        manager.write_report(&mut report).unwrap();
         assert_eq!(
-            manager.customer_account_provider.get_available(1),
-            Ok(Some(Decimal::ZERO))
+            String::from_utf8(report).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes
+1,10.0000,0,10.0000,false,,USD,0,0
+"
+        );
+    }
+
+    #[test]
+    fn amounts_beyond_the_scale_are_normalized_before_use() {
+        let mut manager = in_memory_manager(None).with_scale(2);
+        run_requests(
+            &mut manager,
+            vec![
+                (TransactionType::Deposit, 1, Some(Decimal::new(1999, 3))),
+                (TransactionType::Withdrawal, 2, Some(Decimal::new(15, 3))),
+                (TransactionType::Dispute, 1, None),
+            ],
+        );
+        assert_account(
+            &mut manager,
+            Decimal::new(-1, 2),
+            Decimal::new(199, 2),
+            false,
         );
         assert_eq!(
-            manager.customer_account_provider.get_held_amount(1),
-            Ok(Some(Decimal::ZERO))
+            manager.recompute_account(1, "USD"),
+            Ok(AccountBalances {
+                available: Decimal::new(-1, 2),
+                held: Decimal::new(199, 2),
+            })
+        );
+    }
+
+    #[test]
+    fn sled_providers_produce_the_same_report_as_in_memory_ones() {
+        let requests = generated_requests(3000);
+        let in_memory_report = report_after(
+            DefaultTransactionsManager::new(
+                InMemoryTransactionHistoryProvider::new(),
+                InMemoryCustomerAccountProvider::new(),
+            ),
+            requests.clone(),
+        );
+        let sled_report = report_after(
+            DefaultTransactionsManager::new(
+                SledTransactionHistoryProvider::new_temporary().unwrap(),
+                SledCustomerAccountProvider::new_temporary().unwrap(),
+            ),
+            requests,
+        );
+        assert_eq!(in_memory_report.iter().filter(|c| **c == b'\n').count(), 21);
+        assert_eq!(sled_report, in_memory_report);
+    }
+
+    #[test]
+    fn write_report_includes_the_last_activity_of_the_client() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        );
+        for (transaction_type, client_id, transaction_id, amount, timestamp) in [
+            (
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+                Some(300),
+            ),
+            (
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Decimal::new(5, 0)),
+                Some(100),
+            ),
+            (TransactionType::Dispute, 1, 2, None, Some(200)),
+            // Skipped requests are not an activity of the client
+            (
+                TransactionType::Withdrawal,
+                1,
+                3,
+                Some(Decimal::new(50, 0)),
+                Some(400),
+            ),
+            (
+                TransactionType::Deposit,
+                2,
+                4,
+                Some(Decimal::new(1, 0)),
+                None,
+            ),
+        ] {
+            manager
+                .handle_transaction(TransactionRequest {
+                    transaction_type,
+                    client_id,
+                    transaction_id,
+                    amount,
+                    timestamp,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let mut output: Vec<u8> = vec![];
+        assert_eq!(manager.write_report(&mut output), Ok(()));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n1,10,5,15,false,300,USD,0,1\n2,1,0,1,false,,USD,0,0\n"
+        );
+    }
+
+    #[test]
+    fn write_report_writes_json_lines_when_asked() {
+        let mut manager = DefaultTransactionsManager::new(
+            InMemoryTransactionHistoryProvider::new(),
+            InMemoryCustomerAccountProvider::new(),
+        )
+        .with_report_format(ReportFormat::Jsonl);
+        run_requests(
+            &mut manager,
+            vec![(TransactionType::Deposit, 1, Some(Decimal::new(15, 1)))],
         );
+        let mut output: Vec<u8> = vec![];
+        assert_eq!(manager.write_report(&mut output), Ok(()));
         assert_eq!(
-            manager.customer_account_provider.get_locked_status(1),
-            Ok(Some(true))
+            String::from_utf8(output).unwrap(),
+            "{\"client\":1,\"available\":\"1.5\",\"held\":\"0\",\"total\":\"1.5\",\"locked\":false,\"last_activity\":null,\"currency\":\"USD\",\"total_charged_back\":\"0\",\"open_disputes\":0}\n"
         );
     }
 
-    // chargeback
-    // Works as expected, even if locked
+    // Only counts the written bytes, so the test itself doesn't hold the report in memory
+    struct CountingWriter {
+        written: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streamed_report_is_byte_identical_to_the_listed_one() {
+        let mut manager = in_memory_manager(None).with_report_order(ReportOrder::ProviderOrder);
+        manager
+            .process_all(generated_requests(500).into_iter(), None)
+            .unwrap();
+        let accounts = manager.customer_account_provider.list_accounts().unwrap();
+        assert!(accounts.len() > 10);
+        for format in [
+            ReportFormat::Csv,
+            ReportFormat::Jsonl,
+            ReportFormat::Json,
+            ReportFormat::Table,
+        ] {
+            let formatter = format.formatter(false);
+            let mut listed = vec![];
+            formatter.format(&accounts, &mut listed).unwrap();
+            let mut streamed = vec![];
+            manager
+                .write_report_with(formatter.as_ref(), &mut streamed)
+                .unwrap();
+            assert_eq!(streamed, listed);
+        }
+    }
+
+    // With the provider order the rows are streamed one by one, so the peak memory doesn't grow with the
+    // number of accounts (the previous Vec based path held all 1M reports plus the whole CSV buffer at once)
+    #[test]
+    fn write_report_streams_a_million_accounts_without_listing_them() {
+        let mut mock_customer_account_provider = MockCustomerAccountProvider::new();
+        mock_customer_account_provider
+            .expect_iter_accounts()
+            .times(1)
+            .returning(|| {
+                Box::new((0..1_000_000u32).map(|i| CustomerAccountReport {
+                    client: (i % (CustomerId::MAX as u32 + 1)) as CustomerId,
+                    available: Decimal::ONE,
+                    held: Decimal::ZERO,
+                    total: Decimal::ONE,
+                    locked: false,
+                    last_activity: None,
+                    currency: "USD".to_owned(),
+                    total_charged_back: Decimal::ZERO,
+                    open_disputes: 0,
+                    total_deposited: Decimal::ZERO,
+                    total_withdrawn: Decimal::ZERO,
+                }))
+            });
+        mock_customer_account_provider
+            .expect_list_accounts()
+            .times(0);
+        let manager = DefaultTransactionsManager::new(
+            MockTransactionHistoryProvider::new(),
+            mock_customer_account_provider,
+        )
+        .with_report_order(ReportOrder::ProviderOrder);
+        let mut output = CountingWriter { written: 0 };
+        assert_eq!(manager.write_report(&mut output), Ok(()));
+        let header = "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n".len();
+        assert!(output.written > header + 1_000_000 * "0,1,0,1,false,,USD,0,0\n".len());
+    }
 }
@@ -0,0 +1,128 @@
+//! The checks of the requests shared by the transactions managers and the DryRunValidator, so the dry run can't drift
+//! from what the managers accept.
+
+use log::warn;
+use rust_decimal::Decimal;
+
+use crate::transaction_request::{TransactionRequest, TransactionType};
+
+// Rules for the amounts of deposits and withdrawals, by default only positive amounts are accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ValidationConfig {
+    // Zero-amount deposits create the account row without moving any money
+    pub allow_zero_amount: bool,
+    pub max_amount: Option<Decimal>,
+    pub require_amount_scale_le: Option<u32>,
+    // Accepts the disputes, resolves and chargebacks repeating an amount, as some exports do, dropping it so the
+    // amount of the referenced transaction is used
+    pub ignore_follow_up_amounts: bool,
+}
+
+impl ValidationConfig {
+    pub fn is_valid(&self, transaction_request: &TransactionRequest) -> bool {
+        match &transaction_request.transaction_type {
+            TransactionType::Deposit => self.has_valid_amount(transaction_request),
+            TransactionType::Withdrawal => self.has_valid_amount(transaction_request),
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.ignore_follow_up_amounts || has_no_amount(transaction_request)
+            }
+            TransactionType::Unlock => has_no_amount(transaction_request),
+        }
+    }
+
+    // A deposit or withdrawal above max_amount, which is_valid rejects along with the rest of the invalid amounts
+    pub fn exceeds_max_amount(&self, transaction_request: &TransactionRequest) -> bool {
+        !references_transaction(&transaction_request.transaction_type)
+            && self
+                .max_amount
+                .zip(transaction_request.amount)
+                .is_some_and(|(max_amount, amount)| amount > max_amount)
+    }
+
+    fn has_valid_amount(&self, transaction_request: &TransactionRequest) -> bool {
+        has_positive_amount(transaction_request, self.allow_zero_amount)
+            && !self.exceeds_max_amount(transaction_request)
+            && transaction_request.amount.is_some_and(|amount| {
+                self.require_amount_scale_le
+                    .is_none_or(|scale| amount.scale() <= scale)
+            })
+    }
+
+    // Drops the amount of a follow-up request when ignore_follow_up_amounts accepted it
+    pub(crate) fn drop_ignored_amount(&self, transaction_request: &mut TransactionRequest) {
+        if !self.ignore_follow_up_amounts
+            || !references_transaction(&transaction_request.transaction_type)
+        {
+            return;
+        }
+        if let Some(amount) = transaction_request.amount.take() {
+            warn!(
+                "tx={} client={} ignoring the amount {} of the {:?} request",
+                transaction_request.transaction_id,
+                transaction_request.client_id,
+                amount,
+                transaction_request.transaction_type
+            );
+        }
+    }
+}
+
+// Zero counts as positive only when allowed
+pub(crate) fn has_positive_amount(
+    transaction_request: &TransactionRequest,
+    allow_zero: bool,
+) -> bool {
+    transaction_request
+        .amount
+        .is_some_and(|amount| amount > Decimal::ZERO || (allow_zero && amount.is_zero()))
+}
+
+pub(crate) fn has_no_amount(transaction_request: &TransactionRequest) -> bool {
+    transaction_request.amount.is_none()
+}
+
+// Disputes, resolves and chargebacks reference a deposit or withdrawal by its ID instead of being transactions
+pub(crate) fn references_transaction(transaction_type: &TransactionType) -> bool {
+    matches!(
+        transaction_type,
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(transaction_type: TransactionType, amount: Option<Decimal>) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type,
+            client_id: 1,
+            transaction_id: 1,
+            amount,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn only_the_deposits_and_withdrawals_exceed_the_max_amount() {
+        let validation_config = ValidationConfig {
+            max_amount: Some(Decimal::TEN),
+            ignore_follow_up_amounts: true,
+            ..Default::default()
+        };
+        let above_max = Some(Decimal::new(101, 1));
+        for transaction_type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            let transaction_request = request(transaction_type, above_max);
+            assert!(validation_config.exceeds_max_amount(&transaction_request));
+            assert!(!validation_config.is_valid(&transaction_request));
+        }
+        assert!(!validation_config
+            .exceeds_max_amount(&request(TransactionType::Deposit, Some(Decimal::TEN))));
+        assert!(
+            !validation_config.exceeds_max_amount(&request(TransactionType::Dispute, above_max))
+        );
+        assert!(!ValidationConfig::default()
+            .exceeds_max_amount(&request(TransactionType::Deposit, above_max)));
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{common_types::TransactionId, transaction_request::TransactionRequest};
+
+// Deposits and withdrawals executed by process_all with a write batch size, written to the history together
+pub(crate) struct WriteBatch {
+    // IDs of the batch that were missing from the history when it started, they need no duplicate check read
+    new_ids: HashSet<TransactionId>,
+    // The executed transactions not written yet, in their execution order
+    pending: Vec<TransactionRequest>,
+    pending_positions: HashMap<TransactionId, usize>,
+}
+
+impl WriteBatch {
+    pub(crate) fn new(new_ids: HashSet<TransactionId>) -> Self {
+        WriteBatch {
+            new_ids,
+            pending: Vec::new(),
+            pending_positions: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, transaction_request: TransactionRequest) {
+        self.pending_positions
+            .insert(transaction_request.transaction_id, self.pending.len());
+        self.pending.push(transaction_request);
+    }
+
+    // The executed transaction of the batch with the ID, as it would be read from the history
+    pub(crate) fn pending(&self, transaction_id: TransactionId) -> Option<&TransactionRequest> {
+        self.pending_positions
+            .get(&transaction_id)
+            .map(|position| &self.pending[*position])
+    }
+
+    // True when the ID is neither in the history nor executed in the batch
+    pub(crate) fn is_new(&self, transaction_id: TransactionId) -> bool {
+        self.new_ids.contains(&transaction_id)
+            && !self.pending_positions.contains_key(&transaction_id)
+    }
+
+    // The pending transactions, to be written to the history. They're not new anymore once written.
+    pub(crate) fn take_pending(&mut self) -> Vec<TransactionRequest> {
+        for transaction_id in self.pending_positions.drain().map(|(id, _)| id) {
+            self.new_ids.remove(&transaction_id);
+        }
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::transaction_request::TransactionType;
+
+    use super::*;
+
+    fn deposit(transaction_id: TransactionId) -> TransactionRequest {
+        TransactionRequest {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn taken_transactions_are_neither_pending_nor_new() {
+        let mut write_batch = WriteBatch::new(HashSet::from([1, 2]));
+        write_batch.push(deposit(1));
+        assert_eq!(write_batch.pending(1), Some(&deposit(1)));
+        assert!(!write_batch.is_new(1));
+        assert!(write_batch.is_new(2));
+        assert!(!write_batch.is_new(3));
+        assert_eq!(write_batch.take_pending(), vec![deposit(1)]);
+        assert_eq!(write_batch.pending(1), None);
+        assert!(!write_batch.is_new(1));
+        assert!(write_batch.is_new(2));
+    }
+}
@@ -0,0 +1,38 @@
+use simple_payment_engine::{
+    async_customer_account_provider::SyncCustomerAccountProviderAdapter,
+    async_transactions_manager::AsyncTransactionsManager,
+    transaction_history_provider::async_transaction_history_provider::SyncTransactionHistoryProviderAdapter,
+    transaction_requests_reader::{SyntheticReader, TransactionRequestsReader},
+    DefaultTransactionsManager, InMemoryCustomerAccountProvider,
+    InMemoryTransactionHistoryProvider, TransactionsManager,
+};
+
+// The async manager over the adapted in-memory providers has to give exactly what the sync one gives
+#[tokio::test]
+async fn async_manager_matches_the_sync_one() {
+    let reader = SyntheticReader::new(20_000, 200, 42)
+        .with_withdrawal_percent(30)
+        .with_dispute_percent(10);
+
+    let mut sync_manager = DefaultTransactionsManager::new(
+        InMemoryTransactionHistoryProvider::new(),
+        InMemoryCustomerAccountProvider::new(),
+    );
+    let sync_summary = sync_manager.process_all(reader.read(), None).unwrap();
+    let mut sync_report = vec![];
+    sync_manager.write_report(&mut sync_report).unwrap();
+
+    let mut async_manager = AsyncTransactionsManager::new(
+        SyncTransactionHistoryProviderAdapter::new(InMemoryTransactionHistoryProvider::new()),
+        SyncCustomerAccountProviderAdapter::new(InMemoryCustomerAccountProvider::new()),
+    );
+    let async_summary = async_manager.process_all(reader.read()).await.unwrap();
+    let mut async_report = vec![];
+    async_manager.write_report(&mut async_report).await.unwrap();
+
+    assert_eq!(async_summary, sync_summary);
+    assert_eq!(
+        String::from_utf8(async_report).unwrap(),
+        String::from_utf8(sync_report).unwrap()
+    );
+}
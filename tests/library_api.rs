@@ -0,0 +1,57 @@
+use std::fs;
+
+use rust_decimal::Decimal;
+use simple_payment_engine::{process, CustomerAccountReport, DefaultTransactionRequestsReader};
+
+// Embedding the engine through the crate root only, without the module paths
+#[test]
+fn process_returns_the_final_accounts() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    fs::write(
+        &input_path,
+        "type,client,tx,amount\n\
+         deposit,2,1,5.5\n\
+         deposit,1,2,10\n\
+         withdrawal,1,3,2.25\n\
+         dispute,2,1,\n\
+         chargeback,2,1,\n",
+    )
+    .unwrap();
+
+    let accounts = process(&DefaultTransactionRequestsReader::new(
+        input_path.to_str().unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(
+        accounts,
+        vec![
+            CustomerAccountReport {
+                client: 1,
+                available: Decimal::new(775, 2),
+                held: Decimal::ZERO,
+                total: Decimal::new(775, 2),
+                locked: false,
+                last_activity: None,
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::ZERO,
+                open_disputes: 0,
+                total_deposited: Decimal::TEN,
+                total_withdrawn: Decimal::new(225, 2),
+            },
+            CustomerAccountReport {
+                client: 2,
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                total: Decimal::ZERO,
+                locked: true,
+                last_activity: None,
+                currency: "USD".to_owned(),
+                total_charged_back: Decimal::new(55, 1),
+                open_disputes: 0,
+                total_deposited: Decimal::new(55, 1),
+                total_withdrawn: Decimal::ZERO,
+            },
+        ]
+    );
+}
@@ -0,0 +1,253 @@
+use std::{fs, process::Command};
+
+// The report is usually piped into other tools, so nothing else may end up in stdout, even with all the logs enabled
+#[test]
+fn stdout_contains_only_the_report() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    let log_path = directory.path().join("engine.log");
+    fs::write(
+        &input_path,
+        "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\ndispute,1,9,\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+        .arg(&input_path)
+        .args(["--log-level", "trace"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked,last_activity,currency,total_charged_back,open_disputes\n\
+         1,2,0,2,false,,USD,0,0\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("skip reason=\"insufficient funds\" tx=2 client=1 type=withdrawal"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+        .arg(&input_path)
+        .args(["--log-level", "info", "--log-file"])
+        .arg(&log_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    assert!(fs::read_to_string(&log_path)
+        .unwrap()
+        .contains("skip reason=\"transaction not found\" tx=9 client=1 type=dispute"));
+}
+
+#[test]
+fn report_is_written_into_the_output_file() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    let output_path = directory.path().join("report.json");
+    fs::write(
+        &input_path,
+        "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\ndeposit,2,3,1.5\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+        .arg(&input_path)
+        .args([
+            "--format",
+            "json",
+            "--log-level",
+            "info",
+            "--quiet",
+            "--output",
+        ])
+        .arg(&output_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("reason=\"insufficient funds\""));
+    assert_eq!(
+        fs::read_to_string(&output_path).unwrap(),
+        "[{\"client\":1,\"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false,\
+         \"last_activity\":null,\"currency\":\"USD\",\"total_charged_back\":\"0\",\"open_disputes\":0},\
+         {\"client\":2,\"available\":\"1.5\",\"held\":\"0\",\"total\":\"1.5\",\"locked\":false,\
+         \"last_activity\":null,\"currency\":\"USD\",\"total_charged_back\":\"0\",\"open_disputes\":0}]\n"
+    );
+}
+
+#[test]
+fn threads_give_the_same_report() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    let mut input = "type,client,tx,amount\n".to_owned();
+    for transaction_id in 1..=2000u32 {
+        let client_id = transaction_id % 37;
+        input += &match transaction_id % 7 {
+            0 => format!(
+                "dispute,{},{},\n",
+                client_id,
+                transaction_id.saturating_sub(37)
+            ),
+            1 => format!(
+                "chargeback,{},{},\n",
+                client_id,
+                transaction_id.saturating_sub(38)
+            ),
+            2 | 3 => format!("withdrawal,{},{},1.5\n", client_id, transaction_id),
+            _ => format!("deposit,{},{},2.25\n", client_id, transaction_id),
+        };
+    }
+    fs::write(&input_path, input).unwrap();
+
+    let report_with = |threads: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+            .arg(&input_path)
+            .args(["--threads", threads])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let serial_report = report_with("1");
+    assert!(serial_report.lines().count() > 30);
+    assert_eq!(report_with("4"), serial_report);
+}
+
+#[test]
+fn manifest_refuses_a_rerun_of_the_same_input() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    let copy_path = directory.path().join("transactions-copy.csv");
+    let manifest_path = directory.path().join("manifest.json");
+    let input = "type,client,tx,amount\ndeposit,1,1,2.0\n";
+    fs::write(&input_path, input).unwrap();
+    fs::write(&copy_path, input).unwrap();
+
+    let run = |path: &std::path::Path, extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+            .arg(path)
+            .arg("--manifest")
+            .arg(&manifest_path)
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&input_path, &[]);
+    assert!(output.status.success());
+    assert!(!output.stdout.is_empty());
+    assert!(fs::read_to_string(&manifest_path)
+        .unwrap()
+        .contains("transactions.csv"));
+
+    // The copy has the same content, so it's refused without writing a report
+    let output = run(&copy_path, &[]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("pass --force to process it again"));
+
+    let output = run(&copy_path, &["--force"]);
+    assert!(output.status.success());
+    assert!(!output.stdout.is_empty());
+}
+
+#[test]
+fn journal_carries_the_state_into_the_next_run() {
+    let directory = tempfile::tempdir().unwrap();
+    let journal_path = directory.path().join("journal.jsonl");
+    let header = "type,client,tx,amount\n";
+    let first_half = "deposit,1,1,10.0\ndeposit,2,2,5.0\ndispute,1,1,\n";
+    let second_half = "resolve,1,1,\nwithdrawal,1,3,4.0\nwithdrawal,2,4,9.0\n";
+    let write_input = |name: &str, content: &str| {
+        let path = directory.path().join(name);
+        fs::write(&path, format!("{}{}", header, content)).unwrap();
+        path
+    };
+    let report_of = |path: &std::path::Path, journal: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"));
+        command.arg(path);
+        if journal {
+            command.arg("--journal").arg(&journal_path);
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let uninterrupted = report_of(
+        &write_input("all.csv", &format!("{}{}", first_half, second_half)),
+        false,
+    );
+    report_of(&write_input("first.csv", first_half), true);
+    assert_eq!(
+        report_of(&write_input("second.csv", second_half), true),
+        uninterrupted
+    );
+    assert!(uninterrupted.contains("1,6,0,6,false"));
+}
+
+#[test]
+fn dry_run_writes_the_findings_instead_of_the_report() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    fs::write(
+        &input_path,
+        "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,1,2.0\ndispute,1,9,\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+        .arg(&input_path)
+        .arg("--dry-run")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "row,tx,kind\n2,1,duplicate_transaction_id\n3,9,unknown_reference\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("The dry run found 2 problems"));
+}
+
+#[test]
+fn audit_records_the_decision_and_the_balances_of_every_request() {
+    let directory = tempfile::tempdir().unwrap();
+    let input_path = directory.path().join("transactions.csv");
+    let audit_path = directory.path().join("audit.csv");
+    fs::write(
+        &input_path,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         deposit,1,2,5.0\n\
+         withdrawal,1,3,20.0\n\
+         deposit,2,4,1.0\n\
+         dispute,1,1,\n\
+         chargeback,1,1,\n\
+         withdrawal,1,5,1.0\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_simple_payment_engine"))
+        .arg(&input_path)
+        .arg("--audit")
+        .arg(&audit_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(&audit_path).unwrap(),
+        "tx,client,type,decision,reason,available_after,held_after\n\
+         1,1,deposit,executed,,10,0\n\
+         2,1,deposit,executed,,15,0\n\
+         3,1,withdrawal,skipped,insufficient funds,15,0\n\
+         4,2,deposit,executed,,1,0\n\
+         1,1,dispute,executed,,5,10\n\
+         1,1,chargeback,executed,,5,0\n\
+         5,1,withdrawal,skipped,account locked,5,0\n"
+    );
+}